@@ -29,7 +29,19 @@ openssl x509 -req \
           -set_serial 20283 \
           -extensions server -extfile openssl.cnf
 
+# Self-signed, so servers trusting only the CA above reject it as a client certificate
+openssl req -nodes \
+          -x509 \
+          -newkey rsa:2048 \
+          -keyout untrusted.key \
+          -out untrusted.chain \
+          -sha256 \
+          -batch \
+          -days 2000 \
+          -subj "/CN=untrusted.example.com"
+
 cat server.crt ca.crt > server.chain
 openssl asn1parse -in ca.crt -out ca.der
 openssl rsa -in server.key -out server.rsa
-rm *.csr ca.crt ca.key server.crt server.key
+openssl rsa -in untrusted.key -out untrusted.rsa
+rm *.csr ca.crt ca.key server.crt server.key untrusted.key
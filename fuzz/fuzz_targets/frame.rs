@@ -0,0 +1,20 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use quinn_proto::fuzzing::{Frame, FrameIter};
+use quinn_proto::Version;
+
+fuzz_target!(|data: &[u8]| {
+    for &version in &[Version::V1, Version::V2] {
+        let mut failed = false;
+        for frame in FrameIter::new(Bytes::from(data), version) {
+            // Nothing is decoded past a malformed frame
+            assert!(!failed);
+            match frame {
+                Ok(Frame::Ack(ref ack)) => for _ in ack.iter() {},
+                Ok(_) => {}
+                Err(_) => failed = true,
+            }
+        }
+    }
+});
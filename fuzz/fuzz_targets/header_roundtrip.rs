@@ -0,0 +1,37 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use quinn_proto::fuzzing::{
+    set_payload_length, Crypto, Header, PartialDecode, AEAD_TAG_SIZE, MAX_CID_SIZE,
+};
+use quinn_proto::{ConnectionId, Side, Version};
+
+fuzz_target!(|header: Header| {
+    let id = ConnectionId::new([0; MAX_CID_SIZE], 8);
+    let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+    let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+
+    let mut buf = Vec::new();
+    let partial = header.encode(&mut buf);
+    // Enough payload to sample for header protection
+    buf.extend_from_slice(&[0; 32]);
+    if let Some(slot) = partial.len_slot.clone() {
+        set_payload_length(&mut buf, slot, AEAD_TAG_SIZE).unwrap();
+    }
+    buf.extend_from_slice(&[0; AEAD_TAG_SIZE]);
+    if header.number().is_some() {
+        Header::encrypt_header(&mut buf, partial.header_len, client.local_header_key());
+    }
+
+    let dest_id_len = match header {
+        Header::Short { ref id, .. } => id.len(),
+        _ => 0,
+    };
+    let (decoded, rest) =
+        PartialDecode::new(BytesMut::from(buf), dest_id_len).expect("failed to decode header");
+    assert!(rest.is_empty());
+    let packet = decoded
+        .finish(server.remote_header_key())
+        .expect("failed to finish decoding header");
+    assert_eq!(packet.header, header);
+});
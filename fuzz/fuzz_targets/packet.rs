@@ -0,0 +1,20 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use quinn_proto::fuzzing::{Crypto, PartialDecode, MAX_CID_SIZE};
+use quinn_proto::{ConnectionId, Side, Version};
+
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    // The first byte picks the length of the connection IDs that short headers are decoded with
+    let dest_id_len = data[0] as usize % (MAX_CID_SIZE + 1);
+    let id = ConnectionId::new([0; MAX_CID_SIZE], 8);
+    let crypto = Crypto::new_handshake(&id, Side::Server, Version::V1);
+    for partial in PartialDecode::decode_all(BytesMut::from(&data[1..]), dest_id_len) {
+        if let Ok(partial) = partial {
+            let _ = partial.finish(crypto.remote_header_key());
+        }
+    }
+});
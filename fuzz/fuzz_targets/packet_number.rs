@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use quinn_proto::fuzzing::PacketNumber;
+
+fuzz_target!(|input: (u64, u64)| {
+    let (n, largest_acked) = input;
+    // Expansion must cope with anything a peer can provoke
+    let _ = PacketNumber::U8(n as u8).expand(largest_acked);
+    let _ = PacketNumber::U32(n as u32).expand(largest_acked);
+
+    let max = 1 << 62;
+    if n >= max || largest_acked > n {
+        return;
+    }
+    if let Ok(pn) = PacketNumber::new(n, largest_acked) {
+        assert_eq!(pn.expand(largest_acked), n);
+    }
+});
@@ -0,0 +1,85 @@
+//! Measure the cost of decoding packet headers
+//!
+//! Encodes a short and a long header packet once, then repeatedly decodes each, reporting the mean time per packet
+//! both for the partial decode that picks a connection by ID and for the full decode that also removes header
+//! protection. Each figure includes copying the packet into a fresh buffer, as when it's received.
+//!
+//! Packet parsing is only exposed to the fuzz targets, so run with `cargo bench --features fuzzing --bench decode`.
+
+extern crate bytes;
+extern crate quinn_proto as quinn;
+
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use quinn::fuzzing::{
+    set_payload_length, Crypto, Header, PacketNumber, PartialDecode, AEAD_TAG_SIZE,
+};
+use quinn::{ConnectionId, Side, Version};
+
+/// Number of times each packet is decoded
+const ITERATIONS: u32 = 1000 * 1000;
+/// Size of each packet's payload (bytes)
+const PAYLOAD_LEN: usize = 1200;
+/// Long header packet type of Handshake packets in QUIC version 1
+const HANDSHAKE: u8 = 0x2;
+
+fn main() {
+    let dcid = ConnectionId::from_slice(&[0xab; 8]).unwrap();
+    let scid = ConnectionId::from_slice(&[0xcd; 8]).unwrap();
+    let client = Crypto::new_handshake(&dcid, Side::Client, Version::V1);
+    let server = Crypto::new_handshake(&dcid, Side::Server, Version::V1);
+
+    let short = Header::Short {
+        id: dcid.clone(),
+        number: PacketNumber::U16(0x1234),
+        spin: false,
+        key_phase: false,
+    };
+    let long = Header::Long {
+        version: Version::V1,
+        ty: HANDSHAKE,
+        source_id: scid,
+        destination_id: dcid.clone(),
+        number: PacketNumber::U16(0x1234),
+    };
+    for &(name, header) in &[("short", &short), ("long", &long)] {
+        let mut packet = Vec::new();
+        let partial = header.encode(&mut packet);
+        packet.extend_from_slice(&[0; PAYLOAD_LEN]);
+        if let Some(slot) = partial.len_slot.clone() {
+            set_payload_length(&mut packet, slot, AEAD_TAG_SIZE).unwrap();
+        }
+        packet.extend_from_slice(&[0; AEAD_TAG_SIZE]);
+        Header::encrypt_header(&mut packet, partial.header_len, client.local_header_key());
+
+        let start = Instant::now();
+        let mut check = 0;
+        for _ in 0..ITERATIONS {
+            let (decoded, _) = PartialDecode::new(BytesMut::from(&packet[..]), dcid.len()).unwrap();
+            check += decoded.dst_cid().len();
+        }
+        report(name, "partial", Instant::now() - start, check);
+
+        let start = Instant::now();
+        let mut check = 0;
+        for _ in 0..ITERATIONS {
+            let (decoded, _) = PartialDecode::new(BytesMut::from(&packet[..]), dcid.len()).unwrap();
+            let decoded = decoded.finish(server.remote_header_key()).unwrap();
+            check += decoded.header_data.len();
+        }
+        report(name, "full", Instant::now() - start, check);
+    }
+}
+
+/// Print the mean time taken per iteration, along with `check` so the work can't be optimized away
+fn report(header: &str, stage: &str, dt: Duration, check: usize) {
+    let nanos = dt.as_secs() as f64 * 1e9 + dt.subsec_nanos() as f64;
+    println!(
+        "{} header, {} decode: {:.1}ns ({})",
+        header,
+        stage,
+        nanos / f64::from(ITERATIONS),
+        check
+    );
+}
@@ -0,0 +1,181 @@
+//! Compare congestion controllers on a simulated bottleneck link
+//!
+//! Flows share a single drop-tail queue in front of a fixed-rate link. For each scenario we report every flow's
+//! throughput, mean RTT, and number of packets lost, showing how well each algorithm uses the link, how much queueing
+//! delay it induces, and how fairly it shares with competing flows.
+
+extern crate quinn_proto as quinn;
+
+use std::cmp;
+use std::collections::VecDeque;
+
+use quinn::{
+    BbrFactory, Config, CongestionController, CongestionControllerFactory, CubicFactory,
+    NewRenoFactory,
+};
+
+/// Simulation time step (μs)
+const TICK: u64 = 100;
+/// Length of each scenario (μs)
+const DURATION: u64 = 30 * 1000 * 1000;
+/// Bottleneck bandwidth (bytes/s): 10 Mbps
+const BANDWIDTH: u64 = 1250 * 1000;
+
+fn main() {
+    let config = Config::default();
+    let new_reno = ("NewReno", &NewRenoFactory as &CongestionControllerFactory);
+    let cubic = ("CUBIC", &CubicFactory as &CongestionControllerFactory);
+    let bbr = ("BBR", &BbrFactory as &CongestionControllerFactory);
+
+    println!("# Throughput: a single flow with a 40ms RTT and one BDP of buffer");
+    for &alg in &[new_reno, cubic, bbr] {
+        simulate(&config, &[(alg, 40)], 1.0);
+    }
+
+    println!("# RTT fairness: flows with 20ms and 80ms RTTs");
+    for &alg in &[new_reno, cubic, bbr] {
+        simulate(&config, &[(alg, 20), (alg, 80)], 1.0);
+    }
+
+    println!("# Coexistence: competing algorithms with a 40ms RTT");
+    simulate(&config, &[(bbr, 40), (new_reno, 40)], 1.0);
+    simulate(&config, &[(bbr, 40), (cubic, 40)], 1.0);
+    simulate(&config, &[(cubic, 40), (new_reno, 40)], 1.0);
+}
+
+struct Flow {
+    name: &'static str,
+    cc: Box<CongestionController>,
+    /// Round-trip propagation time (μs)
+    rtprop: u64,
+    smoothed_rtt: u64,
+    next_packet: u64,
+    next_send: u64,
+    /// Ack arrival time, packet number, and send time of every delivered packet in flight, in order
+    acks: VecDeque<(u64, u64, u64)>,
+    /// Number and send time of packets dropped at the bottleneck and not yet detected as lost
+    dropped: VecDeque<(u64, u64)>,
+    delivered: u64,
+    rtt_sum: u64,
+    lost: u64,
+}
+
+impl Flow {
+    fn in_flight(&self) -> u64 {
+        (self.acks.len() + self.dropped.len()) as u64
+    }
+
+    /// Declare lost the dropped packets sent before `packet`, or before `deadline`
+    fn detect_lost(&mut self, mss: u64, packet: u64, deadline: u64) {
+        let mut largest_lost = None;
+        let mut lost = 0;
+        while self
+            .dropped
+            .front()
+            .map_or(false, |&(x, sent)| x < packet || sent < deadline)
+        {
+            largest_lost = self.dropped.pop_front().map(|x| x.0);
+            lost += 1;
+        }
+        if let Some(largest) = largest_lost {
+            self.cc.on_packets_lost(largest, lost * mss);
+            self.lost += lost;
+        }
+    }
+
+    fn on_ack(&mut self, mss: u64, now: u64, packet: u64, sent: u64) {
+        // Later packets arriving reveals the loss of earlier ones
+        self.detect_lost(mss, packet, 0);
+
+        let rtt = now - sent;
+        self.smoothed_rtt = if self.smoothed_rtt == 0 {
+            rtt
+        } else {
+            (7 * self.smoothed_rtt + rtt) / 8
+        };
+        self.cc.on_ack_received(now, self.smoothed_rtt, packet, mss);
+        self.delivered += mss;
+        self.rtt_sum += rtt;
+    }
+}
+
+/// Run each of `flows`, given as an algorithm and RTT (ms), through a bottleneck with `buffer` BDPs of queue
+fn simulate(
+    config: &Config,
+    flows: &[((&'static str, &CongestionControllerFactory), u64)],
+    buffer: f64,
+) {
+    let mss = config.default_mss;
+    let mut flows = flows
+        .iter()
+        .map(|&((name, factory), rtt)| Flow {
+            name,
+            cc: factory.build(config),
+            rtprop: rtt * 1000,
+            smoothed_rtt: 0,
+            next_packet: 1,
+            next_send: 0,
+            acks: VecDeque::new(),
+            dropped: VecDeque::new(),
+            delivered: 0,
+            rtt_sum: 0,
+            lost: 0,
+        })
+        .collect::<Vec<_>>();
+    let max_rtprop = flows.iter().map(|x| x.rtprop).max().unwrap();
+    let capacity = (buffer * (BANDWIDTH * max_rtprop / (1000 * 1000)) as f64) as u64 / mss;
+
+    // Departure times of the packets currently queued at the bottleneck
+    let mut queue = VecDeque::<u64>::new();
+    let mut now = 0;
+    while now < DURATION {
+        while queue.front().map_or(false, |&x| x <= now) {
+            queue.pop_front();
+        }
+        for flow in &mut flows {
+            while flow.acks.front().map_or(false, |x| x.0 <= now) {
+                let (time, packet, sent) = flow.acks.pop_front().unwrap();
+                flow.on_ack(mss, time, packet, sent);
+            }
+            // Losses at the tail of a flight are only detected by timeout
+            let timeout = 2 * cmp::max(flow.smoothed_rtt, flow.rtprop);
+            if now > timeout {
+                flow.detect_lost(mss, 0, now - timeout);
+            }
+        }
+        for flow in &mut flows {
+            while (flow.in_flight() + 1) * mss <= flow.cc.window() && flow.next_send <= now {
+                let packet = flow.next_packet;
+                flow.next_packet += 1;
+                flow.cc.on_packet_sent(now, packet, mss);
+                if queue.len() as u64 >= capacity {
+                    flow.dropped.push_back((packet, now));
+                } else {
+                    let departure = cmp::max(now, queue.back().cloned().unwrap_or(0))
+                        + mss * 1000 * 1000 / BANDWIDTH;
+                    queue.push_back(departure);
+                    flow.acks.push_back((departure + flow.rtprop, packet, now));
+                }
+                flow.next_send = cmp::max(flow.next_send, now)
+                    + flow
+                        .cc
+                        .pacing_rate()
+                        .map_or(0, |rate| mss * 1000 * 1000 / rate);
+            }
+        }
+        now += TICK;
+    }
+
+    for flow in &flows {
+        let packets = flow.delivered / mss;
+        println!(
+            "{:8} {:3}ms: {:5.2} Mbps, mean RTT {:6.1}ms, {:5} lost",
+            flow.name,
+            flow.rtprop / 1000,
+            flow.delivered as f64 * 8.0 / (DURATION as f64 / 1e6) / 1e6,
+            flow.rtt_sum as f64 / cmp::max(packets, 1) as f64 / 1000.0,
+            flow.lost
+        );
+    }
+    println!();
+}
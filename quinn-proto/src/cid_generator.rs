@@ -0,0 +1,72 @@
+use rand::OsRng;
+
+use endpoint::Config;
+use packet::ConnectionId;
+use MAX_CID_SIZE;
+
+/// Chooses the connection IDs an endpoint issues to identify its connections
+///
+/// Peers address packets to these IDs, so a generator can encode information in them for intermediaries to act on, e.g.
+/// the server that a load balancer should route to. Every ID generated must be `Config::local_cid_len` bytes long, or
+/// the connection it was for fails. IDs already in use are discarded and another generated, a few times over.
+pub trait ConnectionIdGenerator: Send {
+    /// Generate a new connection ID
+    fn generate_cid(&mut self) -> ConnectionId;
+
+    /// Whether `cid` might have been generated by this generator
+    ///
+    /// Packets addressed to unknown connection IDs that fail this check are dropped without sending a stateless reset.
+    fn validate_cid(&self, _cid: &ConnectionId) -> bool {
+        true
+    }
+}
+
+/// Reasons a `ConnectionIdGenerator` failed to supply a usable connection ID
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Fail)]
+pub enum ConnectionIdError {
+    #[fail(
+        display = "generated connection ID was {} bytes long, not local_cid_len",
+        _0
+    )]
+    WrongLength(usize),
+    #[fail(display = "generated connection IDs were all in use already")]
+    Exhausted,
+}
+
+/// Constructs the `ConnectionIdGenerator` of each new endpoint
+pub trait ConnectionIdGeneratorFactory: Send + Sync {
+    fn build(&self, config: &Config) -> Box<ConnectionIdGenerator>;
+}
+
+/// Generates connection IDs made up entirely of random bytes
+pub struct RandomConnectionIdGenerator {
+    rng: OsRng,
+    len: usize,
+}
+
+impl RandomConnectionIdGenerator {
+    /// Generate IDs of `len` bytes
+    pub fn new(len: usize) -> Self {
+        assert!(len <= MAX_CID_SIZE, "connection ID too long");
+        Self {
+            rng: OsRng::new().unwrap(),
+            len,
+        }
+    }
+}
+
+impl ConnectionIdGenerator for RandomConnectionIdGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        ConnectionId::random(&mut self.rng, self.len as u8)
+    }
+}
+
+/// Builds a `RandomConnectionIdGenerator` producing IDs of `Config::local_cid_len` bytes
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RandomConnectionIdGeneratorFactory;
+
+impl ConnectionIdGeneratorFactory for RandomConnectionIdGeneratorFactory {
+    fn build(&self, config: &Config) -> Box<ConnectionIdGenerator> {
+        Box::new(RandomConnectionIdGenerator::new(config.local_cid_len))
+    }
+}
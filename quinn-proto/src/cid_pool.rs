@@ -0,0 +1,166 @@
+//! Bookkeeping for the connection IDs we've issued to the peer
+//!
+//! Each ID is numbered in order of issue, starting from the one chosen during the handshake. The peer may address
+//! packets to any ID that it hasn't retired, and retires IDs it no longer needs, e.g. on moving to a new path. We issue
+//! replacements for retired IDs so that the peer always has a spare to migrate with.
+
+use std::collections::{btree_map, BTreeMap};
+use std::vec;
+
+use packet::ConnectionId;
+use TransportError;
+
+/// Connection IDs issued to the peer that it hasn't retired
+#[derive(Debug, Clone)]
+pub struct ConnectionIdPool {
+    /// Active IDs, by sequence number
+    active: BTreeMap<u64, ConnectionId>,
+    /// Sequence number of the next ID to issue
+    next_sequence: u64,
+    /// Largest number of IDs that may be active at once
+    limit: usize,
+    /// IDs the peer has retired that packets are still routed by
+    retired: Vec<ConnectionId>,
+    /// IDs with a smaller sequence number have been, or are being, retired at our request
+    retire_prior_to: u64,
+}
+
+impl ConnectionIdPool {
+    /// Track `initial`, the ID chosen during the handshake, which has sequence number 0
+    pub fn new(initial: ConnectionId, limit: usize) -> Self {
+        let mut active = BTreeMap::new();
+        active.insert(0, initial);
+        Self {
+            active,
+            next_sequence: 1,
+            limit,
+            retired: Vec::new(),
+            retire_prior_to: 0,
+        }
+    }
+
+    /// Change the largest number of IDs that may be active at once, e.g. to respect a limit set by the peer
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Number of IDs that must be issued to bring the pool up to its limit
+    ///
+    /// IDs we've asked the peer to retire don't count, so that they can be replaced before the peer gets around to it.
+    pub fn needed(&self) -> usize {
+        let current = self.active.range(self.retire_prior_to..).count();
+        self.limit.saturating_sub(current)
+    }
+
+    /// Ask the peer to retire every ID issued so far, in the frames issuing their replacements
+    pub fn retire_all(&mut self) {
+        self.retire_prior_to = self.next_sequence;
+    }
+
+    /// Smallest sequence number of an ID the peer may continue to use
+    pub fn retire_prior_to(&self) -> u64 {
+        self.retire_prior_to
+    }
+
+    /// Record the issue of `id`, returning its sequence number
+    pub fn insert(&mut self, id: ConnectionId) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.active.insert(sequence, id);
+        sequence
+    }
+
+    /// Handle the peer retiring the ID with `sequence`
+    ///
+    /// Retiring an ID twice is harmless, as frames may be retransmitted, but retiring one we never issued is not.
+    pub fn retire(&mut self, sequence: u64) -> Result<(), TransportError> {
+        if sequence >= self.next_sequence {
+            return Err(TransportError::PROTOCOL_VIOLATION);
+        }
+        if let Some(id) = self.active.remove(&sequence) {
+            self.retired.push(id);
+        }
+        Ok(())
+    }
+
+    /// The active ID with `sequence`, if any
+    pub fn get(&self, sequence: u64) -> Option<&ConnectionId> {
+        self.active.get(&sequence)
+    }
+
+    /// Take the IDs retired since the last call, which packets should no longer be routed by
+    pub fn drain_retired(&mut self) -> vec::Drain<ConnectionId> {
+        self.retired.drain(..)
+    }
+
+    /// The active IDs, in order of issue
+    pub fn iter(&self) -> btree_map::Values<u64, ConnectionId> {
+        self.active.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use MAX_CID_SIZE;
+
+    fn cid(x: u8) -> ConnectionId {
+        ConnectionId::new([x; MAX_CID_SIZE], 8)
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut pool = ConnectionIdPool::new(cid(0), 3);
+        assert_eq!(pool.needed(), 2);
+        assert_eq!(pool.insert(cid(1)), 1);
+        assert_eq!(pool.insert(cid(2)), 2);
+        assert_eq!(pool.needed(), 0);
+        // A stricter limit from the peer leaves no room for more
+        pool.set_limit(2);
+        assert_eq!(pool.needed(), 0);
+        assert_eq!(
+            pool.iter().cloned().collect::<Vec<_>>(),
+            [cid(0), cid(1), cid(2)]
+        );
+    }
+
+    #[test]
+    fn retire() {
+        let mut pool = ConnectionIdPool::new(cid(0), 2);
+        pool.insert(cid(1));
+        assert_eq!(pool.retire(0), Ok(()));
+        assert_eq!(pool.drain_retired().collect::<Vec<_>>(), [cid(0)]);
+        // Room for a replacement, which gets a fresh sequence number
+        assert_eq!(pool.needed(), 1);
+        assert_eq!(pool.insert(cid(2)), 2);
+        assert_eq!(pool.iter().cloned().collect::<Vec<_>>(), [cid(1), cid(2)]);
+
+        // Retransmitted retirements are ignored
+        assert_eq!(pool.retire(0), Ok(()));
+        assert_eq!(pool.drain_retired().count(), 0);
+        assert_eq!(pool.needed(), 0);
+
+        // IDs that were never issued can't be retired
+        assert_eq!(pool.retire(3), Err(TransportError::PROTOCOL_VIOLATION));
+    }
+
+    #[test]
+    fn retire_all() {
+        let mut pool = ConnectionIdPool::new(cid(0), 2);
+        pool.insert(cid(1));
+        pool.retire_all();
+        assert_eq!(pool.retire_prior_to(), 2);
+        // Replacements may be issued while the old IDs are still in use
+        assert_eq!(pool.needed(), 2);
+        assert_eq!(pool.insert(cid(2)), 2);
+        assert_eq!(pool.insert(cid(3)), 3);
+        assert_eq!(pool.needed(), 0);
+        assert_eq!(pool.iter().count(), 4);
+
+        assert_eq!(pool.retire(0), Ok(()));
+        assert_eq!(pool.retire(1), Ok(()));
+        assert_eq!(pool.drain_retired().collect::<Vec<_>>(), [cid(0), cid(1)]);
+        assert_eq!(pool.needed(), 0);
+        assert_eq!(pool.iter().cloned().collect::<Vec<_>>(), [cid(2), cid(3)]);
+    }
+}
@@ -0,0 +1,655 @@
+//! BBR congestion control, version 1, as described in draft-cardwell-iccrg-bbr-congestion-control-00
+//!
+//! Rather than treating loss as the signal of congestion, BBR models the path by its bottleneck bandwidth and round-trip
+//! propagation time. It paces packets out at the estimated bandwidth and caps the data in flight at a small multiple of
+//! the bandwidth-delay product, periodically probing for more bandwidth and for a lower RTT.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+
+use rand::prng::XorShiftRng;
+use rand::{self, Rng, SeedableRng};
+
+use super::{CongestionController, CongestionControllerFactory, CongestionEvent};
+use endpoint::Config;
+
+/// Gain allowing the sending rate to double every round trip: 2/ln(2)
+const HIGH_GAIN: f64 = 2.885;
+/// Pacing gains cycled through in PROBE_BW, each phase lasting one round-trip propagation time
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// Window gain in PROBE_BW, leaving room for delayed and aggregated acks
+const CWND_GAIN: f64 = 2.0;
+/// Number of round trips over which the bottleneck bandwidth estimate is the maximum delivery rate
+const BTL_BW_FILTER_LEN: u64 = 10;
+/// Age at which the round-trip propagation time estimate goes stale, prompting PROBE_RTT (μs)
+const RTPROP_FILTER_LEN: u64 = 10 * 1000 * 1000;
+/// Minimum time spent in PROBE_RTT (μs)
+const PROBE_RTT_DURATION: u64 = 200 * 1000;
+/// Growth in the bandwidth estimate per round below which STARTUP considers the pipe to be filling up
+const FULL_BW_THRESHOLD: f64 = 1.25;
+/// Number of rounds of insufficient growth after which STARTUP considers the pipe full
+const FULL_BW_COUNT: u32 = 3;
+/// Window during PROBE_RTT, and the least BBR ever uses (packets)
+const MIN_PIPE_CWND: u64 = 4;
+
+/// Phase of the BBR state machine
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BbrMode {
+    /// Rapidly ramping up to find the bottleneck bandwidth
+    Startup,
+    /// Draining the queue created during startup
+    Drain,
+    /// Sending at the bottleneck bandwidth, periodically probing for more
+    ProbeBw,
+    /// Briefly reducing the data in flight to measure the round-trip propagation time
+    ProbeRtt,
+}
+
+/// The BBR algorithm
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    mss: u64,
+    initial_window: u64,
+    mode: BbrMode,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    /// Picks the phase PROBE_BW starts in
+    rng: XorShiftRng,
+
+    //
+    // Path model
+    //
+    /// Windowed maximum of recent delivery rates (round, bytes/s), in decreasing order of rate
+    btl_bw_filter: VecDeque<(u64, u64)>,
+    /// Minimum RTT observed recently (μs)
+    rtprop: Option<u64>,
+    /// When `rtprop` was last set
+    rtprop_stamp: u64,
+    rtprop_expired: bool,
+
+    //
+    // Delivery rate estimation
+    //
+    /// Bytes acknowledged over the lifetime of the connection
+    delivered: u64,
+    /// When `delivered` last changed
+    delivered_time: Option<u64>,
+    /// Packets carrying retransmittable data that have been neither acknowledged nor declared lost
+    sent_packets: BTreeMap<u64, SentPacket>,
+    bytes_in_flight: u64,
+    /// How far out of order a packet may be acknowledged before it's given up on
+    reordering_threshold: u64,
+
+    //
+    // Round trip counting
+    //
+    round_count: u64,
+    /// Value of `delivered` that marks the end of the current round trip
+    next_round_delivered: u64,
+
+    //
+    // STARTUP
+    //
+    filled_pipe: bool,
+    full_bw: u64,
+    full_bw_count: u32,
+
+    //
+    // PROBE_BW
+    //
+    cycle_index: usize,
+    cycle_stamp: u64,
+    /// Whether packets were lost in the current gain cycle phase
+    cycle_loss: bool,
+
+    //
+    // PROBE_RTT
+    //
+    probe_rtt_done_stamp: Option<u64>,
+    probe_rtt_round_done: bool,
+    /// Window to restore once PROBE_RTT or recovery ends
+    prior_window: u64,
+
+    //
+    // Recovery
+    //
+    largest_sent_packet: u64,
+    end_of_recovery: u64,
+    in_recovery: bool,
+}
+
+/// State recorded per packet for estimating the delivery rate once it's acknowledged
+#[derive(Debug, Copy, Clone)]
+struct SentPacket {
+    time: u64,
+    bytes: u64,
+    delivered: u64,
+    delivered_time: u64,
+}
+
+impl Bbr {
+    pub fn new(config: &Config) -> Self {
+        Self::with_seed(config, rand::thread_rng().gen())
+    }
+
+    /// Like `new`, but with the randomness BBR uses to desynchronize competing flows seeded by `seed`
+    pub fn with_seed(config: &Config, seed: [u8; 16]) -> Self {
+        Self {
+            mss: config.default_mss,
+            initial_window: config.initial_window,
+            mode: BbrMode::Startup,
+            window: config.initial_window,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            rng: XorShiftRng::from_seed(seed),
+
+            btl_bw_filter: VecDeque::new(),
+            rtprop: None,
+            rtprop_stamp: 0,
+            rtprop_expired: false,
+
+            delivered: 0,
+            delivered_time: None,
+            sent_packets: BTreeMap::new(),
+            bytes_in_flight: 0,
+            reordering_threshold: u64::from(config.reordering_threshold),
+
+            round_count: 0,
+            next_round_delivered: 0,
+
+            filled_pipe: false,
+            full_bw: 0,
+            full_bw_count: 0,
+
+            cycle_index: 0,
+            cycle_stamp: 0,
+            cycle_loss: false,
+
+            probe_rtt_done_stamp: None,
+            probe_rtt_round_done: false,
+            prior_window: 0,
+
+            largest_sent_packet: 0,
+            end_of_recovery: 0,
+            in_recovery: false,
+        }
+    }
+
+    pub fn mode(&self) -> BbrMode {
+        self.mode
+    }
+
+    /// Estimated bottleneck bandwidth (bytes/s)
+    pub fn bandwidth(&self) -> u64 {
+        self.btl_bw_filter.front().map_or(0, |x| x.1)
+    }
+
+    /// Estimated round-trip propagation time (μs)
+    pub fn min_rtt(&self) -> Option<u64> {
+        self.rtprop
+    }
+
+    fn min_pipe_window(&self) -> u64 {
+        MIN_PIPE_CWND * self.mss
+    }
+
+    /// `gain` times the estimated bandwidth-delay product (bytes)
+    fn bdp(&self, gain: f64) -> u64 {
+        match self.rtprop {
+            Some(rtprop) if self.bandwidth() != 0 => {
+                (self.bandwidth() as f64 * rtprop as f64 / 1e6 * gain) as u64
+            }
+            // No measurements yet
+            _ => (self.initial_window as f64 * gain) as u64,
+        }
+    }
+
+    /// Forget packets below `packet`, which were lost
+    fn discard_below(&mut self, packet: u64) {
+        let rest = self.sent_packets.split_off(&packet);
+        for info in self.sent_packets.values() {
+            self.bytes_in_flight -= info.bytes;
+        }
+        self.sent_packets = rest;
+    }
+
+    fn update_btl_bw(&mut self, rate: u64) {
+        while self.btl_bw_filter.back().map_or(false, |x| x.1 <= rate) {
+            self.btl_bw_filter.pop_back();
+        }
+        self.btl_bw_filter.push_back((self.round_count, rate));
+        while self.btl_bw_filter.front().unwrap().0 + BTL_BW_FILTER_LEN <= self.round_count {
+            self.btl_bw_filter.pop_front();
+        }
+    }
+
+    fn check_full_pipe(&mut self, round_start: bool) {
+        if self.filled_pipe || !round_start {
+            return;
+        }
+        if self.bandwidth() as f64 >= self.full_bw as f64 * FULL_BW_THRESHOLD {
+            // Still growing
+            self.full_bw = self.bandwidth();
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        if self.full_bw_count >= FULL_BW_COUNT {
+            self.filled_pipe = true;
+        }
+    }
+
+    fn check_drain(&mut self, now: u64) {
+        if self.mode == BbrMode::Startup && self.filled_pipe {
+            self.mode = BbrMode::Drain;
+            self.pacing_gain = 1.0 / HIGH_GAIN;
+            self.cwnd_gain = HIGH_GAIN;
+        }
+        if self.mode == BbrMode::Drain && self.bytes_in_flight <= self.bdp(1.0) {
+            self.enter_probe_bw(now);
+        }
+    }
+
+    fn enter_startup(&mut self) {
+        self.mode = BbrMode::Startup;
+        self.pacing_gain = HIGH_GAIN;
+        self.cwnd_gain = HIGH_GAIN;
+    }
+
+    fn enter_probe_bw(&mut self, now: u64) {
+        self.mode = BbrMode::ProbeBw;
+        self.cwnd_gain = CWND_GAIN;
+        // Start in a random phase other than draining, so competing flows don't synchronize
+        self.cycle_index = PACING_GAIN_CYCLE.len() - 1 - self.rng.gen_range(0, 7);
+        self.advance_cycle_phase(now);
+    }
+
+    fn advance_cycle_phase(&mut self, now: u64) {
+        self.cycle_stamp = now;
+        self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.cycle_loss = false;
+    }
+
+    fn update_gain_cycling(&mut self, now: u64, prior_in_flight: u64) {
+        if self.mode != BbrMode::ProbeBw {
+            return;
+        }
+        let full_length = now - self.cycle_stamp > self.rtprop.unwrap_or(0);
+        let next = if self.pacing_gain > 1.0 {
+            // Probe until we've actually put the extra data in flight, or it caused loss
+            full_length && (self.cycle_loss || prior_in_flight >= self.bdp(self.pacing_gain))
+        } else if self.pacing_gain < 1.0 {
+            // Drain until the queue we may have created is gone
+            full_length || prior_in_flight <= self.bdp(1.0)
+        } else {
+            full_length
+        };
+        if next {
+            self.advance_cycle_phase(now);
+        }
+    }
+
+    fn check_probe_rtt(&mut self, now: u64, round_start: bool) {
+        if self.mode != BbrMode::ProbeRtt && self.rtprop_expired {
+            self.mode = BbrMode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.prior_window = cmp::max(self.prior_window, self.window);
+            self.probe_rtt_done_stamp = None;
+        }
+        if self.mode != BbrMode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done_stamp {
+            None => {
+                if self.bytes_in_flight <= self.min_pipe_window() {
+                    self.probe_rtt_done_stamp = Some(now + PROBE_RTT_DURATION);
+                    self.probe_rtt_round_done = false;
+                    self.next_round_delivered = self.delivered;
+                }
+            }
+            Some(done) => {
+                if round_start {
+                    self.probe_rtt_round_done = true;
+                }
+                if self.probe_rtt_round_done && now > done {
+                    self.rtprop_stamp = now;
+                    self.window = cmp::max(self.window, self.prior_window);
+                    self.prior_window = 0;
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.enter_startup();
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_window(&mut self, acked: u64) {
+        let target = self.bdp(self.cwnd_gain) + 3 * self.mss;
+        if self.filled_pipe {
+            self.window = cmp::min(self.window + acked, target);
+        } else if self.window < target || self.delivered < self.initial_window {
+            self.window += acked;
+        }
+        self.window = cmp::max(self.window, self.min_pipe_window());
+        if self.mode == BbrMode::ProbeRtt {
+            self.window = cmp::min(self.window, self.min_pipe_window());
+        }
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_packet_sent(&mut self, now: u64, packet: u64, bytes: u64) {
+        self.largest_sent_packet = packet;
+        if bytes == 0 {
+            return;
+        }
+        let delivered_time = *self.delivered_time.get_or_insert(now);
+        self.sent_packets.insert(
+            packet,
+            SentPacket {
+                time: now,
+                bytes,
+                delivered: self.delivered,
+                delivered_time,
+            },
+        );
+        self.bytes_in_flight += bytes;
+    }
+
+    fn on_ack_received(&mut self, now: u64, _rtt: u64, packet: u64, _bytes: u64) {
+        let info = match self.sent_packets.remove(&packet) {
+            Some(x) => x,
+            None => {
+                return;
+            }
+        };
+        let prior_in_flight = self.bytes_in_flight;
+        self.bytes_in_flight -= info.bytes;
+        // Earlier packets may yet be acknowledged out of order, until the connection would consider them lost
+        self.discard_below((packet + 1).saturating_sub(self.reordering_threshold));
+        self.delivered += info.bytes;
+        self.delivered_time = Some(now);
+
+        let round_start = info.delivered >= self.next_round_delivered;
+        if round_start {
+            self.next_round_delivered = self.delivered;
+            self.round_count += 1;
+        }
+
+        let interval = now - info.delivered_time;
+        if interval != 0 {
+            self.update_btl_bw((self.delivered - info.delivered) * 1000 * 1000 / interval);
+        }
+
+        let rtt = now - info.time;
+        self.rtprop_expired = now > self.rtprop_stamp + RTPROP_FILTER_LEN;
+        if self.rtprop.map_or(true, |x| rtt <= x) || self.rtprop_expired {
+            self.rtprop = Some(rtt);
+            self.rtprop_stamp = now;
+        }
+
+        if self.in_recovery && packet > self.end_of_recovery {
+            self.in_recovery = false;
+            self.window = cmp::max(self.window, self.prior_window);
+            self.prior_window = 0;
+        }
+
+        self.check_full_pipe(round_start);
+        self.check_drain(now);
+        self.update_gain_cycling(now, prior_in_flight);
+        self.check_probe_rtt(now, round_start);
+        self.set_window(info.bytes);
+    }
+
+    fn on_packets_lost(&mut self, largest_lost: u64, _bytes: u64) {
+        self.discard_below(largest_lost + 1);
+        self.cycle_loss = true;
+        self.on_congestion_event(largest_lost, CongestionEvent::Loss);
+    }
+
+    fn on_congestion_event(&mut self, packet: u64, _event: CongestionEvent) {
+        if packet <= self.end_of_recovery {
+            return;
+        }
+        self.end_of_recovery = self.largest_sent_packet;
+        // BBR's model doesn't depend on loss, but conserve packets for a round trip to let the queue drain
+        if !self.in_recovery {
+            self.in_recovery = true;
+            self.prior_window = cmp::max(self.prior_window, self.window);
+        }
+        self.window = cmp::max(self.bytes_in_flight, self.min_pipe_window());
+    }
+
+    fn on_persistent_congestion(&mut self) {
+        self.window = self.min_pipe_window();
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        match self.bandwidth() {
+            0 => None,
+            bw => Some((bw as f64 * self.pacing_gain) as u64),
+        }
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.mode == BbrMode::Startup
+    }
+
+    fn on_mtu_update(&mut self, mtu: u16) {
+        self.mss = mtu as u64;
+    }
+}
+
+/// Builds a `Bbr` controller for each connection
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BbrFactory;
+
+impl CongestionControllerFactory for BbrFactory {
+    fn build(&self, config: &Config) -> Box<CongestionController> {
+        Box::new(Bbr::new(config))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use congestion::NewReno;
+
+    /// Simulation time step (μs)
+    const TICK: u64 = 100;
+    /// Fixed so that runs are reproducible
+    const SEED: [u8; 16] = [42; 16];
+
+    /// A bottleneck link with an unbounded FIFO queue, carrying a single flow
+    struct Path {
+        /// Bytes/s
+        bandwidth: u64,
+        /// μs
+        rtprop: u64,
+        /// Fraction of packets dropped at random, as by a lossy link rather than congestion
+        loss_rate: f64,
+        rng: XorShiftRng,
+        mss: u64,
+        /// When the link will finish transmitting everything queued so far
+        busy_until: u64,
+        /// Ack arrival time and number of each packet in flight, in order
+        in_flight: VecDeque<(u64, u64)>,
+        /// Packets dropped and not yet declared lost, in order
+        dropped: VecDeque<u64>,
+        /// Bytes acknowledged so far
+        delivered: u64,
+        now: u64,
+        next_packet: u64,
+        next_send: u64,
+    }
+
+    impl Path {
+        fn new(bandwidth: u64, rtprop: u64) -> Self {
+            Self {
+                bandwidth,
+                rtprop,
+                loss_rate: 0.0,
+                rng: XorShiftRng::from_seed(SEED),
+                mss: Config::default().default_mss,
+                busy_until: 0,
+                in_flight: VecDeque::new(),
+                dropped: VecDeque::new(),
+                delivered: 0,
+                now: 0,
+                next_packet: 1,
+                next_send: 0,
+            }
+        }
+
+        /// Send MSS-sized packets as fast as `cc` permits until `until`, calling `observe` after every tick
+        fn run<C, F>(&mut self, cc: &mut C, until: u64, mut observe: F)
+        where
+            C: CongestionController,
+            F: FnMut(&C),
+        {
+            let mss = self.mss;
+            while self.now < until {
+                while self.in_flight.front().map_or(false, |x| x.0 <= self.now) {
+                    let (time, packet) = self.in_flight.pop_front().unwrap();
+                    // A later packet arriving reveals the loss of earlier ones
+                    let mut largest_lost = None;
+                    let mut lost = 0;
+                    while self.dropped.front().map_or(false, |&x| x < packet) {
+                        largest_lost = self.dropped.pop_front();
+                        lost += 1;
+                    }
+                    if let Some(largest) = largest_lost {
+                        cc.on_packets_lost(largest, lost * mss);
+                    }
+                    cc.on_ack_received(time, 0, packet, mss);
+                    self.delivered += mss;
+                }
+                while (self.in_flight.len() as u64 + self.dropped.len() as u64 + 1) * mss
+                    <= cc.window()
+                    && self.next_send <= self.now
+                {
+                    let packet = self.next_packet;
+                    self.next_packet += 1;
+                    cc.on_packet_sent(self.now, packet, mss);
+                    if self.rng.gen_bool(self.loss_rate) {
+                        self.dropped.push_back(packet);
+                    } else {
+                        self.busy_until = cmp::max(self.now, self.busy_until)
+                            + mss * 1000 * 1000 / self.bandwidth;
+                        self.in_flight
+                            .push_back((self.busy_until + self.rtprop, packet));
+                    }
+                    self.next_send = cmp::max(self.next_send, self.now)
+                        + cc.pacing_rate().map_or(0, |rate| mss * 1000 * 1000 / rate);
+                }
+                self.now += TICK;
+                observe(&*cc);
+            }
+        }
+    }
+
+    #[test]
+    fn converge_and_probe_rtt() {
+        let config = Config::default();
+        let mss = config.default_mss;
+        let mut cc = Bbr::with_seed(&config, SEED);
+        // 10 Mbps, 40ms
+        let mut path = Path::new(1250 * 1000, 40 * 1000);
+        let mut modes = vec![cc.mode()];
+        path.run(&mut cc, 5 * 1000 * 1000, |cc| {
+            if *modes.last().unwrap() != cc.mode() {
+                modes.push(cc.mode());
+            }
+        });
+        assert_eq!(modes, [BbrMode::Startup, BbrMode::Drain, BbrMode::ProbeBw]);
+        let bandwidth = cc.bandwidth() as f64;
+        assert!((bandwidth - 1.25e6).abs() < 0.05 * 1.25e6);
+        let min_rtt = cc.min_rtt().unwrap();
+        // Propagation delay plus the time to transmit one packet across the bottleneck
+        assert!(min_rtt >= 40 * 1000 && min_rtt <= 42 * 1000);
+        assert!(cc.window() >= cc.bdp(1.0) && cc.window() <= cc.bdp(CWND_GAIN) + 3 * mss);
+        assert_eq!(cc.pacing_rate(), Some((bandwidth * cc.pacing_gain) as u64));
+
+        // A longer path is only discovered once the old minimum RTT expires and PROBE_RTT drains the queue
+        path.rtprop = 60 * 1000;
+        let mut probe_rtt_windows = Vec::new();
+        path.run(&mut cc, 20 * 1000 * 1000, |cc| {
+            if cc.mode() == BbrMode::ProbeRtt {
+                probe_rtt_windows.push(cc.window());
+            }
+        });
+        assert!(!probe_rtt_windows.is_empty());
+        assert!(probe_rtt_windows.iter().all(|&x| x == MIN_PIPE_CWND * mss));
+        assert_eq!(cc.mode(), BbrMode::ProbeBw);
+        let min_rtt = cc.min_rtt().unwrap();
+        assert!(min_rtt >= 60 * 1000 && min_rtt <= 62 * 1000);
+    }
+
+    #[test]
+    fn recovery() {
+        let config = Config::default();
+        let mut cc = Bbr::with_seed(&config, SEED);
+        for packet in 1..11 {
+            cc.on_packet_sent(0, packet, 1000);
+        }
+        cc.on_packets_lost(4, 4000);
+        // Conserve packets while recovering
+        assert_eq!(cc.bytes_in_flight, 6000);
+        assert_eq!(cc.window(), 6000);
+        cc.on_packet_sent(0, 11, 1000);
+        cc.on_ack_received(1000, 0, 11, 1000);
+        // The window is restored once a packet sent after the loss is acknowledged
+        assert!(cc.window() >= config.initial_window);
+    }
+
+    #[test]
+    fn reordered_acks() {
+        let config = Config::default();
+        let mut cc = Bbr::with_seed(&config, SEED);
+        for packet in 1..4 {
+            cc.on_packet_sent(0, packet, 1000);
+        }
+        cc.on_ack_received(1000, 0, 2, 1000);
+        // Acknowledged after a later packet, but still counted as delivered
+        cc.on_ack_received(1000, 0, 1, 1000);
+        assert_eq!(cc.delivered, 2000);
+        assert_eq!(cc.bytes_in_flight, 1000);
+
+        for packet in 4..11 {
+            cc.on_packet_sent(1000, packet, 1000);
+        }
+        cc.on_ack_received(2000, 0, 9, 1000);
+        // Packets 3 to 6 are too far behind to still be in flight
+        assert_eq!(cc.bytes_in_flight, 3000);
+    }
+
+    /// Fraction of a 50 Mbps, 100ms path's capacity that `cc` uses once past startup, with 1% of packets lost at
+    /// random regardless of how fast it sends
+    fn lossy_utilization<C: CongestionController>(cc: &mut C) -> f64 {
+        let mut path = Path::new(6250 * 1000, 100 * 1000);
+        path.loss_rate = 0.01;
+        path.run(&mut *cc, 2 * 1000 * 1000, |_| {});
+        let delivered = path.delivered;
+        path.run(&mut *cc, 10 * 1000 * 1000, |_| {});
+        (path.delivered - delivered) as f64 / (8 * path.bandwidth) as f64
+    }
+
+    #[test]
+    fn random_loss() {
+        let config = Config::default();
+        // Loss doesn't enter into BBR's model of the path, so it keeps the pipe full
+        assert!(lossy_utilization(&mut Bbr::with_seed(&config, SEED)) > 0.8);
+        // Whereas every loss halves NewReno's window, keeping it a small fraction of the bandwidth-delay product
+        assert!(lossy_utilization(&mut NewReno::new(&config)) < 0.25);
+    }
+}
@@ -0,0 +1,282 @@
+//! CUBIC congestion control, as described in RFC 8312
+//!
+//! After a reduction, the window grows along a cubic function of the time elapsed: quickly at first, flattening out as it
+//! approaches the window at which congestion last occurred, then probing with accelerating growth beyond it.
+
+use std::cmp;
+
+use super::{CongestionController, CongestionControllerFactory, CongestionEvent};
+use endpoint::Config;
+
+/// Scaling constant of the cubic function (segments/s³)
+const C: f64 = 0.4;
+/// Multiplicative decrease factor
+const BETA: f64 = 0.7;
+
+/// The CUBIC algorithm
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: u64,
+    minimum_window: u64,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// Slow start threshold in bytes.
+    ssthresh: u64,
+    /// The largest packet number sent when congestion was detected. When a larger packet is acknowledged, we exit
+    /// recovery.
+    end_of_recovery: u64,
+    largest_sent_packet: u64,
+    state: CubicState,
+}
+
+impl Cubic {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            mss: config.default_mss,
+            minimum_window: config.minimum_window,
+            window: config.initial_window,
+            ssthresh: u64::max_value(),
+            end_of_recovery: 0,
+            largest_sent_packet: 0,
+            state: CubicState::default(),
+        }
+    }
+
+    pub fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+
+    pub fn in_recovery(&self, packet: u64) -> bool {
+        packet <= self.end_of_recovery
+    }
+}
+
+/// Progress through the current congestion avoidance epoch
+#[derive(Debug, Clone, Default)]
+struct CubicState {
+    /// Window just before the last reduction, less any adjustment for fast convergence (bytes)
+    w_max: f64,
+    /// Window just before the previous reduction (bytes)
+    w_last_max: f64,
+    /// Time the cubic function takes to grow back to `w_max` (s)
+    k: f64,
+    /// Start of the current epoch (μs), or `None` until the first ack after a reduction
+    epoch_start: Option<u64>,
+    /// Estimate of the window a Reno flow would have reached in the same time (bytes)
+    w_est: f64,
+}
+
+impl CubicState {
+    /// Target window `t` seconds into the epoch (bytes)
+    fn w_cubic(&self, t: f64, mss: f64) -> f64 {
+        C * (t - self.k).powi(3) * mss + self.w_max
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_packet_sent(&mut self, _now: u64, packet: u64, _bytes: u64) {
+        self.largest_sent_packet = packet;
+    }
+
+    fn on_ack_received(&mut self, now: u64, rtt: u64, packet: u64, bytes: u64) {
+        // Do not increase congestion window in recovery period.
+        if self.in_recovery(packet) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+            return;
+        }
+
+        let mss = self.mss as f64;
+        let window = self.window as f64;
+        let acked = bytes as f64;
+        let new = {
+            let state = &mut self.state;
+            let start = match state.epoch_start {
+                Some(x) => x,
+                None => {
+                    state.epoch_start = Some(now);
+                    if window < state.w_max {
+                        state.k = ((state.w_max - window) / (C * mss)).cbrt();
+                    } else {
+                        state.k = 0.0;
+                        state.w_max = window;
+                    }
+                    state.w_est = window;
+                    now
+                }
+            };
+            let t = now.saturating_sub(start) as f64 / 1e6;
+            // A Reno flow with the same decrease factor grows by 3 * (1 - β) / (1 + β) segments per RTT
+            state.w_est += 3.0 * (1.0 - BETA) / (1.0 + BETA) * mss * acked / window;
+            if state.w_cubic(t, mss) < state.w_est {
+                // Reno-friendly region
+                state.w_est
+            } else {
+                // Concave and convex regions: head for where the curve will be an RTT from now, at most growing by
+                // half the window per RTT
+                let target = state
+                    .w_cubic(t + rtt as f64 / 1e6, mss)
+                    .min(1.5 * window)
+                    .max(window);
+                window + (target - window) * acked / window
+            }
+        };
+        self.window = cmp::max(self.window, new as u64);
+    }
+
+    fn on_congestion_event(&mut self, packet: u64, event: CongestionEvent) {
+        // Start a new recovery epoch if the packet is larger than the end of the previous recovery epoch.
+        if self.in_recovery(packet) {
+            return;
+        }
+        self.end_of_recovery = self.largest_sent_packet;
+        match event {
+            CongestionEvent::Loss | CongestionEvent::Ecn => {
+                let window = self.window as f64;
+                // Fast convergence: if we're losing ground, cede some more to make room for newer flows
+                self.state.w_max = if window < self.state.w_last_max {
+                    window * (1.0 + BETA) / 2.0
+                } else {
+                    window
+                };
+                self.state.w_last_max = window;
+                self.state.epoch_start = None;
+                self.window = cmp::max((window * BETA) as u64, self.minimum_window);
+                self.ssthresh = self.window;
+            }
+        }
+    }
+
+    fn on_persistent_congestion(&mut self) {
+        self.window = self.minimum_window;
+        self.state.epoch_start = None;
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.window < self.ssthresh
+    }
+
+    fn on_mtu_update(&mut self, mtu: u16) {
+        self.mss = mtu as u64;
+    }
+}
+
+/// Builds a `Cubic` controller for each connection
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CubicFactory;
+
+impl CongestionControllerFactory for CubicFactory {
+    fn build(&self, config: &Config) -> Box<CongestionController> {
+        Box::new(Cubic::new(config))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RTT: u64 = 100 * 1000;
+
+    /// Send a window's worth of MSS-sized packets, then acknowledge them all one RTT later
+    fn round(cc: &mut Cubic, now: &mut u64, next_packet: &mut u64) {
+        let mss = cc.mss;
+        let count = cc.window() / mss;
+        let first = *next_packet;
+        for packet in first..first + count {
+            cc.on_packet_sent(*now, packet, mss);
+        }
+        *next_packet += count;
+        *now += RTT;
+        for packet in first..first + count {
+            cc.on_ack_received(*now, RTT, packet, mss);
+        }
+    }
+
+    #[test]
+    fn loss_and_recovery() {
+        let config = Config::default();
+        let mut cc = Cubic::new(&config);
+        let mss = config.default_mss;
+        let mut now = 0;
+        let mut packet = 1;
+
+        // Slow start doubles the window every round trip
+        round(&mut cc, &mut now, &mut packet);
+        assert_eq!(cc.window(), 2 * config.initial_window);
+        while cc.window() < 100 * mss {
+            round(&mut cc, &mut now, &mut packet);
+        }
+
+        let w_max = cc.window();
+        cc.on_packet_sent(now, packet, mss);
+        cc.on_packets_lost(packet, mss);
+        packet += 1;
+        let reduced = (w_max as f64 * BETA) as u64;
+        assert_eq!(cc.window(), reduced);
+        assert_eq!(cc.ssthresh(), reduced);
+        // Acks for packets sent before the loss don't grow the window
+        cc.on_ack_received(now, RTT, packet - 2, mss);
+        assert_eq!(cc.window(), reduced);
+
+        let epoch_start = now;
+        let k = ((w_max - reduced) as f64 / (C * mss as f64)).cbrt();
+        let w_cubic = |t: f64| C * (t - k).powi(3) * mss as f64 + w_max as f64;
+        let mut windows = vec![cc.window()];
+        while now - epoch_start < 2 * (k * 1e6) as u64 {
+            round(&mut cc, &mut now, &mut packet);
+            let window = cc.window();
+            // The window tracks the cubic curve to within a few segments, and never exceeds the next RTT's target
+            let t = (now - epoch_start) as f64 / 1e6;
+            assert!((window as f64 - w_cubic(t)).abs() < 4.0 * mss as f64);
+            assert!(window as f64 <= w_cubic(t + RTT as f64 / 1e6));
+            windows.push(window);
+        }
+
+        let growth = windows.windows(2).map(|x| x[1] - x[0]).collect::<Vec<_>>();
+        let plateau = (k * 1e6) as usize / RTT as usize;
+        // Concave region: growth slows as the window approaches the previous maximum. The epoch only begins with the
+        // first round's acks, so skip that round.
+        assert!(growth[1..plateau - 1]
+            .windows(2)
+            .all(|x| x[1] <= x[0] + mss));
+        assert!(growth[1] > 4 * growth[plateau - 1]);
+        // Convex region: growth accelerates beyond it
+        assert!(growth[plateau + 1..].windows(2).all(|x| x[1] + mss >= x[0]));
+        assert!(*growth.last().unwrap() > 4 * growth[plateau + 1]);
+    }
+
+    #[test]
+    fn fast_convergence() {
+        let config = Config::default();
+        let mut cc = Cubic::new(&config);
+        cc.on_packet_sent(0, 1, 1000);
+        cc.on_congestion_event(1, CongestionEvent::Loss);
+        let first = cc.window();
+        cc.on_packet_sent(0, 2, 1000);
+        cc.on_congestion_event(2, CongestionEvent::Ecn);
+        // The second reduction came before the window recovered, so the next epoch aims lower
+        assert_eq!(cc.window(), (first as f64 * BETA) as u64);
+        assert_eq!(cc.state.w_max, first as f64 * (1.0 + BETA) / 2.0);
+    }
+
+    #[test]
+    fn reno_friendly() {
+        let config = Config::default();
+        let mut cc = Cubic::new(&config);
+        // A tiny window recovers very quickly along the cubic curve, so the Reno estimate dominates at first
+        cc.on_packet_sent(0, 1, 1000);
+        cc.on_congestion_event(1, CongestionEvent::Loss);
+        let mut now = 0;
+        let mut packet = 2;
+        round(&mut cc, &mut now, &mut packet);
+        assert!(cc.window() > cc.ssthresh());
+        assert_eq!(cc.window() as f64, cc.state.w_est.floor());
+    }
+}
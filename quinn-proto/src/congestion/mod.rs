@@ -0,0 +1,251 @@
+//! Pluggable congestion control
+//!
+//! Each connection owns a `CongestionController`, constructed by the `CongestionControllerFactory` in its endpoint's
+//! `Config`. The connection remains responsible for loss detection and for tracking the bytes in flight; the controller
+//! only decides how many bytes may be outstanding at once.
+
+use std::cmp;
+
+use endpoint::Config;
+
+mod bbr;
+pub use self::bbr::{Bbr, BbrFactory, BbrMode};
+mod cubic;
+pub use self::cubic::{Cubic, CubicFactory};
+
+/// Signals of network congestion that the congestion controller responds to
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CongestionEvent {
+    /// A packet was declared lost
+    Loss,
+    /// The peer reported packets marked congestion experienced
+    Ecn,
+}
+
+/// Congestion control algorithm governing a single connection
+pub trait CongestionController: Send {
+    /// A packet was sent. `bytes` is 0 iff the packet was ack-only.
+    fn on_packet_sent(&mut self, now: u64, packet: u64, bytes: u64);
+    /// A packet carrying `bytes` of retransmittable data was acknowledged
+    ///
+    /// `rtt` is the connection's smoothed round-trip time estimate (μs).
+    fn on_ack_received(&mut self, now: u64, rtt: u64, packet: u64, bytes: u64);
+    /// Packets carrying a total of `bytes` of retransmittable data were declared lost, the largest being `largest_lost`
+    fn on_packets_lost(&mut self, largest_lost: u64, _bytes: u64) {
+        self.on_congestion_event(largest_lost, CongestionEvent::Loss);
+    }
+    /// Respond to congestion affecting packets up to and including `packet`
+    fn on_congestion_event(&mut self, packet: u64, event: CongestionEvent);
+    /// Every ack-eliciting packet sent over several probe timeouts was lost, so the path's capacity is unknown
+    fn on_persistent_congestion(&mut self);
+    /// Maximum number of bytes that may be in flight
+    fn window(&self) -> u64;
+    /// Rate at which packets should be paced out (bytes/s), if any
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+    /// Whether the controller is growing the window as fast as it can to find the path's capacity
+    ///
+    /// Packets are paced out faster meanwhile, so as not to slow the search.
+    fn in_slow_start(&self) -> bool {
+        false
+    }
+    /// The path was found to carry UDP payloads of up to `mtu` bytes, so full-sized packets are now that large
+    fn on_mtu_update(&mut self, _mtu: u16) {}
+}
+
+/// Constructs a fresh `CongestionController` for each new connection
+pub trait CongestionControllerFactory: Send + Sync {
+    fn build(&self, config: &Config) -> Box<CongestionController>;
+}
+
+/// The NewReno algorithm, as described in the QUIC recovery draft
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    /// Size of a full-sized packet in bytes
+    mss: u64,
+    minimum_window: u64,
+    loss_reduction_factor: u16,
+    /// Maximum number of bytes in flight that may be sent.
+    window: u64,
+    /// The largest packet number sent when QUIC detects a loss. When a larger packet is acknowledged, QUIC exits recovery.
+    end_of_recovery: u64,
+    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is slow start and the
+    /// window grows by the number of bytes acknowledged.
+    ssthresh: u64,
+    largest_sent_packet: u64,
+}
+
+impl NewReno {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            mss: config.default_mss,
+            minimum_window: config.minimum_window,
+            loss_reduction_factor: config.loss_reduction_factor,
+            window: config.initial_window,
+            end_of_recovery: 0,
+            ssthresh: u64::max_value(),
+            largest_sent_packet: 0,
+        }
+    }
+
+    pub fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+
+    pub fn in_recovery(&self, packet: u64) -> bool {
+        packet <= self.end_of_recovery
+    }
+}
+
+impl CongestionController for NewReno {
+    fn on_packet_sent(&mut self, _now: u64, packet: u64, _bytes: u64) {
+        self.largest_sent_packet = packet;
+    }
+
+    fn on_ack_received(&mut self, _now: u64, _rtt: u64, packet: u64, bytes: u64) {
+        // Do not increase congestion window in recovery period.
+        if self.in_recovery(packet) {
+            return;
+        }
+        if self.window < self.ssthresh {
+            // Slow start.
+            self.window += bytes;
+        } else {
+            // Congestion avoidance.
+            self.window += self.mss * bytes / self.window;
+        }
+    }
+
+    fn on_congestion_event(&mut self, packet: u64, event: CongestionEvent) {
+        // Start a new recovery epoch if the packet is larger than the end of the previous recovery epoch.
+        if self.in_recovery(packet) {
+            return;
+        }
+        self.end_of_recovery = self.largest_sent_packet;
+        match event {
+            // NewReno treats CE marks exactly like loss
+            CongestionEvent::Loss | CongestionEvent::Ecn => {
+                // *= factor
+                self.window = (self.window * self.loss_reduction_factor as u64) >> 16;
+                self.window = cmp::max(self.window, self.minimum_window);
+                self.ssthresh = self.window;
+            }
+        }
+    }
+
+    fn on_persistent_congestion(&mut self) {
+        self.window = self.minimum_window;
+    }
+
+    fn window(&self) -> u64 {
+        self.window
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.window < self.ssthresh
+    }
+
+    fn on_mtu_update(&mut self, mtu: u16) {
+        self.mss = mtu as u64;
+    }
+}
+
+/// Builds a `NewReno` controller for each connection
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NewRenoFactory;
+
+impl CongestionControllerFactory for NewRenoFactory {
+    fn build(&self, config: &Config) -> Box<CongestionController> {
+        Box::new(NewReno::new(config))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Holds the window fixed, whatever happens
+    struct Fixed(u64);
+
+    impl CongestionController for Fixed {
+        fn on_packet_sent(&mut self, _: u64, _: u64, _: u64) {}
+        fn on_ack_received(&mut self, _: u64, _: u64, _: u64, _: u64) {}
+        fn on_congestion_event(&mut self, _: u64, _: CongestionEvent) {}
+        fn on_persistent_congestion(&mut self) {}
+        fn window(&self) -> u64 {
+            self.0
+        }
+    }
+
+    struct FixedFactory(u64);
+
+    impl CongestionControllerFactory for FixedFactory {
+        fn build(&self, _: &Config) -> Box<CongestionController> {
+            Box::new(Fixed(self.0))
+        }
+    }
+
+    /// Send packets 1 through 10, ack 1 through 3, then lose 4
+    fn exercise(cc: &mut CongestionController) {
+        for packet in 1..11 {
+            cc.on_packet_sent(0, packet, 1000);
+        }
+        for packet in 1..4 {
+            cc.on_ack_received(0, 0, packet, 1000);
+        }
+        cc.on_packets_lost(4, 1000);
+    }
+
+    #[test]
+    fn new_reno() {
+        let config = Config::default();
+        let mut cc = NewRenoFactory.build(&config);
+        assert_eq!(cc.window(), config.initial_window);
+        assert_eq!(cc.pacing_rate(), None);
+        exercise(&mut *cc);
+        // Slow start grows the window by 3000 bytes, then the loss halves it
+        assert_eq!(cc.window(), (config.initial_window + 3000) / 2);
+        // Packets sent before the loss was detected don't grow the window or trigger another reduction
+        cc.on_ack_received(0, 0, 10, 1000);
+        cc.on_packets_lost(9, 1000);
+        assert_eq!(cc.window(), (config.initial_window + 3000) / 2);
+        cc.on_persistent_congestion();
+        assert_eq!(cc.window(), config.minimum_window);
+    }
+
+    #[test]
+    fn new_reno_avoidance() {
+        let config = Config::default();
+        let mut cc = NewReno::new(&config);
+        cc.on_packet_sent(0, 1, 1000);
+        cc.on_congestion_event(1, CongestionEvent::Ecn);
+        let window = cc.window();
+        assert_eq!(cc.ssthresh(), window);
+        cc.on_packet_sent(0, 2, 1000);
+        cc.on_ack_received(0, 0, 2, 1000);
+        assert_eq!(cc.window(), window + config.default_mss * 1000 / window);
+    }
+
+    #[test]
+    fn new_reno_mtu_update() {
+        let config = Config::default();
+        let mut cc = NewReno::new(&config);
+        cc.on_packet_sent(0, 1, 1000);
+        cc.on_congestion_event(1, CongestionEvent::Loss);
+        let window = cc.window();
+        // Congestion avoidance grows the window by one full-sized packet per window acknowledged
+        cc.on_mtu_update(1400);
+        cc.on_packet_sent(0, 2, window);
+        cc.on_ack_received(0, 0, 2, window);
+        assert_eq!(cc.window(), window + 1400);
+    }
+
+    #[test]
+    fn stub() {
+        let config = Config::default();
+        let mut cc = FixedFactory(4321).build(&config);
+        exercise(&mut *cc);
+        assert_eq!(cc.window(), 4321);
+    }
+}
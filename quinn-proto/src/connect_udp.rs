@@ -0,0 +1,383 @@
+//! Capsules for proxying UDP over HTTP (CONNECT-UDP, RFC 9298)
+//!
+//! A CONNECT-UDP proxy relays UDP payloads between its client and a target as HTTP datagrams, sent in QUIC DATAGRAM
+//! frames where the peer supports them, or otherwise as capsules (RFC 9297) on the request stream of the tunnel. HTTP/3
+//! itself is out of scope here; this module only encodes and decodes capsules, including the address and route
+//! capsules CONNECT-IP (RFC 9484) defines for the same tunnels.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bytes::{Buf, BufMut, Bytes};
+
+use coding::{self, BufExt, BufMutExt};
+
+/// Context ID of the HTTP datagrams carrying UDP payloads
+pub const UDP_PAYLOAD_CONTEXT: u64 = 0;
+
+mod types {
+    pub const DATAGRAM: u64 = 0x00;
+    pub const ADDRESS_ASSIGN: u64 = 0x01;
+    pub const ADDRESS_REQUEST: u64 = 0x02;
+    pub const ROUTE_ADVERTISEMENT: u64 = 0x03;
+}
+
+/// A capsule sent on the request stream of a CONNECT tunnel
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Capsule {
+    /// An HTTP datagram, which carries a UDP payload if `context_id` is `UDP_PAYLOAD_CONTEXT`
+    Datagram { context_id: u64, payload: Bytes },
+    /// Addresses the sender has assigned to the receiver
+    AddressAssign(Vec<AssignedAddress>),
+    /// Addresses the sender would like the receiver to assign to it
+    AddressRequest(Vec<AssignedAddress>),
+    /// Ranges of addresses the sender can route packets to
+    RouteAdvertisement(Vec<AddressRange>),
+}
+
+/// An address prefix assigned, or requested, in an ADDRESS_ASSIGN or ADDRESS_REQUEST capsule
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AssignedAddress {
+    /// Identifies the request an assignment answers, or 0 for an unsolicited assignment
+    ///
+    /// Never 0 in a request.
+    pub request_id: u64,
+    pub address: IpAddr,
+    /// Number of leading bits of `address` that make up the prefix
+    pub prefix_len: u8,
+}
+
+/// An inclusive range of addresses advertised in a ROUTE_ADVERTISEMENT capsule
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AddressRange {
+    /// Lowest address of the range, of the same family as `end`
+    pub start: IpAddr,
+    pub end: IpAddr,
+    /// IP protocol number routes are limited to, or 0 for any protocol
+    pub protocol: u8,
+}
+
+#[derive(Debug, Fail, Copy, Clone, Eq, PartialEq)]
+pub enum CapsuleError {
+    /// The buffer ended before the capsule did
+    ///
+    /// The caller should retry from the start of the capsule once more data has arrived.
+    #[fail(display = "incomplete capsule")]
+    Incomplete,
+    /// The capsule's value doesn't fit the layout of its type
+    #[fail(display = "malformed capsule")]
+    Malformed,
+    /// The capsule is of a type not known here, and has been skipped
+    ///
+    /// Unknown capsules must be ignored, so this isn't a protocol error.
+    #[fail(display = "unknown capsule type {:x}", _0)]
+    UnknownType(u64),
+}
+
+impl From<coding::UnexpectedEnd> for CapsuleError {
+    fn from(_: coding::UnexpectedEnd) -> Self {
+        CapsuleError::Malformed
+    }
+}
+
+impl Capsule {
+    pub fn encode<W: BufMut>(&self, w: &mut W) {
+        let mut value = Vec::new();
+        let ty = match *self {
+            Capsule::Datagram {
+                context_id,
+                ref payload,
+            } => {
+                value.write_var(context_id);
+                value.extend_from_slice(payload);
+                types::DATAGRAM
+            }
+            Capsule::AddressAssign(ref addresses) => {
+                for x in addresses {
+                    x.encode(&mut value);
+                }
+                types::ADDRESS_ASSIGN
+            }
+            Capsule::AddressRequest(ref addresses) => {
+                for x in addresses {
+                    x.encode(&mut value);
+                }
+                types::ADDRESS_REQUEST
+            }
+            Capsule::RouteAdvertisement(ref ranges) => {
+                for x in ranges {
+                    x.encode(&mut value);
+                }
+                types::ROUTE_ADVERTISEMENT
+            }
+        };
+        w.write_var(ty);
+        w.write_var(value.len() as u64);
+        w.put_slice(&value);
+    }
+
+    /// Decode the capsule at the front of `buf`, consuming it
+    pub fn decode<B: Buf>(buf: &mut B) -> Result<Self, CapsuleError> {
+        let ty = buf.get_var().map_err(|_| CapsuleError::Incomplete)?;
+        let len = buf.get_var().map_err(|_| CapsuleError::Incomplete)?;
+        if (buf.remaining() as u64) < len {
+            return Err(CapsuleError::Incomplete);
+        }
+        let mut value = vec![0; len as usize];
+        buf.copy_to_slice(&mut value);
+        let mut value = io::Cursor::new(Bytes::from(value));
+
+        let capsule = match ty {
+            types::DATAGRAM => {
+                let context_id = value.get_var()?;
+                let start = value.position() as usize;
+                Capsule::Datagram {
+                    context_id,
+                    payload: value.get_ref().slice_from(start),
+                }
+            }
+            types::ADDRESS_ASSIGN => {
+                let mut addresses = Vec::new();
+                while value.has_remaining() {
+                    addresses.push(AssignedAddress::decode(&mut value)?);
+                }
+                Capsule::AddressAssign(addresses)
+            }
+            types::ADDRESS_REQUEST => {
+                let mut addresses = Vec::new();
+                while value.has_remaining() {
+                    let x = AssignedAddress::decode(&mut value)?;
+                    if x.request_id == 0 {
+                        return Err(CapsuleError::Malformed);
+                    }
+                    addresses.push(x);
+                }
+                Capsule::AddressRequest(addresses)
+            }
+            types::ROUTE_ADVERTISEMENT => {
+                let mut ranges = Vec::new();
+                while value.has_remaining() {
+                    ranges.push(AddressRange::decode(&mut value)?);
+                }
+                Capsule::RouteAdvertisement(ranges)
+            }
+            _ => return Err(CapsuleError::UnknownType(ty)),
+        };
+        Ok(capsule)
+    }
+}
+
+impl AssignedAddress {
+    fn encode<W: BufMut>(&self, w: &mut W) {
+        w.write_var(self.request_id);
+        w.write(ip_version(self.address));
+        put_address(w, self.address);
+        w.write(self.prefix_len);
+    }
+
+    fn decode<B: Buf>(r: &mut B) -> Result<Self, CapsuleError> {
+        let request_id = r.get_var()?;
+        let version = r.get()?;
+        let address = get_address(r, version)?;
+        let prefix_len = r.get()?;
+        if prefix_len > max_prefix_len(address) {
+            return Err(CapsuleError::Malformed);
+        }
+        Ok(Self {
+            request_id,
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl AddressRange {
+    fn encode<W: BufMut>(&self, w: &mut W) {
+        debug_assert_eq!(ip_version(self.start), ip_version(self.end));
+        w.write(ip_version(self.start));
+        put_address(w, self.start);
+        put_address(w, self.end);
+        w.write(self.protocol);
+    }
+
+    fn decode<B: Buf>(r: &mut B) -> Result<Self, CapsuleError> {
+        let version = r.get()?;
+        let start = get_address(r, version)?;
+        let end = get_address(r, version)?;
+        if start > end {
+            return Err(CapsuleError::Malformed);
+        }
+        Ok(Self {
+            start,
+            end,
+            protocol: r.get()?,
+        })
+    }
+}
+
+fn ip_version(x: IpAddr) -> u8 {
+    match x {
+        IpAddr::V4(_) => 4,
+        IpAddr::V6(_) => 6,
+    }
+}
+
+fn max_prefix_len(x: IpAddr) -> u8 {
+    match x {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn put_address<W: BufMut>(w: &mut W, x: IpAddr) {
+    match x {
+        IpAddr::V4(x) => w.put_slice(&x.octets()),
+        IpAddr::V6(x) => w.put_slice(&x.octets()),
+    }
+}
+
+fn get_address<B: Buf>(r: &mut B, version: u8) -> Result<IpAddr, CapsuleError> {
+    match version {
+        4 => Ok(Ipv4Addr::from(r.get::<u32>()?).into()),
+        6 => {
+            if r.remaining() < 16 {
+                return Err(CapsuleError::Malformed);
+            }
+            let mut octets = [0; 16];
+            r.copy_to_slice(&mut octets);
+            Ok(Ipv6Addr::from(octets).into())
+        }
+        _ => Err(CapsuleError::Malformed),
+    }
+}
+
+/// Encode a DATAGRAM capsule carrying `payload` under `context_id`
+pub fn encode_datagram_capsule(context_id: u64, payload: &[u8]) -> Bytes {
+    let mut buf = Vec::new();
+    Capsule::Datagram {
+        context_id,
+        payload: payload.into(),
+    }.encode(&mut buf);
+    buf.into()
+}
+
+/// Decode the capsule at the front of `buf`, of whatever type
+pub fn decode_datagram_capsule<B: Buf>(buf: &mut B) -> Result<Capsule, CapsuleError> {
+    Capsule::decode(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(capsule: Capsule) {
+        let mut buf = Vec::new();
+        capsule.encode(&mut buf);
+        let mut r = io::Cursor::new(buf);
+        assert_eq!(Capsule::decode(&mut r), Ok(capsule));
+        assert!(!r.has_remaining());
+    }
+
+    #[test]
+    fn datagram_coding() {
+        let encoded = encode_datagram_capsule(UDP_PAYLOAD_CONTEXT, b"hello");
+        assert_eq!(&encoded[..], b"\x00\x06\x00hello");
+        assert_eq!(
+            decode_datagram_capsule(&mut io::Cursor::new(encoded)),
+            Ok(Capsule::Datagram {
+                context_id: UDP_PAYLOAD_CONTEXT,
+                payload: Bytes::from(&b"hello"[..]),
+            })
+        );
+        roundtrip(Capsule::Datagram {
+            context_id: 1 << 20,
+            payload: Bytes::new(),
+        });
+    }
+
+    #[test]
+    fn address_coding() {
+        let addresses = vec![
+            AssignedAddress {
+                request_id: 1,
+                address: Ipv4Addr::new(192, 0, 2, 0).into(),
+                prefix_len: 24,
+            },
+            AssignedAddress {
+                request_id: 2,
+                address: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+                prefix_len: 128,
+            },
+        ];
+        roundtrip(Capsule::AddressAssign(addresses.clone()));
+        roundtrip(Capsule::AddressRequest(addresses));
+        roundtrip(Capsule::AddressAssign(Vec::new()));
+    }
+
+    #[test]
+    fn route_coding() {
+        roundtrip(Capsule::RouteAdvertisement(vec![
+            AddressRange {
+                start: Ipv4Addr::new(192, 0, 2, 0).into(),
+                end: Ipv4Addr::new(192, 0, 2, 255).into(),
+                protocol: 0,
+            },
+            AddressRange {
+                start: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0).into(),
+                end: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xffff).into(),
+                protocol: 17,
+            },
+        ]));
+    }
+
+    #[test]
+    fn incomplete() {
+        let encoded = encode_datagram_capsule(UDP_PAYLOAD_CONTEXT, b"hello");
+        for len in 0..encoded.len() {
+            assert_eq!(
+                Capsule::decode(&mut io::Cursor::new(&encoded[..len])),
+                Err(CapsuleError::Incomplete)
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_skipped() {
+        let mut buf = Vec::new();
+        buf.write_var(0x2a);
+        buf.write_var(3);
+        buf.extend_from_slice(b"abc");
+        Capsule::AddressAssign(Vec::new()).encode(&mut buf);
+        let mut r = io::Cursor::new(buf);
+        assert_eq!(
+            Capsule::decode(&mut r),
+            Err(CapsuleError::UnknownType(0x2a))
+        );
+        assert_eq!(
+            Capsule::decode(&mut r),
+            Ok(Capsule::AddressAssign(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: &[(&str, &[u8])] = &[
+            ("bad IP version", &[0x01, 0x07, 0x00, 0x05, 0, 0, 0, 0, 0]),
+            ("truncated address", &[0x01, 0x04, 0x00, 0x04, 0, 0]),
+            ("prefix too long", &[0x01, 0x07, 0x00, 0x04, 0, 0, 0, 0, 33]),
+            ("zero request ID", &[0x02, 0x07, 0x00, 0x04, 0, 0, 0, 0, 32]),
+            (
+                "range backwards",
+                &[0x03, 0x0a, 0x04, 0, 0, 0, 1, 0, 0, 0, 0, 0],
+            ),
+        ];
+        for &(name, case) in cases {
+            assert_eq!(
+                Capsule::decode(&mut io::Cursor::new(case)),
+                Err(CapsuleError::Malformed),
+                "{}",
+                name
+            );
+        }
+    }
+}
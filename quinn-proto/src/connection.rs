@@ -1,26 +1,49 @@
 use std::collections::{hash_map, BTreeMap, VecDeque};
 use std::net::SocketAddrV6;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{cmp, io, mem};
 
 use bytes::{Buf, Bytes, BytesMut};
+use constant_time_eq::constant_time_eq;
 use fnv::{FnvHashMap, FnvHashSet};
-use rand::distributions::Distribution;
+use rand::{distributions::Distribution, Rng};
 use slog::Logger;
 
+use cid_pool::ConnectionIdPool;
 use coding::{BufExt, BufMutExt};
-use crypto::{ConnectError, Crypto, TLSError, TlsSession, ACK_DELAY_EXPONENT};
-use endpoint::{Config, Context, Event, Io, Timer};
+use congestion::{CongestionController, CongestionEvent};
+use crypto::{
+    is_valid_retry, Certificate, ConnectError, Crypto, SessionTicket, TLSError, TlsSession,
+};
+use endpoint::{Config, Context, Event, Io, MultipathPolicy, Timer};
+use pacing::{self, Pacer};
 use packet::{
-    set_payload_length, types, ConnectionId, Header, Packet, PacketNumber, AEAD_TAG_SIZE,
+    self, payload_length_width, set_payload_length, types, ConnectionId, Header, HeaderError,
+    Packet, PacketNumber, PartialDecode, SpaceId, AEAD_TAG_SIZE,
 };
+use pmtud::PmtudState;
+use qlog::{PacketType, QlogWriter};
+use rack::RackState;
 use range_set::RangeSet;
+use scheduler::StreamScheduler;
 use stream::{self, Stream};
-use transport_parameters::TransportParameters;
+use transport_parameters::{self, PreferredAddress, TransportParameters};
 use {
-    frame, Directionality, Frame, Side, StreamId, TransportError, MIN_INITIAL_SIZE, MIN_MTU,
-    VERSION,
+    frame, Directionality, EcnCodepoint, Frame, Side, StreamId, TransportError, Version,
+    MAX_CID_SIZE, MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE,
 };
 
+/// Tell the application that the connection was lost to `reason`, logging it as a `tracing` warning too
+macro_rules! connection_lost {
+    ($self:ident, $ctx:ident, $conn:expr, $reason:expr) => {{
+        let reason = ConnectionError::from($reason);
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!(parent: &$self.span, reason = %reason, "connection lost");
+        $ctx.events.push_back(($conn, Event::ConnectionLost { reason }));
+    }};
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct ConnectionHandle(pub usize);
 
@@ -38,13 +61,33 @@ pub struct Connection {
     pub remote: SocketAddrV6,
     pub state: Option<State>,
     pub side: Side,
-    pub mtu: u16,
+    /// The version of QUIC in use
+    pub version: Version,
+    /// The version the client first attempted, which differs from `version` after version negotiation
+    pub initial_version: Version,
     pub rx_packet: u64,
     pub rx_packet_time: u64,
     pub crypto: Option<Crypto>,
-    pub prev_crypto: Option<(u64, Crypto)>,
-    //pub zero_rtt_crypto: Option<Crypto>,
+    /// 1-RTT keys from before the most recent key update, kept for packets the peer sent before it
+    pub prev_crypto: Option<PrevCrypto>,
+    /// Keys for 0-RTT packets, while we're resuming a previous connection and the handshake is incomplete
+    pub zero_rtt_crypto: Option<Crypto>,
+    /// Bytes of new stream data the peer may yet send in 0-RTT packets, as a server
+    pub early_data_budget: u64,
     pub key_phase: bool,
+    /// Number of the first packet sent with the current 1-RTT keys
+    ///
+    /// The keys mustn't be updated again until a packet from this one on has been acknowledged.
+    pub key_phase_start: u64,
+    /// Whether the application asked for a key update that has yet to happen
+    pub key_update_requested: bool,
+    /// Number of times the 1-RTT keys have been updated, at either side's initiative
+    pub key_updates: u64,
+    /// Latency spin bit to send in short headers
+    ///
+    /// The server reflects the value most recently received from the client, and the client inverts the value most
+    /// recently received from the server, so the bit flips once per round trip.
+    pub spin: bool,
     pub params: TransportParameters,
     /// Streams with data buffered for reading by the application
     pub readable_streams: FnvHashSet<StreamId>,
@@ -53,28 +96,46 @@ pub struct Connection {
     /// Limit on outgoing data, dictated by peer
     pub max_data: u64,
     pub data_sent: u64,
+    /// Stream data written by the application that the peer has yet to acknowledge
+    pub unacked_data: u64,
+    /// Limit on `unacked_data`, beyond which writes block
+    pub send_buffer_size: u64,
+    /// The limit at which we last told the peer the connection was blocked
+    pub blocked_at: Option<u64>,
+    pub flow_control_stats: FlowControlStats,
     /// Sum of end offsets of all streams. Includes gaps, so it's an upper bound.
     pub data_recvd: u64,
     /// Limit on incoming data
     pub local_max_data: u64,
     /// Server name (for client-side)
     pub server_name: Option<String>,
+    /// Address validation token echoed in our Initial packets, from the server's Retry or a NEW_TOKEN frame received
+    /// on an earlier connection (for client-side)
+    pub retry_token: Bytes,
+    /// Whether we've received a Retry, which a server may send only once (for client-side)
+    pub retried: bool,
+    /// Address validation token to issue in a NEW_TOKEN frame once the handshake completes (for server-side)
+    pub new_token: Option<Bytes>,
 
     //
     // Loss Detection
     //
     /// The number of times the handshake packets have been retransmitted without receiving an ack.
     pub handshake_count: u32,
-    /// The number of times a tail loss probe has been sent without receiving an ack.
-    pub tlp_count: u32,
-    /// The number of times an rto has been sent without receiving an ack.
-    pub rto_count: u32,
-    /// The largest packet number gap between the largest acked retransmittable packet and an unacknowledged
-    /// retransmittable packet before it is declared lost.
-    pub reordering_threshold: u32,
-    /// The time at which the next packet will be considered lost based on early transmit or exceeding the reordering
-    /// window in time.
-    pub loss_time: u64,
+    /// The number of times the probe timeout has fired without receiving an ack.
+    pub pto_count: u32,
+    /// Number of probe packets still to be sent in response to the probe timeout
+    ///
+    /// Probes are sent regardless of the congestion window and the pacer, carrying new data if there is any, or else a
+    /// PING, so the peer's ACK of them reveals which earlier packets were lost.
+    pub probes_pending: u8,
+    /// The largest packet number acknowledged in each packet number space, indexed by `SpaceId`
+    pub largest_acked: [Option<u64>; 3],
+    /// The time at which the next packet in each packet number space will be considered lost by the time threshold or
+    /// RACK, or 0 if none is awaited.
+    pub loss_time: [u64; 3],
+    /// The most recently sent packet to have been acknowledged, by which RACK judges older 1-RTT packets lost
+    pub rack: RackState,
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet. μs
     pub latest_rtt: u64,
     /// The smoothed RTT of the connection, computed as described in RFC6298. μs
@@ -83,16 +144,18 @@ pub struct Connection {
     pub rttvar: u64,
     /// The minimum RTT seen in the connection, ignoring ack delay.
     pub min_rtt: u64,
-    /// The maximum ack delay in an incoming ACK frame for this connection.
+    /// Longest the peer may delay acknowledging our ack-eliciting packets (μs)
     ///
-    /// Excludes ack delays for ack only packets and those that create an RTT sample less than min_rtt.
+    /// Advertised by the peer, or raised further by our own ACK_FREQUENCY requests.
     pub max_ack_delay: u64,
-    /// The last packet number sent prior to the first retransmission timeout.
-    pub largest_sent_before_rto: u64,
     /// The time the most recently sent retransmittable packet was sent.
     pub time_of_last_sent_retransmittable_packet: u64,
     /// The time the most recently sent handshake packet was sent.
     pub time_of_last_sent_handshake_packet: u64,
+    /// Whether an ack-eliciting packet has restarted the idle timer since a packet was last received.
+    ///
+    /// Later ones mustn't, or a connection whose peer has vanished would be kept alive by its own keep-alives.
+    pub idle_restarted_by_send: bool,
     /// The packet number of the most recently sent packet.
     pub largest_sent_packet: u64,
     /// The largest packet number the remote peer acknowledged in an ACK frame.
@@ -109,13 +172,22 @@ pub struct Connection {
     /// The size does not include IP or UDP overhead. Packets only containing ACK frames do not count towards
     /// bytes_in_flight to ensure congestion control does not impede congestion feedback.
     pub bytes_in_flight: u64,
-    /// Maximum number of bytes in flight that may be sent.
-    pub congestion_window: u64,
-    /// The largest packet number sent when QUIC detects a loss. When a larger packet is acknowledged, QUIC exits recovery.
-    pub end_of_recovery: u64,
-    /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is slow start and the
-    /// window grows by the number of bytes acknowledged.
-    pub ssthresh: u64,
+    /// Determines the maximum number of bytes in flight that may be sent.
+    pub congestion: Box<CongestionController>,
+    /// Spreads datagrams out at the congestion controller's pacing rate
+    pub pacer: Pacer,
+
+    //
+    // ECN
+    //
+    /// Progress of validating that the path preserves ECN markings
+    pub ecn_state: EcnState,
+    /// Number of ECT(0) probe packets declared lost during validation
+    pub ecn_probes_lost: u8,
+    /// Codepoints of the packets we've received, reported to the peer in ACK frames
+    pub ecn_counters: frame::EcnCounts,
+    /// Most recent codepoint counts reported by the peer
+    pub peer_ecn_counters: frame::EcnCounts,
 
     //
     // Handshake retransmit state
@@ -124,6 +196,11 @@ pub struct Connection {
     /// handshake completion, i.e. whether we're waiting for proof that the peer has advanced their handshake state
     /// machine.
     pub awaiting_handshake: bool,
+    /// Whether the handshake is confirmed, i.e. it's complete and the client knows the server agrees. Only then may
+    /// handshake state be discarded and the keys be updated.
+    pub handshake_confirmed: bool,
+    /// The certificate chain the peer authenticated with, copied out of the TLS session when the handshake completes
+    pub peer_certificates: Option<Vec<Certificate>>,
     pub handshake_pending: Retransmits,
     pub handshake_crypto: Crypto,
 
@@ -132,12 +209,30 @@ pub struct Connection {
     //
     pub pending: Retransmits,
     pub pending_acks: RangeSet,
+    /// 0-RTT packets received, which may only be acknowledged once we can send 1-RTT packets
+    pub zero_rtt_acks: RangeSet,
     /// Set iff we have received a non-ack frame since the last ack-only packet we sent
     pub permit_ack_only: bool,
+    /// Number of ack-eliciting packets received since we last sent an ACK
+    pub unacked_ack_eliciting: u64,
+    /// Number of ack-eliciting packets we may receive before acknowledging them immediately, as requested by the peer
+    pub ack_eliciting_threshold: u64,
+    /// Longest we may delay an acknowledgement, as requested by the peer (μs)
+    pub local_max_ack_delay: u64,
+    /// Sequence number of the most recent ACK_FREQUENCY frame received, if any
+    pub ack_frequency_received: Option<u64>,
+    /// Sequence number of the next ACK_FREQUENCY frame to send
+    pub next_ack_frequency: u64,
+    /// When the ACK for the oldest unacknowledged packet is due, if one is being delayed
+    pub ack_timer: Option<u64>,
 
     // Timer updates: None if no change, Some(None) to stop, Some(Some(_)) to reset
     pub set_idle: Option<Option<u64>>,
     pub set_loss_detection: Option<Option<u64>>,
+    pub set_ack: Option<Option<u64>>,
+    pub set_path_validation: Option<Option<u64>>,
+    pub set_pacing: Option<Option<u64>>,
+    pub set_keep_alive: Option<Option<u64>>,
 
     //
     // Stream states
@@ -151,7 +246,64 @@ pub struct Connection {
     // Remotely initiated
     pub max_remote_uni_streams: u64,
     pub max_remote_bi_streams: u64,
+    /// Receive window of streams the peer opens after those permitted initially
+    pub stream_receive_window: u64,
     pub finished_streams: Vec<StreamId>,
+    /// Chooses which stream's queued data is sent next
+    pub scheduler: StreamScheduler,
+
+    //
+    // Datagrams
+    //
+    /// Received datagrams not yet read by the application
+    pub datagrams: VecDeque<Bytes>,
+    /// Datagrams waiting to be transmitted. Never retransmitted, and the oldest are dropped when the application sends
+    /// faster than the path can carry them.
+    pub outgoing_datagrams: VecDeque<frame::Datagram>,
+
+    //
+    // Migration
+    //
+    /// Remote addresses we've sent to or received authenticated packets from, including `remote`
+    pub paths: FnvHashMap<SocketAddrV6, Path>,
+    /// The validated path we most recently moved off, to return to if the one we moved to fails validation
+    pub prev_remote: Option<SocketAddrV6>,
+    /// Unused connection IDs issued by the peer, with their sequence numbers and stateless reset tokens
+    pub remote_cids: VecDeque<(u64, ConnectionId, [u8; RESET_TOKEN_SIZE])>,
+    /// Sequence number of `remote_id`
+    pub remote_cid_sequence: u64,
+    /// Connection IDs issued by the peer with smaller sequence numbers must be retired
+    pub remote_retire_prior_to: u64,
+    /// Connection IDs we've issued to the peer, including `local_id`, that it hasn't retired
+    pub cid_pool: ConnectionIdPool,
+    /// PATH_CHALLENGE and PATH_RESPONSE frames to send on paths other than the active one, by destination
+    pub off_path_frames: VecDeque<(SocketAddrV6, frame::Type, u64)>,
+    /// Identifier to assign the next path we track
+    pub next_path_id: u64,
+    /// Path the datagram most recently assembled by `next_datagram` is to be sent on
+    pub tx_path: SocketAddrV6,
+    /// Number of datagrams sent while spreading traffic over several paths, to take turns with
+    pub round_robin: usize,
+
+    //
+    // Logging
+    //
+    /// Trace that events on this connection are recorded in, if any
+    pub qlog: Option<Arc<Mutex<QlogWriter>>>,
+    /// Whether the start of the connection has been recorded in `qlog`
+    pub qlog_started: bool,
+    /// Parent of the `tracing` events emitted for this connection, identifying it by local CID and remote address
+    #[cfg(feature = "tracing")]
+    pub span: ::tracing::Span,
+}
+
+/// The 1-RTT keys in use before a key update
+pub struct PrevCrypto {
+    pub crypto: Crypto,
+    /// Number and time of receipt of the first packet the peer protected with the new keys, once one has arrived
+    ///
+    /// Packets numbered below it were protected with these keys.
+    pub end_packet: Option<(u64, u64)>,
 }
 
 /// Represents one or more packets subject to retransmission
@@ -160,7 +312,9 @@ pub struct SentPacket {
     pub time: u64,
     /// 0 iff ack-only
     pub bytes: u16,
-    pub handshake: bool,
+    pub space: SpaceId,
+    /// Whether the packet was marked ECT(0)
+    pub ecn: bool,
     pub acks: RangeSet,
     pub retransmits: Retransmits,
 }
@@ -171,16 +325,199 @@ impl SentPacket {
     }
 }
 
+/// Identifies one of a connection's network paths
+///
+/// The path a connection starts out on is `PathId(0)`. Others are numbered in the order they're first used.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PathId(pub u64);
+
+/// State of a network path to the peer
+#[derive(Debug, Clone)]
+pub struct Path {
+    /// Identifies the path to the application
+    pub id: PathId,
+    /// Whether the application opened this path to use alongside the active one, rather than in place of it
+    pub extra: bool,
+    /// Connection ID issued by the peer for use on this path alone, with its sequence number and stateless reset token,
+    /// if it isn't the active path
+    pub remote_cid: Option<(u64, ConnectionId, [u8; RESET_TOKEN_SIZE])>,
+    /// Whether the peer has proven that it receives packets sent to this address
+    pub validated: bool,
+    /// Data of the most recent PATH_CHALLENGE sent on this path, if any
+    pub challenge: Option<u64>,
+    /// When we give up on validating this path, if we're awaiting a response to `challenge`
+    pub validation_deadline: Option<u64>,
+    /// Bytes received from this address
+    pub total_recvd: u64,
+    /// Bytes sent to this address
+    pub total_sent: u64,
+    pub pmtud: PmtudState,
+    /// Whether each of the last `LOSS_WINDOW` retransmittable packets acknowledged or declared lost while this path was
+    /// active was lost, oldest first
+    pub outcomes: VecDeque<bool>,
+    /// Number of packets in `outcomes` that were lost
+    pub lost: usize,
+    /// When we last sampled the delivery rates
+    pub rate_sample_time: Option<u64>,
+    /// Bytes acknowledged since the last rate sample
+    pub acked_since_sample: u64,
+    /// `total_recvd` at the last rate sample
+    pub recvd_at_sample: u64,
+    /// Smoothed rate at which the peer acknowledges our data, in bytes per second
+    pub send_rate: u64,
+    /// Smoothed rate at which we receive data from the peer, in bytes per second
+    pub recv_rate: u64,
+}
+
+impl Path {
+    fn new(config: &Config, id: PathId, validated: bool) -> Self {
+        Self {
+            id,
+            extra: false,
+            remote_cid: None,
+            validated,
+            challenge: None,
+            validation_deadline: None,
+            total_recvd: 0,
+            total_sent: 0,
+            pmtud: PmtudState::new(MIN_MTU, config.max_mtu, config.mtu_discovery),
+            outcomes: VecDeque::with_capacity(LOSS_WINDOW),
+            lost: 0,
+            rate_sample_time: None,
+            acked_since_sample: 0,
+            recvd_at_sample: 0,
+            send_rate: 0,
+            recv_rate: 0,
+        }
+    }
+
+    /// Record whether a retransmittable packet was lost or acknowledged
+    fn on_packet_resolved(&mut self, lost: bool) {
+        if self.outcomes.len() == LOSS_WINDOW && self.outcomes.pop_front().unwrap() {
+            self.lost -= 1;
+        }
+        self.outcomes.push_back(lost);
+        if lost {
+            self.lost += 1;
+        }
+    }
+
+    /// Update the delivery rates on receipt of an ACK newly acknowledging `acked` bytes
+    fn on_ack(&mut self, now: u64, acked: u64) {
+        self.acked_since_sample += acked;
+        let elapsed = match self.rate_sample_time {
+            Some(x) => now - x,
+            None => {
+                self.rate_sample_time = Some(now);
+                self.acked_since_sample = 0;
+                self.recvd_at_sample = self.total_recvd;
+                return;
+            }
+        };
+        if elapsed == 0 {
+            // Wait for ACKs to be spread out enough in time to measure
+            return;
+        }
+        let send_rate = self.acked_since_sample * 1_000_000 / elapsed;
+        let recv_rate = (self.total_recvd - self.recvd_at_sample) * 1_000_000 / elapsed;
+        // Smooth like the RTT, so a single burst doesn't dominate
+        self.send_rate = (7 * self.send_rate + send_rate) / 8;
+        self.recv_rate = (7 * self.recv_rate + recv_rate) / 8;
+        self.rate_sample_time = Some(now);
+        self.acked_since_sample = 0;
+        self.recvd_at_sample = self.total_recvd;
+    }
+
+    /// Fraction of recent packets that were lost
+    fn loss_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        self.lost as f64 / self.outcomes.len() as f64
+    }
+}
+
+/// Number of the most recent packets over which `PathStats::loss_rate` is measured
+const LOSS_WINDOW: usize = 256;
+
+/// Snapshot of measurements of the active network path of a connection
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PathStats {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+    /// Variation in round-trip time samples
+    pub rtt_variance: Duration,
+    /// Fraction of the recent retransmittable packets sent on this path that were declared lost
+    pub loss_rate: f64,
+    /// Congestion window, in bytes
+    pub cwnd: u64,
+    /// Bytes of retransmittable packets sent but neither acknowledged nor declared lost
+    pub bytes_in_flight: u64,
+    /// Rate at which the peer acknowledges data, in bytes per second
+    pub send_rate: u64,
+    /// Rate at which data is received from the peer, in bytes per second
+    pub recv_rate: u64,
+}
+
+/// Counts of flow control stalls on a connection, in either direction
+///
+/// A sender reports each limit it's blocked at once, so these count distinct stalls, except that a lost report is sent,
+/// and counted, again. Frequent stalls suggest a receive window too small for the connection's bandwidth-delay
+/// product.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct FlowControlStats {
+    /// BLOCKED frames sent, each reporting a connection-level limit we were blocked at
+    pub blocked_sent: u64,
+    /// STREAM_BLOCKED frames sent, each reporting a stream-level limit we were blocked at
+    pub stream_blocked_sent: u64,
+    /// BLOCKED frames received
+    pub blocked_received: u64,
+    /// STREAM_BLOCKED frames received
+    pub stream_blocked_received: u64,
+    /// STREAM_ID_BLOCKED frames received, each reporting that the peer wanted more streams than we allow
+    pub stream_id_blocked_received: u64,
+}
+
+/// Number of ECT(0) marked packets sent to test a path's ECN support
+pub const ECN_PROBE_PACKETS: u8 = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EcnState {
+    /// Marking probe packets; the number sent so far
+    Testing(u8),
+    /// All probes sent, awaiting acknowledgement
+    Unknown,
+    /// The peer has reported our markings, so all packets are marked
+    Capable,
+    /// Markings were lost or misreported, or ECN is disabled; packets are sent unmarked
+    Failed,
+}
+
 #[derive(Debug, Clone)]
 pub struct Retransmits {
     pub max_data: bool,
     pub max_uni_stream_id: bool,
     pub max_bi_stream_id: bool,
+    /// Whether to tell the peer we're blocked from opening streams of each directionality
+    pub uni_stream_id_blocked: bool,
+    pub bi_stream_id_blocked: bool,
+    /// Whether to tell the peer we're blocked by connection-level flow control
+    pub blocked: bool,
+    /// Streams on which to tell the peer we're blocked by stream-level flow control
+    pub stream_blocked: FnvHashSet<StreamId>,
     pub ping: bool,
-    pub new_connection_id: Option<ConnectionId>,
+    /// Whether to tell the client the handshake is confirmed
+    pub handshake_done: bool,
+    pub new_cids: Vec<frame::NewConnectionId>,
+    /// Sequence numbers of connection IDs issued by the peer that we've stopped using
+    pub retire_cids: Vec<u64>,
+    pub new_token: Option<Bytes>,
     pub stream: VecDeque<frame::Stream>,
     /// packet number, token
     pub path_response: Option<(u64, u64)>,
+    /// PATH_CHALLENGE data validating the active path
+    pub challenge: Option<u64>,
+    pub ack_frequency: Option<frame::AckFrequency>,
     pub rst_stream: Vec<(StreamId, u16)>,
     pub stop_sending: Vec<(StreamId, u16)>,
     pub max_stream_data: FnvHashSet<StreamId>,
@@ -191,10 +528,19 @@ impl Retransmits {
         !self.max_data
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
+            && !self.uni_stream_id_blocked
+            && !self.bi_stream_id_blocked
+            && !self.blocked
+            && self.stream_blocked.is_empty()
             && !self.ping
-            && self.new_connection_id.is_none()
+            && !self.handshake_done
+            && self.new_cids.is_empty()
+            && self.retire_cids.is_empty()
+            && self.new_token.is_none()
             && self.stream.is_empty()
             && self.path_response.is_none()
+            && self.challenge.is_none()
+            && self.ack_frequency.is_none()
             && self.rst_stream.is_empty()
             && self.stop_sending.is_empty()
             && self.max_stream_data.is_empty()
@@ -219,10 +565,19 @@ impl Default for Retransmits {
             max_data: false,
             max_uni_stream_id: false,
             max_bi_stream_id: false,
+            uni_stream_id_blocked: false,
+            bi_stream_id_blocked: false,
+            blocked: false,
+            stream_blocked: FnvHashSet::default(),
             ping: false,
-            new_connection_id: None,
+            handshake_done: false,
+            new_cids: Vec::new(),
+            retire_cids: Vec::new(),
+            new_token: None,
             stream: VecDeque::new(),
             path_response: None,
+            challenge: None,
+            ack_frequency: None,
             rst_stream: Vec::new(),
             stop_sending: Vec::new(),
             max_stream_data: FnvHashSet::default(),
@@ -234,15 +589,31 @@ impl ::std::ops::AddAssign for Retransmits {
     fn add_assign(&mut self, rhs: Self) {
         self.max_data |= rhs.max_data;
         self.ping |= rhs.ping;
+        self.handshake_done |= rhs.handshake_done;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
-        if let Some(x) = rhs.new_connection_id {
-            self.new_connection_id = Some(x);
+        self.uni_stream_id_blocked |= rhs.uni_stream_id_blocked;
+        self.bi_stream_id_blocked |= rhs.bi_stream_id_blocked;
+        self.blocked |= rhs.blocked;
+        self.stream_blocked.extend(&rhs.stream_blocked);
+        self.new_cids.extend(rhs.new_cids.into_iter());
+        self.retire_cids.extend_from_slice(&rhs.retire_cids);
+        if rhs.new_token.is_some() {
+            self.new_token = rhs.new_token;
         }
         self.stream.extend(rhs.stream.into_iter());
         if let Some((packet, token)) = rhs.path_response {
             self.path_challenge(packet, token);
         }
+        if rhs.challenge.is_some() {
+            self.challenge = rhs.challenge;
+        }
+        // Only the most recent request matters
+        if let Some(x) = rhs.ack_frequency {
+            if self.ack_frequency.map_or(true, |y| x.sequence > y.sequence) {
+                self.ack_frequency = Some(x);
+            }
+        }
         self.rst_stream.extend_from_slice(&rhs.rst_stream);
         self.stop_sending.extend_from_slice(&rhs.stop_sending);
         self.max_stream_data.extend(&rhs.max_stream_data);
@@ -270,9 +641,11 @@ impl Connection {
         remote: SocketAddrV6,
         initial_packet_number: u64,
         side: Side,
+        version: Version,
         config: &Config,
     ) -> Self {
-        let handshake_crypto = Crypto::new_handshake(&initial_id, side);
+        let handshake_crypto = Crypto::new_handshake(&initial_id, side, version);
+        let cid_pool = ConnectionIdPool::new(local_id.clone(), ISSUED_CIDS + 1);
         let mut streams = FnvHashMap::default();
         for i in 0..config.max_remote_uni_streams {
             streams.insert(
@@ -298,65 +671,107 @@ impl Connection {
                 Stream::new_bi(config.stream_receive_window as u64),
             );
         }
+        let mut paths = FnvHashMap::default();
+        paths.insert(remote, Path::new(config, PathId(0), true));
+        #[cfg(feature = "tracing")]
+        let span =
+            ::tracing::debug_span!("connection", connection_id = %local_id, remote_addr = %remote);
         Self {
             initial_id,
             local_id,
             remote_id,
             remote,
             side,
+            version,
+            initial_version: version,
             state: None,
-            mtu: MIN_MTU,
             rx_packet: 0,
             rx_packet_time: 0,
             crypto: None,
             prev_crypto: None,
-            //zero_rtt_crypto: None,
+            zero_rtt_crypto: None,
+            early_data_budget: 0,
             key_phase: false,
-            params: TransportParameters::new(config),
+            key_phase_start: initial_packet_number,
+            key_update_requested: false,
+            key_updates: 0,
+            spin: false,
+            params: TransportParameters {
+                // Ours stand in until the peer's arrive, minus anything only the peer can permit
+                custom: Default::default(),
+                grease_quic_bit: false,
+                min_ack_delay: None,
+                ..TransportParameters::new(config)
+            },
             readable_streams: FnvHashSet::default(),
             blocked_streams: FnvHashSet::default(),
+            blocked_at: None,
+            flow_control_stats: FlowControlStats::default(),
             max_data: 0,
             data_sent: 0,
+            unacked_data: 0,
+            send_buffer_size: u64::from(config.send_buffer_size),
             data_recvd: 0,
             local_max_data: config.receive_window as u64,
             server_name: None,
+            retry_token: Bytes::new(),
+            retried: false,
+            new_token: None,
 
             handshake_count: 0,
-            tlp_count: 0,
-            rto_count: 0,
-            reordering_threshold: if config.using_time_loss_detection {
-                u32::max_value()
-            } else {
-                config.reordering_threshold
-            },
-            loss_time: 0,
+            pto_count: 0,
+            probes_pending: 0,
+            largest_acked: [None; 3],
+            loss_time: [0; 3],
+            rack: RackState::new(),
             latest_rtt: 0,
             smoothed_rtt: 0,
             rttvar: 0,
             min_rtt: u64::max_value(),
-            max_ack_delay: 0,
-            largest_sent_before_rto: 0,
+            max_ack_delay: u64::from(TransportParameters::default().max_ack_delay) * 1000,
             time_of_last_sent_retransmittable_packet: 0,
+            idle_restarted_by_send: false,
             time_of_last_sent_handshake_packet: 0,
             largest_sent_packet: initial_packet_number.overflowing_sub(1).0,
             largest_acked_packet: 0,
             sent_packets: BTreeMap::new(),
 
             bytes_in_flight: 0,
-            congestion_window: config.initial_window,
-            end_of_recovery: 0,
-            ssthresh: u64::max_value(),
+            congestion: config.congestion_controller_factory.build(config),
+            pacer: Pacer::new(config.pacing_burst),
+
+            ecn_state: if config.ecn {
+                EcnState::Testing(0)
+            } else {
+                EcnState::Failed
+            },
+            ecn_probes_lost: 0,
+            ecn_counters: frame::EcnCounts::default(),
+            peer_ecn_counters: frame::EcnCounts::default(),
 
             awaiting_handshake: false,
+            handshake_confirmed: false,
+            peer_certificates: None,
             handshake_pending: Retransmits::default(),
             handshake_crypto,
 
             pending: Retransmits::default(),
             pending_acks: RangeSet::new(),
+            zero_rtt_acks: RangeSet::new(),
             permit_ack_only: false,
+            unacked_ack_eliciting: 0,
+            ack_eliciting_threshold: 0,
+            local_max_ack_delay: 0,
+            ack_frequency_received: None,
+            next_ack_frequency: 0,
+            ack_timer: None,
 
             set_idle: None,
             set_loss_detection: None,
+            set_ack: None,
+            set_path_validation: None,
+            set_pacing: None,
+            set_keep_alive: None,
 
             streams,
             next_uni_stream: 0,
@@ -368,17 +783,89 @@ impl Connection {
             max_bi_streams: 0,
             max_remote_uni_streams: config.max_remote_uni_streams as u64,
             max_remote_bi_streams,
+            stream_receive_window: u64::from(config.stream_receive_window),
             finished_streams: Vec::new(),
+            scheduler: StreamScheduler::new(),
+
+            datagrams: VecDeque::new(),
+            outgoing_datagrams: VecDeque::new(),
+
+            paths,
+            prev_remote: None,
+            remote_cids: VecDeque::new(),
+            remote_cid_sequence: 0,
+            remote_retire_prior_to: 0,
+            cid_pool,
+            off_path_frames: VecDeque::new(),
+            next_path_id: 1,
+            tx_path: remote,
+            round_robin: 0,
+
+            qlog: None,
+            qlog_started: false,
+            #[cfg(feature = "tracing")]
+            span,
         }
     }
 
-    /// Initiate a connection
-    pub fn connect(&mut self, ctx: &Context, server_name: &str) -> Result<(), ConnectError> {
-        let mut tls = TlsSession::new_client(
-            &ctx.config.tls_client_config,
-            server_name,
-            &TransportParameters::new(&ctx.config),
-        ).unwrap();
+    /// Record events on this connection in `qlog`
+    pub fn with_qlog(mut self, qlog: Arc<Mutex<QlogWriter>>) -> Self {
+        self.qlog = Some(qlog);
+        self
+    }
+
+    /// Record an event with `f` in our qlog trace, if any, recording the start of the connection first if necessary
+    ///
+    /// `f` is passed the ID that groups the connection's events.
+    pub fn qlog<F: FnOnce(&mut QlogWriter, &ConnectionId)>(&mut self, now: u64, f: F) {
+        let qlog = match self.qlog {
+            Some(ref x) => x.clone(),
+            None => return,
+        };
+        let mut qlog = qlog.lock().unwrap();
+        if !self.qlog_started {
+            self.qlog_started = true;
+            qlog.connection_started(
+                now,
+                &self.initial_id,
+                &self.remote,
+                &self.local_id,
+                &self.remote_id,
+            );
+        }
+        f(&mut *qlog, &self.initial_id);
+    }
+
+    /// Initiate a connection, resuming the session described by `ticket` if any
+    pub fn connect(
+        &mut self,
+        ctx: &Context,
+        server_name: &str,
+        ticket: Option<SessionTicket>,
+    ) -> Result<(), ConnectError> {
+        let mut params = TransportParameters {
+            version: self.initial_version.number(),
+            ..TransportParameters::new(&ctx.config)
+        };
+        if let Some(ticket) = ticket {
+            params.resumption_ticket = Some(ticket.id);
+            // The server's previous parameters govern our 0-RTT data until the new handshake completes
+            self.set_params(TransportParameters {
+                // Bound to the old connection's IDs
+                stateless_reset_token: None,
+                preferred_address: None,
+                ..ticket.params.clone()
+            });
+            // Writes block once the server's limit on early data is reached, until the handshake completes
+            self.max_data = cmp::min(self.max_data, u64::from(ticket.max_early_data));
+            self.zero_rtt_crypto = Some(Crypto::new_0rtt(&ticket, self.version));
+        }
+        // Lets a server that validates addresses skip Retry
+        if let Some(token) = ctx.config.token_store.lock().unwrap().load(server_name) {
+            self.retry_token = token;
+        }
+        let mut tls =
+            TlsSession::new_client(&ctx.config.tls_client_config, server_name, &params).unwrap();
         self.server_name = Some(server_name.into());
         let mut outgoing = Vec::new();
         tls.write_tls(&mut outgoing).unwrap();
@@ -396,13 +883,14 @@ impl Connection {
         ctx: &mut Context,
         mut tls: TlsSession,
         params: TransportParameters,
-        //zero_rtt_crypto: Option<Crypto>,
+        zero_rtt_crypto: Option<Crypto>,
         now: u64,
         packet_number: u64,
+        ecn: Option<EcnCodepoint>,
         conn: ConnectionHandle,
     ) {
-        //self.zero_rtt_crypto = zero_rtt_crypto;
-        self.on_packet_authenticated(ctx, now, packet_number);
+        self.zero_rtt_crypto = zero_rtt_crypto;
+        self.on_packet_authenticated(ctx, now, PacketType::Initial, packet_number, ecn);
         let mut outgoing = Vec::new();
         tls.write_tls(&mut outgoing).unwrap();
         self.transmit_handshake(&outgoing);
@@ -414,6 +902,35 @@ impl Connection {
         self.set_params(params);
         ctx.dirty_conns.insert(conn);
         ctx.incoming_handshakes += 1;
+        if self.zero_rtt_crypto.is_some() {
+            // Let the application read 0-RTT data without waiting for the handshake to complete
+            ctx.incoming.push_back(conn);
+        }
+    }
+
+    /// Take over the streams of `old`, a connection reset by a Retry before any of its 0-RTT packets could be accepted
+    ///
+    /// Data written in 0-RTT is queued to be sent again once the handshake completes.
+    fn inherit_early_data(&mut self, old: &mut Connection) {
+        for (id, stream) in old.streams.drain() {
+            if id != StreamId(0) {
+                self.streams.insert(id, stream);
+            }
+        }
+        for (_, packet) in mem::replace(&mut old.sent_packets, BTreeMap::new()) {
+            if packet.space == SpaceId::Data {
+                self.pending += packet.retransmits;
+            }
+        }
+        self.pending += mem::replace(&mut old.pending, Retransmits::default());
+        self.next_uni_stream = old.next_uni_stream;
+        self.next_bi_stream = old.next_bi_stream;
+        self.max_uni_streams = old.max_uni_streams;
+        self.max_bi_streams = old.max_bi_streams;
+        self.max_data = old.max_data;
+        self.data_sent = old.data_sent;
+        self.params = old.params.clone();
+        self.max_ack_delay = old.max_ack_delay;
     }
 
     pub fn get_tx_number(&mut self) -> u64 {
@@ -431,20 +948,52 @@ impl Connection {
         packet: SentPacket,
     ) {
         self.largest_sent_packet = packet_number;
+        // Application data sent by a client before the handshake completes is 0-RTT
+        let zero_rtt = match self.state {
+            Some(State::Handshake(_)) => self.side == Side::Client,
+            _ => false,
+        };
+        let ty = PacketType::new(packet.space, zero_rtt);
+        self.qlog(now, |qlog, group| {
+            qlog.packet_sent(now, group, ty, packet_number, &packet)
+        });
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!(parent: &self.span, pn = packet_number, space = ?packet.space, bytes = packet.bytes, "packet sent");
         let bytes = packet.bytes;
-        let handshake = packet.handshake;
+        let handshake = packet.space != SpaceId::Data;
         if handshake {
             self.awaiting_handshake = true;
         }
+        if packet.ecn {
+            if let EcnState::Testing(n) = self.ecn_state {
+                self.ecn_state = if n + 1 == ECN_PROBE_PACKETS {
+                    EcnState::Unknown
+                } else {
+                    EcnState::Testing(n + 1)
+                };
+            }
+        }
         self.sent_packets.insert(packet_number, packet);
+        self.congestion
+            .on_packet_sent(now, packet_number, bytes as u64);
         if bytes != 0 {
             self.time_of_last_sent_retransmittable_packet = now;
+            if !self.idle_restarted_by_send {
+                self.idle_restarted_by_send = true;
+                self.reset_idle_timeout(config, now);
+            }
+            if let Some(interval) = config.keep_alive_interval {
+                self.set_keep_alive = Some(Some(now + interval));
+            }
             if handshake {
                 self.time_of_last_sent_handshake_packet = now;
+            } else if self.probes_pending != 0 {
+                self.probes_pending -= 1;
             }
             self.bytes_in_flight += bytes as u64;
             self.set_loss_detection_alarm(config);
         }
+        self.maybe_update_keys(config);
     }
 
     pub fn on_ack_received(
@@ -460,21 +1009,85 @@ impl Connection {
         if let Some(info) = self.sent_packets.get(&ack.largest).cloned() {
             self.latest_rtt = now - info.time;
             let delay = ack.delay << self.params.ack_delay_exponent;
-            self.update_rtt(delay, info.ack_only());
+            self.update_rtt(delay);
         }
+        let mut newly_acked_ecn = 0;
+        let mut newly_acked_bytes = 0;
+        let mut newly_acked_packets = 0;
+        let mut newest_acked = None;
+        let mut newest_ping = None;
+        let mut reordered = false;
+        let mut largest_newly_acked = [None; 3];
+        let rack_packet = self.rack.packet;
         for range in &ack {
             // Avoid DoS from unreasonably huge ack ranges
             let packets = self
                 .sent_packets
                 .range(range)
-                .map(|(&n, _)| n)
-                .collect::<Vec<_>>();
+                .map(|(&n, info)| {
+                    if newest_acked.map_or(true, |(x, _)| n > x) {
+                        newest_acked = Some((n, info.time));
+                    }
+                    let largest = &mut largest_newly_acked[info.space as usize];
+                    *largest = cmp::max(*largest, Some(n));
+                    if rack_packet.map_or(false, |x| n < x) {
+                        reordered = true;
+                    }
+                    if info.ecn {
+                        newly_acked_ecn += 1;
+                    }
+                    if info.retransmits.ping && newest_ping.map_or(true, |x| info.time > x) {
+                        newest_ping = Some(info.time);
+                    }
+                    if !info.ack_only() {
+                        newly_acked_bytes += info.bytes as u64;
+                        newly_acked_packets += 1;
+                    }
+                    n
+                }).collect::<Vec<_>>();
             for packet in packets {
-                self.on_packet_acked(&ctx.config, packet);
+                self.on_packet_acked(now, packet);
+            }
+        }
+        for (largest, &newly) in self.largest_acked.iter_mut().zip(&largest_newly_acked) {
+            *largest = cmp::max(*largest, newly);
+        }
+        {
+            let path = self.paths.get_mut(&self.remote).unwrap();
+            for _ in 0..newly_acked_packets {
+                path.on_packet_resolved(false);
             }
+            path.on_ack(now, newly_acked_bytes);
+        }
+        if let Some(newest) = newest_acked {
+            self.rack.on_ack(now, newest, reordered, self.smoothed_rtt);
         }
-        self.detect_lost_packets(&ctx.config, now, ack.largest);
+        if let Some(sent) = newest_ping {
+            ctx.events
+                .push_back((conn, Event::PingAcknowledged { sent }));
+        }
+        if !self.handshake_confirmed
+            && !self.version.handshake_done()
+            && largest_newly_acked[SpaceId::Data as usize]
+                .map_or(false, |x| x >= self.key_phase_start)
+        {
+            // The server can only have acknowledged a 1-RTT packet once it completed the handshake
+            trace!(ctx.log, "handshake confirmed");
+            self.handshake_confirmed = true;
+            self.handshake_cleanup(&ctx.config, now);
+        }
+        if ack.largest >= self.key_phase_start {
+            // The peer has the current keys, so a key update that was held back may now proceed
+            self.maybe_update_keys(&ctx.config);
+        }
+        self.process_ecn(newly_acked_ecn, ack.largest, ack.ecn);
+        self.detect_lost_packets(&ctx.config, now);
         self.set_loss_detection_alarm(&ctx.config);
+        let (window, in_flight) = (self.congestion.window(), self.bytes_in_flight);
+        let (smoothed_rtt, latest_rtt) = (self.smoothed_rtt, self.latest_rtt);
+        self.qlog(now, |qlog, group| {
+            qlog.metrics_updated(now, group, window, in_flight, smoothed_rtt, latest_rtt)
+        });
         if was_blocked && !self.blocked() {
             for stream in self.blocked_streams.drain() {
                 ctx.events
@@ -483,13 +1096,53 @@ impl Connection {
         }
     }
 
-    pub fn update_rtt(&mut self, ack_delay: u64, ack_only: bool) {
+    /// Validate the ECN counts reported by an ACK frame and respond to congestion they signal
+    ///
+    /// `newly_acked` is the number of ECT(0) marked packets that the ACK acknowledged for the first time.
+    fn process_ecn(&mut self, newly_acked: u64, largest_acked: u64, ecn: Option<frame::EcnCounts>) {
+        if self.ecn_state == EcnState::Failed {
+            return;
+        }
+        let ecn = match ecn {
+            Some(x) => x,
+            None => {
+                if newly_acked != 0 {
+                    // Our markings were removed by the path or ignored by the peer
+                    self.ecn_state = EcnState::Failed;
+                }
+                return;
+            }
+        };
+        let old = self.peer_ecn_counters;
+        if ecn.ect0 < old.ect0 || ecn.ect1 < old.ect1 || ecn.ce < old.ce {
+            // Counts never decrease, except through reordering of ACK frames
+            if largest_acked >= self.largest_acked_packet {
+                self.ecn_state = EcnState::Failed;
+            }
+            return;
+        }
+        self.peer_ecn_counters = ecn;
+        let ce_increase = ecn.ce - old.ce;
+        if (ecn.ect0 - old.ect0) + ce_increase < newly_acked || ecn.ect1 != old.ect1 {
+            // We only send ECT(0), so any other outcome indicates a path that rewrites markings
+            self.ecn_state = EcnState::Failed;
+            return;
+        }
+        if newly_acked != 0 {
+            self.ecn_state = EcnState::Capable;
+        }
+        if ce_increase != 0 {
+            self.congestion
+                .on_congestion_event(largest_acked, CongestionEvent::Ecn);
+        }
+    }
+
+    pub fn update_rtt(&mut self, ack_delay: u64) {
         self.min_rtt = cmp::min(self.min_rtt, self.latest_rtt);
+        // The peer promised not to delay ACKs for longer, so any excess is part of the path's round trip
+        let ack_delay = cmp::min(ack_delay, self.max_ack_delay);
         if self.latest_rtt - self.min_rtt > ack_delay {
             self.latest_rtt -= ack_delay;
-            if !ack_only {
-                self.max_ack_delay = cmp::max(self.max_ack_delay, ack_delay);
-            }
         }
         if self.smoothed_rtt == 0 {
             self.smoothed_rtt = self.latest_rtt;
@@ -501,40 +1154,28 @@ impl Connection {
         }
     }
 
-    // Not timing-aware, so it's safe to call this for inferred acks, such as arise from high-latency handshakes
-    pub fn on_packet_acked(&mut self, config: &Config, packet: u64) {
+    // Takes no RTT sample, so it's safe to call this for inferred acks, such as arise from high-latency handshakes
+    pub fn on_packet_acked(&mut self, now: u64, packet: u64) {
         let info = if let Some(x) = self.sent_packets.remove(&packet) {
             x
         } else {
             return;
         };
+        for (&remote, path) in self.paths.iter_mut() {
+            if path.pmtud.on_acked(packet) && remote == self.remote {
+                self.congestion.on_mtu_update(path.pmtud.plpmtu);
+            }
+        }
         if info.bytes != 0 {
             // Congestion control
             self.bytes_in_flight -= info.bytes as u64;
-            // Do not increase congestion window in recovery period.
-            if !self.in_recovery(packet) {
-                if self.congestion_window < self.ssthresh {
-                    // Slow start.
-                    self.congestion_window += info.bytes as u64;
-                } else {
-                    // Congestion avoidance.
-                    self.congestion_window +=
-                        config.default_mss * info.bytes as u64 / self.congestion_window;
-                }
-            }
+            self.congestion
+                .on_ack_received(now, self.smoothed_rtt, packet, info.bytes as u64);
         }
 
         // Loss recovery
-
-        // If a packet sent prior to RTO was acked, then the RTO was spurious.  Otherwise, inform congestion control.
-        if self.rto_count > 0 && packet > self.largest_sent_before_rto {
-            // Retransmission timeout verified
-            self.congestion_window = config.minimum_window;
-        }
-
         self.handshake_count = 0;
-        self.tlp_count = 0;
-        self.rto_count = 0;
+        self.pto_count = 0;
 
         // Update state for confirmed delivery of frames
         for (id, _) in info.retransmits.rst_stream {
@@ -556,6 +1197,10 @@ impl Connection {
                     continue;
                 };
                 ss.bytes_in_flight -= frame.data.len() as u64;
+                // Data of reset streams was forgotten when they were reset
+                if frame.id != StreamId(0) && !ss.state.was_reset() {
+                    self.unacked_data -= frame.data.len() as u64;
+                }
                 if ss.state == stream::SendState::DataSent && ss.bytes_in_flight == 0 {
                     ss.state = stream::SendState::DataRecvd;
                     true
@@ -571,59 +1216,128 @@ impl Connection {
         self.pending_acks.subtract(&info.acks);
     }
 
-    pub fn detect_lost_packets(&mut self, config: &Config, now: u64, largest_acked: u64) {
-        self.loss_time = 0;
-        let mut lost_packets = Vec::<u64>::new();
-        let delay_until_lost;
+    /// Declare lost the packets acknowledged neither in time nor soon enough after later packets in the same space
+    ///
+    /// A packet is lost once a packet sent `reordering_threshold` packets after it has been acknowledged, or once a
+    /// fraction of an RTT longer than the RTT has passed since it was sent with a later packet since acknowledged.
+    /// RACK takes the place of both for 1-RTT packets if enabled.
+    pub fn detect_lost_packets(&mut self, config: &Config, now: u64) {
+        self.loss_time = [0; 3];
         let rtt = cmp::max(self.latest_rtt, self.smoothed_rtt);
-        if config.using_time_loss_detection {
-            // factor * (1 + fraction)
-            delay_until_lost = (rtt + (rtt * config.time_reordering_fraction as u64)) >> 16;
-        } else if largest_acked == self.largest_sent_packet {
-            // Early retransmit alarm.
-            delay_until_lost = (5 * rtt) / 4;
-        } else {
-            delay_until_lost = u64::max_value();
-        }
-        for (&packet, info) in self.sent_packets.range(0..largest_acked) {
-            let time_since_sent = now - info.time;
-            let delta = largest_acked - packet;
-            // Use of >= for time comparison here is critical so that we successfully detect lost packets in testing
-            // when rtt = 0
-            if time_since_sent >= delay_until_lost || delta > self.reordering_threshold as u64 {
-                lost_packets.push(packet);
-            } else if self.loss_time == 0 && delay_until_lost != u64::max_value() {
-                self.loss_time = now + delay_until_lost - time_since_sent;
+        let loss_delay = cmp::max(
+            rtt + ((rtt * u64::from(config.time_reordering_fraction)) >> 16),
+            config.timer_granularity,
+        );
+        let congestion_period =
+            self.pto_base(config) * u64::from(config.persistent_congestion_threshold);
+        let mut lost_packets = Vec::<u64>::new();
+        let mut persistent_congestion = false;
+        for &space in &[SpaceId::Initial, SpaceId::Handshake, SpaceId::Data] {
+            let largest_acked = match self.largest_acked[space as usize] {
+                Some(x) => x,
+                None => continue,
+            };
+            let rack = space == SpaceId::Data && config.using_rack;
+            // When the earliest of a run of lost ack-eliciting packets, with none outstanding between them, was sent
+            let mut run_start = None;
+            for (&packet, info) in self.sent_packets.range(0..largest_acked) {
+                if info.space != space {
+                    continue;
+                }
+                if rack && !self.rack.is_older(packet) {
+                    run_start = None;
+                    continue;
+                }
+                let loss_time = if rack {
+                    self.rack.loss_time(info.time, self.smoothed_rtt)
+                } else {
+                    info.time + loss_delay
+                };
+                let reordered_past =
+                    !rack && largest_acked - packet >= u64::from(config.reordering_threshold);
+                // Use of >= for time comparison here is critical so that we successfully detect lost packets in
+                // testing when rtt = 0
+                if now >= loss_time || reordered_past {
+                    lost_packets.push(packet);
+                    if !info.ack_only() {
+                        match run_start {
+                            Some(start) => {
+                                persistent_congestion |= info.time - start > congestion_period;
+                            }
+                            None => {
+                                run_start = Some(info.time);
+                            }
+                        }
+                    }
+                } else {
+                    let due = &mut self.loss_time[space as usize];
+                    if *due == 0 || loss_time < *due {
+                        *due = loss_time;
+                    }
+                    run_start = None;
+                }
             }
         }
 
-        if let Some(largest_lost) = lost_packets.last().cloned() {
+        if let Some(largest_lost) = lost_packets.iter().cloned().max() {
             let old_bytes_in_flight = self.bytes_in_flight;
+            let mut probe_bytes = 0;
             for packet in lost_packets {
                 let mut info = self.sent_packets.remove(&packet).unwrap();
-                if info.handshake {
+                let ty = PacketType::new(info.space, false);
+                self.qlog(now, |qlog, group| qlog.packet_lost(now, group, ty, packet));
+                #[cfg(feature = "tracing")]
+                ::tracing::trace!(parent: &self.span, pn = packet, space = ?info.space, "packet lost");
+                if info.space != SpaceId::Data {
                     self.handshake_pending += info.retransmits;
                 } else {
                     self.pending += info.retransmits;
                 }
                 self.bytes_in_flight -= info.bytes as u64;
+                if info.ecn {
+                    self.on_ecn_probe_lost();
+                }
+                if self.paths.values_mut().any(|x| x.pmtud.on_lost(packet)) {
+                    probe_bytes += info.bytes as u64;
+                } else if info.bytes != 0 {
+                    self.paths
+                        .get_mut(&self.remote)
+                        .unwrap()
+                        .on_packet_resolved(true);
+                }
+            }
+            // Don't apply congestion penalty for lost ack-only packets, or MTU probes, which are lost for their size
+            let lost_bytes = old_bytes_in_flight - self.bytes_in_flight - probe_bytes;
+            if lost_bytes != 0 {
+                self.congestion.on_packets_lost(largest_lost, lost_bytes);
+                if persistent_congestion {
+                    // Nothing got through for several probe timeouts, so the path's capacity is unknown
+                    self.congestion.on_persistent_congestion();
+                }
             }
-            // Don't apply congestion penalty for lost ack-only packets
-            let lost_nonack = old_bytes_in_flight != self.bytes_in_flight;
-            // Start a new recovery epoch if the lost packet is larger than the end of the previous recovery epoch.
-            if lost_nonack && !self.in_recovery(largest_lost) {
-                self.end_of_recovery = self.largest_sent_packet;
-                // *= factor
-                self.congestion_window =
-                    (self.congestion_window * config.loss_reduction_factor as u64) >> 16;
-                self.congestion_window = cmp::max(self.congestion_window, config.minimum_window);
-                self.ssthresh = self.congestion_window;
+        }
+    }
+
+    fn on_ecn_probe_lost(&mut self) {
+        match self.ecn_state {
+            EcnState::Testing(_) | EcnState::Unknown => {}
+            _ => {
+                return;
             }
         }
+        self.ecn_probes_lost += 1;
+        if self.ecn_probes_lost == ECN_PROBE_PACKETS {
+            // Some paths drop ECN-marked packets outright
+            self.ecn_state = EcnState::Failed;
+        }
     }
 
-    pub fn in_recovery(&self, packet: u64) -> bool {
-        packet <= self.end_of_recovery
+    /// Codepoint to mark the next outgoing packet with, if any
+    pub fn ecn_codepoint(&self) -> Option<EcnCodepoint> {
+        match self.ecn_state {
+            EcnState::Testing(_) | EcnState::Capable => Some(EcnCodepoint::ECT0),
+            EcnState::Unknown | EcnState::Failed => None,
+        }
     }
 
     pub fn set_loss_detection_alarm(&mut self, config: &Config) {
@@ -640,7 +1354,8 @@ impl Connection {
             } else {
                 alarm_duration = 2 * self.smoothed_rtt;
             }
-            alarm_duration = cmp::max(alarm_duration + self.max_ack_delay, config.min_tlp_timeout);
+            // The peer acknowledges handshake packets immediately, so its ACK delay doesn't apply
+            alarm_duration = cmp::max(alarm_duration, config.timer_granularity);
             alarm_duration *= 2u64.pow(self.handshake_count);
             self.set_loss_detection = Some(Some(
                 self.time_of_last_sent_handshake_packet + alarm_duration,
@@ -648,37 +1363,62 @@ impl Connection {
             return;
         }
 
-        if self.loss_time != 0 {
-            // Early retransmit timer or time loss detection.
-            alarm_duration = self.loss_time - self.time_of_last_sent_retransmittable_packet;
-        } else {
-            // TLP or RTO alarm
-            alarm_duration = self.rto(config);
-            if self.tlp_count < config.max_tlps {
-                // Tail Loss Probe
-                let tlp_duration = cmp::max(
-                    (3 * self.smoothed_rtt) / 2 + self.max_ack_delay,
-                    config.min_tlp_timeout,
-                );
-                alarm_duration = cmp::min(alarm_duration, tlp_duration);
-            }
+        if let Some(loss_time) = self.earliest_loss_time() {
+            // Time threshold loss detection or RACK. The deadline may predate the last packet sent.
+            self.set_loss_detection = Some(Some(loss_time));
+            return;
         }
+        // Probe timeout
         self.set_loss_detection = Some(Some(
-            self.time_of_last_sent_retransmittable_packet + alarm_duration,
+            self.time_of_last_sent_retransmittable_packet + self.pto(config),
         ));
     }
 
-    /// Retransmit time-out
-    pub fn rto(&self, config: &Config) -> u64 {
-        let computed = self.smoothed_rtt + 4 * self.rttvar + self.max_ack_delay;
-        cmp::max(computed, config.min_rto_timeout) * 2u64.pow(self.rto_count)
+    /// When the next packet in any packet number space is due to be declared lost, if any is awaited
+    pub fn earliest_loss_time(&self) -> Option<u64> {
+        self.loss_time.iter().cloned().filter(|&x| x != 0).min()
+    }
+
+    /// Probe timeout, backed off exponentially while probes go unanswered
+    pub fn pto(&self, config: &Config) -> u64 {
+        self.pto_base(config) * 2u64.pow(self.pto_count)
+    }
+
+    /// Time in which an ACK for an ack-eliciting packet is expected, allowing for RTT variation and the peer's ACK delay
+    fn pto_base(&self, config: &Config) -> u64 {
+        let (smoothed_rtt, rttvar) = if self.smoothed_rtt == 0 {
+            // No RTT sample yet
+            (config.default_initial_rtt, config.default_initial_rtt / 2)
+        } else {
+            (self.smoothed_rtt, self.rttvar)
+        };
+        smoothed_rtt + cmp::max(4 * rttvar, config.timer_granularity) + self.max_ack_delay
     }
 
-    pub fn on_packet_authenticated(&mut self, ctx: &mut Context, now: u64, packet: u64) {
+    pub fn on_packet_authenticated(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        ty: PacketType,
+        packet: u64,
+        ecn: Option<EcnCodepoint>,
+    ) {
         trace!(ctx.log, "packet authenticated"; "connection" => %self.local_id, "pn" => packet);
+        self.qlog(now, |qlog, group| {
+            qlog.packet_received(now, group, ty, packet)
+        });
+        #[cfg(feature = "tracing")]
+        ::tracing::trace!(parent: &self.span, pn = packet, "packet received");
         self.reset_idle_timeout(&ctx.config, now);
+        self.idle_restarted_by_send = false;
+        match ecn {
+            Some(EcnCodepoint::ECT0) => self.ecn_counters.ect0 += 1,
+            Some(EcnCodepoint::ECT1) => self.ecn_counters.ect1 += 1,
+            Some(EcnCodepoint::CE) => self.ecn_counters.ce += 1,
+            None => {}
+        }
         self.pending_acks.insert_one(packet);
-        if self.pending_acks.len() > MAX_ACK_BLOCKS {
+        if self.pending_acks.len() > ctx.config.max_ack_ranges {
             self.pending_acks.pop_min();
         }
         if packet > self.rx_packet {
@@ -687,92 +1427,532 @@ impl Connection {
         }
     }
 
-    pub fn reset_idle_timeout(&mut self, config: &Config, now: u64) {
-        let dt = cmp::min(config.idle_timeout, self.params.idle_timeout) as u64 * 1_000_000;
-        self.set_idle = Some(Some(now + dt));
-    }
-
-    /// Consider all previously transmitted handshake packets to be delivered. Called when we receive a new handshake packet.
-    pub fn handshake_cleanup(&mut self, config: &Config) {
-        if !self.awaiting_handshake {
+    /// Account for an authenticated packet from `remote`, following the peer there if it has migrated
+    ///
+    /// `largest` indicates whether the packet has the highest number yet received, so that reordered packets from an
+    /// old path can't cause us to move back to it.
+    fn on_path_packet(
+        &mut self,
+        ctx: &mut Context,
+        conn: ConnectionHandle,
+        remote: SocketAddrV6,
+        len: usize,
+        largest: bool,
+        payload: &Bytes,
+    ) {
+        if !self.paths.contains_key(&remote) {
+            self.track_path(&ctx.config, remote, false);
+        }
+        self.paths.get_mut(&remote).unwrap().total_recvd += len as u64;
+        // Servers don't migrate; clients only move to a server's preferred address, once it's validated
+        if remote == self.remote || !largest || self.side == Side::Client {
             return;
         }
-        self.awaiting_handshake = false;
-        self.handshake_pending = Retransmits::default();
-        let mut packets = Vec::new();
-        for (&packet, info) in &self.sent_packets {
-            if info.handshake {
-                packets.push(packet);
-            }
+        if self.multipath(&ctx.config) == Some(MultipathPolicy::RoundRobin) {
+            // The peer's other paths carry traffic alongside the active one rather than replacing it
+            return;
         }
-        for packet in packets {
-            self.on_packet_acked(config, packet);
+        let probing = frame::Iter::new(payload.clone(), self.version).all(|frame| match frame {
+            Ok(Frame::Padding)
+            | Ok(Frame::PathChallenge(_))
+            | Ok(Frame::PathResponse(_))
+            | Ok(Frame::NewConnectionId(_)) => true,
+            _ => false,
+        });
+        if probing {
+            // The peer may only be testing the path, so stay put and answer on it
+            return;
         }
-        self.set_loss_detection_alarm(config);
+        let old = self.remote;
+        debug!(ctx.log, "peer migrated"; "connection" => %self.local_id, "old" => %old, "new" => %remote);
+        self.set_path(ctx, remote);
+        self.rotate_remote_id();
+        if !self.paths[&remote].validated {
+            self.challenge_path(ctx);
+        }
+        ctx.events
+            .push_back((conn, Event::PathMigrated { old, new: remote }));
     }
 
-    pub fn update_keys(
-        &mut self,
-        packet: u64,
-        header: &[u8],
-        payload: &mut BytesMut,
-    ) -> Result<(), ()> {
-        let new = self.crypto.as_mut().unwrap().update(self.side);
-        new.decrypt(packet, header, payload)?;
-        let old = mem::replace(self.crypto.as_mut().unwrap(), new);
-        self.prev_crypto = Some((packet, old));
-        self.key_phase = !self.key_phase;
-        Ok(())
+    /// Start tracking a path to `remote`
+    fn track_path(&mut self, config: &Config, remote: SocketAddrV6, validated: bool) {
+        if self.paths.len() >= MAX_PATHS {
+            // Forget unused paths rather than let the peer grow the table without bound
+            let (active, prev) = (self.remote, self.prev_remote);
+            self.paths
+                .retain(|&x, path| x == active || Some(x) == prev || path.extra);
+        }
+        let id = PathId(self.next_path_id);
+        self.next_path_id += 1;
+        self.paths.insert(remote, Path::new(config, id, validated));
     }
 
-    pub fn decrypt(
-        &self,
-        handshake: bool,
-        packet: u64,
-        header: &[u8],
-        payload: &mut BytesMut,
-    ) -> Result<(), ()> {
-        match (handshake, &self.prev_crypto) {
-            (true, _) => &self.handshake_crypto,
-            (false, &Some((boundary, ref prev))) if packet < boundary => prev,
-            _ => self.crypto.as_ref().unwrap(),
-        }.decrypt(packet, header, payload)
+    /// How traffic is spread over paths, if both we and the peer support multipath
+    fn multipath(&self, config: &Config) -> Option<MultipathPolicy> {
+        if self.params.enable_multipath {
+            config.multipath_policy
+        } else {
+            None
+        }
     }
 
-    pub fn transmit_handshake(&mut self, messages: &[u8]) {
-        let offset = {
-            let ss = self
-                .streams
-                .get_mut(&StreamId(0))
-                .unwrap()
-                .send_mut()
-                .unwrap();
-            let x = ss.offset;
-            ss.offset += messages.len() as u64;
-            ss.bytes_in_flight += messages.len() as u64;
-            x
-        };
-        self.handshake_pending.stream.push_back(frame::Stream {
-            id: StreamId(0),
-            fin: false,
-            offset,
-            data: messages.into(),
-        });
-        self.awaiting_handshake = true;
+    /// Open an additional path to `remote`, to be used alongside the active one once it's validated
+    pub fn add_path(
+        &mut self,
+        ctx: &mut Context,
+        remote: SocketAddrV6,
+    ) -> Result<PathId, AddPathError> {
+        if ctx.config.multipath_policy.is_none() {
+            return Err(AddPathError::Disabled);
+        }
+        match *self.state.as_ref().unwrap() {
+            State::Established(_) if self.params.enable_multipath => {}
+            _ => return Err(AddPathError::UnsupportedByPeer),
+        }
+        if self.paths.contains_key(&remote) {
+            return Err(AddPathError::AlreadyOpen);
+        }
+        if self.paths.values().filter(|x| x.extra).count() + 1 >= MAX_PATHS {
+            return Err(AddPathError::TooManyPaths);
+        }
+        // Observers mustn't be able to link the paths by connection ID
+        let cid = self
+            .remote_cids
+            .pop_front()
+            .ok_or(AddPathError::NoConnectionIds)?;
+        debug!(ctx.log, "adding path"; "connection" => %self.local_id, "remote" => %remote);
+        self.track_path(&ctx.config, remote, false);
+        let token = ctx.rng.gen::<u64>();
+        let path = self.paths.get_mut(&remote).unwrap();
+        path.extra = true;
+        path.remote_cid = Some(cid);
+        path.challenge = Some(token);
+        self.off_path_frames
+            .push_back((remote, frame::Type::PATH_CHALLENGE, token));
+        Ok(path.id)
     }
 
-    pub fn transmit(&mut self, stream: StreamId, data: Bytes) {
-        let ss = self.streams.get_mut(&stream).unwrap().send_mut().unwrap();
-        assert_eq!(ss.state, stream::SendState::Ready);
-        let offset = ss.offset;
-        ss.offset += data.len() as u64;
-        ss.bytes_in_flight += data.len() as u64;
-        if stream != StreamId(0) {
-            self.data_sent += data.len() as u64;
+    /// Pick the path to send the next datagram on
+    fn next_tx_path(&mut self, config: &Config) -> SocketAddrV6 {
+        if self.multipath(config) != Some(MultipathPolicy::RoundRobin) || self.awaiting_handshake {
+            return self.remote;
         }
-        self.pending.stream.push_back(frame::Stream {
-            offset,
-            fin: false,
+        let active = self.remote;
+        let mut usable = self
+            .paths
+            .iter()
+            .filter(|&(&addr, x)| addr == active || x.extra && x.validated)
+            .map(|(&addr, x)| (x.id, addr))
+            .collect::<Vec<_>>();
+        usable.sort();
+        self.round_robin = self.round_robin.wrapping_add(1);
+        usable[self.round_robin % usable.len()].1
+    }
+
+    /// Value to send the QUIC bit with next, randomized if both we and the peer permit greasing it
+    fn quic_bit<R: Rng>(&self, config: &Config, rng: &mut R) -> bool {
+        !(config.grease_quic_bit && self.params.grease_quic_bit) || rng.gen()
+    }
+
+    /// The connection ID to address packets sent on `tx_path` with
+    fn tx_remote_id(&self) -> ConnectionId {
+        match self
+            .paths
+            .get(&self.tx_path)
+            .and_then(|x| x.remote_cid.as_ref())
+        {
+            Some(&(_, ref id, _)) if self.tx_path != self.remote => id.clone(),
+            _ => self.remote_id.clone(),
+        }
+    }
+
+    /// Move to a validated standby path after the active one timed out, if multipath offers one
+    pub fn fail_over(&mut self, ctx: &mut Context, conn: ConnectionHandle) {
+        if self.multipath(&ctx.config) != Some(MultipathPolicy::ActiveStandby) {
+            return;
+        }
+        let standby = self
+            .paths
+            .iter()
+            .filter(|&(_, x)| x.extra && x.validated && x.remote_cid.is_some())
+            .min_by_key(|&(_, x)| x.id)
+            .map(|(&addr, _)| addr);
+        let new = match standby {
+            Some(x) => x,
+            None => return,
+        };
+        let old = self.remote;
+        debug!(ctx.log, "failing over"; "connection" => %self.local_id, "old" => %old, "new" => %new);
+        // The paths trade connection IDs, so the old one remains usable as a standby
+        let (sequence, id, reset_token) =
+            self.paths.get_mut(&new).unwrap().remote_cid.take().unwrap();
+        let old_cid = (
+            self.remote_cid_sequence,
+            mem::replace(&mut self.remote_id, id),
+            self.params
+                .stateless_reset_token
+                .unwrap_or([0; RESET_TOKEN_SIZE]),
+        );
+        self.remote_cid_sequence = sequence;
+        self.params.stateless_reset_token = Some(reset_token);
+        {
+            let path = self.paths.get_mut(&old).unwrap();
+            path.extra = true;
+            path.remote_cid = Some(old_cid);
+        }
+        self.paths.get_mut(&new).unwrap().extra = false;
+        self.set_path(ctx, new);
+        ctx.events
+            .push_back((conn, Event::PathMigrated { old, new }));
+    }
+
+    /// Make the path to `remote` the active one
+    fn set_path(&mut self, ctx: &Context, remote: SocketAddrV6) {
+        if remote != self.remote && self.paths.get(&self.remote).map_or(false, |x| x.validated) {
+            self.prev_remote = Some(self.remote);
+        }
+        if remote.ip() != self.remote.ip() {
+            // Congestion and RTT state describes the old path, so start afresh. A change of port alone likely means
+            // NAT rebinding, which leaves the path otherwise intact.
+            self.congestion = ctx.config.congestion_controller_factory.build(&ctx.config);
+            self.latest_rtt = 0;
+            self.smoothed_rtt = 0;
+            self.rttvar = 0;
+            self.min_rtt = u64::max_value();
+        }
+        self.remote = remote;
+    }
+
+    /// Switch to a connection ID the peer issued but we haven't used, if any, so observers can't link a new path to the
+    /// old one
+    fn rotate_remote_id(&mut self) {
+        if let Some((sequence, id, reset_token)) = self.remote_cids.pop_front() {
+            // Let the peer forget the old ID and issue us another
+            self.pending.retire_cids.push(self.remote_cid_sequence);
+            self.remote_id = id;
+            self.remote_cid_sequence = sequence;
+            // Stateless resets will carry the token bound to the new ID
+            self.params.stateless_reset_token = Some(reset_token);
+        }
+    }
+
+    /// Whether `data` ends in a stateless reset token issued by the peer
+    ///
+    /// Every known token is compared in constant time, so response timing can't help an attacker guess one.
+    pub fn is_stateless_reset(&self, data: &[u8]) -> bool {
+        if data.len() < RESET_TOKEN_SIZE {
+            return false;
+        }
+        let tail = &data[data.len() - RESET_TOKEN_SIZE..];
+        self.params
+            .stateless_reset_token
+            .iter()
+            .chain(self.remote_cids.iter().map(|&(_, _, ref token)| token))
+            .chain(
+                self.paths
+                    .values()
+                    .filter_map(|x| x.remote_cid.as_ref().map(|&(_, _, ref token)| token)),
+            )
+            .fold(false, |found, token| constant_time_eq(tail, token) | found)
+    }
+
+    /// Send a PATH_CHALLENGE on the active path, which is validated when the peer echoes it
+    fn challenge_path(&mut self, ctx: &mut Context) {
+        let token = ctx.rng.gen::<u64>();
+        self.paths.get_mut(&self.remote).unwrap().challenge = Some(token);
+        self.pending.challenge = Some(token);
+    }
+
+    /// Note that a PATH_CHALLENGE was sent to `remote`, scheduling its retransmission
+    ///
+    /// Validation fails if the peer hasn't answered within three probe timeouts of the first challenge.
+    fn on_challenge_sent(&mut self, config: &Config, remote: SocketAddrV6, now: u64) {
+        let pto = self.pto(config);
+        if let Some(path) = self.paths.get_mut(&remote) {
+            if path.validation_deadline.is_none() {
+                path.validation_deadline = Some(now + 3 * pto);
+            }
+        }
+        if let Some(deadline) = self.next_validation_deadline() {
+            self.set_path_validation = Some(Some(cmp::min(now + pto, deadline)));
+        }
+    }
+
+    /// When the earliest outstanding path validation fails, if any
+    fn next_validation_deadline(&self) -> Option<u64> {
+        self.paths
+            .values()
+            .filter_map(|x| x.validation_deadline)
+            .min()
+    }
+
+    /// Retransmit unanswered PATH_CHALLENGEs, giving up on paths whose deadline has passed
+    pub fn path_validation_timeout(&mut self, ctx: &mut Context, conn: ConnectionHandle, now: u64) {
+        let mut failed = Vec::new();
+        for (&remote, path) in &mut self.paths {
+            let (deadline, token) = match (path.validation_deadline, path.challenge) {
+                (Some(deadline), Some(token)) => (deadline, token),
+                _ => continue,
+            };
+            if deadline <= now {
+                // A late response still validates the path, so the challenge is kept
+                path.validation_deadline = None;
+                failed.push(remote);
+            } else if remote == self.remote {
+                self.pending.challenge = Some(token);
+            } else if self.crypto.is_some() {
+                self.off_path_frames
+                    .push_back((remote, frame::Type::PATH_CHALLENGE, token));
+            }
+        }
+        for remote in failed {
+            debug!(ctx.log, "path validation failed"; "connection" => %self.local_id, "remote" => %remote);
+            if remote == self.remote && self.side == Side::Server {
+                // Whoever sent from the new address may not be the peer, e.g. if an attacker replayed its packets, so
+                // return to an address the peer has proven it's at
+                let fallback = match self.prev_remote.take() {
+                    Some(x) if self.paths.get(&x).map_or(false, |x| x.validated) => Some(x),
+                    _ => None,
+                };
+                if let Some(new) = fallback {
+                    self.set_path(ctx, new);
+                    ctx.events
+                        .push_back((conn, Event::PathMigrated { old: remote, new }));
+                }
+            }
+            ctx.events
+                .push_back((conn, Event::PathValidationFailed { remote }));
+        }
+        // Retransmissions may be blocked, e.g. by the anti-amplification limit, so don't rely on them to reschedule us
+        if let Some(deadline) = self.next_validation_deadline() {
+            self.set_path_validation = Some(Some(deadline));
+        }
+    }
+
+    /// Begin validating the path to the server's preferred address, if it advertised one we can reach
+    ///
+    /// We move to the new path if the server answers our PATH_CHALLENGE.
+    fn probe_preferred_address(&mut self, ctx: &mut Context) {
+        let preferred = match self.params.preferred_address {
+            Some(ref x) => x.clone(),
+            None => return,
+        };
+        // Stick to the address family already in use
+        let mapped = {
+            let segments = self.remote.ip().segments();
+            segments[..5] == [0; 5] && segments[5] == 0xffff
+        };
+        let remote = if mapped {
+            preferred
+                .address_v4
+                .map(|x| SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0))
+        } else {
+            preferred.address_v6
+        };
+        let remote = match remote {
+            Some(x) if x != self.remote => x,
+            _ => return,
+        };
+        let PreferredAddress {
+            connection_id,
+            stateless_reset_token,
+            ..
+        } = preferred;
+        debug!(ctx.log, "probing preferred address"; "connection" => %self.local_id, "remote" => %remote);
+        self.track_path(&ctx.config, remote, false);
+        let token = ctx.rng.gen::<u64>();
+        self.paths.get_mut(&remote).unwrap().challenge = Some(token);
+        // The preferred address's connection ID has sequence number 1, and must be used there
+        self.remote_cids
+            .push_front((1, connection_id, stateless_reset_token));
+        self.off_path_frames
+            .push_back((remote, frame::Type::PATH_CHALLENGE, token));
+    }
+
+    /// Move to a new path after our local address changed, sending subsequent packets to `remote`
+    pub fn migrate(&mut self, ctx: &mut Context, remote: SocketAddrV6) {
+        assert_eq!(self.side, Side::Client, "only clients may migrate");
+        match *self.state.as_ref().unwrap() {
+            State::Established(_) => {}
+            _ => panic!("migration requires an established connection"),
+        }
+        debug!(ctx.log, "migrating"; "connection" => %self.local_id, "remote" => %remote);
+        self.track_path(&ctx.config, remote, false);
+        self.set_path(ctx, remote);
+        self.rotate_remote_id();
+        self.challenge_path(ctx);
+    }
+
+    /// Measurements of the active path
+    pub fn path_stats(&self) -> PathStats {
+        let path = &self.paths[&self.remote];
+        PathStats {
+            rtt: Duration::from_micros(self.smoothed_rtt),
+            rtt_variance: Duration::from_micros(self.rttvar),
+            loss_rate: path.loss_rate(),
+            cwnd: self.congestion.window(),
+            bytes_in_flight: self.bytes_in_flight,
+            send_rate: path.send_rate,
+            recv_rate: path.recv_rate,
+        }
+    }
+
+    /// Offer the peer an additional connection ID that routes to us, for use on new paths
+    pub fn issue_cid(&mut self, id: ConnectionId, reset_token: [u8; RESET_TOKEN_SIZE]) {
+        let sequence = self.cid_pool.insert(id.clone());
+        self.pending.new_cids.push(frame::NewConnectionId {
+            sequence,
+            retire_prior_to: self.cid_pool.retire_prior_to(),
+            id,
+            reset_token,
+        });
+    }
+
+    /// Largest UDP payload we may send on the active path
+    pub fn mtu(&self) -> u16 {
+        self.paths[&self.remote].pmtud.plpmtu
+    }
+
+    /// Handle a report that the active path carries UDP payloads of at most `mtu` bytes
+    ///
+    /// Ignored unless `quoted`, the start of the datagram the report echoes, was addressed to the connection ID we're
+    /// sending to, which an off-path attacker can't know.
+    pub fn handle_ptb(&mut self, log: &Logger, mtu: u16, quoted: &[u8]) {
+        if PartialDecode::peek_destination_id(quoted, self.remote_id.len()).as_ref()
+            != Some(&self.remote_id)
+        {
+            debug!(log, "ignoring unverifiable packet too big"; "connection" => %self.local_id, "reported" => mtu);
+            return;
+        }
+        let remote = self.remote;
+        let pmtud = &mut self.paths.get_mut(&remote).unwrap().pmtud;
+        let prev = pmtud.plpmtu;
+        pmtud.on_ptb(mtu);
+        if pmtud.plpmtu != prev {
+            self.congestion.on_mtu_update(pmtud.plpmtu);
+        }
+        trace!(log, "got packet too big"; "connection" => %self.local_id, "reported" => mtu, "mtu" => pmtud.plpmtu);
+    }
+
+    /// Number of bytes we may send on the active path, or `None` if unlimited
+    ///
+    /// Until a server has validated a path, it sends at most three times what it received on it, so that it can't be
+    /// used to amplify an attack on a spoofed address.
+    fn amplification_budget(&self) -> Option<usize> {
+        let path = &self.paths[&self.remote];
+        if self.side == Side::Client || path.validated {
+            return None;
+        }
+        Some((3 * path.total_recvd).saturating_sub(path.total_sent) as usize)
+    }
+
+    pub fn reset_idle_timeout(&mut self, config: &Config, now: u64) {
+        let local = config.max_idle_timeout;
+        let remote = u64::from(self.params.max_idle_timeout) * 1000;
+        let timeout = match (local, remote) {
+            (0, 0) => {
+                self.set_idle = Some(None);
+                return;
+            }
+            (0, x) | (x, 0) => x,
+            (x, y) => cmp::min(x, y),
+        };
+        // Don't give up on a peer we merely haven't had time to hear from
+        let timeout = cmp::max(timeout, 3 * self.pto(config));
+        self.set_idle = Some(Some(now + timeout));
+    }
+
+    /// Consider all previously transmitted handshake packets to be delivered. Called when we receive a new handshake packet.
+    pub fn handshake_cleanup(&mut self, config: &Config, now: u64) {
+        if !self.awaiting_handshake {
+            return;
+        }
+        self.awaiting_handshake = false;
+        self.handshake_pending = Retransmits::default();
+        let mut packets = Vec::new();
+        for (&packet, info) in &self.sent_packets {
+            if info.space != SpaceId::Data {
+                packets.push(packet);
+            }
+        }
+        for packet in packets {
+            self.on_packet_acked(now, packet);
+        }
+        self.set_loss_detection_alarm(config);
+    }
+
+    /// Ask for the 1-RTT keys to be updated as soon as the peer has acknowledged a packet protected with the current ones
+    pub fn force_key_update(&mut self, config: &Config) {
+        self.key_update_requested = true;
+        self.maybe_update_keys(config);
+    }
+
+    /// Initiate a key update if one is due and permitted
+    fn maybe_update_keys(&mut self, config: &Config) {
+        // Updates are only permitted once the handshake is confirmed, and not until the peer has acknowledged a packet
+        // protected with the current keys, proving it has them too
+        if !self.handshake_confirmed || self.largest_acked_packet < self.key_phase_start {
+            return;
+        }
+        let sent = self.largest_sent_packet + 1 - self.key_phase_start;
+        if !self.key_update_requested && config.key_update_interval.map_or(true, |n| sent < n) {
+            return;
+        }
+        let next = self.crypto.as_ref().unwrap().update(self.side);
+        self.install_keys(next, None);
+    }
+
+    /// Switch to the next generation of 1-RTT keys
+    ///
+    /// `end_packet` is the first packet received from the peer under `next` if it initiated the update, or `None` if we
+    /// did.
+    fn install_keys(&mut self, next: Crypto, end_packet: Option<(u64, u64)>) {
+        let old = mem::replace(self.crypto.as_mut().unwrap(), next);
+        self.prev_crypto = Some(PrevCrypto {
+            crypto: old,
+            end_packet,
+        });
+        self.key_phase = !self.key_phase;
+        self.key_phase_start = self.largest_sent_packet + 1;
+        self.key_update_requested = false;
+        self.key_updates += 1;
+    }
+
+    pub fn transmit_handshake(&mut self, messages: &[u8]) {
+        let offset = {
+            let ss = self
+                .streams
+                .get_mut(&StreamId(0))
+                .unwrap()
+                .send_mut()
+                .unwrap();
+            let x = ss.offset;
+            ss.offset += messages.len() as u64;
+            ss.bytes_in_flight += messages.len() as u64;
+            x
+        };
+        self.handshake_pending.stream.push_back(frame::Stream {
+            id: StreamId(0),
+            fin: false,
+            offset,
+            data: messages.into(),
+        });
+        self.awaiting_handshake = true;
+    }
+
+    pub fn transmit(&mut self, stream: StreamId, data: Bytes) {
+        let ss = self.streams.get_mut(&stream).unwrap().send_mut().unwrap();
+        assert_eq!(ss.state, stream::SendState::Ready);
+        let offset = ss.offset;
+        ss.offset += data.len() as u64;
+        ss.bytes_in_flight += data.len() as u64;
+        if stream != StreamId(0) {
+            self.data_sent += data.len() as u64;
+            self.unacked_data += data.len() as u64;
+        }
+        self.pending.stream.push_back(frame::Stream {
+            offset,
+            fin: false,
             data,
             id: stream,
         });
@@ -793,7 +1973,7 @@ impl Connection {
             stream.directionality() == Directionality::Bi || stream.initiator() == self.side,
             "only streams supporting outgoing data may be reset"
         );
-        {
+        let abandoned = {
             // reset is a noop on a closed stream
             let stream = if let Some(x) = self.streams.get_mut(&stream) {
                 x.send_mut().unwrap()
@@ -809,6 +1989,17 @@ impl Connection {
                 _ => {}
             }
             stream.state = stream::SendState::ResetSent { stop_reason: None };
+            stream.bytes_in_flight
+        };
+        // Free data that was waiting to be sent
+        self.pending.stream.retain(|x| x.id != stream);
+        let was_blocked = self.blocked();
+        self.unacked_data -= abandoned;
+        if was_blocked && !self.blocked() {
+            for stream in self.blocked_streams.drain() {
+                ctx.events
+                    .push_back((conn_h, Event::StreamWritable { stream }));
+            }
         }
         self.pending.rst_stream.push((stream, error_code));
         ctx.dirty_conns.insert(conn_h);
@@ -866,22 +2057,12 @@ impl Connection {
             Ok(()) => Ok(()),
             Err(e @ TLSError::AlertReceived(_)) => {
                 debug!(ctx.log, "TLS error {}", e);
-                ctx.events.push_back((
-                    conn,
-                    Event::ConnectionLost {
-                        reason: TransportError::TLS_FATAL_ALERT_RECEIVED.into(),
-                    },
-                ));
+                connection_lost!(self, ctx, conn, TransportError::TLS_FATAL_ALERT_RECEIVED);
                 Err(TransportError::TLS_FATAL_ALERT_RECEIVED)
             }
             Err(e) => {
                 debug!(ctx.log, "TLS error {}", e);
-                ctx.events.push_back((
-                    conn,
-                    Event::ConnectionLost {
-                        reason: TransportError::PROTOCOL_VIOLATION.into(),
-                    },
-                ));
+                connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                 Err(TransportError::PROTOCOL_VIOLATION)
             }
         }
@@ -892,10 +2073,17 @@ impl Connection {
         ctx: &mut Context,
         now: u64,
         packet_number: u64,
+        ecn: Option<EcnCodepoint>,
         payload: Bytes,
+        reset_token: Option<[u8; RESET_TOKEN_SIZE]>,
+        preferred_address: Option<PreferredAddress>,
         conn: ConnectionHandle,
     ) -> Result<(), TLSError> {
-        let frame = if let Ok(Some(frame)) = parse_initial(&ctx.log, payload) {
+        if let Some(ref x) = preferred_address {
+            // Routes to us, so must be forgotten with the connection
+            self.cid_pool.insert(x.connection_id.clone());
+        }
+        let frame = if let Ok(Some(frame)) = parse_initial(&ctx.log, payload, self.version) {
             frame
         } else {
             return Ok(());
@@ -904,7 +2092,18 @@ impl Connection {
         trace!(ctx.log, "got initial");
         let mut tls = TlsSession::new_server(
             &ctx.config.tls_server_config,
-            &TransportParameters::new(&ctx.config),
+            &TransportParameters {
+                issues_tickets: ctx.config.max_session_tickets != 0,
+                max_early_data: if ctx.config.max_session_tickets != 0 {
+                    ctx.config.max_early_data
+                } else {
+                    0
+                },
+                stateless_reset_token: reset_token,
+                preferred_address,
+                version: self.version.number(),
+                ..TransportParameters::new(&ctx.config)
+            },
         );
         self.read_tls(&mut tls, &frame);
         tls.process_new_packets()?;
@@ -912,7 +2111,30 @@ impl Connection {
             Side::Server,
             &mut io::Cursor::new(tls.get_quic_transport_parameters().unwrap()),
         ).unwrap();
-        self.handshake_complete(ctx, tls, params, now, packet_number, conn);
+        let zero_rtt_crypto = match params.resumption_ticket {
+            Some(ref id) => match ctx.take_ticket(id) {
+                Some(ticket) => {
+                    trace!(ctx.log, "accepting 0-RTT"; "max early data" => ticket.max_early_data);
+                    self.early_data_budget = u64::from(ticket.max_early_data);
+                    Some(Crypto::new_0rtt(&ticket, self.version))
+                }
+                None => {
+                    debug!(ctx.log, "rejecting 0-RTT with unknown session ticket");
+                    None
+                }
+            },
+            None => None,
+        };
+        self.handshake_complete(
+            ctx,
+            tls,
+            params,
+            zero_rtt_crypto,
+            now,
+            packet_number,
+            ecn,
+            conn,
+        );
         Ok(())
     }
 
@@ -935,106 +2157,84 @@ impl Connection {
         now: u64,
         conn: ConnectionHandle,
         remote: SocketAddrV6,
+        ecn: Option<EcnCodepoint>,
         mut packet: Packet,
         state: State,
     ) -> State {
         match state {
             State::Handshake(mut state) => {
                 match packet.header {
-                    Header::Long {
-                        ty: types::RETRY,
-                        number,
+                    Header::Retry {
                         destination_id: conn_id,
                         source_id: remote_id,
+                        orig_dst_cid,
                         ..
                     } => {
-                        // FIXME: the below guards fail to handle repeated retries resulting from retransmitted initials
-                        if state.clienthello_packet.is_none() {
+                        if self.side == Side::Server {
                             // Received Retry as a server
                             debug!(ctx.log, "received retry from client"; "connection" => %conn_id);
-                            ctx.events.push_back((
-                                conn,
-                                Event::ConnectionLost {
-                                    reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                },
-                            ));
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                             State::handshake_failed(TransportError::PROTOCOL_VIOLATION, None)
-                        } else if state.clienthello_packet.unwrap() > number {
-                            // Retry corresponds to an outdated Initial; must be a duplicate, so ignore it
-                            State::Handshake(state)
-                        } else if self
-                            .decrypt(
-                                true,
-                                number as u64,
-                                &packet.header_data,
-                                &mut packet.payload,
-                            ).is_ok()
+                        } else if state.remote_id_set
+                            || self.retried
+                            || orig_dst_cid != self.initial_id
                         {
-                            if let Ok(Some(frame)) = parse_initial(&ctx.log, packet.payload.into())
-                            {
-                                self.read_tls(&mut state.tls, &frame);
-                            } else {
-                                debug!(ctx.log, "invalid retry payload");
-                                ctx.events.push_back((
-                                    conn,
-                                    Event::ConnectionLost {
-                                        reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                    },
-                                ));
-                                return State::handshake_failed(
-                                    TransportError::PROTOCOL_VIOLATION,
-                                    None,
-                                );
-                            }
-                            match state.tls.process_new_packets() {
-                                Ok(()) => {
-                                    self.on_packet_authenticated(ctx, now, number as u64);
-                                    trace!(ctx.log, "resending ClientHello"; "remote_id" => %remote_id);
-                                    let local_id = self.local_id.clone();
-                                    // Discard transport state
-                                    let mut new = Connection::new(
-                                        remote_id.clone(),
-                                        local_id,
-                                        remote_id,
-                                        remote,
-                                        ctx.initial_packet_number.sample(&mut ctx.rng),
-                                        Side::Client,
-                                        &ctx.config,
-                                    );
-                                    mem::replace(self, new);
-                                    // Send updated ClientHello
-                                    let mut outgoing = Vec::new();
-                                    let mut tls = TlsSession::new_client(
-                                        &ctx.config.tls_client_config,
-                                        self.server_name.as_ref().unwrap(),
-                                        &TransportParameters::new(&ctx.config),
-                                    ).unwrap();
-                                    state.tls.write_tls(&mut outgoing).unwrap();
-                                    self.transmit_handshake(&outgoing);
-                                    // Prepare to receive Handshake packets that start stream 0 from offset 0
-                                    State::Handshake(state::Handshake {
-                                        tls,
-                                        clienthello_packet: state.clienthello_packet,
-                                        remote_id_set: state.remote_id_set,
-                                    })
-                                }
-                                Err(e) => {
-                                    debug!(ctx.log, "handshake failed"; "reason" => %e);
-                                    ctx.events.push_back((
-                                        conn,
-                                        Event::ConnectionLost {
-                                            reason: TransportError::TLS_HANDSHAKE_FAILED.into(),
-                                        },
-                                    ));
-                                    State::handshake_failed(
-                                        TransportError::TLS_HANDSHAKE_FAILED,
-                                        None,
-                                    )
-                                }
-                            }
-                        } else {
-                            debug!(ctx.log, "failed to authenticate retry packet");
+                            // Only our first flight may be retried, and only once
+                            debug!(ctx.log, "discarding unexpected retry"; "connection" => %conn_id);
+                            State::Handshake(state)
+                        } else if !is_valid_retry(
+                            &self.initial_id,
+                            &[&packet.header_data[..], &packet.payload[..]].concat(),
+                        ) {
+                            // Corrupted, or forged by an attacker that didn't see our Initial
+                            debug!(ctx.log, "discarding retry with invalid integrity tag"; "connection" => %conn_id);
                             State::Handshake(state)
+                        } else if packet.payload.len() == AEAD_TAG_SIZE {
+                            debug!(ctx.log, "discarding retry without token"; "connection" => %conn_id);
+                            State::Handshake(state)
+                        } else {
+                            trace!(ctx.log, "resending ClientHello with retry token"; "remote_id" => %remote_id);
+                            let local_id = self.local_id.clone();
+                            // Discard transport state
+                            let mut new = Connection::new(
+                                remote_id.clone(),
+                                local_id,
+                                remote_id,
+                                remote,
+                                ctx.initial_packet_number.sample(&mut ctx.rng),
+                                Side::Client,
+                                self.version,
+                                &ctx.config,
+                            );
+                            new.initial_version = self.initial_version;
+                            new.server_name = self.server_name.take();
+                            // The new connection is known by a new initial ID, so starts a new group of events
+                            new.qlog = self.qlog.take();
+                            let token_len = packet.payload.len() - AEAD_TAG_SIZE;
+                            packet.payload.truncate(token_len);
+                            new.retry_token = packet.payload.freeze();
+                            new.retried = true;
+                            if self.zero_rtt_crypto.is_some() {
+                                new.inherit_early_data(self);
+                            }
+                            mem::replace(self, new);
+                            // Send a fresh ClientHello in an Initial carrying the token
+                            let mut outgoing = Vec::new();
+                            let mut tls = TlsSession::new_client(
+                                &ctx.config.tls_client_config,
+                                self.server_name.as_ref().unwrap(),
+                                &TransportParameters {
+                                    version: self.initial_version.number(),
+                                    ..TransportParameters::new(&ctx.config)
+                                },
+                            ).unwrap();
+                            tls.write_tls(&mut outgoing).unwrap();
+                            self.transmit_handshake(&outgoing);
+                            State::Handshake(state::Handshake {
+                                tls,
+                                clienthello_packet: None,
+                                remote_id_set: false,
+                            })
                         }
                     }
                     Header::Long {
@@ -1044,6 +2244,7 @@ impl Connection {
                         number,
                         ..
                     } => {
+                        let number = number.expand(self.rx_packet);
                         if !state.remote_id_set {
                             trace!(ctx.log, "got remote connection id"; "connection" => %id, "remote_id" => %remote_id);
                             self.remote_id = remote_id;
@@ -1051,18 +2252,35 @@ impl Connection {
                         }
                         if self
                             .decrypt(
-                                true,
-                                number as u64,
+                                SpaceId::Handshake,
+                                number,
                                 &packet.header_data,
                                 &mut packet.payload,
                             ).is_err()
                         {
                             debug!(ctx.log, "failed to authenticate handshake packet");
+                            ctx.stats.dropped_packets += 1;
                             return State::Handshake(state);
                         };
-                        self.on_packet_authenticated(ctx, now, number as u64);
+                        if let Err(e) = packet.check_reserved_bits() {
+                            debug!(ctx.log, "got illegal handshake packet"; "reason" => %e);
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                            return State::handshake_failed(
+                                TransportError::PROTOCOL_VIOLATION,
+                                None,
+                            );
+                        }
+                        self.on_packet_authenticated(ctx, now, PacketType::Handshake, number, ecn);
                         // Complete handshake (and ultimately send Finished)
-                        for frame in frame::Iter::new(packet.payload.into()) {
+                        for frame in frame::Iter::new(packet.payload.into(), self.version) {
+                            let frame = match frame {
+                                Ok(x) => x,
+                                Err(e) => {
+                                    debug!(ctx.log, "received malformed frame"; "type" => %e.ty, "reason" => e.reason);
+                                    connection_lost!(self, ctx, conn, TransportError::from(e));
+                                    return State::handshake_failed(TransportError::from(e), None);
+                                }
+                            };
                             match frame {
                                 Frame::Ack(_) => {}
                                 _ => {
@@ -1078,12 +2296,12 @@ impl Connection {
                                 ) => self.read_tls(&mut state.tls, &frame),
                                 Frame::Stream(frame::Stream { .. }) => {
                                     debug!(ctx.log, "non-stream-0 stream frame in handshake");
-                                    ctx.events.push_back((
+                                    connection_lost!(
+                                        self,
+                                        ctx,
                                         conn,
-                                        Event::ConnectionLost {
-                                            reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                        },
-                                    ));
+                                        TransportError::PROTOCOL_VIOLATION
+                                    );
                                     return State::handshake_failed(
                                         TransportError::PROTOCOL_VIOLATION,
                                         None,
@@ -1093,34 +2311,39 @@ impl Connection {
                                     self.on_ack_received(ctx, now, conn, ack);
                                 }
                                 Frame::ConnectionClose(reason) => {
-                                    ctx.events.push_back((
+                                    connection_lost!(
+                                        self,
+                                        ctx,
                                         conn,
-                                        Event::ConnectionLost {
-                                            reason: ConnectionError::ConnectionClosed { reason },
-                                        },
-                                    ));
+                                        ConnectionError::ConnectionClosed { reason }
+                                    );
                                     return State::Draining(state.into());
                                 }
-                                Frame::ApplicationClose(reason) => {
-                                    ctx.events.push_back((
+                                Frame::ApplicationClose(_) => {
+                                    // The application can't have closed a connection its handshake hasn't authenticated
+                                    debug!(ctx.log, "APPLICATION_CLOSE in handshake");
+                                    connection_lost!(
+                                        self,
+                                        ctx,
                                         conn,
-                                        Event::ConnectionLost {
-                                            reason: ConnectionError::ApplicationClosed { reason },
-                                        },
-                                    ));
-                                    return State::Draining(state.into());
+                                        TransportError::PROTOCOL_VIOLATION
+                                    );
+                                    return State::handshake_failed(
+                                        TransportError::PROTOCOL_VIOLATION,
+                                        None,
+                                    );
                                 }
                                 Frame::PathChallenge(value) => {
-                                    self.handshake_pending.path_challenge(number as u64, value);
+                                    self.handshake_pending.path_challenge(number, value);
                                 }
                                 _ => {
                                     debug!(ctx.log, "unexpected frame type in handshake"; "connection" => %id, "type" => %frame.ty());
-                                    ctx.events.push_back((
+                                    connection_lost!(
+                                        self,
+                                        ctx,
                                         conn,
-                                        Event::ConnectionLost {
-                                            reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                        },
-                                    ));
+                                        TransportError::PROTOCOL_VIOLATION
+                                    );
                                     return State::handshake_failed(
                                         TransportError::PROTOCOL_VIOLATION,
                                         None,
@@ -1136,17 +2359,38 @@ impl Connection {
                                     let params = TransportParameters::read(
                                         self.side,
                                         &mut io::Cursor::new(params),
-                                    ).unwrap();
+                                    );
+                                    let tampered = match params {
+                                        Ok(ref x) => {
+                                            !self.version_negotiation_valid(&ctx.config, x)
+                                        }
+                                        Err(e) => {
+                                            e == transport_parameters::Error::VersionNegotiation
+                                        }
+                                    };
+                                    if tampered {
+                                        debug!(ctx.log, "version negotiation was tampered with"; "connection" => %id);
+                                        connection_lost!(
+                                            self,
+                                            ctx,
+                                            conn,
+                                            TransportError::VERSION_NEGOTIATION_ERROR
+                                        );
+                                        return State::handshake_failed(
+                                            TransportError::VERSION_NEGOTIATION_ERROR,
+                                            None,
+                                        );
+                                    }
+                                    let params = params.unwrap();
                                     self.set_params(params);
                                 } else {
                                     debug!(ctx.log, "remote didn't send transport params");
-                                    ctx.events.push_back((
+                                    connection_lost!(
+                                        self,
+                                        ctx,
                                         conn,
-                                        Event::ConnectionLost {
-                                            reason: TransportError::TRANSPORT_PARAMETER_ERROR
-                                                .into(),
-                                        },
-                                    ));
+                                        TransportError::TRANSPORT_PARAMETER_ERROR
+                                    );
                                     return State::handshake_failed(
                                         TransportError::TLS_HANDSHAKE_FAILED,
                                         None,
@@ -1157,7 +2401,9 @@ impl Connection {
                                     "{connection} established",
                                     connection = id.clone()
                                 );
-                                self.handshake_cleanup(&ctx.config);
+                                #[cfg(feature = "tracing")]
+                                ::tracing::debug!(parent: &self.span, "handshake complete");
+                                self.handshake_cleanup(&ctx.config, now);
                                 let mut msgs = Vec::new();
                                 state.tls.write_tls(&mut msgs).unwrap();
                                 if self.side == Side::Client {
@@ -1176,18 +2422,65 @@ impl Connection {
                                                     .map(|x| x.into()),
                                             },
                                         ));
+                                        if ctx.config.use_preferred_address {
+                                            self.probe_preferred_address(ctx);
+                                        }
+                                        if self.zero_rtt_crypto.is_some() && !self.blocked() {
+                                            // Writes are no longer limited by the server's tolerance for early data
+                                            for stream in self.blocked_streams.drain() {
+                                                ctx.events.push_back((
+                                                    conn,
+                                                    Event::StreamWritable { stream },
+                                                ));
+                                            }
+                                        }
+                                        if self.params.issues_tickets {
+                                            let ticket = SessionTicket::new(
+                                                &state.tls,
+                                                self.params.max_early_data,
+                                                self.params.clone(),
+                                            );
+                                            let ticket = ticket.encode();
+                                            ctx.config.session_ticket_store.lock().unwrap().save(
+                                                self.server_name.as_ref().unwrap(),
+                                                Bytes::from(&ticket[..]),
+                                            );
+                                            ctx.events.push_back((
+                                                conn,
+                                                Event::NewSessionTicket { ticket },
+                                            ));
+                                        }
                                     }
                                     Side::Server => {
                                         ctx.incoming_handshakes -= 1;
-                                        ctx.incoming.push_back(conn);
+                                        // Connections that accepted 0-RTT were already made available
+                                        if self.zero_rtt_crypto.is_none() {
+                                            ctx.incoming.push_back(conn);
+                                        }
+                                        ctx.remember_ticket(SessionTicket::new(
+                                            &state.tls,
+                                            ctx.config.max_early_data,
+                                            self.params.clone(),
+                                        ));
+                                        self.pending.new_token = self.new_token.take();
+                                        // The server's handshake is confirmed as soon as it completes
+                                        self.handshake_confirmed = true;
+                                        self.pending.handshake_done = self.version.handshake_done();
                                     }
                                 }
-                                self.crypto = Some(Crypto::new_1rtt(&state.tls, self.side));
+                                self.crypto =
+                                    Some(Crypto::new_1rtt(&state.tls, self.side, self.version));
+                                self.key_phase_start = self.largest_sent_packet + 1;
+                                self.zero_rtt_crypto = None;
+                                let zero_rtt_acks =
+                                    mem::replace(&mut self.zero_rtt_acks, RangeSet::new());
+                                self.pending_acks.add(&zero_rtt_acks);
+                                self.peer_certificates = state.tls.get_peer_certificates();
                                 State::Established(state::Established { tls: state.tls })
                             }
                             Ok(()) => {
                                 trace!(ctx.log, "handshake ongoing"; "connection" => %id);
-                                self.handshake_cleanup(&ctx.config);
+                                self.handshake_cleanup(&ctx.config, now);
                                 let mut response = Vec::new();
                                 state.tls.write_tls(&mut response).unwrap();
                                 if !response.is_empty() {
@@ -1201,77 +2494,97 @@ impl Connection {
                             }
                             Err(e) => {
                                 debug!(ctx.log, "handshake failed"; "reason" => %e);
-                                ctx.events.push_back((
+                                connection_lost!(
+                                    self,
+                                    ctx,
                                     conn,
-                                    Event::ConnectionLost {
-                                        reason: TransportError::TLS_HANDSHAKE_FAILED.into(),
-                                    },
-                                ));
-                                State::handshake_failed(TransportError::TLS_HANDSHAKE_FAILED, None)
+                                    TransportError::TLS_HANDSHAKE_FAILED
+                                );
+                                // Tell the peer why, e.g. that its certificate was rejected
+                                let mut alert = Vec::new();
+                                state.tls.write_tls(&mut alert).unwrap();
+                                let alert = if alert.is_empty() {
+                                    None
+                                } else {
+                                    Some(alert.into())
+                                };
+                                State::handshake_failed(TransportError::TLS_HANDSHAKE_FAILED, alert)
                             }
                         }
                     }
-                    Header::Long {
-                        ty: types::INITIAL, ..
-                    }
-                        if self.side == Side::Server =>
-                    {
-                        trace!(ctx.log, "dropping duplicate Initial");
+                    Header::Initial { .. } => {
+                        if self.side == Side::Server {
+                            trace!(ctx.log, "dropping duplicate Initial");
+                        } else {
+                            debug!(ctx.log, "dropping Initial sent to client");
+                        }
                         State::Handshake(state)
                     }
-                    /*Header::Long {
+                    Header::Long {
                         ty: types::ZERO_RTT,
                         number,
                         destination_id: ref id,
                         ..
                     } if self.side == Side::Server =>
                     {
+                        let number = number.expand(self.rx_packet);
                         if let Some(ref crypto) = self.zero_rtt_crypto {
                             if crypto
-                                .decrypt(number as u64, &packet.header_data, &mut packet.payload)
+                                .decrypt(number, &packet.header_data, &mut packet.payload)
                                 .is_err()
                             {
-                                debug!(
-                                    ctx.log,
-                                    "{connection} failed to authenticate 0-RTT packet",
-                                    connection = id.clone()
-                                );
+                                debug!(ctx.log, "failed to authenticate 0-RTT packet"; "connection" => %id);
+                                ctx.stats.dropped_packets += 1;
                                 return State::Handshake(state);
                             }
                         } else {
-                            debug!(
-                                ctx.log,
-                                "{connection} ignoring unsupported 0-RTT packet",
-                                connection = id.clone()
-                            );
+                            debug!(ctx.log, "ignoring unsupported 0-RTT packet"; "connection" => %id);
+                            ctx.stats.dropped_packets += 1;
                             return State::Handshake(state);
                         };
-                        self.on_packet_authenticated(ctx, now, number as u64);
-                        match self.process_payload(
+                        if let Err(e) = packet.check_reserved_bits() {
+                            debug!(ctx.log, "got illegal 0-RTT packet"; "connection" => %id, "reason" => %e);
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                            return State::handshake_failed(
+                                TransportError::PROTOCOL_VIOLATION,
+                                None,
+                            );
+                        }
+                        self.on_packet_authenticated(ctx, now, PacketType::ZeroRtt, number, ecn);
+                        // Acknowledgements of 0-RTT packets must wait for 1-RTT keys
+                        self.pending_acks.remove(number..number + 1);
+                        self.zero_rtt_acks.insert_one(number);
+                        let data_recvd = self.data_recvd;
+                        let result = self.process_payload(
                             ctx,
                             now,
                             conn,
-                            number as u64,
-                            packet.payload.into(),
-                            state.tls.get_mut(),
-                        ) {
-                            Err(e) => State::HandshakeFailed(state::HandshakeFailed {
-                                reason: e,
-                                app_closed: false,
-                                alert: None,
-                            }),
+                            remote,
+                            id,
+                            number,
+                            packet.payload.freeze(),
+                            &mut state.tls,
+                        );
+                        // Retransmitted data is only counted once
+                        let early_data = self.data_recvd - data_recvd;
+                        if early_data > self.early_data_budget && result.is_ok() {
+                            debug!(ctx.log, "0-RTT data exceeds limit"; "connection" => %id);
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                            return State::handshake_failed(
+                                TransportError::PROTOCOL_VIOLATION,
+                                None,
+                            );
+                        }
+                        self.early_data_budget = self.early_data_budget.saturating_sub(early_data);
+                        match result {
+                            Err(e) => State::handshake_failed(e, None),
                             Ok(true) => State::Draining(state.into()),
                             Ok(false) => State::Handshake(state),
                         }
-                    }*/
+                    }
                     Header::Long { ty, .. } => {
                         debug!(ctx.log, "unexpected packet type"; "type" => format!("{:02X}", ty));
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         State::handshake_failed(TransportError::PROTOCOL_VIOLATION, None)
                     }
                     Header::VersionNegotiate {
@@ -1280,60 +2593,154 @@ impl Connection {
                         let mut payload = io::Cursor::new(&packet.payload[..]);
                         if packet.payload.len() % 4 != 0 {
                             debug!(ctx.log, "malformed version negotiation"; "connection" => %id);
-                            ctx.events.push_back((
-                                conn,
-                                Event::ConnectionLost {
-                                    reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                },
-                            ));
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                             return State::handshake_failed(
                                 TransportError::PROTOCOL_VIOLATION,
                                 None,
                             );
                         }
+                        let mut offered = Vec::new();
                         while payload.has_remaining() {
                             let version = payload.get::<u32>().unwrap();
-                            if version == VERSION {
+                            if version == self.version.number() {
                                 // Our version is supported, so this packet is spurious
                                 return State::Handshake(state);
                             }
+                            offered.push(version);
                         }
-                        debug!(ctx.log, "remote doesn't support our version");
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: ConnectionError::VersionMismatch,
+                        if self.side == Side::Server
+                            || state.remote_id_set
+                            || self.version != self.initial_version
+                        {
+                            // Only the client's first flight may prompt version negotiation, and only once
+                            debug!(ctx.log, "discarding unexpected version negotiation"; "connection" => %id);
+                            return State::Handshake(state);
+                        }
+                        let version = ctx
+                            .config
+                            .versions
+                            .iter()
+                            .cloned()
+                            .find(|x| offered.contains(&x.number()));
+                        let version = match version {
+                            Some(x) => x,
+                            None => {
+                                debug!(ctx.log, "remote doesn't support our version");
+                                connection_lost!(self, ctx, conn, ConnectionError::VersionMismatch);
+                                return State::Draining(state.into());
+                            }
+                        };
+                        trace!(ctx.log, "resending ClientHello in a version the server supports"; "version" => version);
+                        // Discard transport state
+                        let mut new = Connection::new(
+                            self.initial_id.clone(),
+                            self.local_id.clone(),
+                            self.remote_id.clone(),
+                            remote,
+                            ctx.initial_packet_number.sample(&mut ctx.rng),
+                            Side::Client,
+                            version,
+                            &ctx.config,
+                        );
+                        new.initial_version = self.initial_version;
+                        new.server_name = self.server_name.take();
+                        new.qlog = self.qlog.take();
+                        new.qlog_started = self.qlog_started;
+                        if self.zero_rtt_crypto.is_some() {
+                            new.inherit_early_data(self);
+                        }
+                        mem::replace(self, new);
+                        let mut outgoing = Vec::new();
+                        let mut tls = TlsSession::new_client(
+                            &ctx.config.tls_client_config,
+                            self.server_name.as_ref().unwrap(),
+                            &TransportParameters {
+                                version: self.initial_version.number(),
+                                ..TransportParameters::new(&ctx.config)
                             },
-                        ));
-                        State::Draining(state.into())
+                        ).unwrap();
+                        tls.write_tls(&mut outgoing).unwrap();
+                        self.transmit_handshake(&outgoing);
+                        State::Handshake(state::Handshake {
+                            tls,
+                            clienthello_packet: None,
+                            remote_id_set: false,
+                        })
                     }
                     // TODO: SHOULD buffer these to improve reordering tolerance.
                     Header::Short { .. } => {
                         trace!(ctx.log, "dropping short packet during handshake");
+                        ctx.stats.dropped_packets += 1;
                         State::Handshake(state)
                     }
                 }
             }
             State::Established(mut state) => {
                 let id = self.local_id.clone();
-                if let Header::Long { .. } = packet.header {
-                    trace!(ctx.log, "discarding unprotected packet"; "connection" => %id);
+                let handshake_packet = match packet.header {
+                    Header::Long {
+                        ty: types::HANDSHAKE,
+                        ..
+                    } => true,
+                    _ => false,
+                };
+                if handshake_packet && self.awaiting_handshake && !self.handshake_confirmed {
+                    // Until the server confirms the handshake it may still reject it, e.g. for want of a trusted client
+                    // certificate
+                    if let Ok((payload, _)) = self.decrypt_packet(&ctx.config, now, true, packet) {
+                        for frame in frame::Iter::new(payload.into(), self.version) {
+                            if let Ok(Frame::ConnectionClose(reason)) = frame {
+                                connection_lost!(
+                                    self,
+                                    ctx,
+                                    conn,
+                                    ConnectionError::ConnectionClosed { reason }
+                                );
+                                return State::Draining(state.into());
+                            }
+                        }
+                    }
                     return State::Established(state);
                 }
-                let (payload, number) = match self.decrypt_packet(false, packet) {
+                let spin = match packet.header {
+                    Header::Initial { .. } | Header::Long { .. } | Header::Retry { .. } => {
+                        trace!(ctx.log, "discarding unprotected packet"; "connection" => %id);
+                        return State::Established(state);
+                    }
+                    Header::Short { spin, .. } => spin,
+                    _ => false,
+                };
+                let len = packet.header_data.len() + packet.payload.len();
+                let dst_cid = packet.header.destination_id().clone();
+                let reset = self.is_stateless_reset(&packet.payload);
+                let (payload, number) = match self.decrypt_packet(&ctx.config, now, false, packet) {
                     Ok(x) => x,
+                    Err(None) if reset => {
+                        debug!(ctx.log, "got stateless reset"; "connection" => %id);
+                        return State::Drained;
+                    }
                     Err(None) => {
                         trace!(ctx.log, "failed to authenticate packet"; "connection" => %id);
+                        ctx.stats.dropped_packets += 1;
                         return State::Established(state);
                     }
                     Err(Some(e)) => {
                         warn!(ctx.log, "got illegal packet"; "connection" => %id);
-                        ctx.events
-                            .push_back((conn, Event::ConnectionLost { reason: e.into() }));
+                        connection_lost!(self, ctx, conn, e);
                         return State::closed(e);
                     }
                 };
-                self.on_packet_authenticated(ctx, now, number);
+                if ctx.config.enable_spin_bit && number > self.rx_packet {
+                    // Only track the spin of the latest packet, so reordering can't cause spurious flips
+                    self.spin = match self.side {
+                        Side::Client => !spin,
+                        Side::Server => spin,
+                    };
+                }
+                let payload = Bytes::from(payload);
+                let largest = number > self.rx_packet;
+                self.on_path_packet(ctx, conn, remote, len, largest, &payload);
+                self.on_packet_authenticated(ctx, now, PacketType::OneRtt, number, ecn);
                 if self.awaiting_handshake {
                     assert_eq!(
                         self.side,
@@ -1341,10 +2748,19 @@ impl Connection {
                         "only the client confirms handshake completion based on a protected packet"
                     );
                     // Forget about unacknowledged handshake packets
-                    self.handshake_cleanup(&ctx.config);
+                    self.handshake_cleanup(&ctx.config, now);
                 }
                 match self
-                    .process_payload(ctx, now, conn, number, payload.into(), &mut state.tls)
+                    .process_payload(
+                        ctx,
+                        now,
+                        conn,
+                        remote,
+                        &dst_cid,
+                        number,
+                        payload,
+                        &mut state.tls,
+                    )
                     .and_then(|x| {
                         self.drive_tls(ctx, conn, &mut state.tls)?;
                         Ok(x)
@@ -1355,10 +2771,10 @@ impl Connection {
                 }
             }
             State::HandshakeFailed(state) => {
-                if let Ok((payload, _)) = self.decrypt_packet(true, packet) {
-                    for frame in frame::Iter::new(payload.into()) {
+                if let Ok((payload, _)) = self.decrypt_packet(&ctx.config, now, true, packet) {
+                    for frame in frame::Iter::new(payload.into(), self.version) {
                         match frame {
-                            Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
+                            Ok(Frame::ConnectionClose(_)) | Ok(Frame::ApplicationClose(_)) => {
                                 trace!(ctx.log, "draining");
                                 return State::Draining(state.into());
                             }
@@ -1369,10 +2785,10 @@ impl Connection {
                 State::HandshakeFailed(state)
             }
             State::Closed(state) => {
-                if let Ok((payload, _)) = self.decrypt_packet(false, packet) {
-                    for frame in frame::Iter::new(payload.into()) {
+                if let Ok((payload, _)) = self.decrypt_packet(&ctx.config, now, false, packet) {
+                    for frame in frame::Iter::new(payload.into(), self.version) {
                         match frame {
-                            Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
+                            Ok(Frame::ConnectionClose(_)) | Ok(Frame::ApplicationClose(_)) => {
                                 trace!(ctx.log, "draining");
                                 return State::Draining(state.into());
                             }
@@ -1392,12 +2808,23 @@ impl Connection {
         ctx: &mut Context,
         now: u64,
         conn: ConnectionHandle,
+        remote: SocketAddrV6,
+        dst_cid: &ConnectionId,
         number: u64,
         payload: Bytes,
         tls: &mut TlsSession,
     ) -> Result<bool, state::CloseReason> {
         let cid = self.local_id.clone();
-        for frame in frame::Iter::new(payload) {
+        let mut ack_eliciting = false;
+        for frame in frame::Iter::new(payload, self.version) {
+            let frame = match frame {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!(ctx.log, "received malformed frame"; "type" => %e.ty, "reason" => e.reason);
+                    connection_lost!(self, ctx, conn, TransportError::from(e));
+                    return Err(TransportError::from(e).into());
+                }
+            };
             match frame {
                 Frame::Padding => {}
                 _ => {
@@ -1407,7 +2834,7 @@ impl Connection {
             match frame {
                 Frame::Ack(_) => {}
                 _ => {
-                    self.permit_ack_only = true;
+                    ack_eliciting = true;
                 }
             }
             match frame {
@@ -1416,18 +2843,18 @@ impl Connection {
                     let data_recvd = self.data_recvd;
                     let max_data = self.local_max_data;
                     let stream = {
-                        match self.get_recv_stream(frame.id) {
+                        let open = self.get_recv_stream(frame.id).map(|x| x.is_some());
+                        match open {
                             Err(e) => {
                                 debug!(ctx.log, "received illegal stream frame"; "stream" => frame.id.0);
-                                ctx.events
-                                    .push_back((conn, Event::ConnectionLost { reason: e.into() }));
+                                connection_lost!(self, ctx, conn, e);
                                 return Err(e.into());
                             }
-                            Ok(None) => {
+                            Ok(false) => {
                                 trace!(ctx.log, "dropping frame for closed stream");
                                 continue;
                             }
-                            _ => {}
+                            Ok(true) => {}
                         }
                         self.streams.get_mut(&frame.id).unwrap()
                     };
@@ -1438,12 +2865,12 @@ impl Connection {
                         if let Some(final_offset) = rs.final_offset() {
                             if end > final_offset || (frame.fin && end != final_offset) {
                                 debug!(ctx.log, "final offset error"; "frame end" => end, "final offset" => final_offset);
-                                ctx.events.push_back((
+                                connection_lost!(
+                                    self,
+                                    ctx,
                                     conn,
-                                    Event::ConnectionLost {
-                                        reason: TransportError::FINAL_OFFSET_ERROR.into(),
-                                    },
-                                ));
+                                    TransportError::FINAL_OFFSET_ERROR
+                                );
                                 return Err(TransportError::FINAL_OFFSET_ERROR.into());
                             }
                         }
@@ -1453,12 +2880,7 @@ impl Connection {
                             debug!(ctx.log, "flow control error";
                                    "stream" => frame.id.0, "recvd" => data_recvd, "new bytes" => new_bytes,
                                    "max data" => max_data, "end" => end, "stream max data" => rs.max_data);
-                            ctx.events.push_back((
-                                conn,
-                                Event::ConnectionLost {
-                                    reason: TransportError::FLOW_CONTROL_ERROR.into(),
-                                },
-                            ));
+                            connection_lost!(self, ctx, conn, TransportError::FLOW_CONTROL_ERROR);
                             return Err(TransportError::FLOW_CONTROL_ERROR.into());
                         }
                         if frame.fin {
@@ -1469,12 +2891,7 @@ impl Connection {
                         rs.recvd.insert(frame.offset..end);
                         if frame.id == StreamId(0) && frame.fin {
                             debug!(ctx.log, "got fin on stream 0"; "connection" => cid);
-                            ctx.events.push_back((
-                                conn,
-                                Event::ConnectionLost {
-                                    reason: TransportError::PROTOCOL_VIOLATION.into(),
-                                },
-                            ));
+                            connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                             return Err(TransportError::PROTOCOL_VIOLATION.into());
                         }
                         rs.buffer(frame.data, frame.offset);
@@ -1513,45 +2930,79 @@ impl Connection {
                     }
                 }
                 Frame::Padding | Frame::Ping => {}
+                Frame::HandshakeDone => {
+                    if self.side == Side::Server {
+                        debug!(ctx.log, "got HANDSHAKE_DONE from client");
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    if !self.handshake_confirmed {
+                        trace!(ctx.log, "handshake confirmed");
+                        self.handshake_confirmed = true;
+                        // Handshake packets are no longer sent or accepted, so forget any still in flight
+                        self.handshake_cleanup(&ctx.config, now);
+                    }
+                }
                 Frame::ConnectionClose(reason) => {
-                    ctx.events.push_back((
+                    connection_lost!(
+                        self,
+                        ctx,
                         conn,
-                        Event::ConnectionLost {
-                            reason: ConnectionError::ConnectionClosed { reason },
-                        },
-                    ));
+                        ConnectionError::ConnectionClosed { reason }
+                    );
                     return Ok(true);
                 }
                 Frame::ApplicationClose(reason) => {
-                    ctx.events.push_back((
+                    connection_lost!(
+                        self,
+                        ctx,
                         conn,
-                        Event::ConnectionLost {
-                            reason: ConnectionError::ApplicationClosed { reason },
-                        },
-                    ));
+                        ConnectionError::ApplicationClosed { reason }
+                    );
                     return Ok(true);
                 }
-                Frame::Invalid(ty) => {
-                    debug!(ctx.log, "received malformed frame"; "type" => %ty);
-                    ctx.events.push_back((
-                        conn,
-                        Event::ConnectionLost {
-                            reason: TransportError::frame(ty).into(),
-                        },
-                    ));
-                    return Err(TransportError::frame(ty).into());
-                }
                 Frame::PathChallenge(x) => {
-                    self.pending.path_challenge(number, x);
+                    if remote == self.remote {
+                        self.pending.path_challenge(number, x);
+                    } else if self.crypto.is_some() && self.off_path_frames.len() < MAX_PATHS {
+                        // Responses must be sent on the path the challenge arrived on, which takes 1-RTT keys that a
+                        // challenge carried in 0-RTT may arrive before
+                        self.off_path_frames
+                            .push_back((remote, frame::Type::PATH_RESPONSE, x));
+                    }
                 }
-                Frame::PathResponse(_) => {
+                Frame::PathResponse(token) => {
+                    // A response validates the path its challenge was sent on, wherever it arrives from
+                    let validated = self
+                        .paths
+                        .iter_mut()
+                        .find(|&(_, ref x)| x.challenge == Some(token))
+                        .map(|(&addr, path)| {
+                            path.validated = true;
+                            path.validation_deadline = None;
+                            (addr, path.extra)
+                        });
+                    if let Some((addr, extra)) = validated {
+                        trace!(ctx.log, "path validated"; "connection" => cid.clone(), "remote" => %addr);
+                        ctx.events
+                            .push_back((conn, Event::PathValidated { remote: addr }));
+                        if self.next_validation_deadline().is_none() {
+                            self.set_path_validation = Some(None);
+                        }
+                        if self.side == Side::Client && addr != self.remote && !extra {
+                            // Other than paths opened for multipath, we only challenge paths to the server's preferred
+                            // address, which is now usable
+                            let old = self.remote;
+                            debug!(ctx.log, "moving to preferred address"; "connection" => cid.clone(), "remote" => %addr);
+                            self.set_path(ctx, addr);
+                            self.rotate_remote_id();
+                            ctx.events
+                                .push_back((conn, Event::PathMigrated { old, new: addr }));
+                        }
+                        continue;
+                    }
                     debug!(ctx.log, "unsolicited PATH_RESPONSE");
-                    ctx.events.push_back((
-                        conn,
-                        Event::ConnectionLost {
-                            reason: TransportError::UNSOLICITED_PATH_RESPONSE.into(),
-                        },
-                    ));
+                    connection_lost!(self, ctx, conn, TransportError::UNSOLICITED_PATH_RESPONSE);
                     return Err(TransportError::UNSOLICITED_PATH_RESPONSE.into());
                 }
                 Frame::MaxData(bytes) => {
@@ -1567,12 +3018,7 @@ impl Connection {
                 Frame::MaxStreamData { id, offset } => {
                     if id.initiator() != self.side && id.directionality() == Directionality::Uni {
                         debug!(ctx.log, "got MAX_STREAM_DATA on recv-only stream");
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
                     if let Some(stream) = self.streams.get_mut(&id) {
@@ -1588,12 +3034,7 @@ impl Connection {
                         }
                     } else {
                         debug!(ctx.log, "got MAX_STREAM_DATA on unopened stream");
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
                 }
@@ -1602,8 +3043,9 @@ impl Connection {
                         Directionality::Uni => &mut self.max_uni_streams,
                         Directionality::Bi => &mut self.max_bi_streams,
                     };
-                    if id.index() > *limit {
-                        *limit = id.index();
+                    // The frame carries the greatest permitted stream ID, so the count is one more than its index
+                    if id.index() >= *limit {
+                        *limit = id.index() + 1;
                         ctx.events.push_back((
                             conn,
                             Event::StreamAvailable {
@@ -1619,59 +3061,102 @@ impl Connection {
                 }) => {
                     if id == StreamId(0) {
                         debug!(ctx.log, "got RST_STREAM on stream 0");
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
-                    let offset = match self.get_recv_stream(id) {
+                    let open = self.get_recv_stream(id).map(|x| x.is_some());
+                    let offset = match open {
                         Err(e) => {
                             debug!(ctx.log, "received illegal RST_STREAM");
-                            ctx.events
-                                .push_back((conn, Event::ConnectionLost { reason: e.into() }));
+                            connection_lost!(self, ctx, conn, e);
                             return Err(e.into());
                         }
-                        Ok(None) => {
+                        Ok(false) => {
                             trace!(ctx.log, "received RST_STREAM on closed stream");
                             continue;
                         }
-                        Ok(Some(stream)) => {
-                            let rs = stream.recv_mut().unwrap();
-                            if let Some(offset) = rs.final_offset() {
-                                if offset != final_offset {
-                                    ctx.events.push_back((
-                                        conn,
-                                        Event::ConnectionLost {
-                                            reason: TransportError::FINAL_OFFSET_ERROR.into(),
-                                        },
-                                    ));
-                                    return Err(TransportError::FINAL_OFFSET_ERROR.into());
-                                }
+                        Ok(true) => {
+                            let rs = self.streams.get_mut(&id).unwrap().recv_mut().unwrap();
+                            let conflict = match rs.final_offset() {
+                                Some(offset) => offset != final_offset,
+                                // The stream can't end before data we've already received
+                                None => final_offset < rs.limit(),
+                            };
+                            if conflict {
+                                debug!(ctx.log, "final offset error"; "stream" => id.0, "final offset" => final_offset, "received" => rs.limit());
+                                connection_lost!(
+                                    self,
+                                    ctx,
+                                    conn,
+                                    TransportError::FINAL_OFFSET_ERROR
+                                );
+                                return Err(TransportError::FINAL_OFFSET_ERROR.into());
                             }
+                            if final_offset > rs.max_data {
+                                debug!(ctx.log, "flow control error"; "stream" => id.0, "final offset" => final_offset, "stream max data" => rs.max_data);
+                                connection_lost!(
+                                    self,
+                                    ctx,
+                                    conn,
+                                    TransportError::FLOW_CONTROL_ERROR
+                                );
+                                return Err(TransportError::FLOW_CONTROL_ERROR.into());
+                            }
+                            // Data that won't arrive was accounted for when the stream first finished
+                            let limit = if rs.is_finished() {
+                                final_offset
+                            } else {
+                                rs.limit()
+                            };
                             if !rs.is_closed() {
                                 rs.state = stream::RecvState::ResetRecvd {
                                     size: final_offset,
                                     error_code,
                                 };
                             }
-                            rs.limit()
+                            limit
                         }
                     };
-                    self.data_recvd += final_offset.saturating_sub(offset);
+                    let unsent = final_offset.saturating_sub(offset);
+                    if self.data_recvd + unsent > self.local_max_data {
+                        debug!(ctx.log, "flow control error"; "stream" => id.0, "recvd" => self.data_recvd, "unsent" => unsent, "max data" => self.local_max_data);
+                        connection_lost!(self, ctx, conn, TransportError::FLOW_CONTROL_ERROR);
+                        return Err(TransportError::FLOW_CONTROL_ERROR.into());
+                    }
+                    self.data_recvd += unsent;
+                    if unsent > 0 {
+                        // The application can't return credit for data it will never read, so do it now
+                        self.local_max_data += unsent;
+                        self.pending.max_data = true;
+                    }
                     self.readable_streams.insert(id);
                     ctx.readable_conns.insert(conn);
                 }
                 Frame::Blocked { offset } => {
                     debug!(ctx.log, "peer claims to be blocked at connection level"; "offset" => offset);
+                    self.flow_control_stats.blocked_received += 1;
+                    ctx.events.push_back((
+                        conn,
+                        Event::PeerBlocked {
+                            stream: None,
+                            offset,
+                        },
+                    ));
                 }
                 Frame::StreamBlocked { id, offset } => {
                     debug!(ctx.log, "peer claims to be blocked at stream level"; "stream" => id, "offset" => offset);
+                    self.flow_control_stats.stream_blocked_received += 1;
+                    ctx.events.push_back((
+                        conn,
+                        Event::PeerBlocked {
+                            stream: Some(id),
+                            offset,
+                        },
+                    ));
                 }
                 Frame::StreamIdBlocked { id } => {
                     debug!(ctx.log, "peer claims to be blocked at stream ID level"; "stream" => id);
+                    self.flow_control_stats.stream_id_blocked_received += 1;
                 }
                 Frame::StopSending { id, error_code } => {
                     if self
@@ -1680,46 +3165,270 @@ impl Connection {
                         .map_or(true, |x| x.send().map_or(true, |ss| ss.offset == 0))
                     {
                         debug!(ctx.log, "got STOP_SENDING on invalid stream");
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
-                    self.reset(ctx, id, 0, conn);
-                    self.streams.get_mut(&id).unwrap().send_mut().unwrap().state =
-                        stream::SendState::ResetSent {
-                            stop_reason: Some(error_code),
-                        };
+                    let sending = match self.streams[&id].send().unwrap().state {
+                        stream::SendState::Ready | stream::SendState::DataSent => true,
+                        _ => false,
+                    };
+                    // Retransmissions, and requests to stop streams that are already finished or reset, need no
+                    // response
+                    if sending {
+                        self.reset(ctx, id, 0, conn);
+                        self.streams.get_mut(&id).unwrap().send_mut().unwrap().state =
+                            stream::SendState::ResetSent {
+                                stop_reason: Some(error_code),
+                            };
+                        // Let blocked writers learn that the peer stopped the stream
+                        ctx.events
+                            .push_back((conn, Event::StreamWritable { stream: id }));
+                    }
                 }
-                Frame::NewConnectionId { .. } => {
+                Frame::NewConnectionId(frame) => {
                     if self.remote_id.is_empty() {
                         debug!(ctx.log, "got NEW_CONNECTION_ID for connection {connection} with empty remote ID",
                                connection=self.local_id.clone());
-                        ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: TransportError::PROTOCOL_VIOLATION.into(),
-                            },
-                        ));
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
                         return Err(TransportError::PROTOCOL_VIOLATION.into());
                     }
-                    trace!(ctx.log, "ignoring NEW_CONNECTION_ID (unimplemented)");
+                    // Frames may arrive out of order, so a later one may have already retired more IDs
+                    let retire_prior_to =
+                        cmp::max(frame.retire_prior_to, self.remote_retire_prior_to);
+                    self.remote_retire_prior_to = retire_prior_to;
+                    {
+                        let retire_cids = &mut self.pending.retire_cids;
+                        self.remote_cids.retain(|x| {
+                            if x.0 < retire_prior_to {
+                                retire_cids.push(x.0);
+                                false
+                            } else {
+                                true
+                            }
+                        });
+                        for path in self.paths.values_mut() {
+                            // Such a path falls back to the active path's ID
+                            if path
+                                .remote_cid
+                                .as_ref()
+                                .map_or(false, |x| x.0 < retire_prior_to)
+                            {
+                                retire_cids.push(path.remote_cid.take().unwrap().0);
+                            }
+                        }
+                    }
+                    let sequence = frame.sequence;
+                    if sequence <= self.remote_cid_sequence
+                        || self.remote_cids.iter().any(|x| x.0 == sequence)
+                        || self
+                            .paths
+                            .values()
+                            .any(|x| x.remote_cid.as_ref().map_or(false, |x| x.0 == sequence))
+                    {
+                        trace!(ctx.log, "ignoring duplicate NEW_CONNECTION_ID"; "sequence" => sequence);
+                    } else if sequence < retire_prior_to {
+                        trace!(ctx.log, "retiring obsolete NEW_CONNECTION_ID"; "sequence" => sequence);
+                        self.pending.retire_cids.push(sequence);
+                    } else if self.remote_cids.len() == MAX_REMOTE_CIDS {
+                        trace!(ctx.log, "ignoring excess NEW_CONNECTION_ID"; "sequence" => sequence);
+                    } else {
+                        self.remote_cids
+                            .push_back((sequence, frame.id, frame.reset_token));
+                    }
+                    if self.remote_cid_sequence < retire_prior_to {
+                        debug!(ctx.log, "peer retired the connection ID in use"; "connection" => %self.local_id, "retire_prior_to" => retire_prior_to);
+                        self.rotate_remote_id();
+                    }
+                }
+                Frame::RetireConnectionId { sequence } => {
+                    let result = if self.local_id.is_empty() {
+                        // We never issued any connection IDs to retire
+                        Err(TransportError::PROTOCOL_VIOLATION)
+                    } else if self.cid_pool.get(sequence) == Some(dst_cid) {
+                        // The peer can't retire the ID it's still using, which this packet was addressed to
+                        Err(TransportError::PROTOCOL_VIOLATION)
+                    } else {
+                        self.cid_pool.retire(sequence)
+                    };
+                    if let Err(e) = result {
+                        debug!(ctx.log, "got illegal RETIRE_CONNECTION_ID"; "sequence" => sequence);
+                        connection_lost!(self, ctx, conn, e);
+                        return Err(e.into());
+                    }
+                    trace!(ctx.log, "connection ID retired"; "sequence" => sequence);
+                }
+                Frame::NewToken { token } => {
+                    if self.side == Side::Server {
+                        debug!(ctx.log, "got NEW_TOKEN from client");
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    trace!(ctx.log, "got NEW_TOKEN"; "len" => token.len());
+                    ctx.config
+                        .token_store
+                        .lock()
+                        .unwrap()
+                        .save(self.server_name.as_ref().unwrap(), token);
+                }
+                Frame::Datagram(frame) => {
+                    if ctx
+                        .config
+                        .max_datagram_frame_size
+                        .map_or(true, |x| frame.size() > x as usize)
+                    {
+                        debug!(ctx.log, "got unexpected or oversized DATAGRAM"; "len" => frame.data.len());
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    if self.datagrams.len() == MAX_BUFFERED_DATAGRAMS {
+                        trace!(ctx.log, "dropping oldest buffered datagram");
+                        self.datagrams.pop_front();
+                    }
+                    self.datagrams.push_back(frame.data);
+                    ctx.events.push_back((conn, Event::DatagramReceived));
+                }
+                Frame::AckFrequency(frame) => {
+                    if ctx
+                        .config
+                        .min_ack_delay
+                        .map_or(true, |x| frame.request_max_ack_delay < u64::from(x))
+                    {
+                        debug!(ctx.log, "got unexpected or illegal ACK_FREQUENCY"; "delay" => frame.request_max_ack_delay);
+                        connection_lost!(self, ctx, conn, TransportError::PROTOCOL_VIOLATION);
+                        return Err(TransportError::PROTOCOL_VIOLATION.into());
+                    }
+                    if self
+                        .ack_frequency_received
+                        .map_or(true, |x| frame.sequence > x)
+                    {
+                        trace!(ctx.log, "ACK frequency changed"; "threshold" => frame.ack_eliciting_threshold, "delay" => frame.request_max_ack_delay);
+                        self.ack_frequency_received = Some(frame.sequence);
+                        self.ack_eliciting_threshold = frame.ack_eliciting_threshold;
+                        self.local_max_ack_delay = frame.request_max_ack_delay;
+                    }
                 }
             }
         }
+        if ack_eliciting {
+            self.on_ack_eliciting(now);
+        }
         Ok(false)
     }
 
-    pub fn next_packet(&mut self, log: &Logger, config: &Config, now: u64) -> Option<Vec<u8>> {
+    /// Decide when to acknowledge an ack-eliciting packet that was just received
+    ///
+    /// Absent an ACK_FREQUENCY frame from the peer the threshold is 0, so every such packet is acknowledged at once.
+    fn on_ack_eliciting(&mut self, now: u64) {
+        self.unacked_ack_eliciting += 1;
+        if self.unacked_ack_eliciting > self.ack_eliciting_threshold {
+            self.permit_ack_only = true;
+            if self.ack_timer.take().is_some() {
+                self.set_ack = Some(None);
+            }
+        } else if self.ack_timer.is_none() {
+            let time = now + self.local_max_ack_delay;
+            self.ack_timer = Some(time);
+            self.set_ack = Some(Some(time));
+        }
+    }
+
+    /// Handle expiry of the delayed ACK timer
+    pub fn ack_timeout(&mut self) {
+        self.ack_timer = None;
+        self.permit_ack_only = true;
+    }
+
+    /// Assemble the next datagram to transmit, if any, along with the ECN codepoint to mark it with
+    ///
+    /// Long header packets are followed by further packets while space remains, so that e.g. an Initial and a
+    /// Handshake packet can be delivered together.
+    pub fn next_datagram<R: Rng>(
+        &mut self,
+        log: &Logger,
+        config: &Config,
+        rng: &mut R,
+        now: u64,
+    ) -> Result<Option<(Vec<u8>, Option<EcnCodepoint>)>, ConnectionError> {
+        self.tx_path = self.next_tx_path(config);
+        let mut mtu = self.paths[&self.tx_path].pmtud.plpmtu as usize;
+        if let Some(budget) = self.amplification_budget() {
+            if budget < MIN_COALESCE_SPACE {
+                trace!(log, "blocked by anti-amplification limit"; "budget" => budget);
+                return Ok(None);
+            }
+            mtu = cmp::min(mtu, budget);
+        }
+        // Probes mustn't wait, or they'd be late to find out what was lost, nor may the handshake, which everything
+        // else waits on
+        let handshake = match *self.state.as_ref().unwrap() {
+            State::Handshake(_) => true,
+            _ => self.awaiting_handshake && !self.handshake_pending.is_empty(),
+        };
+        let rate = if config.enable_pacing && self.probes_pending == 0 && !handshake {
+            let window = self.congestion.window();
+            let slow_start = self.congestion.in_slow_start();
+            self.congestion
+                .pacing_rate()
+                .or_else(|| pacing::window_rate(window, self.smoothed_rtt, slow_start))
+        } else {
+            None
+        };
+        if let Some(rate) = rate {
+            if let Some(time) = self.pacer.delay(now, rate, mtu as u64) {
+                trace!(log, "pacing"; "until" => time);
+                self.set_pacing = Some(Some(time));
+                return Ok(None);
+            }
+        }
+        let (mut datagram, ecn) = match self.next_packet(log, config, rng, now, mtu)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut ty = packet::long_type(datagram[0], self.version);
+        let mut has_initial = ty == Some(types::INITIAL);
+        // Short header packets extend to the end of the datagram, so nothing may follow them
+        while ty.is_some() && mtu - datagram.len() >= MIN_COALESCE_SPACE {
+            // The whole datagram carries one codepoint, so a packet that would be marked differently must wait
+            if self.ecn_codepoint() != ecn {
+                break;
+            }
+            let next = match self.next_packet(log, config, rng, now, mtu - datagram.len())? {
+                Some((x, _)) => x,
+                None => break,
+            };
+            trace!(log, "coalescing packet"; "len" => next.len());
+            ty = packet::long_type(next[0], self.version);
+            has_initial |= ty == Some(types::INITIAL);
+            datagram.extend_from_slice(&next);
+        }
+        if has_initial && datagram.len() < MIN_INITIAL_SIZE {
+            // The Initial left room for coalesced packets that didn't materialize, so pad the datagram instead
+            datagram.resize(MIN_INITIAL_SIZE, 0);
+        }
+        self.paths.get_mut(&self.tx_path).unwrap().total_sent += datagram.len() as u64;
+        if rate.is_some() {
+            self.pacer.on_sent(datagram.len() as u64);
+        }
+        Ok(Some((datagram, ecn)))
+    }
+
+    /// Assemble a packet of at most `space` bytes, if there's anything to send
+    ///
+    /// Fails if the connection can't continue, in which case it should be abandoned.
+    pub fn next_packet<R: Rng>(
+        &mut self,
+        log: &Logger,
+        config: &Config,
+        rng: &mut R,
+        now: u64,
+        space: usize,
+    ) -> Result<Option<(Vec<u8>, Option<EcnCodepoint>)>, ConnectionError> {
         let established = match *self.state.as_ref().unwrap() {
             State::Handshake(_) => false,
             State::Established(_) => true,
             ref e => {
                 assert!(e.is_closed());
-                return None;
+                return Ok(None);
             }
         };
 
@@ -1730,7 +3439,10 @@ impl Connection {
         let ack_only;
         let is_initial;
         let header_len;
-        let handshake;
+        let partial_encode;
+        let space_id;
+        let send_datagrams;
+        let length_width = payload_length_width(space);
 
         {
             let crypto;
@@ -1740,10 +3452,10 @@ impl Connection {
                     || (!self.pending_acks.is_empty() && self.permit_ack_only))
             {
                 // (re)transmit handshake data in long-header packets
-                buf.reserve_exact(self.mtu as usize);
+                buf.reserve_exact(space);
                 number = self.get_tx_number();
                 trace!(log, "sending handshake packet"; "pn" => number);
-                let ty = if self.side == Side::Client && self
+                let header = if self.side == Side::Client && self
                     .handshake_pending
                     .stream
                     .front()
@@ -1755,57 +3467,90 @@ impl Connection {
                         }
                     }
                     is_initial = true;
-                    types::INITIAL
+                    Header::Initial {
+                        version: self.version,
+                        number: PacketNumber::U32(number as u32),
+                        source_id: self.local_id.clone(),
+                        destination_id: self.remote_id.clone(),
+                        token: self.retry_token.clone(),
+                    }
                 } else {
                     is_initial = false;
-                    types::HANDSHAKE
+                    Header::Long {
+                        version: self.version,
+                        ty: types::HANDSHAKE,
+                        number: PacketNumber::U32(number as u32),
+                        source_id: self.local_id.clone(),
+                        destination_id: self.remote_id.clone(),
+                    }
                 };
-                Header::Long {
-                    ty,
-                    number: number as u32,
-                    source_id: self.local_id.clone(),
-                    destination_id: self.remote_id.clone(),
-                }.encode(&mut buf);
+                space_id = header.space();
+                // Initial packets always carry the QUIC bit, as the peer can't yet have agreed to greasing
+                let quic_bit = is_initial || self.quic_bit(config, rng);
+                partial_encode = header.encode_with_quic_bit(&mut buf, length_width, quic_bit);
                 pending = &mut self.handshake_pending;
                 crypto = &self.handshake_crypto;
-            } else if established {
-                //|| (self.zero_rtt_crypto.is_some() && self.side == Side::Client) {
+                send_datagrams = false;
+            } else if established || (self.zero_rtt_crypto.is_some() && self.side == Side::Client) {
                 // Send 0RTT or 1RTT data
                 is_initial = false;
-                if self.congestion_blocked()
+                space_id = SpaceId::Data;
+                if self.probes_pending != 0 && self.pending.is_empty() {
+                    // Nothing new to probe with, but an ACK of a PING tells us as much
+                    self.pending.ping = true;
+                }
+                if self.congestion_blocked() && self.probes_pending == 0
                     || self.pending.is_empty()
-                        && (!self.permit_ack_only || self.pending_acks.is_empty())
+                        && (!established
+                            || self.outgoing_datagrams.is_empty()
+                                && (!self.permit_ack_only || self.pending_acks.is_empty()))
                 {
-                    return None;
+                    return Ok(None);
                 }
                 number = self.get_tx_number();
-                buf.reserve_exact(self.mtu as usize);
-                trace!(log, "sending protected packet"; "pn" => number);
+                buf.reserve_exact(space);
 
-                /*if !established {
+                if !established {
+                    trace!(log, "sending 0-RTT packet"; "pn" => number);
+                    let quic_bit = self.quic_bit(config, rng);
                     crypto = self.zero_rtt_crypto.as_ref().unwrap();
-                    Header::Long {
+                    // Long header packet numbers are always sent in full
+                    partial_encode = Header::Long {
+                        version: self.version,
                         ty: types::ZERO_RTT,
-                        number: number as u32,
+                        number: PacketNumber::U32(number as u32),
                         source_id: self.local_id.clone(),
-                        destination_id: self.initial_id.clone(),
-                    }.encode(&mut buf);
-                } else {*/
-                crypto = self.crypto.as_ref().unwrap();
-                Header::Short {
-                    id: self.remote_id.clone(),
-                    number: PacketNumber::new(number, self.largest_acked_packet),
-                    key_phase: self.key_phase,
-                }.encode(&mut buf);
-                //}
+                        destination_id: self.remote_id.clone(),
+                    }.encode_with_quic_bit(&mut buf, length_width, quic_bit);
+                } else {
+                    trace!(log, "sending protected packet"; "pn" => number);
+                    let quic_bit = self.quic_bit(config, rng);
+                    crypto = self.crypto.as_ref().unwrap();
+                    let pn = match PacketNumber::new(number, self.largest_acked_packet) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            debug!(log, "{}", e; "pn" => number, "largest acked" => self.largest_acked_packet);
+                            return Err(TransportError::INTERNAL_ERROR.into());
+                        }
+                    };
+                    partial_encode = Header::Short {
+                        id: self.tx_remote_id(),
+                        number: pn,
+                        spin: self.spin,
+                        key_phase: self.key_phase,
+                    }.encode_with_quic_bit(&mut buf, length_width, quic_bit);
+                }
 
                 pending = &mut self.pending;
+                send_datagrams = established;
             } else {
-                return None;
+                return Ok(None);
             }
-            ack_only = pending.is_empty();
+            ack_only =
+                pending.is_empty() && (!send_datagrams || self.outgoing_datagrams.is_empty());
             header_len = buf.len() as u16;
-            let max_size = self.mtu as usize - AEAD_TAG_SIZE;
+            let tag_len = crypto.tag_len();
+            let max_size = space - tag_len;
 
             // PING
             if pending.ping {
@@ -1815,16 +3560,36 @@ impl Connection {
                 buf.write(frame::Type::PING);
             }
 
+            // HANDSHAKE_DONE
+            if pending.handshake_done && buf.len() < max_size {
+                trace!(log, "HANDSHAKE_DONE");
+                pending.handshake_done = false;
+                sent.handshake_done = true;
+                buf.write(frame::Type::HANDSHAKE_DONE);
+            }
+
             // ACK
             // We will never ack protected packets in handshake packets because handshake_cleanup ensures we never send
             // handshake packets after receiving protected packets.
             // 0-RTT packets must never carry acks (which would have to be of handshake packets)
-            if !self.pending_acks.is_empty() {
-                //&& !crypto.is_0rtt() {
-                let delay = (now - self.rx_packet_time) >> ACK_DELAY_EXPONENT;
+            if !self.pending_acks.is_empty() && !crypto.is_0rtt() {
+                let delay = (now - self.rx_packet_time) >> config.ack_delay_exponent;
                 trace!(log, "ACK"; "ranges" => ?self.pending_acks.iter().collect::<Vec<_>>(), "delay" => delay);
-                frame::Ack::encode(delay, &self.pending_acks, &mut buf);
-                acks = self.pending_acks.clone();
+                // Only report ECN counts once the peer is known to be marking packets
+                let ecn = if self.ecn_counters == frame::EcnCounts::default() {
+                    None
+                } else {
+                    Some(&self.ecn_counters)
+                };
+                let mut ack = Vec::new();
+                frame::Ack::encode(delay, &self.pending_acks, ecn, &mut ack);
+                if buf.len() + ack.len() <= max_size {
+                    buf.extend_from_slice(&ack);
+                    acks = self.pending_acks.clone();
+                } else {
+                    // Too little space left in a coalesced packet; the ACK can go in its own datagram
+                    acks = RangeSet::new();
+                }
             } else {
                 acks = RangeSet::new();
             }
@@ -1839,6 +3604,63 @@ impl Connection {
                 }
             }
 
+            // PATH_CHALLENGE
+            if buf.len() + 9 < max_size {
+                if let Some(x) = pending.challenge.take() {
+                    trace!(log, "PATH_CHALLENGE"; "value" => format!("{:08x}", x));
+                    sent.challenge = Some(x);
+                    buf.write(frame::Type::PATH_CHALLENGE);
+                    buf.write(x);
+                }
+            }
+
+            // ACK_FREQUENCY
+            if buf.len() + 25 < max_size && !crypto.is_0rtt() {
+                if let Some(x) = pending.ack_frequency.take() {
+                    trace!(log, "ACK_FREQUENCY"; "sequence" => x.sequence, "threshold" => x.ack_eliciting_threshold, "delay" => x.request_max_ack_delay);
+                    x.encode(&mut buf);
+                    sent.ack_frequency = Some(x);
+                }
+            }
+
+            // NEW_CONNECTION_ID
+            while buf.len() + 18 + MAX_CID_SIZE + RESET_TOKEN_SIZE < max_size {
+                let frame = if let Some(x) = pending.new_cids.pop() {
+                    x
+                } else {
+                    break;
+                };
+                trace!(log, "NEW_CONNECTION_ID"; "sequence" => frame.sequence, "retire_prior_to" => frame.retire_prior_to, "id" => %frame.id);
+                frame.encode(&mut buf);
+                sent.new_cids.push(frame);
+            }
+
+            // RETIRE_CONNECTION_ID
+            while buf.len() + 9 < max_size {
+                let sequence = if let Some(x) = pending.retire_cids.pop() {
+                    x
+                } else {
+                    break;
+                };
+                trace!(log, "RETIRE_CONNECTION_ID"; "sequence" => sequence);
+                buf.write(frame::Type::RETIRE_CONNECTION_ID);
+                buf.write_var(sequence);
+                sent.retire_cids.push(sequence);
+            }
+
+            // NEW_TOKEN
+            if let Some(token) = pending.new_token.take() {
+                if buf.len() + 9 + token.len() < max_size {
+                    trace!(log, "NEW_TOKEN"; "len" => token.len());
+                    buf.write(frame::Type::NEW_TOKEN);
+                    buf.write_var(token.len() as u64);
+                    buf.extend_from_slice(&token);
+                    sent.new_token = Some(token);
+                } else {
+                    pending.new_token = Some(token);
+                }
+            }
+
             // RST_STREAM
             while buf.len() + 19 < max_size {
                 let (id, error_code) = if let Some(x) = pending.rst_stream.pop() {
@@ -1856,6 +3678,7 @@ impl Connection {
                 frame::RstStream {
                     id,
                     error_code,
+                    // Everything written counted against flow control, including data abandoned before it was sent
                     final_offset: stream.send().unwrap().offset,
                 }.encode(&mut buf);
             }
@@ -1893,25 +3716,61 @@ impl Connection {
 
             // MAX_STREAM_DATA
             while buf.len() + 17 < max_size {
-                let id = if let Some(x) = pending.max_stream_data.iter().next() {
+                let id = if let Some(x) = pending.max_stream_data.iter().next() {
+                    *x
+                } else {
+                    break;
+                };
+                pending.max_stream_data.remove(&id);
+                let rs = if let Some(x) = self.streams.get(&id) {
+                    x.recv().unwrap()
+                } else {
+                    continue;
+                };
+                if rs.is_finished() {
+                    continue;
+                }
+                sent.max_stream_data.insert(id);
+                trace!(log, "MAX_STREAM_DATA"; "stream" => id.0, "value" => rs.max_data);
+                buf.write(frame::Type::MAX_STREAM_DATA);
+                buf.write(id);
+                buf.write_var(rs.max_data);
+            }
+
+            // BLOCKED
+            if pending.blocked && buf.len() + 9 < max_size {
+                pending.blocked = false;
+                if self.data_sent >= self.max_data {
+                    trace!(log, "BLOCKED"; "offset" => self.max_data);
+                    self.flow_control_stats.blocked_sent += 1;
+                    sent.blocked = true;
+                    buf.write(frame::Type::BLOCKED);
+                    buf.write_var(self.max_data);
+                }
+            }
+
+            // STREAM_BLOCKED
+            while buf.len() + 17 < max_size {
+                let id = if let Some(x) = pending.stream_blocked.iter().next() {
                     *x
                 } else {
                     break;
                 };
-                pending.max_stream_data.remove(&id);
-                let rs = if let Some(x) = self.streams.get(&id) {
-                    x.recv().unwrap()
+                pending.stream_blocked.remove(&id);
+                let ss = if let Some(x) = self.streams.get(&id) {
+                    x.send().unwrap()
                 } else {
                     continue;
                 };
-                if rs.is_finished() {
+                if ss.state != stream::SendState::Ready || ss.offset < ss.max_data {
                     continue;
                 }
-                sent.max_stream_data.insert(id);
-                trace!(log, "MAX_STREAM_DATA"; "stream" => id.0, "value" => rs.max_data);
-                buf.write(frame::Type::MAX_STREAM_DATA);
+                trace!(log, "STREAM_BLOCKED"; "stream" => id.0, "offset" => ss.max_data);
+                self.flow_control_stats.stream_blocked_sent += 1;
+                sent.stream_blocked.insert(id);
+                buf.write(frame::Type::STREAM_BLOCKED);
                 buf.write(id);
-                buf.write_var(rs.max_data);
+                buf.write_var(ss.max_data);
             }
 
             // MAX_STREAM_ID uni
@@ -1940,59 +3799,131 @@ impl Connection {
                 ));
             }
 
-            // STREAM
-            while buf.len() + 25 < max_size {
-                let mut stream = if let Some(x) = pending.stream.pop_front() {
-                    x
-                } else {
-                    break;
-                };
-                if stream.id != StreamId(0) && self
-                    .streams
-                    .get(&stream.id)
-                    .map_or(true, |s| s.send().unwrap().state.was_reset())
-                {
-                    continue;
+            // STREAM_ID_BLOCKED uni
+            if pending.uni_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.uni_stream_id_blocked = false;
+                if self.next_uni_stream >= self.max_uni_streams {
+                    sent.uni_stream_id_blocked = true;
+                    trace!(log, "STREAM_ID_BLOCKED (unidirectional)");
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(
+                        self.side,
+                        Directionality::Uni,
+                        self.max_uni_streams,
+                    ));
                 }
-                let len = cmp::min(stream.data.len(), max_size as usize - buf.len() - 25);
-                let data = stream.data.split_to(len);
-                let fin = stream.fin && stream.data.is_empty();
-                trace!(log, "STREAM"; "id" => stream.id.0, "off" => stream.offset, "len" => len, "fin" => fin);
-                let frame = frame::Stream {
-                    id: stream.id,
-                    offset: stream.offset,
-                    fin,
-                    data,
-                };
-                frame.encode(true, &mut buf);
-                sent.stream.push_back(frame);
-                if !stream.data.is_empty() {
-                    let stream = frame::Stream {
-                        offset: stream.offset + len as u64,
-                        ..stream
-                    };
-                    pending.stream.push_front(stream);
+            }
+
+            // STREAM_ID_BLOCKED bi
+            if pending.bi_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.bi_stream_id_blocked = false;
+                if self.next_bi_stream >= self.max_bi_streams {
+                    sent.bi_stream_id_blocked = true;
+                    trace!(log, "STREAM_ID_BLOCKED (bidirectional)");
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(
+                        self.side,
+                        Directionality::Bi,
+                        self.max_bi_streams,
+                    ));
                 }
             }
 
-            if is_initial && buf.len() < MIN_INITIAL_SIZE - AEAD_TAG_SIZE {
-                buf.resize(
-                    MIN_INITIAL_SIZE - AEAD_TAG_SIZE,
-                    frame::Type::PADDING.into(),
-                );
+            {
+                // The last STREAM frame may omit its length, so padding must go through the builder from here on
+                let mut builder = frame::Builder::new(&mut buf, max_size, self.version);
+
+                // DATAGRAM
+                while send_datagrams {
+                    let frame = if let Some(x) = self.outgoing_datagrams.front() {
+                        Frame::Datagram(x.clone())
+                    } else {
+                        break;
+                    };
+                    if !builder.push(&frame) {
+                        break;
+                    }
+                    let datagram = self.outgoing_datagrams.pop_front().unwrap();
+                    trace!(log, "DATAGRAM"; "len" => datagram.data.len());
+                }
+
+                // STREAM
+                while builder.remaining() > 0 {
+                    let index = if let Some(x) = self.scheduler.next_frame(&pending.stream) {
+                        x
+                    } else {
+                        break;
+                    };
+                    let mut stream = pending.stream.remove(index).unwrap();
+                    if stream.id != StreamId(0) && self
+                        .streams
+                        .get(&stream.id)
+                        .map_or(true, |s| s.send().unwrap().state.was_reset())
+                    {
+                        continue;
+                    }
+                    let len = if let Some(x) = builder.push_stream(&stream) {
+                        x
+                    } else {
+                        pending.stream.insert(index, stream);
+                        break;
+                    };
+                    let data = stream.data.split_to(len);
+                    let fin = stream.fin && stream.data.is_empty();
+                    self.scheduler.on_sent(stream.id, len);
+                    trace!(log, "STREAM"; "id" => stream.id.0, "off" => stream.offset, "len" => len, "fin" => fin);
+                    sent.stream.push_back(frame::Stream {
+                        id: stream.id,
+                        offset: stream.offset,
+                        fin,
+                        data,
+                    });
+                    if !stream.data.is_empty() {
+                        let stream = frame::Stream {
+                            offset: stream.offset + len as u64,
+                            ..stream
+                        };
+                        pending.stream.insert(index, stream);
+                    }
+                }
+
+                if builder.len() == header_len as usize {
+                    // Nothing fit in the available space
+                    return Ok(None);
+                }
+                // An Initial followed by other handshake data leaves space to coalesce it in the same datagram
+                if is_initial && pending.is_empty() {
+                    builder.pad_to(MIN_INITIAL_SIZE - tag_len);
+                }
+                // Header protection samples ciphertext starting 4 bytes past the start of the packet number
+                builder.pad_to(partial_encode.pn_offset + 4);
             }
-            if !crypto.is_1rtt() {
-                set_payload_length(&mut buf, header_len as usize);
+            if let Some(slot) = partial_encode.len_slot {
+                if let Err(e) = set_payload_length(&mut buf, slot, tag_len) {
+                    debug!(log, "{}", e; "pn" => number, "len" => buf.len());
+                    return Err(TransportError::INTERNAL_ERROR.into());
+                }
             }
             crypto.encrypt(number, &mut buf, header_len as usize);
-            handshake = crypto.is_handshake();
+            Header::encrypt_header(&mut buf, header_len as usize, crypto.local_header_key());
         }
 
         // If we sent any acks, don't immediately resend them.  Setting this even if ack_only is false needlessly
         // prevents us from ACKing the next packet if it's ACK-only, but saves the need for subtler logic to avoid
         // double-transmitting acks all the time.
         self.permit_ack_only &= acks.is_empty();
+        if !acks.is_empty() {
+            self.unacked_ack_eliciting = 0;
+            if self.ack_timer.take().is_some() {
+                self.set_ack = Some(None);
+            }
+        }
 
+        if sent.challenge.is_some() {
+            let remote = self.remote;
+            self.on_challenge_sent(config, remote, now);
+        }
+        let ecn = self.ecn_codepoint();
         self.on_packet_sent(
             config,
             now,
@@ -2001,29 +3932,127 @@ impl Connection {
                 acks,
                 time: now,
                 bytes: if ack_only { 0 } else { buf.len() as u16 },
-                handshake,
+                space: space_id,
+                ecn: ecn.is_some(),
                 retransmits: sent,
             },
         );
 
-        Some(buf)
+        Ok(Some((buf, ecn)))
     }
 
-    // TLP/RTO transmit
-    pub fn force_transmit(&mut self, config: &Config, now: u64) -> Box<[u8]> {
+    /// Assemble a packet carrying a path validation frame for a path other than the active one, if any
+    pub fn next_off_path_packet<R: Rng>(
+        &mut self,
+        config: &Config,
+        rng: &mut R,
+        now: u64,
+    ) -> Option<(SocketAddrV6, Box<[u8]>)> {
+        let (remote, ty, token) = self.off_path_frames.pop_front()?;
+        if self.state.as_ref().unwrap().is_closed() || self.crypto.is_none() {
+            self.off_path_frames.clear();
+            return None;
+        }
         let number = self.get_tx_number();
+        let pn = PacketNumber::new(number, self.largest_acked_packet).ok()?;
         let mut buf = Vec::new();
+        // Use a connection ID distinct from the active path's, if we have one, so the paths can't be linked
+        let id = match self.paths.get(&remote).and_then(|x| x.remote_cid.as_ref()) {
+            Some(&(_, ref id, _)) => id.clone(),
+            None => self
+                .remote_cids
+                .front()
+                .map_or_else(|| self.remote_id.clone(), |x| x.1.clone()),
+        };
+        let quic_bit = self.quic_bit(config, rng);
+        Header::Short {
+            id,
+            number: pn,
+            spin: self.spin,
+            key_phase: self.key_phase,
+        }.encode_with_quic_bit(&mut buf, 0, quic_bit);
+        let header_len = buf.len() as u16;
+        buf.write(ty);
+        buf.write(token);
+        {
+            let crypto = self.crypto.as_ref().unwrap();
+            crypto.encrypt(number, &mut buf, header_len as usize);
+            Header::encrypt_header(&mut buf, header_len as usize, crypto.local_header_key());
+        }
+        {
+            let path = self.paths.get_mut(&remote)?;
+            if self.side == Side::Server
+                && !path.validated
+                && path.total_sent + buf.len() as u64 > 3 * path.total_recvd
+            {
+                return None;
+            }
+            path.total_sent += buf.len() as u64;
+        }
+        if ty == frame::Type::PATH_CHALLENGE {
+            self.on_challenge_sent(config, remote, now);
+        }
+        self.on_packet_sent(
+            config,
+            now,
+            number,
+            SentPacket {
+                time: now,
+                bytes: buf.len() as u16,
+                space: SpaceId::Data,
+                ecn: false,
+                acks: RangeSet::new(),
+                retransmits: Retransmits::default(),
+            },
+        );
+        Some((remote, buf.into()))
+    }
+
+    /// Assemble a PING padded to the size of the active path's next MTU probe, if one is due
+    pub fn next_mtu_probe<R: Rng>(
+        &mut self,
+        log: &Logger,
+        config: &Config,
+        rng: &mut R,
+        now: u64,
+    ) -> Option<Box<[u8]>> {
+        match *self.state.as_ref().unwrap() {
+            State::Established(_) if !self.awaiting_handshake => {}
+            _ => return None,
+        }
+        let size = self.paths[&self.remote].pmtud.next_probe()?;
+        let window = self.congestion.window().saturating_sub(self.bytes_in_flight);
+        let budget = self.amplification_budget().unwrap_or(usize::max_value());
+        if window < size as u64 || budget < size as usize {
+            return None;
+        }
+        let number = self.get_tx_number();
+        trace!(log, "sending MTU probe"; "pn" => number, "size" => size);
+        let pn = PacketNumber::new(number, self.largest_acked_packet).ok()?;
+        let mut buf = Vec::with_capacity(size as usize);
+        let quic_bit = self.quic_bit(config, rng);
         Header::Short {
             id: self.remote_id.clone(),
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            number: pn,
+            spin: self.spin,
             key_phase: self.key_phase,
-        }.encode(&mut buf);
+        }.encode_with_quic_bit(&mut buf, 0, quic_bit);
         let header_len = buf.len() as u16;
         buf.push(frame::Type::PING.into());
-        self.crypto
-            .as_ref()
-            .unwrap()
-            .encrypt(number, &mut buf, header_len as usize);
+        {
+            let crypto = self.crypto.as_ref().unwrap();
+            buf.resize(
+                size as usize - crypto.tag_len(),
+                frame::Type::PADDING.into(),
+            );
+            crypto.encrypt(number, &mut buf, header_len as usize);
+            Header::encrypt_header(&mut buf, header_len as usize, crypto.local_header_key());
+        }
+        {
+            let path = self.paths.get_mut(&self.remote).unwrap();
+            path.pmtud.on_probe_sent(number);
+            path.total_sent += buf.len() as u64;
+        }
         self.on_packet_sent(
             config,
             now,
@@ -2031,12 +4060,13 @@ impl Connection {
             SentPacket {
                 time: now,
                 bytes: buf.len() as u16,
-                handshake: false,
+                space: SpaceId::Data,
+                ecn: false,
                 acks: RangeSet::new(),
                 retransmits: Retransmits::default(),
             },
         );
-        buf.into()
+        Some(buf.into())
     }
 
     pub fn make_close(&mut self, reason: &state::CloseReason) -> Box<[u8]> {
@@ -2044,19 +4074,21 @@ impl Connection {
         let mut buf = Vec::new();
         Header::Short {
             id: self.remote_id.clone(),
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            // Closing is best effort, so make do with the widest encoding if need be
+            number: PacketNumber::new(number, self.largest_acked_packet)
+                .unwrap_or(PacketNumber::U32(number as u32)),
+            spin: self.spin,
             key_phase: self.key_phase,
         }.encode(&mut buf);
         let header_len = buf.len() as u16;
-        let max_len = self.mtu - header_len - AEAD_TAG_SIZE as u16;
+        let crypto = self.crypto.as_ref().unwrap();
+        let max_len = self.mtu() - header_len - crypto.tag_len() as u16;
         match *reason {
             state::CloseReason::Application(ref x) => x.encode(&mut buf, max_len),
-            state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
+            state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len, self.version),
         }
-        self.crypto
-            .as_ref()
-            .unwrap()
-            .encrypt(number, &mut buf, header_len as usize);
+        crypto.encrypt(number, &mut buf, header_len as usize);
+        Header::encrypt_header(&mut buf, header_len as usize, crypto.local_header_key());
         buf.into()
     }
 
@@ -2079,9 +4111,9 @@ impl Connection {
             self.close_common(ctx, now, conn);
             ctx.io.push_back(Io::Transmit {
                 destination: self.remote,
+                ecn: None,
                 packet: self.make_close(&reason),
             });
-            self.reset_idle_timeout(&ctx.config, now);
             ctx.dirty_conns.insert(conn);
         }
         self.state = Some(match self.state.take().unwrap() {
@@ -2109,14 +4141,46 @@ impl Connection {
 
     pub fn close_common(&mut self, ctx: &mut Context, now: u64, conn: ConnectionHandle) {
         trace!(ctx.log, "connection closed");
+        self.qlog(now, |qlog, group| {
+            qlog.connection_state_updated(now, group, "closing")
+        });
         self.set_loss_detection = Some(None);
+        self.set_path_validation = Some(None);
+        // The close timer bounds what remains of the connection's life
+        self.set_idle = Some(None);
+        if self.ack_timer.take().is_some() {
+            self.set_ack = Some(None);
+        }
         ctx.io.push_back(Io::TimerStart {
             connection: conn,
             timer: Timer::Close,
-            time: now + 3 * self.rto(&ctx.config),
+            time: now + 3 * self.pto(&ctx.config),
         });
     }
 
+    /// Whether the peer's view of version negotiation, authenticated by the handshake, agrees with ours
+    ///
+    /// Otherwise an attacker may have forged a Version Negotiation packet to downgrade the connection to a version
+    /// both peers prefer less.
+    fn version_negotiation_valid(&self, config: &Config, params: &TransportParameters) -> bool {
+        match self.side {
+            Side::Client => {
+                // We should have settled on our favorite of the versions the server supports
+                params.version == self.version.number()
+                    && config
+                        .versions
+                        .iter()
+                        .find(|x| params.supported_versions.contains(&x.number()))
+                        == Some(&self.version)
+            }
+            Side::Server => {
+                // The client should only have abandoned its first choice if we don't support it
+                params.version == self.version.number()
+                    || !config.versions.iter().any(|x| x.number() == params.version)
+            }
+        }
+    }
+
     pub fn set_params(&mut self, params: TransportParameters) {
         self.max_bi_streams = params.initial_max_streams_bidi as u64;
         if self.side == Side::Client {
@@ -2124,6 +4188,11 @@ impl Connection {
         } // Account for TLS stream
         self.max_uni_streams = params.initial_max_streams_uni as u64;
         self.max_data = params.initial_max_data as u64;
+        self.max_ack_delay = u64::from(params.max_ack_delay) * 1000;
+        self.cid_pool.set_limit(cmp::min(
+            params.active_connection_id_limit as usize,
+            ISSUED_CIDS + 1,
+        ));
         for i in 0..self.max_remote_bi_streams {
             let id = StreamId::new(!self.side, Directionality::Bi, i as u64);
             self.streams
@@ -2152,9 +4221,14 @@ impl Connection {
                     Stream::new_bi(config.stream_receive_window as u64),
                 )
             }
-            _ => {
+            Directionality::Uni => {
+                self.pending.uni_stream_id_blocked = true;
+                return None;
+            }
+            Directionality::Bi => {
+                self.pending.bi_stream_id_blocked = true;
                 return None;
-            } // TODO: Queue STREAM_ID_BLOCKED
+            }
         };
         stream.send_mut().unwrap().max_data = self.params.initial_max_stream_data as u64;
         let old = self.streams.insert(id, stream);
@@ -2169,21 +4243,35 @@ impl Connection {
         match self.streams.entry(id) {
             hash_map::Entry::Vacant(_) => unreachable!(),
             hash_map::Entry::Occupied(e) => {
-                if e.get().is_closed() {
-                    e.remove_entry();
-                    if id.initiator() != self.side {
-                        match id.directionality() {
-                            Directionality::Uni => {
-                                self.max_remote_uni_streams += 1;
-                                self.pending.max_uni_stream_id = true;
-                            }
-                            Directionality::Bi => {
-                                self.max_remote_bi_streams += 1;
-                                self.pending.max_bi_stream_id = true;
-                            }
-                        }
-                    }
+                if !e.get().is_closed() {
+                    return;
                 }
+                e.remove_entry();
+            }
+        }
+        self.scheduler.remove(id);
+        if id.initiator() == self.side {
+            return;
+        }
+        // Permit the peer to open another stream in place of the one that closed
+        match id.directionality() {
+            Directionality::Uni => {
+                self.streams.insert(
+                    StreamId::new(!self.side, Directionality::Uni, self.max_remote_uni_streams),
+                    stream::Recv::new(self.stream_receive_window).into(),
+                );
+                self.max_remote_uni_streams += 1;
+                self.pending.max_uni_stream_id = true;
+            }
+            Directionality::Bi => {
+                let mut stream = Stream::new_bi(self.stream_receive_window);
+                stream.send_mut().unwrap().max_data = self.params.initial_max_stream_data as u64;
+                self.streams.insert(
+                    StreamId::new(!self.side, Directionality::Bi, self.max_remote_bi_streams),
+                    stream,
+                );
+                self.max_remote_bi_streams += 1;
+                self.pending.max_bi_stream_id = true;
             }
         }
     }
@@ -2283,6 +4371,92 @@ impl Connection {
         }
     }
 
+    /// Like `read`, but returns the next contiguous chunk of data, which isn't copied if it arrived in order
+    pub fn read_chunk(&mut self, id: StreamId) -> Result<Bytes, ReadError> {
+        assert_ne!(id, StreamId(0), "cannot read an internal stream");
+        let rs = self.streams.get_mut(&id).unwrap().recv_mut().unwrap();
+        assert!(
+            !rs.unordered,
+            "cannot perform ordered reads following unordered reads on a stream"
+        );
+
+        let mut chunk = None;
+        // A frame that begins at or before the read offset can be handed out directly
+        loop {
+            let next = rs.assembler.offset();
+            if !rs.assembler.is_empty() || !rs.buffered.front().map_or(false, |x| x.1 <= next) {
+                break;
+            }
+            let (mut data, offset) = rs.buffered.pop_front().unwrap();
+            let skip = next - offset;
+            if skip >= data.len() as u64 {
+                // Retransmitted data we've already read
+                continue;
+            }
+            data.split_to(skip as usize);
+            rs.assembler.skip(data.len() as u64);
+            chunk = Some(data);
+            break;
+        }
+        if chunk.is_none() {
+            for (data, offset) in rs.buffered.drain(..) {
+                rs.assembler.insert(offset, &data);
+            }
+            chunk = rs.assembler.next().map(|x| Bytes::from(x.into_vec()));
+        }
+
+        if let Some(chunk) = chunk {
+            // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to reduce overhead
+            self.local_max_data += chunk.len() as u64;
+            self.pending.max_data = true;
+            // Only bother issuing stream credit if the peer wants to send more
+            if let stream::RecvState::Recv { size: None } = rs.state {
+                rs.max_data += chunk.len() as u64;
+                self.pending.max_stream_data.insert(id);
+            }
+            Ok(chunk)
+        } else {
+            match rs.state {
+                stream::RecvState::ResetRecvd { error_code, .. } => {
+                    rs.state = stream::RecvState::Closed;
+                    Err(ReadError::Reset { error_code })
+                }
+                stream::RecvState::Closed => unreachable!(),
+                stream::RecvState::Recv { .. } => Err(ReadError::Blocked),
+                stream::RecvState::DataRecvd { .. } => {
+                    rs.state = stream::RecvState::Closed;
+                    Err(ReadError::Finished)
+                }
+            }
+        }
+    }
+
+    /// Like `read`, but leaves the data to be read again and doesn't close a finished or reset stream
+    pub fn peek(&mut self, id: StreamId, buf: &mut [u8]) -> Result<usize, ReadError> {
+        assert_ne!(id, StreamId(0), "cannot read an internal stream");
+        let rs = self.streams.get_mut(&id).unwrap().recv_mut().unwrap();
+        assert!(
+            !rs.unordered,
+            "cannot perform ordered reads following unordered reads on a stream"
+        );
+
+        for (data, offset) in rs.buffered.drain(..) {
+            rs.assembler.insert(offset, &data);
+        }
+
+        if !rs.assembler.blocked() {
+            return Ok(rs.assembler.peek(buf));
+        }
+        match rs.state {
+            stream::RecvState::ResetRecvd { error_code, .. } => {
+                Err(ReadError::Reset { error_code })
+            }
+            stream::RecvState::Closed => unreachable!(),
+            stream::RecvState::Recv { .. } => Err(ReadError::Blocked),
+            stream::RecvState::DataRecvd { .. } => Err(ReadError::Finished),
+        }
+    }
+
     pub fn stop_sending(&mut self, id: StreamId, error_code: u16) {
         assert!(
             id.directionality() == Directionality::Bi || id.initiator() != self.side,
@@ -2290,26 +4464,55 @@ impl Connection {
         );
         let stream = self
             .streams
-            .get(&id)
+            .get_mut(&id)
             .expect("stream must have begun sending to be stopped")
-            .recv()
+            .recv_mut()
             .unwrap();
-        // Only bother if there's data we haven't received yet
-        if !stream.is_finished() {
+        // Only bother if there's data we haven't received yet, and we haven't already asked
+        if !stream.is_finished() && !stream.stopped {
+            stream.stopped = true;
             self.pending.stop_sending.push((id, error_code));
         }
     }
 
     pub fn congestion_blocked(&self) -> bool {
-        self.congestion_window.saturating_sub(self.bytes_in_flight) < self.mtu as u64
+        self.congestion
+            .window()
+            .saturating_sub(self.bytes_in_flight)
+            < self.mtu() as u64
     }
 
     pub fn blocked(&self) -> bool {
-        self.data_sent >= self.max_data || self.congestion_blocked()
+        self.data_sent >= self.max_data
+            || self.unacked_data >= self.send_buffer_size
+            || self.congestion_blocked()
+    }
+
+    /// Remove header protection from a packet addressed to this connection
+    pub fn decode_packet(&self, partial: PartialDecode) -> Result<Packet, HeaderError> {
+        if partial.version().map_or(false, |x| x != self.version) {
+            return Err(HeaderError::InvalidHeader("packet of another version"));
+        }
+        let crypto = if partial.long_type() == Some(types::ZERO_RTT) {
+            if let Some(ref crypto) = self.zero_rtt_crypto {
+                crypto
+            } else {
+                return Err(HeaderError::InvalidHeader("0-RTT keys not available"));
+            }
+        } else if partial.has_long_header() {
+            &self.handshake_crypto
+        } else if let Some(ref crypto) = self.crypto {
+            crypto
+        } else {
+            return Err(HeaderError::InvalidHeader("1-RTT keys not yet available"));
+        };
+        partial.finish(crypto.remote_header_key())
     }
 
     pub fn decrypt_packet(
         &mut self,
+        config: &Config,
+        now: u64,
         handshake: bool,
         mut packet: Packet,
     ) -> Result<(Vec<u8>, u64), Option<TransportError>> {
@@ -2321,35 +4524,69 @@ impl Connection {
             {
                 (key_phase, number)
             }
-            Header::Long { number, .. } if handshake => (false, PacketNumber::U32(number)),
+            Header::Long {
+                ty: types::HANDSHAKE,
+                number,
+                ..
+            }
+                if handshake =>
+            {
+                (false, number)
+            }
             _ => {
                 return Err(None);
             }
         };
         let number = number.expand(self.rx_packet);
-        if key_phase != self.key_phase {
-            if number <= self.rx_packet {
-                // Illegal key update
-                return Err(Some(TransportError::PROTOCOL_VIOLATION));
-            }
-            if self
-                .update_keys(number, &packet.header_data, &mut packet.payload)
-                .is_ok()
-            {
-                Ok((packet.payload.to_vec(), number))
-            } else {
-                // Invalid key update
-                Err(None)
+        // Packets delayed past the previous key update for this long are lost anyway
+        let prev_expired = self.prev_crypto.as_ref().map_or(false, |prev| {
+            prev.end_packet
+                .map_or(false, |(_, time)| now >= time + 3 * self.pto(config))
+        });
+        if prev_expired {
+            self.prev_crypto = None;
+        }
+        if handshake {
+            self.handshake_crypto
+                .decrypt(number, &packet.header_data, &mut packet.payload)
+                .map_err(|()| None)?;
+        } else if key_phase == self.key_phase {
+            self.crypto
+                .as_ref()
+                .unwrap()
+                .decrypt(number, &packet.header_data, &mut packet.payload)
+                .map_err(|()| None)?;
+            if let Some(ref mut prev) = self.prev_crypto {
+                if prev.end_packet.is_none() {
+                    // The peer has followed a key update we initiated
+                    prev.end_packet = Some((number, now));
+                }
             }
-        } else if self
-            .decrypt(handshake, number, &packet.header_data, &mut packet.payload)
-            .is_ok()
-        {
-            Ok((packet.payload.to_vec(), number))
+        } else if self.prev_crypto.as_ref().map_or(false, |prev| {
+            prev.end_packet.map_or(true, |(end, _)| number < end)
+        }) {
+            // Sent before the most recent key update
+            self.prev_crypto
+                .as_ref()
+                .unwrap()
+                .crypto
+                .decrypt(number, &packet.header_data, &mut packet.payload)
+                .map_err(|()| None)?;
         } else {
-            // Unable to authenticate
-            Err(None)
+            // The peer has initiated a key update
+            let next = self.crypto.as_ref().unwrap().update(self.side);
+            next.decrypt(number, &packet.header_data, &mut packet.payload)
+                .map_err(|()| None)?;
+            if !self.handshake_confirmed || number <= self.rx_packet {
+                // Before the handshake is confirmed, or by a packet older than one already received under the old keys
+                return Err(Some(TransportError::KEY_UPDATE_ERROR));
+            }
+            self.install_keys(next, Some((number, now)));
         }
+        if packet.check_reserved_bits().is_err() {
+            return Err(Some(TransportError::PROTOCOL_VIOLATION));
+        }
+        Ok((packet.payload.to_vec(), number))
     }
 
     pub fn get_recv_stream(&mut self, id: StreamId) -> Result<Option<&mut Stream>, TransportError> {
@@ -2376,22 +4613,77 @@ impl Connection {
     }
 
     pub fn write(&mut self, stream: StreamId, data: &[u8]) -> Result<usize, WriteError> {
+        self.write_vectored(stream, &[data])
+    }
+
+    /// Like `write`, but gathers data from several buffers in order
+    ///
+    /// What fits is copied into a single buffer and sent as one STREAM frame, rather than allocating for each slice.
+    pub fn write_vectored(
+        &mut self,
+        stream: StreamId,
+        bufs: &[&[u8]],
+    ) -> Result<usize, WriteError> {
+        let mut budget = self.write_budget(stream)?;
+        let total = bufs.iter().map(|x| x.len() as u64).sum::<u64>();
+        let mut data = BytesMut::with_capacity(cmp::min(budget, total) as usize);
+        for buf in bufs {
+            let len = budget.min(buf.len() as u64) as usize;
+            data.extend_from_slice(&buf[..len]);
+            budget -= len as u64;
+        }
+        let n = data.len();
+        self.transmit(stream, data.freeze());
+        Ok(n)
+    }
+
+    /// Like `write_vectored`, but takes buffers that are already shared, so none of the data is copied
+    ///
+    /// Each buffer becomes its own STREAM frame.
+    pub fn write_chunks(&mut self, stream: StreamId, bufs: &[Bytes]) -> Result<usize, WriteError> {
+        let mut budget = self.write_budget(stream)?;
+        let mut n = 0;
+        for data in bufs {
+            let len = budget.min(data.len() as u64) as usize;
+            if len == 0 && bufs.len() > 1 {
+                continue;
+            }
+            self.transmit(stream, data.slice_to(len));
+            budget -= len as u64;
+            n += len;
+        }
+        Ok(n)
+    }
+
+    /// How many bytes may be written to `stream` now, or why none may be
+    fn write_budget(&mut self, stream: StreamId) -> Result<u64, WriteError> {
         if self.state.as_ref().unwrap().is_closed() {
             return Err(WriteError::Blocked);
         }
         assert!(stream.directionality() == Directionality::Bi || stream.initiator() == self.side);
         if self.blocked() {
             self.blocked_streams.insert(stream);
+            if self.data_sent >= self.max_data && self.blocked_at != Some(self.max_data) {
+                self.blocked_at = Some(self.max_data);
+                self.pending.blocked = true;
+            }
             return Err(WriteError::Blocked);
         }
-        let (stop_reason, stream_budget) = {
+        let (reset, stop_reason, stream_budget, newly_blocked) = {
             let ss = self
                 .streams
                 .get_mut(&stream)
                 .expect("stream already closed")
                 .send_mut()
                 .unwrap();
+            let newly_blocked = ss.state == stream::SendState::Ready
+                && ss.offset >= ss.max_data
+                && ss.blocked_at != Some(ss.max_data);
+            if newly_blocked {
+                ss.blocked_at = Some(ss.max_data);
+            }
             (
+                ss.state.was_reset(),
                 match ss.state {
                     stream::SendState::ResetSent {
                         ref mut stop_reason,
@@ -2402,6 +4694,7 @@ impl Connection {
                     _ => None,
                 },
                 ss.max_data - ss.offset,
+                newly_blocked,
             )
         };
 
@@ -2409,22 +4702,102 @@ impl Connection {
             self.maybe_cleanup(stream);
             return Err(WriteError::Stopped { error_code });
         }
+        if reset {
+            return Err(WriteError::Reset);
+        }
 
         if stream_budget == 0 {
+            if newly_blocked {
+                self.pending.stream_blocked.insert(stream);
+            }
             return Err(WriteError::Blocked);
         }
 
-        let conn_budget = self.max_data - self.data_sent;
-        let n = conn_budget.min(stream_budget).min(data.len() as u64) as usize;
-        self.transmit(stream, (&data[0..n]).into());
-        Ok(n)
+        let conn_budget = cmp::min(
+            self.max_data - self.data_sent,
+            self.send_buffer_size - self.unacked_data,
+        );
+        Ok(conn_budget.min(stream_budget))
+    }
+
+    /// The value of the peer's transport parameter with `id`, if it's one QUIC doesn't define
+    pub fn custom_param(&self, id: u64) -> Option<&Bytes> {
+        self.params.custom.get(&id)
+    }
+
+    /// Largest datagram payload that may currently be sent, if the peer supports datagrams
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let peer_limit = self.params.max_datagram_frame_size? as usize;
+        // Leave room for the largest possible short header and the AEAD tag
+        let tag_len = self.crypto.as_ref().map_or(AEAD_TAG_SIZE, |x| x.tag_len());
+        let packet_limit = self.mtu() as usize - (1 + MAX_CID_SIZE + 4) - tag_len;
+        // Frame type and a length of at most two bytes
+        cmp::min(peer_limit, packet_limit).checked_sub(3)
+    }
+
+    pub fn send_datagram(&mut self, config: &Config, data: Bytes) -> Result<(), SendDatagramError> {
+        if config.max_datagram_frame_size.is_none() {
+            return Err(SendDatagramError::Disabled);
+        }
+        let max = self
+            .max_datagram_size()
+            .ok_or(SendDatagramError::UnsupportedByPeer)?;
+        if data.len() > max {
+            return Err(SendDatagramError::TooLarge);
+        }
+        if self.outgoing_datagrams.len() == MAX_BUFFERED_DATAGRAMS {
+            // Stale datagrams are usually worth less than fresh ones
+            self.outgoing_datagrams.pop_front();
+        }
+        self.outgoing_datagrams
+            .push_back(frame::Datagram { data });
+        Ok(())
+    }
+
+    pub fn recv_datagram(&mut self) -> Option<Bytes> {
+        self.datagrams.pop_front()
+    }
+
+    pub fn request_ack_frequency(
+        &mut self,
+        ack_eliciting_threshold: u64,
+        max_ack_delay: u64,
+    ) -> Result<(), AckFrequencyError> {
+        let min = self
+            .params
+            .min_ack_delay
+            .ok_or(AckFrequencyError::UnsupportedByPeer)?;
+        if max_ack_delay < u64::from(min) {
+            return Err(AckFrequencyError::DelayTooShort);
+        }
+        let sequence = self.next_ack_frequency;
+        self.next_ack_frequency += 1;
+        self.pending.ack_frequency = Some(frame::AckFrequency {
+            sequence,
+            ack_eliciting_threshold,
+            request_max_ack_delay: max_ack_delay,
+        });
+        // Don't mistake deliberately delayed ACKs for lost packets
+        self.max_ack_delay = cmp::max(self.max_ack_delay, max_ack_delay);
+        Ok(())
     }
 }
 
 /// Extract stream 0 data from an Initial or Retry packet payload
-pub fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Stream>, ()> {
+pub fn parse_initial(
+    log: &Logger,
+    payload: Bytes,
+    version: Version,
+) -> Result<Option<frame::Stream>, ()> {
     let mut result = None;
-    for frame in frame::Iter::new(payload) {
+    for frame in frame::Iter::new(payload, version) {
+        let frame = match frame {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(log, "malformed frame in initial/retry packet"; "ty" => %e.ty, "reason" => e.reason);
+                return Err(());
+            }
+        };
         match frame {
             Frame::Padding => {}
             Frame::Ack(_) => {}
@@ -2522,6 +4895,56 @@ pub enum WriteError {
     /// The peer is no longer accepting data on this stream.
     #[fail(display = "stopped by peer: error {}", error_code)]
     Stopped { error_code: u16 },
+    /// The stream was reset, so no more data may be written.
+    ///
+    /// Returned after a local `reset`, and after `Stopped` has been reported once.
+    #[fail(display = "stream reset")]
+    Reset,
+}
+
+/// Reasons why an additional path can't be opened
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum AddPathError {
+    /// Multipath is disabled locally.
+    #[fail(display = "multipath disabled")]
+    Disabled,
+    /// The peer did not advertise multipath support, or the handshake hasn't completed.
+    #[fail(display = "multipath not supported by peer")]
+    UnsupportedByPeer,
+    /// A path to the address is already open.
+    #[fail(display = "path already open")]
+    AlreadyOpen,
+    /// As many paths as may be tracked at once are open.
+    #[fail(display = "too many paths")]
+    TooManyPaths,
+    /// The peer hasn't issued a spare connection ID for the path to use.
+    #[fail(display = "no spare connection IDs")]
+    NoConnectionIds,
+}
+
+/// Reasons why a datagram might not be sent
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SendDatagramError {
+    /// The peer did not advertise datagram support, or the handshake hasn't completed.
+    #[fail(display = "datagrams not supported by peer")]
+    UnsupportedByPeer,
+    /// Datagram support is disabled locally.
+    #[fail(display = "datagram support disabled")]
+    Disabled,
+    /// The datagram exceeds the largest size the peer will accept in a single packet.
+    #[fail(display = "datagram too large")]
+    TooLarge,
+}
+
+/// Reasons why the peer can't be asked to change how often it acknowledges packets
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum AckFrequencyError {
+    /// The peer did not advertise support for ACK_FREQUENCY frames, or the handshake hasn't completed.
+    #[fail(display = "ACK frequency not supported by peer")]
+    UnsupportedByPeer,
+    /// The requested delay is shorter than the peer's `min_ack_delay`.
+    #[fail(display = "ACK delay too short")]
+    DelayTooShort,
 }
 
 pub enum State {
@@ -2572,6 +4995,16 @@ impl State {
         }
     }
 
+    /// Record that the application is done with a closed connection, so it can be forgotten once drained
+    pub fn set_app_closed(&mut self) {
+        match *self {
+            State::HandshakeFailed(ref mut x) => x.app_closed = true,
+            State::Closed(ref mut x) => x.app_closed = true,
+            State::Draining(ref mut x) => x.app_closed = true,
+            _ => {}
+        }
+    }
+
     pub fn is_drained(&self) -> bool {
         if let State::Drained = *self {
             true
@@ -2662,6 +5095,15 @@ pub mod state {
         }
     }
 }
-
-/// Ensures we can always fit all our ACKs in a single minimum-MTU packet with room to spare
-const MAX_ACK_BLOCKS: usize = 64;
+/// Smallest amount of space remaining in a datagram worth filling with a coalesced packet
+const MIN_COALESCE_SPACE: usize = 128;
+/// Maximum number of paths to track at once
+const MAX_PATHS: usize = 4;
+/// Maximum number of unused connection IDs issued by the peer to remember
+pub const MAX_REMOTE_CIDS: usize = 8;
+/// Number of connection IDs to keep issued to the peer once the connection is established, in addition to the initial
+/// one, if the peer's `active_connection_id_limit` allows
+pub const ISSUED_CIDS: usize = 2;
+/// Bounds memory used by received datagrams the application hasn't read yet, and by sent datagrams awaiting
+/// transmission
+const MAX_BUFFERED_DATAGRAMS: usize = 128;
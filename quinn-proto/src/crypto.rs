@@ -1,25 +1,30 @@
 use std::net::SocketAddrV6;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
-use std::{io, str};
+use std::{io, mem, str};
 
 use blake2::{digest::{Input, VariableOutput}, Blake2b};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BigEndian, Buf, BufMut, ByteOrder, BytesMut};
+use constant_time_eq::constant_time_eq;
 use ring::aead;
 use ring::digest;
 use ring::hkdf;
-use ring::hmac::SigningKey;
+use ring::hmac::{self, SigningKey};
 use rustls::quic::{ClientQuicExt, ServerQuicExt};
-pub use rustls::{Certificate, NoClientAuth, PrivateKey, TLSError};
+use rustls::sign::RSASigningKey;
+pub use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, TLSError,
+};
 pub use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
 use rustls::{KeyLogFile, ProtocolVersion};
 use webpki::DNSNameRef;
 use webpki_roots;
 
+use cid_generator::ConnectionIdError;
 use endpoint::EndpointError;
 use packet::{ConnectionId, AEAD_TAG_SIZE};
 use transport_parameters::TransportParameters;
-use {Side, RESET_TOKEN_SIZE};
+use {Side, Version, MAX_CID_SIZE, RESET_TOKEN_SIZE};
 
 pub enum TlsSession {
     Client(ClientSession),
@@ -87,86 +92,213 @@ pub fn build_client_config() -> ClientConfig {
 }
 
 pub fn build_server_config() -> ServerConfig {
-    let mut config = ServerConfig::new(NoClientAuth::new());
+    configure_server(ServerConfig::new(NoClientAuth::new()))
+}
+
+/// Like `build_server_config`, but clients must authenticate with a certificate issued by one of `client_roots`
+pub fn build_server_config_with_client_auth(client_roots: RootCertStore) -> ServerConfig {
+    let mut config = build_server_config();
+    require_client_auth(&mut config, client_roots);
+    config
+}
+
+/// Make clients of `config` authenticate with a certificate issued by one of `client_roots`
+///
+/// rustls only takes a client verifier when a configuration is created, so this replaces `config` with a new one that
+/// carries over every other setting, including the server's own certificate.
+pub fn require_client_auth(config: &mut ServerConfig, client_roots: RootCertStore) {
+    let old = mem::replace(
+        config,
+        ServerConfig::new(AllowAnyAuthenticatedClient::new(client_roots)),
+    );
+    config.ciphersuites = old.ciphersuites;
+    config.ignore_client_order = old.ignore_client_order;
+    config.mtu = old.mtu;
+    config.session_storage = old.session_storage;
+    config.ticketer = old.ticketer;
+    config.cert_resolver = old.cert_resolver;
+    config.alpn_protocols = old.alpn_protocols;
+    config.versions = old.versions;
+    config.key_log = old.key_log;
+}
+
+fn configure_server(mut config: ServerConfig) -> ServerConfig {
     config.set_protocols(&[ALPN_PROTOCOL.into()]);
     config.key_log = Arc::new(KeyLogFile::new());
     config
 }
 
+/// Present `cert_chain` to servers that ask clients to authenticate
+///
+/// Unlike `ClientConfig::set_single_client_cert`, an unusable `key` is reported rather than causing a panic.
+pub fn set_client_cert(
+    config: &mut ClientConfig,
+    cert_chain: Vec<Certificate>,
+    key: PrivateKey,
+) -> Result<(), TLSError> {
+    RSASigningKey::new(&key).map_err(|()| TLSError::General("invalid private key".into()))?;
+    config.set_single_client_cert(cert_chain, key);
+    Ok(())
+}
+
 fn to_vec(side: Side, params: &TransportParameters) -> Vec<u8> {
     let mut bytes = Vec::new();
     params.write(side, &mut bytes);
     bytes
 }
 
-/// Value used in ACKs we transmit
-pub const ACK_DELAY_EXPONENT: u8 = 3;
 /// Magic value used to indicate 0-RTT support in NewSessionTicket
 //pub const TLS_MAX_EARLY_DATA: u32 = 0xffff_ffff;
 
-pub fn reset_token_for(key: &[u8], id: &ConnectionId) -> [u8; RESET_TOKEN_SIZE] {
-    let mut mac = Blake2b::new_keyed(key, RESET_TOKEN_SIZE);
-    mac.process(id);
-    // TODO: Server ID??
+/// Secret from which the stateless reset tokens of the connection IDs an endpoint issues are derived
+///
+/// Tokens depend only on the key and the connection ID, so an endpoint that restarts with the same key can reset
+/// connections it no longer has any state for.
+pub struct StatelessResetKey(SigningKey);
+
+impl StatelessResetKey {
+    /// Derive a key from `secret`, which should be at least 32 random bytes, e.g. `ListenKeys::reset`
+    pub fn new(secret: &[u8]) -> Self {
+        let salt = SigningKey::new(&digest::SHA256, STATELESS_RESET_SALT);
+        StatelessResetKey(hkdf::extract(&salt, secret))
+    }
+}
+
+const STATELESS_RESET_SALT: &[u8] = b"QUIC stateless reset";
+
+/// Compute the token that authenticates stateless resets of the connection identified by `id`
+pub fn stateless_reset_token(key: &StatelessResetKey, id: &ConnectionId) -> [u8; RESET_TOKEN_SIZE] {
+    let signature = hmac::sign(&key.0, id);
     let mut result = [0; RESET_TOKEN_SIZE];
-    mac.variable_result(&mut result).unwrap();
+    result.copy_from_slice(&signature.as_ref()[..RESET_TOKEN_SIZE]);
     result
 }
 
+/// Length of the identifier by which a client names a session ticket to the server
+pub const SESSION_TICKET_ID_SIZE: usize = 16;
+
+/// A secret agreed by a client and server at the end of a handshake, from which keys for the 0-RTT packets of a later
+/// connection between them are derived
+///
+/// This is quinn's own scheme rather than the TLS 1.3 session resumption QUIC specifies: the secret is exported from
+/// the finished handshake instead of carried in a NewSessionTicket, the server remembers it rather than sealing it into
+/// the ticket, and the two sides find each other's tickets through transport parameters of their own. Only quinn
+/// endpoints can resume connections this way.
+#[derive(Debug, Clone)]
+pub struct SessionTicket {
+    /// Names the ticket to the server, which remembers its secret
+    pub id: [u8; SESSION_TICKET_ID_SIZE],
+    pub secret: Vec<u8>,
+    /// Bytes of stream data the client may send in 0-RTT packets
+    pub max_early_data: u32,
+    /// The server's transport parameters, which limit what the client may send before the new handshake completes
+    pub params: TransportParameters,
+}
+
+impl SessionTicket {
+    pub fn new(tls: &TlsSession, max_early_data: u32, params: TransportParameters) -> Self {
+        const ID_LABEL: &[u8] = b"EXPORTER-QUIC session ticket";
+        const SECRET_LABEL: &[u8] = b"EXPORTER-QUIC 0rtt";
+
+        let mut id = [0; SESSION_TICKET_ID_SIZE];
+        tls.export_keying_material(&mut id, ID_LABEL, None).unwrap();
+        let mut secret = vec![0; digest::SHA256.output_len];
+        tls.export_keying_material(&mut secret, SECRET_LABEL, None)
+            .unwrap();
+        Self {
+            id,
+            secret,
+            max_early_data,
+            params,
+        }
+    }
+
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut buf = Vec::new();
+        buf.put_slice(&self.id);
+        buf.put_u8(self.secret.len() as u8);
+        buf.put_slice(&self.secret);
+        buf.put_u32_be(self.max_early_data);
+        self.params.write(Side::Server, &mut buf);
+        buf.into()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ConnectError> {
+        if data.len() < SESSION_TICKET_ID_SIZE + 1 {
+            return Err(ConnectError::MalformedSession);
+        }
+        let mut id = [0; SESSION_TICKET_ID_SIZE];
+        id.copy_from_slice(&data[..SESSION_TICKET_ID_SIZE]);
+        let secret_len = data[SESSION_TICKET_ID_SIZE] as usize;
+        let rest = &data[SESSION_TICKET_ID_SIZE + 1..];
+        if rest.len() < secret_len + 4 {
+            return Err(ConnectError::MalformedSession);
+        }
+        let max_early_data = BigEndian::read_u32(&rest[secret_len..]);
+        let params =
+            TransportParameters::read(Side::Client, &mut io::Cursor::new(&rest[secret_len + 4..]))
+                .map_err(|_| ConnectError::MalformedSession)?;
+        Ok(Self {
+            id,
+            secret: rest[..secret_len].into(),
+            max_early_data,
+            params,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub enum Crypto {
-    // ZeroRtt(ZeroRttCrypto),
+    ZeroRtt(CryptoContext),
     Handshake(CryptoContext),
     OneRtt(CryptoContext),
 }
 
 impl Crypto {
-    /*
-    pub fn new_0rtt(tls: &TlsSide) -> Self {
-        let suite = tls.get_negotiated_ciphersuite().unwrap();
-        let tls_cipher = tls.current_cipher().unwrap();
-        let digest = tls_cipher.handshake_digest().unwrap();
-        let cipher = Cipher::from_nid(tls_cipher.cipher_nid().unwrap()).unwrap();
-
-        const LABEL: &str = "EXPORTER-QUIC 0rtt";
-
-        let mut secret = vec![0; digest.size()];
-        tls.export_keying_material_early(&mut secret, &LABEL, b"")
-            .unwrap();
-        Crypto::ZeroRtt(ZeroRttCrypto {
-            state: CryptoState::new(digest, cipher, secret.into()),
+    /// Keys for 0-RTT packets, which only ever flow from client to server
+    pub fn new_0rtt(ticket: &SessionTicket, version: Version) -> Self {
+        let (digest, cipher) = (&digest::SHA256, &aead::AES_128_GCM);
+        let state = CryptoState::new(digest, cipher, version, ticket.secret.clone());
+        Crypto::ZeroRtt(CryptoContext {
+            local: state.clone(),
+            remote: state,
+            digest,
             cipher,
+            version,
         })
     }
-    */
 
-    pub fn new_handshake(id: &ConnectionId, side: Side) -> Self {
+    /// Keys for Initial and Handshake packets, derived from the client's first destination connection ID
+    pub fn new_handshake(id: &ConnectionId, side: Side, version: Version) -> Self {
         let (digest, cipher) = (&digest::SHA256, &aead::AES_128_GCM);
         let (local_label, remote_label) = if side == Side::Client {
             (b"client hs", b"server hs")
         } else {
             (b"server hs", b"client hs")
         };
-        let hs_secret = handshake_secret(id);
+        let hs_secret = handshake_secret(id, version);
         let local = CryptoState::new(
             digest,
             cipher,
-            expanded_handshake_secret(&hs_secret, local_label),
+            version,
+            expanded_handshake_secret(&hs_secret, version, local_label),
         );
         let remote = CryptoState::new(
             digest,
             cipher,
-            expanded_handshake_secret(&hs_secret, remote_label),
+            version,
+            expanded_handshake_secret(&hs_secret, version, remote_label),
         );
         Crypto::Handshake(CryptoContext {
             local,
             remote,
             digest: digest,
             cipher: cipher,
+            version,
         })
     }
 
-    pub fn new_1rtt(tls: &TlsSession, side: Side) -> Self {
+    pub fn new_1rtt(tls: &TlsSession, side: Side, version: Version) -> Self {
         let suite = tls.get_negotiated_ciphersuite().unwrap();
         let (cipher, digest) = (suite.get_aead_alg(), suite.get_hash());
 
@@ -181,32 +313,42 @@ impl Crypto {
         let mut local_secret = vec![0; digest.output_len];
         tls.export_keying_material(&mut local_secret, local_label, None)
             .unwrap();
-        let local = CryptoState::new(digest, cipher, local_secret);
+        let local = CryptoState::new(digest, cipher, version, local_secret);
 
         let mut remote_secret = vec![0; digest.output_len];
         tls.export_keying_material(&mut remote_secret, remote_label, None)
             .unwrap();
-        let remote = CryptoState::new(digest, cipher, remote_secret);
+        let remote = CryptoState::new(digest, cipher, version, remote_secret);
         Crypto::OneRtt(CryptoContext {
             local,
             remote,
             digest,
             cipher,
+            version,
         })
     }
 
-    /*
-    pub fn is_0rtt(&self) -> bool {
+    /// The version of QUIC whose packets these keys protect
+    pub fn version(&self) -> Version {
         match *self {
-            Crypto::ZeroRtt(_) => true,
-            _ => false,
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => crypto.version,
         }
     }
-    */
 
-    pub fn is_handshake(&self) -> bool {
+    /// Length of the AEAD tag appended to each packet these keys protect
+    pub fn tag_len(&self) -> usize {
         match *self {
-            Crypto::Handshake(_) => true,
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => crypto.cipher.tag_len(),
+        }
+    }
+
+    pub fn is_0rtt(&self) -> bool {
+        match *self {
+            Crypto::ZeroRtt(_) => true,
             _ => false,
         }
     }
@@ -235,10 +377,9 @@ impl Crypto {
     pub fn encrypt(&self, packet: u64, buf: &mut Vec<u8>, header_len: usize) {
         // FIXME: retain crypter
         let (cipher, state) = match *self {
-            //Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
-            Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
-                (crypto.cipher, &crypto.local)
-            }
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => (crypto.cipher, &crypto.local),
         };
 
         let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
@@ -253,15 +394,14 @@ impl Crypto {
     }
 
     pub fn decrypt(&self, packet: u64, header: &[u8], payload: &mut BytesMut) -> Result<(), ()> {
-        if payload.len() < AEAD_TAG_SIZE {
+        if payload.len() < self.tag_len() {
             return Err(());
         }
 
         let (cipher, state) = match *self {
-            //Crypto::ZeroRtt(ref crypto) => (crypto.cipher, &crypto.state),
-            Crypto::Handshake(ref crypto) | Crypto::OneRtt(ref crypto) => {
-                (crypto.cipher, &crypto.remote)
-            }
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => (crypto.cipher, &crypto.remote),
         };
 
         let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
@@ -275,43 +415,90 @@ impl Crypto {
         Ok(())
     }
 
+    /// Key for protecting the headers of packets we send
+    pub fn local_header_key(&self) -> &HeaderKey {
+        match *self {
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => &crypto.local.header_key,
+        }
+    }
+
+    /// Key for removing protection from the headers of packets we receive
+    pub fn remote_header_key(&self) -> &HeaderKey {
+        match *self {
+            Crypto::ZeroRtt(ref crypto)
+            | Crypto::Handshake(ref crypto)
+            | Crypto::OneRtt(ref crypto) => &crypto.remote.header_key,
+        }
+    }
+
     pub fn update(&self, side: Side) -> Crypto {
         match *self {
             Crypto::OneRtt(ref crypto) => Crypto::OneRtt(CryptoContext {
-                local: crypto.local.update(crypto.digest, crypto.cipher, side),
-                remote: crypto.local.update(crypto.digest, crypto.cipher, !side),
+                local: crypto
+                    .local
+                    .update(crypto.digest, crypto.cipher, crypto.version, side),
+                remote: crypto
+                    .remote
+                    .update(crypto.digest, crypto.cipher, crypto.version, !side),
                 digest: crypto.digest,
                 cipher: crypto.cipher,
+                version: crypto.version,
             }),
             _ => unreachable!(),
         }
     }
 }
 
-/*
+/// Issues and verifies the address validation tokens carried by Retry packets and NEW_TOKEN frames
 pub struct CookieFactory {
     mac_key: [u8; 64],
 }
 
 const COOKIE_MAC_BYTES: usize = 64;
 
+/// First byte of tokens from NEW_TOKEN frames, which can't be mistaken for the length prefixing a Retry token
+const NEW_TOKEN_TAG: u8 = 0xff;
+
 impl CookieFactory {
-    fn new(mac_key: [u8; 64]) -> Self {
+    pub fn new(mac_key: [u8; 64]) -> Self {
         Self { mac_key }
     }
 
-    fn generate(&self, conn: &ConnectionInfo, out: &mut [u8]) -> usize {
-        let mac = self.generate_mac(conn);
-        out[0..COOKIE_MAC_BYTES].copy_from_slice(&mac);
-        COOKIE_MAC_BYTES
+    /// Generate a token proving that `remote` received a Retry at time `now` (μs) in response to an Initial sent to
+    /// `orig_dst_cid`
+    pub fn generate(
+        &self,
+        remote: &SocketAddrV6,
+        orig_dst_cid: &ConnectionId,
+        now: u64,
+    ) -> Vec<u8> {
+        let mut token = Vec::with_capacity(1 + orig_dst_cid.len() + 8 + COOKIE_MAC_BYTES);
+        token.push(orig_dst_cid.len() as u8);
+        token.extend_from_slice(orig_dst_cid);
+        token.put_u64_be(now);
+        token.extend_from_slice(&self.generate_mac(remote, orig_dst_cid, now));
+        token
     }
 
-    fn generate_mac(&self, conn: &ConnectionInfo) -> [u8; COOKIE_MAC_BYTES] {
+    fn generate_mac(
+        &self,
+        remote: &SocketAddrV6,
+        orig_dst_cid: &ConnectionId,
+        issued: u64,
+    ) -> [u8; COOKIE_MAC_BYTES] {
         let mut mac = Blake2b::new_keyed(&self.mac_key, COOKIE_MAC_BYTES);
-        mac.process(&conn.remote.ip().octets());
+        mac.process(&remote.ip().octets());
         {
             let mut buf = [0; 2];
-            BigEndian::write_u16(&mut buf, conn.remote.port());
+            BigEndian::write_u16(&mut buf, remote.port());
+            mac.process(&buf);
+        }
+        mac.process(orig_dst_cid);
+        {
+            let mut buf = [0; 8];
+            BigEndian::write_u64(&mut buf, issued);
             mac.process(&buf);
         }
         let mut result = [0; COOKIE_MAC_BYTES];
@@ -319,15 +506,126 @@ impl CookieFactory {
         result
     }
 
-    fn verify(&self, conn: &ConnectionInfo, cookie_data: &[u8]) -> bool {
-        let expected = self.generate_mac(conn);
-        if !constant_time_eq(cookie_data, &expected) {
+    /// Check that `token` was issued to `remote` no more than `lifetime` μs before `now`, returning the original
+    /// destination connection ID it carries
+    pub fn verify(
+        &self,
+        remote: &SocketAddrV6,
+        token: &[u8],
+        now: u64,
+        lifetime: u64,
+    ) -> Option<ConnectionId> {
+        let (&len, rest) = token.split_first()?;
+        let len = len as usize;
+        if len > MAX_CID_SIZE || rest.len() != len + 8 + COOKIE_MAC_BYTES {
+            return None;
+        }
+        let mut id = [0; MAX_CID_SIZE];
+        id[..len].copy_from_slice(&rest[..len]);
+        let orig_dst_cid = ConnectionId::new(id, len);
+        let issued = BigEndian::read_u64(&rest[len..len + 8]);
+        let expected = self.generate_mac(remote, &orig_dst_cid, issued);
+        if !constant_time_eq(&rest[len + 8..], &expected) {
+            return None;
+        }
+        // Tokens from the future were minted before a clock reset, so their age is unknown
+        if issued > now || now - issued > lifetime {
+            return None;
+        }
+        Some(orig_dst_cid)
+    }
+
+    /// Generate a token for a NEW_TOKEN frame, proving that a client at `remote` completed a handshake at time `now`
+    /// (μs)
+    ///
+    /// Clients keep these for use on later connections, possibly from a different port, so only the IP address is
+    /// bound.
+    pub fn generate_new_token(&self, remote: &SocketAddrV6, now: u64) -> Vec<u8> {
+        let mut token = Vec::with_capacity(1 + 8 + COOKIE_MAC_BYTES);
+        token.push(NEW_TOKEN_TAG);
+        token.put_u64_be(now);
+        token.extend_from_slice(&self.generate_new_token_mac(remote, now));
+        token
+    }
+
+    fn generate_new_token_mac(&self, remote: &SocketAddrV6, issued: u64) -> [u8; COOKIE_MAC_BYTES] {
+        let mut mac = Blake2b::new_keyed(&self.mac_key, COOKIE_MAC_BYTES);
+        // Distinguishes the input from that of a Retry token's MAC
+        mac.process(&[NEW_TOKEN_TAG]);
+        mac.process(&remote.ip().octets());
+        {
+            let mut buf = [0; 8];
+            BigEndian::write_u64(&mut buf, issued);
+            mac.process(&buf);
+        }
+        let mut result = [0; COOKIE_MAC_BYTES];
+        mac.variable_result(&mut result).unwrap();
+        result
+    }
+
+    /// Whether `token` is in the format of those from NEW_TOKEN frames, rather than from Retry packets
+    pub fn is_new_token(token: &[u8]) -> bool {
+        token.first() == Some(&NEW_TOKEN_TAG)
+    }
+
+    /// Check that `token`, from a NEW_TOKEN frame, was issued to the IP address of `remote` no more than `lifetime` μs
+    /// before `now`
+    pub fn verify_new_token(
+        &self,
+        remote: &SocketAddrV6,
+        token: &[u8],
+        now: u64,
+        lifetime: u64,
+    ) -> bool {
+        if token.len() != 1 + 8 + COOKIE_MAC_BYTES || !Self::is_new_token(token) {
             return false;
         }
-        true
+        let issued = BigEndian::read_u64(&token[1..9]);
+        let expected = self.generate_new_token_mac(remote, issued);
+        if !constant_time_eq(&token[9..], &expected) {
+            return false;
+        }
+        issued <= now && now - issued <= lifetime
     }
 }
-*/
+
+/// Compute the tag that authenticates `packet`, a Retry sent in response to an Initial addressed to `orig_dst_cid`
+///
+/// The key is public, so this only protects against corruption and off-path injection, as in RFC 9001 §5.8.
+pub fn retry_integrity_tag(orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; AEAD_TAG_SIZE] {
+    let mut pseudo_packet = Vec::with_capacity(1 + orig_dst_cid.len() + packet.len());
+    pseudo_packet.push(orig_dst_cid.len() as u8);
+    pseudo_packet.extend_from_slice(orig_dst_cid);
+    pseudo_packet.extend_from_slice(packet);
+    let key = aead::SealingKey::new(&aead::AES_128_GCM, &RETRY_INTEGRITY_KEY).unwrap();
+    // Sealing an empty plaintext leaves only the tag
+    let mut tag = [0; AEAD_TAG_SIZE];
+    aead::seal_in_place(
+        &key,
+        &RETRY_INTEGRITY_NONCE,
+        &pseudo_packet,
+        &mut tag,
+        AEAD_TAG_SIZE,
+    ).unwrap();
+    tag
+}
+
+/// Whether `packet`, a Retry including its integrity tag, was sent in response to an Initial addressed to
+/// `orig_dst_cid` and arrived intact
+pub fn is_valid_retry(orig_dst_cid: &ConnectionId, packet: &[u8]) -> bool {
+    if packet.len() < AEAD_TAG_SIZE {
+        return false;
+    }
+    let (data, tag) = packet.split_at(packet.len() - AEAD_TAG_SIZE);
+    constant_time_eq(&retry_integrity_tag(orig_dst_cid, data), tag)
+}
+
+const RETRY_INTEGRITY_KEY: [u8; 16] = [
+    0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68, 0xc8, 0x4e,
+];
+const RETRY_INTEGRITY_NONCE: [u8; 12] = [
+    0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+];
 
 #[derive(Clone)]
 pub struct ConnectionInfo {
@@ -335,42 +633,66 @@ pub struct ConnectionInfo {
     pub(crate) remote: SocketAddrV6,
 }
 
-const HANDSHAKE_SALT: [u8; 20] = [
-    0x9c, 0x10, 0x8f, 0x98, 0x52, 0x0a, 0x5c, 0x5c, 0x32, 0x96, 0x8e, 0x95, 0x0e, 0x8a, 0x2c, 0x5f,
-    0xe0, 0x6d, 0x6c, 0x38,
-];
+/// Salt from which the keys protecting Initial packets of `version` are extracted
+///
+/// These are the salts of RFC 9001 and RFC 9369, but since `qhkdf_expand` uses the draft's labels, the keys derived
+/// from them differ from those of the RFCs.
+fn handshake_salt(version: Version) -> &'static [u8; 20] {
+    const V1: [u8; 20] = [
+        0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c,
+        0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+    ];
+    const V2: [u8; 20] = [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d,
+        0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+    ];
+    match version {
+        Version::V1 => &V1,
+        Version::V2 => &V2,
+    }
+}
 
 #[derive(Clone)]
 pub struct CryptoState {
     secret: Vec<u8>,
     key: Vec<u8>,
     iv: Vec<u8>,
+    header_key: HeaderKey,
 }
 
 impl CryptoState {
     fn new(
         digest: &'static digest::Algorithm,
         cipher: &'static aead::Algorithm,
+        version: Version,
         secret: Vec<u8>,
     ) -> Self {
         let secret_key = SigningKey::new(digest, &secret);
         let mut key = vec![0; cipher.key_len()];
-        qhkdf_expand(&secret_key, b"key", &mut key);
+        qhkdf_expand(&secret_key, version, b"key", &mut key);
         let mut iv = vec![0; cipher.nonce_len()];
-        qhkdf_expand(&secret_key, b"iv", &mut iv);
-        Self { secret, key, iv }
+        qhkdf_expand(&secret_key, version, b"iv", &mut iv);
+        let header_key = HeaderKey::new(cipher, version, &secret_key);
+        Self {
+            secret,
+            key,
+            iv,
+            header_key,
+        }
     }
 
     fn update(
         &self,
         digest: &'static digest::Algorithm,
         cipher: &'static aead::Algorithm,
+        version: Version,
         side: Side,
     ) -> CryptoState {
         let secret_key = SigningKey::new(digest, &self.secret);
         let mut new_secret = vec![0; digest.output_len];
         qhkdf_expand(
             &secret_key,
+            version,
             if side == Side::Client {
                 b"client 1rtt"
             } else {
@@ -378,22 +700,64 @@ impl CryptoState {
             },
             &mut new_secret,
         );
-        Self::new(digest, cipher, new_secret)
+        // Header protection keys are not affected by key updates
+        Self {
+            header_key: self.header_key.clone(),
+            ..Self::new(digest, cipher, version, new_secret)
+        }
     }
 }
 
+/// Key used to mask the packet number and the low bits of the first byte of a packet header
 #[derive(Clone)]
-pub struct ZeroRttCrypto {
-    state: CryptoState,
+pub struct HeaderKey {
     cipher: &'static aead::Algorithm,
+    key: Vec<u8>,
 }
 
+impl HeaderKey {
+    fn new(cipher: &'static aead::Algorithm, version: Version, secret_key: &SigningKey) -> Self {
+        let mut key = vec![0; cipher.key_len()];
+        qhkdf_expand(secret_key, version, b"pn", &mut key);
+        Self { cipher, key }
+    }
+
+    /// Number of ciphertext bytes used to compute a mask
+    pub fn sample_size(&self) -> usize {
+        self.cipher.nonce_len()
+    }
+
+    /// Compute the mask for a packet from a sample of its ciphertext
+    ///
+    /// The mask is the keystream produced by the AEAD when sealing zeroes with the sample as its nonce.
+    pub fn mask(&self, sample: &[u8]) -> [u8; HEADER_MASK_SIZE] {
+        debug_assert_eq!(sample.len(), self.sample_size());
+        let tag_len = self.cipher.tag_len();
+        let key = aead::SealingKey::new(self.cipher, &self.key).unwrap();
+        let mut buf = [0; HEADER_MASK_SIZE + aead::MAX_TAG_LEN];
+        aead::seal_in_place(
+            &key,
+            sample,
+            &[],
+            &mut buf[..HEADER_MASK_SIZE + tag_len],
+            tag_len,
+        ).unwrap();
+        let mut mask = [0; HEADER_MASK_SIZE];
+        mask.copy_from_slice(&buf[..HEADER_MASK_SIZE]);
+        mask
+    }
+}
+
+/// One byte for the first byte of the header, plus up to four packet number bytes
+pub const HEADER_MASK_SIZE: usize = 5;
+
 #[derive(Clone)]
 pub struct CryptoContext {
     local: CryptoState,
     remote: CryptoState,
     digest: &'static digest::Algorithm,
     cipher: &'static aead::Algorithm,
+    version: Version,
 }
 
 #[derive(Debug, Fail)]
@@ -402,6 +766,8 @@ pub enum ConnectError {
     MalformedSession,
     #[fail(display = "TLS error: {}", _0)]
     Tls(TLSError),
+    #[fail(display = "couldn't choose a connection ID: {}", _0)]
+    ConnectionId(ConnectionIdError),
 }
 
 impl From<TLSError> for ConnectError {
@@ -410,23 +776,36 @@ impl From<TLSError> for ConnectError {
     }
 }
 
-pub fn expanded_handshake_secret(prk: &SigningKey, label: &[u8]) -> Vec<u8> {
+impl From<ConnectionIdError> for ConnectError {
+    fn from(x: ConnectionIdError) -> Self {
+        ConnectError::ConnectionId(x)
+    }
+}
+
+pub fn expanded_handshake_secret(prk: &SigningKey, version: Version, label: &[u8]) -> Vec<u8> {
     let mut out = vec![0u8; digest::SHA256.output_len];
-    qhkdf_expand(prk, label, &mut out);
+    qhkdf_expand(prk, version, label, &mut out);
     out
 }
 
-pub fn qhkdf_expand(key: &SigningKey, label: &[u8], out: &mut [u8]) {
-    let mut info = Vec::with_capacity(2 + 1 + 5 + out.len());
+/// Expand `key` into `out` with the draft's QHKDF-Expand, rather than TLS 1.3's HKDF-Expand-Label
+///
+/// Labels are distinguished per version so that keys are never shared between them.
+pub fn qhkdf_expand(key: &SigningKey, version: Version, label: &[u8], out: &mut [u8]) {
+    let prefix: &[u8] = match version {
+        Version::V1 => b"QUIC ",
+        Version::V2 => b"QUICv2 ",
+    };
+    let mut info = Vec::with_capacity(2 + 1 + prefix.len() + label.len());
     info.put_u16_be(out.len() as u16);
-    info.put_u8(5 + (label.len() as u8));
-    info.extend_from_slice(b"QUIC ");
+    info.put_u8((prefix.len() + label.len()) as u8);
+    info.extend_from_slice(prefix);
     info.extend_from_slice(&label);
     hkdf::expand(key, &info, out);
 }
 
-fn handshake_secret(conn_id: &ConnectionId) -> SigningKey {
-    let key = SigningKey::new(&digest::SHA256, &HANDSHAKE_SALT);
+fn handshake_secret(conn_id: &ConnectionId, version: Version) -> SigningKey {
+    let key = SigningKey::new(&digest::SHA256, handshake_salt(version));
     let mut buf = Vec::with_capacity(8);
     buf.put_slice(conn_id);
     hkdf::extract(&key, &buf)
@@ -438,7 +817,8 @@ const ALPN_PROTOCOL: &str = "hq-11";
 mod test {
     use super::*;
     use packet::PacketNumber;
-    use rand;
+    use rand::{self, RngCore};
+    use std::net::Ipv6Addr;
     use MAX_CID_SIZE;
 
     #[test]
@@ -453,11 +833,33 @@ mod test {
         assert_eq!(PacketNumber::U32(0xa0bd197c).expand(0xa0bd197a), 0xa0bd197c);
     }
 
+    #[test]
+    fn stateless_reset_tokens() {
+        let mut rng = rand::thread_rng();
+        let mut secret = [0; 64];
+        rng.fill_bytes(&mut secret);
+        let key = StatelessResetKey::new(&secret);
+        let id = ConnectionId::random(&mut rng, MAX_CID_SIZE as u8);
+        let token = stateless_reset_token(&key, &id);
+        // Deterministic, so that a restarted endpoint arrives at the same token
+        assert_eq!(
+            stateless_reset_token(&StatelessResetKey::new(&secret), &id),
+            token
+        );
+        let other = ConnectionId::random(&mut rng, MAX_CID_SIZE as u8);
+        assert_ne!(stateless_reset_token(&key, &other), token);
+        secret[0] ^= 1;
+        assert_ne!(
+            stateless_reset_token(&StatelessResetKey::new(&secret), &id),
+            token
+        );
+    }
+
     #[test]
     fn handshake_crypto_roundtrip() {
         let conn = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
-        let client = Crypto::new_handshake(&conn, Side::Client);
-        let server = Crypto::new_handshake(&conn, Side::Server);
+        let client = Crypto::new_handshake(&conn, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&conn, Side::Server, Version::V1);
 
         let mut buf = b"headerpayload".to_vec();
         client.encrypt(0, &mut buf, 6);
@@ -469,7 +871,44 @@ mod test {
     }
 
     #[test]
-    fn key_derivation() {
+    fn session_ticket_coding() {
+        let ticket = SessionTicket {
+            id: [0xab; SESSION_TICKET_ID_SIZE],
+            secret: vec![0xcd; digest::SHA256.output_len],
+            max_early_data: 16 * 1024,
+            params: TransportParameters {
+                initial_max_streams_uni: 16,
+                issues_tickets: true,
+                ..TransportParameters::default()
+            },
+        };
+        let encoded = ticket.encode();
+        let decoded = SessionTicket::decode(&encoded).unwrap();
+        assert_eq!(decoded.id, ticket.id);
+        assert_eq!(decoded.secret, ticket.secret);
+        assert_eq!(decoded.max_early_data, ticket.max_early_data);
+        assert_eq!(decoded.params, ticket.params);
+        assert_matches!(
+            SessionTicket::decode(&encoded[..SESSION_TICKET_ID_SIZE + 8]),
+            Err(ConnectError::MalformedSession)
+        );
+
+        // Both sides derive the same keys from the ticket
+        let client = Crypto::new_0rtt(&ticket, Version::V1);
+        let server = Crypto::new_0rtt(&decoded, Version::V1);
+        let mut buf = b"headerpayload".to_vec();
+        client.encrypt(0, &mut buf, 6);
+        let mut header = BytesMut::from(buf);
+        let mut payload = header.split_off(6);
+        server.decrypt(0, &header, &mut payload).unwrap();
+        assert_eq!(&*payload, b"payload");
+    }
+
+    fn check_derivation(
+        version: Version,
+        client: (&[u8], &[u8], &[u8]),
+        server: (&[u8], &[u8], &[u8]),
+    ) {
         let id = ConnectionId(
             [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]
                 .iter()
@@ -478,50 +917,199 @@ mod test {
         );
         let digest = &digest::SHA256;
         let cipher = &aead::AES_128_GCM;
-        let hs_secret = handshake_secret(&id);
-        let client_secret = expanded_handshake_secret(&hs_secret, b"client hs");
-        assert_eq!(
-            &client_secret[..],
-            [
-                0x83, 0x55, 0xf2, 0x1a, 0x3d, 0x8f, 0x83, 0xec, 0xb3, 0xd0, 0xf9, 0x71, 0x08, 0xd3,
-                0xf9, 0x5e, 0x0f, 0x65, 0xb4, 0xd8, 0xae, 0x88, 0xa0, 0x61, 0x1e, 0xe4, 0x9d, 0xb0,
-                0xb5, 0x23, 0x59, 0x1d
-            ]
-        );
-        let client_state = CryptoState::new(digest, cipher, client_secret);
-        assert_eq!(
-            &client_state.key[..],
-            [
-                0x3a, 0xd0, 0x54, 0x2c, 0x4a, 0x85, 0x84, 0x74, 0x00, 0x63, 0x04, 0x9e, 0x3b, 0x3c,
-                0xaa, 0xb2
-            ]
-        );
-        assert_eq!(
-            &client_state.iv[..],
-            [0xd1, 0xfd, 0x26, 0x05, 0x42, 0x75, 0x3a, 0xba, 0x38, 0x58, 0x9b, 0xad]
+        let hs_secret = handshake_secret(&id, version);
+        for &(label, (secret, key, iv)) in
+            &[(&b"client hs"[..], client), (&b"server hs"[..], server)]
+        {
+            let expanded = expanded_handshake_secret(&hs_secret, version, label);
+            assert_eq!(&expanded[..], secret);
+            let state = CryptoState::new(digest, cipher, version, expanded);
+            assert_eq!(&state.key[..], key);
+            assert_eq!(&state.iv[..], iv);
+        }
+    }
+
+    // Neither version is published, so there are no official vectors to check against. These were computed with a
+    // separate HMAC-SHA256 implementation of the derivation, and deliberately differ from RFC 9001's, e.g. its client
+    // Initial secret c00cf151...
+    #[test]
+    fn key_derivation() {
+        check_derivation(
+            Version::V1,
+            (
+                &[
+                    0x7b, 0x67, 0xfd, 0x63, 0x58, 0xd8, 0xdb, 0x3a, 0x2c, 0xc5, 0x34, 0xe8, 0x09,
+                    0x20, 0x2b, 0x22, 0x1e, 0x49, 0xc7, 0xb5, 0xce, 0x45, 0xbd, 0xe7, 0x3b, 0x6f,
+                    0x49, 0xc1, 0x59, 0x62, 0x67, 0x93,
+                ],
+                &[
+                    0x17, 0x00, 0x5b, 0xf1, 0x11, 0x01, 0x5e, 0xc2, 0xaa, 0x15, 0x5d, 0x70, 0x06,
+                    0x48, 0x04, 0xf9,
+                ],
+                &[
+                    0xdb, 0xa1, 0x5d, 0x77, 0x74, 0x24, 0xe4, 0x73, 0xf5, 0x00, 0x78, 0xed,
+                ],
+            ),
+            (
+                &[
+                    0x2e, 0x79, 0xed, 0xd3, 0x5f, 0x78, 0x27, 0x81, 0x77, 0xe2, 0x96, 0xe8, 0x5e,
+                    0xcb, 0x42, 0xf2, 0xa4, 0xe3, 0x5e, 0xb9, 0x10, 0x70, 0x76, 0x75, 0x5d, 0xb6,
+                    0x3e, 0x46, 0x76, 0xbb, 0x47, 0x2f,
+                ],
+                &[
+                    0x28, 0xc3, 0x5b, 0xa0, 0x5f, 0xcb, 0x3b, 0x2c, 0xcb, 0xef, 0x96, 0xa9, 0xb4,
+                    0x7c, 0x58, 0x2d,
+                ],
+                &[
+                    0x24, 0xc2, 0xfb, 0x13, 0x21, 0xc8, 0x3b, 0xfe, 0xac, 0x96, 0x42, 0xbd,
+                ],
+            ),
         );
+    }
 
-        let server_secret = expanded_handshake_secret(&hs_secret, b"server hs");
-        assert_eq!(
-            &server_secret[..],
-            [
-                0xf8, 0x0e, 0x57, 0x71, 0x48, 0x4b, 0x21, 0xcd, 0xeb, 0xb5, 0xaf, 0xe0, 0xa2, 0x56,
-                0xa3, 0x17, 0x41, 0xef, 0xe2, 0xb5, 0xc6, 0xb6, 0x17, 0xba, 0xe1, 0xb2, 0xf1, 0x5a,
-                0x83, 0x04, 0x83, 0xd6
-            ]
+    #[test]
+    fn key_derivation_v2() {
+        // A different salt and labels, so nothing is shared with version 1 for the same connection ID
+        check_derivation(
+            Version::V2,
+            (
+                &[
+                    0x3a, 0xef, 0x5f, 0x45, 0x92, 0xec, 0xae, 0x36, 0x84, 0xa4, 0x71, 0x40, 0x7d,
+                    0x34, 0xb2, 0xa4, 0x8d, 0xe3, 0xa2, 0xac, 0x55, 0x5e, 0xc1, 0x9f, 0x90, 0xfd,
+                    0x4a, 0x13, 0xdf, 0xa4, 0xb1, 0x83,
+                ],
+                &[
+                    0x49, 0xd0, 0xb1, 0x56, 0xc6, 0xdc, 0x87, 0x17, 0x71, 0x50, 0x30, 0x85, 0x06,
+                    0xaa, 0x78, 0xc8,
+                ],
+                &[
+                    0x48, 0x0b, 0x47, 0x88, 0x86, 0xcd, 0x52, 0xe3, 0x23, 0x04, 0x4e, 0x9e,
+                ],
+            ),
+            (
+                &[
+                    0xfc, 0x7c, 0x21, 0xe5, 0x15, 0xb4, 0xf9, 0xa8, 0x4f, 0x37, 0xbf, 0x06, 0x83,
+                    0x7e, 0x72, 0xcb, 0x37, 0x95, 0x45, 0x73, 0xad, 0x6c, 0x66, 0x75, 0x22, 0x6d,
+                    0x08, 0x3f, 0xf7, 0x81, 0x65, 0x65,
+                ],
+                &[
+                    0x3c, 0xc1, 0xaa, 0x2b, 0x72, 0x9b, 0xc4, 0xf2, 0x64, 0x59, 0x3f, 0xc1, 0x3a,
+                    0x19, 0x49, 0x96,
+                ],
+                &[
+                    0x49, 0x65, 0x67, 0xd7, 0xbb, 0x05, 0x7a, 0x8d, 0x2d, 0xf8, 0x38, 0xf2,
+                ],
+            ),
         );
-        let server_state = CryptoState::new(digest, cipher, server_secret);
+
+        // Packets protected under one version can't be read under the other
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V2);
+        let mut buf = b"headerpayload".to_vec();
+        client.encrypt(0, &mut buf, 6);
+        let mut header = BytesMut::from(buf);
+        let payload = header.split_off(6);
+        let v1 = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        assert!(v1.decrypt(0, &header, &mut payload.clone()).is_err());
+        let v2 = Crypto::new_handshake(&id, Side::Server, Version::V2);
+        let mut payload = payload;
+        v2.decrypt(0, &header, &mut payload).unwrap();
+        assert_eq!(&*payload, b"payload");
+    }
+
+    #[test]
+    fn retry_token() {
+        let mut key = [0; 64];
+        rand::thread_rng().fill_bytes(&mut key);
+        let factory = CookieFactory::new(key);
+        let id = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
+        let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4433, 0, 0);
+        const LIFETIME: u64 = 15 * 1000 * 1000;
+        let issued = 1000;
+        let token = factory.generate(&addr, &id, issued);
         assert_eq!(
-            &server_state.key[..],
-            [
-                0xbe, 0xe4, 0xc2, 0x4d, 0x2a, 0xf1, 0x33, 0x80, 0xa9, 0xfa, 0x24, 0xa5, 0xe2, 0xba,
-                0x2c, 0xff
-            ]
+            factory.verify(&addr, &token, issued, LIFETIME),
+            Some(id.clone())
         );
         assert_eq!(
-            &server_state.iv[..],
-            [0x25, 0xb5, 0x8e, 0x24, 0x6d, 0x9e, 0x7d, 0x5f, 0xfe, 0x43, 0x23, 0xfe]
+            factory.verify(&addr, &token, issued + LIFETIME, LIFETIME),
+            Some(id.clone())
         );
+
+        let other = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4434, 0, 0);
+        assert_eq!(factory.verify(&other, &token, issued, LIFETIME), None);
+        let mut tampered = token.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(factory.verify(&addr, &tampered, issued, LIFETIME), None);
+        // Extending a token's life invalidates it
+        let mut tampered = token.clone();
+        tampered[1 + id.len() + 7] ^= 1;
+        assert_eq!(factory.verify(&addr, &tampered, issued, LIFETIME), None);
+        let truncated = &token[..token.len() - 1];
+        assert_eq!(factory.verify(&addr, truncated, issued, LIFETIME), None);
+        assert_eq!(factory.verify(&addr, &[], issued, LIFETIME), None);
+    }
+
+    #[test]
+    fn retry_token_expiry() {
+        let mut key = [0; 64];
+        rand::thread_rng().fill_bytes(&mut key);
+        let factory = CookieFactory::new(key);
+        let id = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
+        let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4433, 0, 0);
+        let token = factory.generate(&addr, &id, 1000);
+        assert_eq!(factory.verify(&addr, &token, 1000 + 501, 500), None);
+        // Issued after the present, e.g. by an endpoint whose clock has since been reset
+        assert_eq!(factory.verify(&addr, &token, 999, 500), None);
+    }
+
+    #[test]
+    fn new_token() {
+        let mut key = [0; 64];
+        rand::thread_rng().fill_bytes(&mut key);
+        let factory = CookieFactory::new(key);
+        let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4433, 0, 0);
+        const LIFETIME: u64 = 24 * 60 * 60 * 1000 * 1000;
+        let issued = 1000;
+        let token = factory.generate_new_token(&addr, issued);
+        assert!(CookieFactory::is_new_token(&token));
+        assert!(factory.verify_new_token(&addr, &token, issued + LIFETIME, LIFETIME));
+        // Later connections from the same host may use another port
+        let other_port = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 4434, 0, 0);
+        assert!(factory.verify_new_token(&other_port, &token, issued, LIFETIME));
+
+        let other_host = SocketAddrV6::new("::2".parse().unwrap(), 4433, 0, 0);
+        assert!(!factory.verify_new_token(&other_host, &token, issued, LIFETIME));
+        assert!(!factory.verify_new_token(&addr, &token, issued + LIFETIME + 1, LIFETIME));
+        assert!(!factory.verify_new_token(&addr, &token, issued - 1, LIFETIME));
+        let mut tampered = token.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!factory.verify_new_token(&addr, &tampered, issued, LIFETIME));
+        // Neither kind of token is accepted as the other
+        let id = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE as u8);
+        let retry = factory.generate(&addr, &id, issued);
+        assert!(!CookieFactory::is_new_token(&retry));
+        assert!(!factory.verify_new_token(&addr, &retry, issued, LIFETIME));
+        assert_eq!(factory.verify(&addr, &token, issued, LIFETIME), None);
+    }
+
+    #[test]
+    fn retry_integrity() {
+        // Example from RFC 9001 Appendix A.4
+        let orig_dst_cid = ConnectionId::from_slice(&hex!("8394c8f03e515708")).unwrap();
+        let packet = hex!("ff000000010008f067a5502a4262b5746f6b656e");
+        let tag = retry_integrity_tag(&orig_dst_cid, &packet);
+        assert_eq!(tag, hex!("04a265ba2eff4d829058fb3f0f2496ba"));
+
+        let mut full = packet.to_vec();
+        full.extend_from_slice(&tag);
+        assert!(is_valid_retry(&orig_dst_cid, &full));
+        // Retries for a different Initial are rejected
+        let other = ConnectionId::from_slice(&hex!("8394c8f03e515709")).unwrap();
+        assert!(!is_valid_retry(&other, &full));
+        full[6] ^= 1;
+        assert!(!is_valid_retry(&orig_dst_cid, &full));
+        assert!(!is_valid_retry(&orig_dst_cid, &tag[..8]));
     }
 }
 
@@ -1,6 +1,6 @@
-use std::collections::VecDeque;
-use std::net::SocketAddrV6;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
 use std::{cmp, io, mem};
 
 use bytes::{Bytes, BytesMut};
@@ -10,18 +10,33 @@ use rand::{distributions, OsRng, Rng, RngCore};
 use slab::Slab;
 use slog::{self, Logger};
 
-use coding::BufMutExt;
+use cid_generator::{
+    ConnectionIdError, ConnectionIdGenerator, ConnectionIdGeneratorFactory,
+    RandomConnectionIdGeneratorFactory,
+};
+use congestion::{CongestionControllerFactory, NewRenoFactory};
 use connection::{
-    state, Connection, ConnectionError, ConnectionHandle, ReadError, State, WriteError,
+    state, AckFrequencyError, AddPathError, Connection, ConnectionError, ConnectionHandle,
+    FlowControlStats, PathId, PathStats, ReadError, SendDatagramError, State, WriteError,
+};
+use crypto::{
+    self, retry_integrity_tag, stateless_reset_token, Certificate, ClientConfig, ConnectError,
+    CookieFactory, Crypto, ServerConfig, SessionTicket, StatelessResetKey, SESSION_TICKET_ID_SIZE,
 };
-use crypto::{self, reset_token_for, ClientConfig, ConnectError, Crypto, ServerConfig};
 use packet::{
-    set_payload_length, types, ConnectionId, Header, HeaderError, Packet, PacketNumber,
-    AEAD_TAG_SIZE,
+    self, set_payload_length, types, ConnectionId, Header, HeaderError, Packet, PacketNumber,
+    PartialDecode, SpaceId,
+};
+use qlog::QlogWriter;
+use ticket_store::{InMemoryTicketStore, SessionTicketStore};
+use token_store::{InMemoryTokenStore, TokenStore};
+use transport_parameters::{
+    custom_params_len, PreferredAddress, MAX_ACK_DELAY_LIMIT, MAX_CUSTOM_PARAMETER,
+    MAX_CUSTOM_PARAMETERS_LEN, MAX_SUPPORTED_VERSIONS, MIN_CUSTOM_PARAMETER,
 };
 use {
-    frame, Directionality, Side, StreamId, TransportError, MAX_CID_SIZE, MIN_INITIAL_SIZE, MIN_MTU,
-    RESET_TOKEN_SIZE, VERSION,
+    frame, Directionality, EcnCodepoint, Side, StreamId, TransportError, Version, MAX_CID_SIZE,
+    MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE,
 };
 
 /// Parameters governing the core QUIC state machine.
@@ -30,10 +45,18 @@ pub struct Config {
     pub max_remote_bi_streams: u16,
     /// Maximum number of peer-initiated  unidirectional streams that may exist at one time.
     pub max_remote_uni_streams: u16,
-    /// Maximum duration of inactivity to accept before timing out the connection (s).
+    /// Maximum duration of inactivity to accept before timing out the connection (μs), or 0 for no limit.
+    ///
+    /// The actual value used is the minimum of this and the peer's own idle timeout, ignoring either if it's 0, but no
+    /// less than three times the probe timeout. Only receiving packets, or sending the first ack-eliciting packet
+    /// since the last was received, defers it. A connection that times out is dropped without notifying the peer.
+    pub max_idle_timeout: u64,
+    /// Period of inactivity after which to send a PING, keeping the connection alive (μs), or `None` to let it idle.
     ///
-    /// Maximum value is 600 seconds. The actual value used is the minimum of this and the peer's own idle timeout.
-    pub idle_timeout: u16,
+    /// A PING is sent whenever nothing ack-eliciting has been sent for this long, which also keeps NAT bindings on the
+    /// path from expiring. Should be shorter than the idle timeout. Unanswered keep-alives don't delay the idle timeout,
+    /// so a connection to a vanished peer still times out.
+    pub keep_alive_interval: Option<u64>,
     /// Maximum number of bytes the peer may transmit on any one stream before becoming blocked.
     ///
     /// This should be set to at least the expected connection latency multiplied by the maximum desired
@@ -46,25 +69,175 @@ pub struct Config {
     /// This should be set to at least the expected connection latency multiplied by the maximum desired
     /// throughput. Larger values can be useful to allow maximum throughput within a stream while another is blocked.
     pub receive_window: u32,
+    /// Maximum number of bytes of stream data written to a connection that may await acknowledgement.
+    ///
+    /// Writes block once this much data is buffered for sending or retransmission, until the peer acknowledges some of
+    /// it, so that an application writing faster than the network can carry the data doesn't buffer it without bound.
+    /// Unlike the peer's receive windows, this is purely a local limit, and is never reported.
+    pub send_buffer_size: u32,
     /// Maximum number of incoming connections to buffer.
     ///
     /// Calling `Endpoint::accept` removes a connection from the buffer, so this does not need to be large.
     pub accept_buffer: u32,
+    /// Maximum size of DATAGRAM frames to accept from the peer, or `None` to disable the extension.
+    ///
+    /// Datagrams can only be exchanged if both peers enable this.
+    pub max_datagram_frame_size: Option<u16>,
+    /// Shortest delay (μs) the peer may ask us to delay acknowledgements by with ACK_FREQUENCY frames, or `None` to
+    /// disable the extension.
+    ///
+    /// Peers that acknowledge less often spend less time sending and processing ACKs, at the cost of slower loss
+    /// recovery. Without this, every ack-eliciting packet is acknowledged immediately.
+    pub min_ack_delay: Option<u32>,
+    /// Versions of QUIC to support, in order of preference.
+    ///
+    /// Connections are initiated with the first, and servers list all of them in Version Negotiation packets. A
+    /// client whose first choice the server doesn't support retries with the first remaining version the server
+    /// lists, so a client only willing to speak its first choice should list nothing else. Must not be empty.
+    pub versions: Vec<Version>,
+    /// Whether to mark outgoing packets with explicit congestion notification codepoints.
+    ///
+    /// Marking stops on any path found not to support ECN.
+    pub ecn: bool,
+    /// Whether to participate in the latency spin bit, letting on-path observers measure RTT.
+    ///
+    /// When disabled, the spin bit is sent as a constant and reveals nothing. Disable for privacy.
+    pub enable_spin_bit: bool,
+    /// Whether to let the peer send packets with the QUIC bit cleared, and send such packets if it does likewise.
+    ///
+    /// Randomizing the bit once fixed by the protocol keeps middleboxes from coming to depend on it (RFC 9287).
+    pub grease_quic_bit: bool,
+    /// Number of packets to send under one set of 1-RTT keys before updating them, or `None` to update them only when
+    /// the peer or the application asks.
+    ///
+    /// Limits how much an attacker can learn from the ciphertext protected by any one key. The default keeps well clear
+    /// of the confidentiality limit of AES-GCM.
+    pub key_update_interval: Option<u64>,
+    /// Whether to require clients to prove ownership of their address before a connection is created.
+    ///
+    /// New clients are sent a Retry carrying a token that they must echo in a second Initial. This costs a round trip,
+    /// but protects against spoofed Initials exhausting server resources, e.g. when under load.
+    pub use_stateless_retry: bool,
+    /// How long a client may take to echo a retry token before it's rejected (μs).
+    ///
+    /// Limits the window in which a token captured by an attacker can be replayed.
+    pub retry_token_lifetime: u64,
+    /// How long a client may use a token from a NEW_TOKEN frame to skip Retry on later connections (μs).
+    ///
+    /// When `use_stateless_retry` is enabled, each client is issued such a token once its handshake completes, so that
+    /// its next connection from the same IP address validates the address without a round trip.
+    pub new_token_lifetime: u64,
+    /// Length of the connection IDs we issue to identify our connections (bytes).
+    ///
+    /// May be 0, in which case the peer omits the connection ID from short header packets and we identify connections
+    /// by remote address alone. This saves space, but precludes connection migration and sharing an address between
+    /// multiple connections to the same peer. May not exceed the longest connection ID QUIC permits.
+    ///
+    /// Load balancers that route by information embedded in connection IDs may require a particular length. Custom
+    /// generators built by `connection_id_generator_factory` must produce IDs of this length.
+    pub local_cid_len: usize,
+    /// Constructs the generator of the connection IDs we issue. Random IDs of `local_cid_len` bytes by default.
+    pub connection_id_generator_factory: Arc<ConnectionIdGeneratorFactory>,
+    /// Decides whether short header packets, by their destination connection ID, are for this endpoint at all.
+    ///
+    /// Consulted before looking up the connection. Packets it rejects are dropped without a stateless reset, so that
+    /// endpoints behind a load balancer routing by information embedded in connection IDs, e.g. a server ID, don't
+    /// reset connections belonging to their neighbours. Unlike a `ConnectionIdGenerator`, this only makes routing
+    /// decisions. Not consulted when `local_cid_len` is 0. Every packet is accepted by default.
+    pub connection_id_filter: Option<Arc<Fn(&[u8]) -> bool + Send + Sync>>,
+    /// Maximum number of version negotiation packets to send per second.
+    ///
+    /// Bounds the traffic an attacker can induce by flooding us with packets of unsupported versions.
+    pub max_version_negotiations: u32,
+    /// Maximum number of session tickets to remember, each of which lets a client resume a connection with 0-RTT.
+    ///
+    /// Data sent by the client in 0-RTT packets is delivered before the handshake completes, saving a round trip. Tickets
+    /// are forgotten once used unless `zero_rtt_anti_replay` is disabled. 0 disables 0-RTT.
+    ///
+    /// Resumption uses a scheme of quinn's own (see `crypto::SessionTicket`), so only quinn clients can take advantage
+    /// of it.
+    pub max_session_tickets: usize,
+    /// Maximum number of bytes of stream data a client may send in 0-RTT packets.
+    ///
+    /// 0-RTT data can be replayed by an attacker, so it should only be used for requests that are safe to repeat. This
+    /// bounds the work a replayed or forged resumption can cause before the handshake completes. Clients that exceed it
+    /// are disconnected with a PROTOCOL_VIOLATION error.
+    pub max_early_data: u32,
+    /// Whether to forget session tickets once they've been used, so that 0-RTT packets can't be replayed into another
+    /// connection.
+    ///
+    /// Disabling this lets a client resume any number of connections from one ticket, e.g. when it opens several in
+    /// parallel, but an attacker who captures a client's 0-RTT packets can then have their data delivered repeatedly.
+    /// Only disable if every 0-RTT request is idempotent.
+    pub zero_rtt_anti_replay: bool,
+    /// Where session tickets received by clients are kept, by server name.
+    ///
+    /// `connect` resumes a connection with 0-RTT using the ticket saved for the server, if any. Tickets are kept in
+    /// memory for as long as the `Config` is by default; share a store between configs, or implement one backed by
+    /// persistent storage, to keep them longer.
+    pub session_ticket_store: Arc<Mutex<SessionTicketStore>>,
+    /// Where address validation tokens received by clients are kept, by server name.
+    ///
+    /// `connect` presents the token saved for the server, if any, so that a server using stateless retry can skip it.
+    /// Tokens are kept in memory for as long as the `Config` is by default.
+    pub token_store: Arc<Mutex<TokenStore>>,
+    /// Whether to probe for a path MTU larger than the minimum every QUIC path must support.
+    ///
+    /// Probes are PING frames padded to candidate sizes. Disable where oversized packets are mishandled, e.g. silently
+    /// fragmented.
+    pub mtu_discovery: bool,
+    /// Largest UDP payload size to probe for. The default suits Ethernet links under IPv6.
+    pub max_mtu: u16,
+    /// IPv4 address clients should migrate to once the handshake completes, if any.
+    ///
+    /// Lets a server reached through a shared address, e.g. anycast, move its connections to one that is its own. The
+    /// address is advertised along with a dedicated connection ID; packets sent to it must reach this endpoint.
+    pub preferred_address_v4: Option<SocketAddrV4>,
+    /// IPv6 address clients should migrate to once the handshake completes, if any.
+    pub preferred_address_v6: Option<SocketAddrV6>,
+    /// Whether to migrate to the preferred address a server advertises, once it's been validated.
+    pub use_preferred_address: bool,
+    /// How to spread traffic over additional paths opened with `Endpoint::add_path`, or None to disable multipath.
+    ///
+    /// Multipath is only used if the peer enables it too. Paths share a single congestion controller and RTT estimate.
+    pub multipath_policy: Option<MultipathPolicy>,
+    /// Transport parameters to send the peer in addition to those defined by QUIC, by ID.
+    ///
+    /// Lets an application protocol negotiate its own extensions. Prefer `set_custom_param`, which checks that IDs are
+    /// in the private use range, 0xff00 to 0xffff.
+    pub custom_transport_parameters: HashMap<u64, Bytes>,
 
-    /// Maximum number of tail loss probes before an RTO fires.
-    pub max_tlps: u32,
-    /// Maximum reordering in packet number space before FACK style loss detection considers a packet lost.
+    /// Maximum reordering in packet number space before packet threshold loss detection considers a packet lost.
     pub reordering_threshold: u32,
-    /// Maximum reordering in time space before time based loss detection considers a packet lost. 0.16 format
+    /// Maximum reordering in time before time threshold loss detection considers a packet lost, as a fraction of the
+    /// RTT to wait beyond the RTT itself. 0.16 format
     pub time_reordering_fraction: u16,
-    /// Whether time based loss detection is in use. If false, uses FACK style loss detection.
-    pub using_time_loss_detection: bool,
-    /// Minimum time in the future a tail loss probe alarm may be set for (μs).
-    pub min_tlp_timeout: u64,
-    /// Minimum time in the future an RTO alarm may be set for (μs).
-    pub min_rto_timeout: u64,
+    /// Whether 1-RTT packets are declared lost by RACK, once a packet sent long enough after them is acknowledged.
+    ///
+    /// RACK allows for reordering in time rather than in packets, so it adapts to the path and finds losses near the end
+    /// of a flight sooner. If false, or for handshake packets, the thresholds above apply.
+    pub using_rack: bool,
+    /// Shortest time loss detection will wait for (μs), reflecting the resolution of timers.
+    pub timer_granularity: u64,
+    /// Number of probe timeouts over which ack-eliciting packets must all be lost for the congestion window to collapse.
+    ///
+    /// Loss this persistent means nothing is getting through, so the path's capacity must be found afresh.
+    pub persistent_congestion_threshold: u32,
     /// The length of the peer’s delayed ack timer (μs).
     pub delayed_ack_timeout: u64,
+    /// Exponent by which the ack delays we report are scaled down, advertised to the peer. At most 20.
+    ///
+    /// Larger values let long delays fit in shorter varints, at the cost of precision.
+    pub ack_delay_exponent: u8,
+    /// Longest we'll delay acknowledging an ack-eliciting packet, advertised to the peer (μs). Must be under 2^14 ms.
+    ///
+    /// The peer allows this much extra time for our ACKs before it deems its packets lost.
+    pub max_ack_delay: u64,
+    /// Maximum number of disjoint ranges of received packets to report in ACK frames. Must be nonzero.
+    ///
+    /// The oldest ranges are forgotten first, so a peer whose packets are lost or reordered in a pathological pattern
+    /// can't make every ACK frame fill a packet. The default fits in a minimum-size packet with room to spare.
+    pub max_ack_ranges: usize,
     /// The default RTT used before an RTT sample is taken (μs)
     pub default_initial_rtt: u64,
 
@@ -76,6 +249,18 @@ pub struct Config {
     pub minimum_window: u64,
     /// Reduction in congestion window when a new loss event is detected. 0.16 format
     pub loss_reduction_factor: u16,
+    /// Constructs the congestion controller of each new connection. NewReno by default.
+    pub congestion_controller_factory: Arc<CongestionControllerFactory>,
+    /// Whether to spread packets out at the rate the congestion controller calls for, rather than sending them back to
+    /// back.
+    ///
+    /// Bursts at the sender's line rate can overflow queues at a slower bottleneck, causing loss. Controllers that don't
+    /// report a pacing rate of their own, unlike e.g. BBR, are paced at a little over one congestion window per
+    /// smoothed RTT, or two while the controller is in slow start. Handshake packets and probes aren't paced.
+    pub enable_pacing: bool,
+    /// Fewest datagrams the pacer lets out back to back, including when it first comes into play. More are, up to 2ms
+    /// of sending at the pacing rate, when that's greater. 0 is taken to mean 1.
+    pub pacing_burst: u64,
 
     pub tls_client_config: Arc<ClientConfig>,
     pub tls_server_config: Arc<ServerConfig>,
@@ -90,24 +275,57 @@ impl Default for Config {
         Self {
             max_remote_bi_streams: 0,
             max_remote_uni_streams: 0,
-            idle_timeout: 10,
+            max_idle_timeout: 10 * 1000 * 1000,
+            keep_alive_interval: None,
             stream_receive_window: STREAM_RWND,
             receive_window: 8 * STREAM_RWND,
+            send_buffer_size: 8 * STREAM_RWND,
             accept_buffer: 1024,
+            max_datagram_frame_size: None,
+            min_ack_delay: None,
+            versions: vec![Version::V1, Version::V2],
+            ecn: true,
+            enable_spin_bit: true,
+            grease_quic_bit: true,
+            key_update_interval: Some(1 << 22),
+            use_stateless_retry: false,
+            retry_token_lifetime: 15 * 1000 * 1000,
+            new_token_lifetime: 24 * 60 * 60 * 1000 * 1000,
+            local_cid_len: LOCAL_ID_LEN,
+            connection_id_generator_factory: Arc::new(RandomConnectionIdGeneratorFactory),
+            connection_id_filter: None,
+            max_version_negotiations: 100,
+            max_session_tickets: 0,
+            max_early_data: 64 * 1024,
+            zero_rtt_anti_replay: true,
+            session_ticket_store: Arc::new(Mutex::new(InMemoryTicketStore::default())),
+            token_store: Arc::new(Mutex::new(InMemoryTokenStore::default())),
+            mtu_discovery: true,
+            max_mtu: 1452,
+            preferred_address_v4: None,
+            preferred_address_v6: None,
+            use_preferred_address: true,
+            multipath_policy: None,
+            custom_transport_parameters: HashMap::new(),
 
-            max_tlps: 2,
             reordering_threshold: 3,
             time_reordering_fraction: 0x2000, // 1/8
-            using_time_loss_detection: false,
-            min_tlp_timeout: 10 * 1000,
-            min_rto_timeout: 200 * 1000,
+            using_rack: true,
+            timer_granularity: 1000,
+            persistent_congestion_threshold: 3,
             delayed_ack_timeout: 25 * 1000,
+            ack_delay_exponent: 3,
+            max_ack_delay: 25 * 1000,
+            max_ack_ranges: 64,
             default_initial_rtt: EXPECTED_RTT as u64 * 1000,
 
             default_mss: 1460,
             initial_window: 10 * 1460,
             minimum_window: 2 * 1460,
             loss_reduction_factor: 0x8000, // 1/2
+            congestion_controller_factory: Arc::new(NewRenoFactory),
+            enable_pacing: true,
+            pacing_burst: 2,
 
             tls_client_config: Arc::new(crypto::build_client_config()),
             tls_server_config: Arc::new(crypto::build_server_config()),
@@ -115,6 +333,42 @@ impl Default for Config {
     }
 }
 
+impl Config {
+    /// Send the peer a transport parameter with `id` and `value`, replacing any set previously with the same `id`.
+    ///
+    /// `id` must be in the range reserved for private use, 0xff00 to 0xffff, so as not to collide with parameters
+    /// defined by QUIC. The peer's custom parameters can be read with `Endpoint::get_custom_param`.
+    pub fn set_custom_param(&mut self, id: u64, value: Bytes) -> Result<(), EndpointError> {
+        check_custom_param(id)?;
+        let replaced = self
+            .custom_transport_parameters
+            .get(&id)
+            .map_or(0, |x| 4 + x.len());
+        let len = custom_params_len(&self.custom_transport_parameters) - replaced + 4 + value.len();
+        if len > MAX_CUSTOM_PARAMETERS_LEN {
+            return Err(EndpointError::CustomParametersTooLong(len));
+        }
+        self.custom_transport_parameters.insert(id, value);
+        Ok(())
+    }
+}
+
+/// How a connection with several validated paths to its peer spreads traffic over them
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MultipathPolicy {
+    /// Send on each path in turn
+    RoundRobin,
+    /// Send on the active path alone, failing over to another when it times out
+    ActiveStandby,
+}
+
+fn check_custom_param(id: u64) -> Result<(), EndpointError> {
+    if id < MIN_CUSTOM_PARAMETER || id > MAX_CUSTOM_PARAMETER {
+        return Err(EndpointError::IllegalCustomParameter(id));
+    }
+    Ok(())
+}
+
 /// The main entry point to the library
 ///
 /// This object performs no I/O whatsoever. Instead, it generates a stream of I/O operations for a backend to perform
@@ -125,7 +379,28 @@ pub struct Endpoint {
     connection_ids: FnvHashMap<ConnectionId, ConnectionHandle>,
     connection_remotes: FnvHashMap<SocketAddrV6, ConnectionHandle>,
     pub(crate) connections: Slab<Connection>,
+    cid_generator: Box<ConnectionIdGenerator>,
     listen_keys: Option<ListenKeys>,
+    /// Derived from `listen_keys`
+    reset_key: Option<StatelessResetKey>,
+    /// Start of the current one-second window for rate limiting version negotiation (μs)
+    version_negotiation_epoch: u64,
+    /// Number of version negotiation packets sent in the current window
+    version_negotiations: u32,
+    /// Trace that events on new connections are recorded in, if any
+    qlog: Option<Arc<Mutex<QlogWriter>>>,
+}
+
+/// Counters describing the traffic an endpoint has handled
+#[derive(Debug, Copy, Clone, Default)]
+pub struct EndpointStats {
+    /// Initial packets from unknown clients ignored because their datagrams were smaller than 1200 bytes
+    pub small_initials_dropped: u64,
+    /// Short header packets dropped because `Config::connection_id_filter` rejected their connection IDs
+    pub filtered_packets: u64,
+    /// Other packets discarded unprocessed, e.g. because they couldn't be authenticated, or arrived for an unknown
+    /// connection or one not yet able to handle them
+    pub dropped_packets: u64,
 }
 
 pub struct Context {
@@ -133,22 +408,49 @@ pub struct Context {
     pub rng: OsRng,
     pub config: Arc<Config>,
     pub io: VecDeque<Io>,
-    // pub session_ticket_buffer: SessionTicketBuffer,
+    /// Session tickets issued to clients that may yet be used for 0-RTT, by ID
+    pub session_tickets: FnvHashMap<[u8; SESSION_TICKET_ID_SIZE], SessionTicket>,
+    /// IDs of `session_tickets` in the order they were issued, so the oldest can be forgotten first
+    pub session_ticket_order: VecDeque<[u8; SESSION_TICKET_ID_SIZE]>,
     pub events: VecDeque<(ConnectionHandle, Event)>,
     pub incoming: VecDeque<ConnectionHandle>,
     pub incoming_handshakes: usize,
     pub dirty_conns: FnvHashSet<ConnectionHandle>,
     pub readable_conns: FnvHashSet<ConnectionHandle>,
     pub initial_packet_number: distributions::Uniform<u64>,
+    pub stats: EndpointStats,
 }
 
 impl Context {
     fn gen_initial_packet_num(&mut self) -> u32 {
         self.initial_packet_number.sample(&mut self.rng) as u32
     }
+
+    /// Remember `ticket` so a client can use it to resume a connection, forgetting the oldest tickets if necessary
+    pub fn remember_ticket(&mut self, ticket: SessionTicket) {
+        if self.config.max_session_tickets == 0 {
+            return;
+        }
+        while self.session_ticket_order.len() >= self.config.max_session_tickets {
+            let id = self.session_ticket_order.pop_front().unwrap();
+            self.session_tickets.remove(&id);
+        }
+        self.session_ticket_order.push_back(ticket.id);
+        self.session_tickets.insert(ticket.id, ticket);
+    }
+
+    /// Retrieve the ticket named `id`, which may not be used again unless anti-replay is disabled
+    pub fn take_ticket(&mut self, id: &[u8; SESSION_TICKET_ID_SIZE]) -> Option<SessionTicket> {
+        if !self.config.zero_rtt_anti_replay {
+            return self.session_tickets.get(id).cloned();
+        }
+        self.session_tickets.remove(id)
+    }
 }
 
 const LOCAL_ID_LEN: usize = 8;
+/// Number of IDs a `ConnectionIdGenerator` may produce that are already in use before we give up on it
+const MAX_CID_ATTEMPTS: usize = 8;
 
 /// Information that should be preserved between restarts for server endpoints.
 ///
@@ -190,6 +492,27 @@ pub enum EndpointError {
     ProtocolTooLong(Box<[u8]>),
     #[fail(display = "invalid DNS name: {}", _0)]
     InvalidDnsName(String),
+    #[fail(display = "connection ID length {} is too long", _0)]
+    ConnectionIdTooLong(usize),
+    #[fail(display = "custom transport parameter {:#x} is outside the private use range", _0)]
+    IllegalCustomParameter(u64),
+    #[fail(
+        display = "custom transport parameters take {} bytes, more than 64511",
+        _0
+    )]
+    CustomParametersTooLong(usize),
+    #[fail(display = "no QUIC versions enabled")]
+    NoVersions,
+    #[fail(display = "{} QUIC versions enabled, more than 62", _0)]
+    TooManyVersions(usize),
+    #[fail(display = "ack delay exponent {} exceeds 20", _0)]
+    AckDelayExponentTooLarge(u8),
+    #[fail(display = "max ack delay of {}μs is 2^14ms or more", _0)]
+    MaxAckDelayTooLarge(u64),
+    #[fail(display = "max idle timeout of {}μs is 2^32ms or more", _0)]
+    IdleTimeoutTooLarge(u64),
+    #[fail(display = "ACK frames must be allowed at least one range")]
+    NoAckRanges,
 }
 
 impl From<crypto::TLSError> for EndpointError {
@@ -204,7 +527,38 @@ impl Endpoint {
         config: Config,
         listen: Option<ListenKeys>,
     ) -> Result<Self, EndpointError> {
+        if config.local_cid_len > MAX_CID_SIZE {
+            return Err(EndpointError::ConnectionIdTooLong(config.local_cid_len));
+        }
+        if config.versions.is_empty() {
+            return Err(EndpointError::NoVersions);
+        }
+        if config.versions.len() > MAX_SUPPORTED_VERSIONS {
+            return Err(EndpointError::TooManyVersions(config.versions.len()));
+        }
+        if config.ack_delay_exponent > 20 {
+            return Err(EndpointError::AckDelayExponentTooLarge(
+                config.ack_delay_exponent,
+            ));
+        }
+        if config.max_ack_delay > (u64::from(MAX_ACK_DELAY_LIMIT) - 1) * 1000 {
+            return Err(EndpointError::MaxAckDelayTooLarge(config.max_ack_delay));
+        }
+        if (config.max_idle_timeout + 999) / 1000 > u64::from(u32::max_value()) {
+            return Err(EndpointError::IdleTimeoutTooLarge(config.max_idle_timeout));
+        }
+        if config.max_ack_ranges == 0 {
+            return Err(EndpointError::NoAckRanges);
+        }
+        for &id in config.custom_transport_parameters.keys() {
+            check_custom_param(id)?;
+        }
+        let custom_len = custom_params_len(&config.custom_transport_parameters);
+        if custom_len > MAX_CUSTOM_PARAMETERS_LEN {
+            return Err(EndpointError::CustomParametersTooLong(custom_len));
+        }
         let rng = OsRng::new().unwrap();
+        let cid_generator = config.connection_id_generator_factory.build(&config);
         let config = Arc::new(config);
         Ok(Self {
             ctx: Context {
@@ -212,22 +566,37 @@ impl Endpoint {
                 rng,
                 config,
                 io: VecDeque::new(),
-                // session_ticket_buffer,
+                session_tickets: FnvHashMap::default(),
+                session_ticket_order: VecDeque::new(),
                 initial_packet_number: distributions::Uniform::from(0..2u64.pow(32) - 1024),
                 events: VecDeque::new(),
                 dirty_conns: FnvHashSet::default(),
                 readable_conns: FnvHashSet::default(),
                 incoming: VecDeque::new(),
                 incoming_handshakes: 0,
+                stats: EndpointStats::default(),
             },
+            reset_key: listen.as_ref().map(|x| StatelessResetKey::new(&x.reset)),
             listen_keys: listen,
             connection_ids_initial: FnvHashMap::default(),
             connection_ids: FnvHashMap::default(),
             connection_remotes: FnvHashMap::default(),
             connections: Slab::new(),
+            cid_generator,
+            version_negotiation_epoch: 0,
+            version_negotiations: 0,
+            qlog: None,
         })
     }
 
+    /// Record events on connections created from now on in `qlog`
+    ///
+    /// Sharing a writer between endpoints interleaves their events in one trace.
+    pub fn with_qlog(mut self, qlog: Arc<Mutex<QlogWriter>>) -> Self {
+        self.qlog = Some(qlog);
+        self
+    }
+
     fn listen(&self) -> bool {
         self.listen_keys.is_some()
     }
@@ -267,100 +636,215 @@ impl Endpoint {
         }
     }
 
+    /// Move pending datagrams into `bufs`, returning how many were stored from its start
+    ///
+    /// Lets a backend hand many datagrams to the operating system at once. Timer operations are left for `poll_io`,
+    /// which must still be called.
+    pub fn poll_transmit_batch(&mut self, now: u64, bufs: &mut [Option<Transmit>]) -> usize {
+        let mut n = 0;
+        while n < bufs.len() {
+            let next = self.ctx.io.iter().position(|x| match *x {
+                Io::Transmit { .. } => true,
+                _ => false,
+            });
+            if let Some(i) = next {
+                if let Some(Io::Transmit {
+                    destination,
+                    ecn,
+                    packet,
+                }) = self.ctx.io.remove(i)
+                {
+                    bufs[n] = Some(Transmit {
+                        destination,
+                        ecn,
+                        packet,
+                        segment_size: None,
+                    });
+                    n += 1;
+                }
+                continue;
+            }
+            let conn = match self.ctx.dirty_conns.iter().next() {
+                Some(&x) => x,
+                None => break,
+            };
+            self.flush_pending(now, conn);
+            self.ctx.dirty_conns.remove(&conn);
+        }
+        n
+    }
+
     /// Process an incoming UDP datagram
-    pub fn handle(&mut self, now: u64, remote: SocketAddrV6, mut data: BytesMut) {
+    ///
+    /// `ecn` is the ECN codepoint from its IP header, if any.
+    pub fn handle(
+        &mut self,
+        now: u64,
+        remote: SocketAddrV6,
+        ecn: Option<EcnCodepoint>,
+        data: BytesMut,
+    ) {
         let datagram_len = data.len();
-        while !data.is_empty() {
-            let (packet, rest) = match Packet::decode(data, LOCAL_ID_LEN) {
+        for partial in PartialDecode::decode_all(data, self.ctx.config.local_cid_len) {
+            let partial = match partial {
                 Ok(x) => x,
                 Err(HeaderError::UnsupportedVersion {
                     source,
                     destination,
                 }) => {
-                    if !self.listen() {
-                        debug!(self.ctx.log, "dropping packet with unsupported version");
-                        return;
-                    }
-                    trace!(self.ctx.log, "sending version negotiation");
-                    // Negotiate versions
-                    let mut buf = Vec::<u8>::new();
-                    Header::VersionNegotiate {
-                        ty: self.ctx.rng.gen(),
-                        source_id: destination,
-                        destination_id: source,
-                    }.encode(&mut buf);
-                    buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                    buf.write(VERSION); // supported version
-                    self.ctx.io.push_back(Io::Transmit {
-                        destination: remote,
-                        packet: buf.into(),
-                    });
+                    self.negotiate_version(now, remote, source, destination);
+                    return;
+                }
+                Err(HeaderError::Malformed {
+                    reason,
+                    offset,
+                    first,
+                    destination,
+                    source,
+                }) => {
+                    debug!(self.ctx.log, "malformed packet"; "reason" => reason, "offset" => offset, "first" => format!("{:#04x}", first), "destination" => destination, "source" => source);
+                    self.ctx.stats.dropped_packets += 1;
                     return;
                 }
                 Err(e) => {
                     trace!(self.ctx.log, "unable to process packet"; "reason" => %e);
+                    self.ctx.stats.dropped_packets += 1;
                     return;
                 }
             };
-            self.handle_packet(now, remote, packet, datagram_len);
-            data = rest;
+            if let Some(version) = partial.version() {
+                // Implemented, but not enabled
+                if !self.ctx.config.versions.contains(&version) {
+                    let source = partial.source_id().unwrap().clone();
+                    let destination = partial.dst_cid().clone();
+                    self.negotiate_version(now, remote, source, destination);
+                    return;
+                }
+            }
+            self.handle_packet(now, remote, ecn, partial, datagram_len);
+        }
+    }
+
+    /// Answer a packet of a version we don't support with a list of the versions we do
+    fn negotiate_version(
+        &mut self,
+        now: u64,
+        remote: SocketAddrV6,
+        source: ConnectionId,
+        destination: ConnectionId,
+    ) {
+        if !self.listen() {
+            debug!(self.ctx.log, "dropping packet with unsupported version");
+            return;
         }
+        if !self.version_negotiation_permitted(now) {
+            debug!(
+                self.ctx.log,
+                "dropping packet with unsupported version due to rate limit"
+            );
+            return;
+        }
+        let packet = self.send_version_negotiation(remote, &destination, &source);
+        self.ctx.io.push_back(Io::Transmit {
+            destination: remote,
+            ecn: None,
+            packet: packet[..].into(),
+        });
+    }
+
+    /// Encode a Version Negotiation packet listing the versions we support, in answer to a packet from `remote`
+    /// addressed to `dcid` from `scid`
+    ///
+    /// `handle` answers packets of unsupported versions itself, subject to `Config::max_version_negotiations`. This is
+    /// for callers that want to answer such packets themselves, e.g. before they reach an endpoint; nothing is rate
+    /// limited or queued for transmission.
+    pub fn send_version_negotiation(
+        &mut self,
+        remote: SocketAddrV6,
+        dcid: &ConnectionId,
+        scid: &ConnectionId,
+    ) -> Bytes {
+        trace!(self.ctx.log, "sending version negotiation"; "remote" => %remote);
+        let versions = self
+            .ctx
+            .config
+            .versions
+            .iter()
+            .map(|x| x.number())
+            .collect::<Vec<_>>();
+        packet::version_negotiate(&mut self.ctx.rng, dcid.clone(), scid.clone(), &versions).into()
+    }
+
+    /// Whether the rate limit on version negotiation permits sending another packet at `now`
+    fn version_negotiation_permitted(&mut self, now: u64) -> bool {
+        if now >= self.version_negotiation_epoch + 1000 * 1000 {
+            self.version_negotiation_epoch = now;
+            self.version_negotiations = 0;
+        }
+        if self.version_negotiations >= self.ctx.config.max_version_negotiations {
+            return false;
+        }
+        self.version_negotiations += 1;
+        true
     }
 
     fn handle_packet(
         &mut self,
         now: u64,
         remote: SocketAddrV6,
-        packet: Packet,
+        ecn: Option<EcnCodepoint>,
+        partial: PartialDecode,
         datagram_len: usize,
     ) {
         //
         // Handle packet on existing connection, if any
         //
 
-        let dest_id = packet.header.destination_id().clone();
+        let dest_id = partial.dst_cid().clone();
+        let accepted = match self.ctx.config.connection_id_filter {
+            Some(ref accept) if !partial.has_long_header() && !dest_id.is_empty() => {
+                accept(&dest_id[..])
+            }
+            _ => true,
+        };
+        if !accepted {
+            trace!(
+                self.ctx.log,
+                "dropping packet for connection {connection} routed elsewhere",
+                connection = dest_id.clone()
+            );
+            self.ctx.stats.filtered_packets += 1;
+            return;
+        }
         if let Some(&conn) = self.connection_ids.get(&dest_id) {
-            self.handle_connected(now, conn, remote, packet);
+            self.handle_connected(now, conn, remote, ecn, partial);
             return;
         }
         if let Some(&conn) = self.connection_ids_initial.get(&dest_id) {
-            self.handle_connected(now, conn, remote, packet);
+            self.handle_connected(now, conn, remote, ecn, partial);
             return;
         }
+        if dest_id.is_empty() {
+            // Connections with empty local IDs are identified by remote address alone
+            if let Some(&conn) = self.connection_remotes.get(&remote) {
+                if self.connections[conn.0].local_id.is_empty() {
+                    self.handle_connected(now, conn, remote, ecn, partial);
+                    return;
+                }
+            }
+        }
         if let Some(&conn) = self.connection_remotes.get(&remote) {
-            if let Some(token) = self.connections[conn.0].params.stateless_reset_token {
-                if packet.payload.len() >= 16
-                    && packet.payload[packet.payload.len() - 16..] == token
+            if self.connections[conn.0].is_stateless_reset(partial.data()) {
+                if !self.connections[conn.0]
+                    .state
+                    .as_ref()
+                    .unwrap()
+                    .is_drained()
                 {
-                    if !self.connections[conn.0]
-                        .state
-                        .as_ref()
-                        .unwrap()
-                        .is_drained()
-                    {
-                        debug!(self.ctx.log, "got stateless reset"; "connection" => %self.connections[conn.0].local_id);
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::LossDetection,
-                        });
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::Close,
-                        });
-                        self.ctx.io.push_back(Io::TimerStop {
-                            connection: conn,
-                            timer: Timer::Idle,
-                        });
-                        self.ctx.events.push_back((
-                            conn,
-                            Event::ConnectionLost {
-                                reason: ConnectionError::Reset,
-                            },
-                        ));
-                        self.connections[conn.0].state = Some(State::Drained);
-                    }
-                    return;
+                    debug!(self.ctx.log, "got stateless reset"; "connection" => %self.connections[conn.0].local_id);
+                    self.kill(conn, ConnectionError::Reset);
                 }
+                return;
             }
         }
 
@@ -369,60 +853,56 @@ impl Endpoint {
         //
 
         if !self.listen() {
-            debug!(self.ctx.log, "dropping packet from unrecognized connection"; "header" => ?packet.header);
+            debug!(self.ctx.log, "dropping packet from unrecognized connection"; "connection" => %dest_id);
+            self.ctx.stats.dropped_packets += 1;
             return;
         }
-        let key_phase = packet.header.key_phase();
-        let Packet {
-            header_data,
-            header,
-            payload,
-        } = packet;
-        if let Header::Long {
-            ty,
-            ref destination_id,
-            ref source_id,
-            number,
-        } = header
-        {
+        if let Some(ty) = partial.long_type() {
             match ty {
                 types::INITIAL => {
                     if datagram_len >= MIN_INITIAL_SIZE {
-                        self.handle_initial(
-                            now,
-                            remote,
-                            destination_id.clone(),
-                            source_id.clone(),
-                            number,
-                            &header_data,
-                            payload,
-                        );
+                        self.handle_initial(now, remote, ecn, partial);
                     } else {
+                        // Responding could amplify an attack, and we needn't even decrypt it to know that
                         debug!(
                             self.ctx.log,
                             "ignoring short initial on {connection}",
-                            connection = destination_id.clone()
+                            connection = dest_id.clone()
                         );
+                        self.ctx.stats.small_initials_dropped += 1;
                     }
                     return;
                 }
-                /*types::ZERO_RTT => {
+                types::ZERO_RTT => {
                     // MAY buffer a limited amount
                     trace!(
                         self.ctx.log,
                         "dropping 0-RTT packet for unknown connection {connection}",
-                        connection = destination_id.clone()
+                        connection = dest_id.clone()
                     );
+                    self.ctx.stats.dropped_packets += 1;
                     return;
-                }*/
+                }
                 _ => {
                     debug!(self.ctx.log, "ignoring packet for unknown connection {connection} with unexpected type {type:02x}",
-                           connection=destination_id.clone(), type=ty);
+                           connection=dest_id.clone(), type=ty);
+                    self.ctx.stats.dropped_packets += 1;
                     return;
                 }
             }
         }
 
+        if !self.cid_generator.validate_cid(&dest_id) {
+            // Not one of ours, even one we've since forgotten, so there's no connection to reset
+            trace!(
+                self.ctx.log,
+                "dropping packet with invalid connection ID {connection}",
+                connection = dest_id.clone()
+            );
+            self.ctx.stats.dropped_packets += 1;
+            return;
+        }
+
         //
         // If we got this far, we're a server receiving a seemingly valid packet for an unknown connection. Send a stateless reset.
         //
@@ -433,25 +913,27 @@ impl Endpoint {
             // Bound padding size to at most 8 bytes larger than input to mitigate amplification attacks
             let padding = self.ctx.rng.gen_range(
                 0,
-                cmp::max(RESET_TOKEN_SIZE + 8, payload.len()) - RESET_TOKEN_SIZE,
+                cmp::max(RESET_TOKEN_SIZE + 8, partial.data().len()) - RESET_TOKEN_SIZE,
             );
             buf.reserve_exact(1 + MAX_CID_SIZE + 1 + padding + RESET_TOKEN_SIZE);
             Header::Short {
                 id: ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE as u8),
                 number: PacketNumber::U8(self.ctx.rng.gen()),
-                key_phase,
+                spin: self.ctx.rng.gen(),
+                key_phase: self.ctx.rng.gen(),
             }.encode(&mut buf);
             {
                 let start = buf.len();
                 buf.resize(start + padding, 0);
                 self.ctx.rng.fill_bytes(&mut buf[start..start + padding]);
             }
-            buf.extend(&reset_token_for(
-                &self.listen_keys.as_ref().unwrap().reset,
+            buf.extend(&stateless_reset_token(
+                self.reset_key.as_ref().unwrap(),
                 &dest_id,
             ));
             self.ctx.io.push_back(Io::Transmit {
                 destination: remote,
+                ecn: None,
                 packet: buf.into(),
             });
         } else {
@@ -463,22 +945,71 @@ impl Endpoint {
     }
 
     /// Initiate a connection
+    ///
+    /// If `Config::session_ticket_store` holds a ticket from an earlier connection to `server_name`, the session it
+    /// describes is resumed as by `connect_with_ticket`. The ticket is removed from the store so it's only used once.
     pub fn connect(
         &mut self,
         remote: SocketAddrV6,
         server_name: &str,
     ) -> Result<ConnectionHandle, ConnectError> {
-        let local_id = ConnectionId::random(&mut self.ctx.rng, LOCAL_ID_LEN as u8);
+        let ticket = self
+            .ctx
+            .config
+            .session_ticket_store
+            .lock()
+            .unwrap()
+            .take(server_name);
+        let ticket = match ticket.map(|x| SessionTicket::decode(&x)) {
+            Some(Ok(x)) => Some(x),
+            Some(Err(e)) => {
+                debug!(self.ctx.log, "ignoring stored session ticket"; "reason" => %e);
+                None
+            }
+            None => None,
+        };
+        self.connect_inner(remote, server_name, ticket)
+    }
+
+    /// Initiate a connection resuming a previous one, allowing data to be sent before the handshake completes
+    ///
+    /// `ticket` is from an `Event::NewSessionTicket` on an earlier connection to the same server. Stream data written
+    /// before the connection is established is sent in 0-RTT packets, and retransmitted after the handshake if the
+    /// server no longer accepts the ticket. Writes block once the server's `max_early_data` is reached, until the
+    /// handshake completes.
+    ///
+    /// An attacker who observes the 0-RTT packets can replay them, so early data must be safe to process more than
+    /// once; servers only prevent this while `Config::zero_rtt_anti_replay` is enabled. 0-RTT data also lacks forward
+    /// secrecy: it's protected by keys derived from the earlier connection.
+    pub fn connect_with_ticket(
+        &mut self,
+        remote: SocketAddrV6,
+        server_name: &str,
+        ticket: &[u8],
+    ) -> Result<ConnectionHandle, ConnectError> {
+        let ticket = SessionTicket::decode(ticket)?;
+        self.connect_inner(remote, server_name, Some(ticket))
+    }
+
+    fn connect_inner(
+        &mut self,
+        remote: SocketAddrV6,
+        server_name: &str,
+        ticket: Option<SessionTicket>,
+    ) -> Result<ConnectionHandle, ConnectError> {
+        let local_id = self.new_local_id()?;
         let remote_id = ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE as u8);
         trace!(self.ctx.log, "initial dcid"; "value" => %remote_id);
+        let version = self.ctx.config.versions[0];
         let conn = self.add_connection(
             remote_id.clone(),
             local_id.clone(),
             remote_id,
             remote,
             Side::Client,
+            version,
         );
-        self.connections[conn.0].connect(&self.ctx, server_name)?;
+        self.connections[conn.0].connect(&self.ctx, server_name, ticket)?;
         self.ctx.dirty_conns.insert(conn);
         Ok(conn)
     }
@@ -490,19 +1021,26 @@ impl Endpoint {
         remote_id: ConnectionId,
         remote: SocketAddrV6,
         side: Side,
+        version: Version,
     ) -> ConnectionHandle {
-        debug_assert!(!local_id.is_empty());
         let packet_num = self.ctx.gen_initial_packet_num();
-        let i = self.connections.insert(Connection::new(
+        let mut connection = Connection::new(
             initial_id,
             local_id.clone(),
             remote_id,
             remote,
             packet_num.into(),
             side,
+            version,
             &self.ctx.config,
-        ));
-        self.connection_ids.insert(local_id, ConnectionHandle(i));
+        );
+        if let Some(ref qlog) = self.qlog {
+            connection = connection.with_qlog(qlog.clone());
+        }
+        let i = self.connections.insert(connection);
+        if !local_id.is_empty() {
+            self.connection_ids.insert(local_id, ConnectionHandle(i));
+        }
         self.connection_remotes.insert(remote, ConnectionHandle(i));
         ConnectionHandle(i)
     }
@@ -511,21 +1049,111 @@ impl Endpoint {
         &mut self,
         now: u64,
         remote: SocketAddrV6,
-        dest_id: ConnectionId,
-        source_id: ConnectionId,
-        packet_number: u32,
-        header: &[u8],
-        mut payload: BytesMut,
+        ecn: Option<EcnCodepoint>,
+        partial: PartialDecode,
     ) {
-        let crypto = Crypto::new_handshake(&dest_id, Side::Server);
+        let dest_id = partial.dst_cid().clone();
+        // Initial packets always carry a version
+        let version = partial.version().unwrap();
+        let crypto = Crypto::new_handshake(&dest_id, Side::Server, version);
+        let packet = match partial.finish(crypto.remote_header_key()) {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(self.ctx.log, "failed to decode initial packet"; "reason" => %e);
+                return;
+            }
+        };
+        // Not acted upon until the packet is authenticated
+        let reserved_bits = packet.check_reserved_bits();
+        let Packet {
+            header,
+            header_data,
+            mut payload,
+        } = packet;
+        let (source_id, token, packet_number) = match header {
+            Header::Initial {
+                source_id,
+                token,
+                number,
+                ..
+            } => (source_id, token, number.expand(0)),
+            _ => unreachable!(),
+        };
         if crypto
-            .decrypt(packet_number as u64, header, &mut payload)
+            .decrypt(packet_number, &header_data, &mut payload)
             .is_err()
         {
             debug!(self.ctx.log, "failed to authenticate initial packet");
+            self.ctx.stats.dropped_packets += 1;
             return;
         };
-        let local_id = ConnectionId::random(&mut self.ctx.rng, LOCAL_ID_LEN as u8);
+
+        if self.ctx.config.use_stateless_retry {
+            if token.is_empty() {
+                self.stateless_retry(now, remote, version, &source_id, &dest_id);
+                return;
+            }
+            let cookies = CookieFactory::new(self.listen_keys.as_ref().unwrap().cookie);
+            if CookieFactory::is_new_token(&token) {
+                let lifetime = self.ctx.config.new_token_lifetime;
+                if cookies.verify_new_token(&remote, &token, now, lifetime) {
+                    trace!(self.ctx.log, "address validated by NEW_TOKEN");
+                } else {
+                    // Perhaps expired, or presented from a new address; the client can still prove itself
+                    debug!(self.ctx.log, "retrying initial with invalid token");
+                    self.stateless_retry(now, remote, version, &source_id, &dest_id);
+                    return;
+                }
+            } else {
+                match cookies.verify(&remote, &token, now, self.ctx.config.retry_token_lifetime) {
+                    Some(orig_dst_cid) => {
+                        trace!(self.ctx.log, "address validated"; "orig_dst_cid" => %orig_dst_cid);
+                    }
+                    None => {
+                        debug!(self.ctx.log, "rejecting initial with invalid retry token");
+                        return;
+                    }
+                }
+            }
+        }
+        let local_id = match self.new_local_id() {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(self.ctx.log, "refusing connection"; "reason" => %e);
+                let n = self.ctx.gen_initial_packet_num();
+                self.ctx.io.push_back(Io::Transmit {
+                    destination: remote,
+                    ecn: None,
+                    packet: handshake_close(
+                        &crypto,
+                        &source_id,
+                        &dest_id,
+                        n,
+                        TransportError::SERVER_BUSY,
+                        None,
+                    ),
+                });
+                return;
+            }
+        };
+
+        if let Err(e) = reserved_bits {
+            debug!(self.ctx.log, "rejecting illegal initial packet"; "reason" => %e);
+            let n = self.ctx.gen_initial_packet_num();
+            self.ctx.io.push_back(Io::Transmit {
+                destination: remote,
+                ecn: None,
+                packet: handshake_close(
+                    &crypto,
+                    &source_id,
+                    &local_id,
+                    n,
+                    TransportError::PROTOCOL_VIOLATION,
+                    None,
+                ),
+            });
+            return;
+        }
 
         if self.ctx.incoming.len() + self.ctx.incoming_handshakes
             == self.ctx.config.accept_buffer as usize
@@ -537,6 +1165,7 @@ impl Endpoint {
             let n = self.ctx.gen_initial_packet_num();
             self.ctx.io.push_back(Io::Transmit {
                 destination: remote,
+                ecn: None,
                 packet: handshake_close(
                     &crypto,
                     &source_id,
@@ -555,13 +1184,52 @@ impl Endpoint {
             source_id.clone(),
             remote,
             Side::Server,
+            version,
         );
         self.connection_ids_initial.insert(dest_id, conn);
+        if self.ctx.config.use_stateless_retry {
+            // Saves the client a Retry on its next connection
+            let token = CookieFactory::new(self.listen_keys.as_ref().unwrap().cookie)
+                .generate_new_token(&remote, now);
+            self.connections[conn.0].new_token = Some(token.into());
+        }
+        // Without connection IDs, packets sent to another address couldn't be routed to the connection
+        let preferred_address = if self.ctx.config.local_cid_len != 0
+            && (self.ctx.config.preferred_address_v4.is_some()
+                || self.ctx.config.preferred_address_v6.is_some())
+        {
+            match self.new_cid(conn) {
+                Ok((connection_id, stateless_reset_token)) => Some(PreferredAddress {
+                    address_v4: self.ctx.config.preferred_address_v4,
+                    address_v6: self.ctx.config.preferred_address_v6,
+                    connection_id,
+                    stateless_reset_token,
+                }),
+                Err(e) => {
+                    debug!(self.ctx.log, "not advertising preferred address"; "reason" => %e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Lets the client recognize our resets should we lose the connection's state
+        let reset_token = if local_id.is_empty() {
+            None
+        } else {
+            Some(stateless_reset_token(
+                self.reset_key.as_ref().unwrap(),
+                &local_id,
+            ))
+        };
         match self.connections[conn.0].handle_initial(
             &mut self.ctx,
             now,
-            packet_number as u64,
+            packet_number,
+            ecn,
             payload.freeze(),
+            reset_token,
+            preferred_address,
             conn,
         ) {
             Ok(()) => {}
@@ -569,6 +1237,7 @@ impl Endpoint {
                 let n = self.ctx.gen_initial_packet_num();
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
+                    ecn: None,
                     packet: handshake_close(
                         &crypto,
                         &source_id,
@@ -582,15 +1251,76 @@ impl Endpoint {
         }
     }
 
+    /// Ask a client to prove it can receive packets at `remote` by echoing a token in a new Initial
+    fn stateless_retry(
+        &mut self,
+        now: u64,
+        remote: SocketAddrV6,
+        version: Version,
+        remote_id: &ConnectionId,
+        orig_dst_cid: &ConnectionId,
+    ) {
+        // The client will address its next Initial to this ID, deriving new handshake keys from it. It needs one even if
+        // our connections are identified by address alone, but then only to key the handshake.
+        let local_id = if self.ctx.config.local_cid_len == 0 {
+            ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE as u8)
+        } else {
+            match self.new_local_id() {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!(self.ctx.log, "not sending retry"; "reason" => %e);
+                    return;
+                }
+            }
+        };
+        trace!(self.ctx.log, "sending retry"; "orig_dst_cid" => %orig_dst_cid, "new_id" => %local_id);
+        let token = CookieFactory::new(self.listen_keys.as_ref().unwrap().cookie)
+            .generate(&remote, orig_dst_cid, now);
+        let mut buf = Vec::new();
+        Header::Retry {
+            version,
+            source_id: local_id,
+            destination_id: remote_id.clone(),
+            orig_dst_cid: orig_dst_cid.clone(),
+        }.encode(&mut buf);
+        buf.extend_from_slice(&token);
+        let tag = retry_integrity_tag(orig_dst_cid, &buf);
+        buf.extend_from_slice(&tag);
+        self.ctx.io.push_back(Io::Transmit {
+            destination: remote,
+            ecn: None,
+            packet: buf.into(),
+        });
+    }
+
     fn handle_connected(
         &mut self,
         now: u64,
         conn: ConnectionHandle,
         remote: SocketAddrV6,
-        packet: Packet,
+        ecn: Option<EcnCodepoint>,
+        partial: PartialDecode,
     ) {
+        let packet = match self.connections[conn.0].decode_packet(partial) {
+            Ok(x) => x,
+            Err(e) => {
+                trace!(self.ctx.log, "unable to remove header protection"; "connection" => %self.connections[conn.0].local_id, "reason" => %e);
+                self.ctx.stats.dropped_packets += 1;
+                return;
+            }
+        };
         trace!(self.ctx.log, "connection got packet"; "connection" => %self.connections[conn.0].local_id, "len" => packet.payload.len());
         let was_closed = self.connections[conn.0].state.as_ref().unwrap().is_closed();
+        let was_drained = self.connections[conn.0]
+            .state
+            .as_ref()
+            .unwrap()
+            .is_drained();
+        let old_remote = self.connections[conn.0].remote;
+        let was_established = match *self.connections[conn.0].state.as_ref().unwrap() {
+            State::Established(_) => true,
+            _ => false,
+        };
 
         // State transitions
         let state = self.connections[conn.0].state.take().unwrap();
@@ -599,9 +1329,15 @@ impl Endpoint {
             now,
             conn,
             remote,
+            ecn,
             packet,
             state,
         );
+        if !was_drained && state.is_drained() {
+            // Connections only skip the draining period when the peer resets them
+            self.kill(conn, ConnectionError::Reset);
+            return;
+        }
 
         if !was_closed && state.is_closed() {
             self.connections[conn.0].close_common(&mut self.ctx, now, conn);
@@ -616,6 +1352,7 @@ impl Endpoint {
                 let n = self.connections[conn.0].get_tx_number();
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
+                    ecn: None,
                     packet: handshake_close(
                         &self.connections[conn.0].handshake_crypto,
                         &self.connections[conn.0].remote_id,
@@ -625,35 +1362,172 @@ impl Endpoint {
                         state.alert.as_ref().map(|x| &x[..]),
                     ),
                 });
-                self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
             }
             State::Closed(ref state) => {
                 self.ctx.io.push_back(Io::Transmit {
                     destination: remote,
+                    ecn: None,
                     packet: self.connections[conn.0].make_close(&state.reason),
                 });
-                self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
             }
             _ => {}
         }
+        let established = match state {
+            State::Established(_) => true,
+            _ => false,
+        };
         self.connections[conn.0].state = Some(state);
+        if established && !was_established {
+            self.connections[conn.0].qlog(now, |qlog, group| {
+                qlog.connection_state_updated(now, group, "handshake_complete")
+            });
+        }
 
+        for id in self.connections[conn.0].cid_pool.drain_retired() {
+            self.connection_ids.remove(&id);
+        }
+        if established {
+            // Replace any connection IDs the peer retired
+            self.issue_cids(conn);
+        }
+        self.update_remote(conn, old_remote);
         self.ctx.dirty_conns.insert(conn);
     }
 
+    /// Give the peer connection IDs it can switch to when migrating, replacing any it has retired
+    fn issue_cids(&mut self, conn: ConnectionHandle) {
+        if self.ctx.config.local_cid_len == 0 {
+            // Connections are identified by address alone, so we can't follow them to a new path anyway
+            return;
+        }
+        for _ in 0..self.connections[conn.0].cid_pool.needed() {
+            match self.new_cid(conn) {
+                Ok((id, reset_token)) => self.connections[conn.0].issue_cid(id, reset_token),
+                Err(e) => {
+                    // The peer can make do with the IDs it has until it next retires one
+                    debug!(self.ctx.log, "not issuing connection ID"; "reason" => %e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Generate an additional connection ID routing to `conn`, and the stateless reset token to issue with it
+    fn new_cid(
+        &mut self,
+        conn: ConnectionHandle,
+    ) -> Result<(ConnectionId, [u8; RESET_TOKEN_SIZE]), ConnectionIdError> {
+        let id = self.new_local_id()?;
+        let reset_token = match self.reset_key {
+            Some(ref key) => stateless_reset_token(key, &id),
+            None => {
+                let mut token = [0; RESET_TOKEN_SIZE];
+                self.ctx.rng.fill_bytes(&mut token);
+                token
+            }
+        };
+        self.connection_ids.insert(id.clone(), conn);
+        Ok((id, reset_token))
+    }
+
+    /// Generate a connection ID for a connection of ours to be identified by, distinct from those already in use
+    fn new_local_id(&mut self) -> Result<ConnectionId, ConnectionIdError> {
+        for _ in 0..MAX_CID_ATTEMPTS {
+            let id = self.cid_generator.generate_cid();
+            // Any other length would prevent short header packets from being decoded
+            if id.len() != self.ctx.config.local_cid_len {
+                return Err(ConnectionIdError::WrongLength(id.len()));
+            }
+            // Empty IDs don't identify connections, so they can be shared
+            if id.is_empty()
+                || !self.connection_ids.contains_key(&id)
+                    && !self.connection_ids_initial.contains_key(&id)
+            {
+                return Ok(id);
+            }
+        }
+        Err(ConnectionIdError::Exhausted)
+    }
+
+    /// Route packets from the connection's current remote address to it, if that changed from `old`
+    fn update_remote(&mut self, conn: ConnectionHandle, old: SocketAddrV6) {
+        let new = self.connections[conn.0].remote;
+        if new != old {
+            self.connection_remotes.remove(&old);
+            self.connection_remotes.insert(new, conn);
+        }
+    }
+
+    /// Abandon `conn` without notifying the peer
+    fn kill(&mut self, conn: ConnectionHandle, reason: ConnectionError) {
+        self.stop_timers(conn);
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!(parent: &self.connections[conn.0].span, reason = %reason, "connection lost");
+        self.ctx
+            .events
+            .push_back((conn, Event::ConnectionLost { reason }));
+        self.connections[conn.0].state = Some(State::Drained);
+    }
+
+    fn stop_timers(&mut self, conn: ConnectionHandle) {
+        for &timer in &[
+            Timer::LossDetection,
+            Timer::Close,
+            Timer::Idle,
+            Timer::Ack,
+            Timer::PathValidation,
+            Timer::Pacing,
+            Timer::KeepAlive,
+        ] {
+            self.ctx.io.push_back(Io::TimerStop {
+                connection: conn,
+                timer,
+            });
+        }
+    }
+
     fn flush_pending(&mut self, now: u64, conn: ConnectionHandle) {
-        let mut sent = false;
-        while let Some(packet) =
-            self.connections[conn.0].next_packet(&self.ctx.log, &self.ctx.config, now)
-        {
+        loop {
+            let (packet, ecn) = match self.connections[conn.0].next_datagram(
+                &self.ctx.log,
+                &self.ctx.config,
+                &mut self.ctx.rng,
+                now,
+            ) {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!(self.ctx.log, "abandoning connection: {}", e; "connection" => %self.connections[conn.0].local_id);
+                    self.kill(conn, e);
+                    return;
+                }
+            };
             self.ctx.io.push_back(Io::Transmit {
-                destination: self.connections[conn.0].remote,
+                destination: self.connections[conn.0].tx_path,
+                ecn,
                 packet: packet.into(),
             });
-            sent = true;
         }
-        if sent {
-            self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
+        while let Some((destination, packet)) =
+            self.connections[conn.0].next_off_path_packet(&self.ctx.config, &mut self.ctx.rng, now)
+        {
+            self.ctx.io.push_back(Io::Transmit {
+                destination,
+                ecn: None,
+                packet,
+            });
+        }
+        if let Some(packet) = self.connections[conn.0].next_mtu_probe(
+            &self.ctx.log,
+            &self.ctx.config,
+            &mut self.ctx.rng,
+            now,
+        ) {
+            self.ctx.io.push_back(Io::Transmit {
+                destination: self.connections[conn.0].remote,
+                ecn: None,
+                packet,
+            });
         }
         {
             let c = &mut self.connections[conn.0];
@@ -685,6 +1559,62 @@ impl Endpoint {
                     });
                 }
             }
+            if let Some(setting) = c.set_ack.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::Ack,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Ack,
+                    });
+                }
+            }
+            if let Some(setting) = c.set_path_validation.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::PathValidation,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::PathValidation,
+                    });
+                }
+            }
+            if let Some(setting) = c.set_pacing.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::Pacing,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::Pacing,
+                    });
+                }
+            }
+            if let Some(setting) = c.set_keep_alive.take() {
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::KeepAlive,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::KeepAlive,
+                    });
+                }
+            }
         }
     }
 
@@ -693,8 +1623,9 @@ impl Endpoint {
             self.connection_ids_initial
                 .remove(&self.connections[conn.0].initial_id);
         }
-        self.connection_ids
-            .remove(&self.connections[conn.0].local_id);
+        for id in self.connections[conn.0].cid_pool.iter() {
+            self.connection_ids.remove(id);
+        }
         self.connection_remotes
             .remove(&self.connections[conn.0].remote);
         self.ctx.dirty_conns.remove(&conn);
@@ -702,10 +1633,28 @@ impl Endpoint {
         self.connections.remove(conn.0);
     }
 
+    /// Handle an ICMP Packet Too Big message concerning a datagram sent to `remote`
+    ///
+    /// `mtu` is the largest UDP payload the path can carry, i.e. the MTU reported by the message less the sizes of the IP
+    /// and UDP headers. `quoted` is the start of the UDP payload the message echoes back. ICMP isn't authenticated, so
+    /// the message is only acted on if `quoted` is recognizably one of the connection's packets, and reports of less
+    /// than every QUIC path must support are taken to mean that minimum.
+    pub fn handle_icmp_ptb(&mut self, remote: SocketAddrV6, mtu: u16, quoted: &[u8]) {
+        let conn = if let Some(&x) = self.connection_remotes.get(&remote) {
+            x
+        } else {
+            return;
+        };
+        self.connections[conn.0].handle_ptb(&self.ctx.log, mtu, quoted);
+    }
+
     /// Handle a timer expiring
     pub fn timeout(&mut self, now: u64, conn: ConnectionHandle, timer: Timer) {
         match timer {
             Timer::Close => {
+                self.connections[conn.0].qlog(now, |qlog, group| {
+                    qlog.connection_state_updated(now, group, "closed")
+                });
                 self.ctx.io.push_back(Io::TimerStop {
                     connection: conn,
                     timer: Timer::Idle,
@@ -723,23 +1672,9 @@ impl Endpoint {
                 }
             }
             Timer::Idle => {
-                self.connections[conn.0].close_common(&mut self.ctx, now, conn);
-                let state = State::Draining(match self.connections[conn.0].state.take().unwrap() {
-                    State::Handshake(x) => x.into(),
-                    State::HandshakeFailed(x) => x.into(),
-                    State::Established(x) => x.into(),
-                    State::Closed(x) => x.into(),
-                    State::Draining(x) => x,
-                    State::Drained => unreachable!(),
-                });
-                self.connections[conn.0].state = Some(state);
-                self.ctx.events.push_back((
-                    conn,
-                    Event::ConnectionLost {
-                        reason: ConnectionError::TimedOut,
-                    },
-                ));
-                self.ctx.dirty_conns.insert(conn); // Ensure the loss detection timer cancellation goes through
+                // The peer has presumably gone away, so there's no one to tell or to wait for
+                trace!(self.ctx.log, "idle timeout"; "connection" => %self.connections[conn.0].local_id);
+                self.kill(conn, ConnectionError::TimedOut);
             }
             Timer::LossDetection => {
                 if self.connections[conn.0].awaiting_handshake {
@@ -747,9 +1682,13 @@ impl Endpoint {
                     let packets = self.connections[conn.0]
                         .sent_packets
                         .iter()
-                        .filter_map(
-                            |(&packet, info)| if info.handshake { Some(packet) } else { None },
-                        ).collect::<Vec<_>>();
+                        .filter_map(|(&packet, info)| {
+                            if info.space != SpaceId::Data {
+                                Some(packet)
+                            } else {
+                                None
+                            }
+                        }).collect::<Vec<_>>();
                     for number in packets {
                         let mut info = self.connections[conn.0]
                             .sent_packets
@@ -759,44 +1698,45 @@ impl Endpoint {
                         self.connections[conn.0].bytes_in_flight -= info.bytes as u64;
                     }
                     self.connections[conn.0].handshake_count += 1;
-                } else if self.connections[conn.0].loss_time != 0 {
-                    // Early retransmit or Time Loss Detection
-                    let largest = self.connections[conn.0].largest_acked_packet;
-                    self.connections[conn.0].detect_lost_packets(&self.ctx.config, now, largest);
-                } else if self.connections[conn.0].tlp_count < self.ctx.config.max_tlps {
-                    trace!(self.ctx.log, "sending TLP {number} in {pn}",
-                           number=self.connections[conn.0].tlp_count,
-                           pn=self.connections[conn.0].largest_sent_packet + 1;
-                           "outstanding" => ?self.connections[conn.0].sent_packets.keys().collect::<Vec<_>>(),
-                           "in flight" => self.connections[conn.0].bytes_in_flight);
-                    // Tail Loss Probe.
-                    self.ctx.io.push_back(Io::Transmit {
-                        destination: self.connections[conn.0].remote,
-                        packet: self.connections[conn.0].force_transmit(&self.ctx.config, now),
-                    });
-                    self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
-                    self.connections[conn.0].tlp_count += 1;
+                } else if self.connections[conn.0].earliest_loss_time().is_some() {
+                    // Time threshold loss detection or RACK
+                    self.connections[conn.0].detect_lost_packets(&self.ctx.config, now);
                 } else {
-                    trace!(self.ctx.log, "RTO fired, retransmitting"; "pn" => self.connections[conn.0].largest_sent_packet + 1,
+                    trace!(self.ctx.log, "PTO fired, sending probes"; "count" => self.connections[conn.0].pto_count,
                            "outstanding" => ?self.connections[conn.0].sent_packets.keys().collect::<Vec<_>>(),
                            "in flight" => self.connections[conn.0].bytes_in_flight);
-                    // RTO
-                    if self.connections[conn.0].rto_count == 0 {
-                        self.connections[conn.0].largest_sent_before_rto =
-                            self.connections[conn.0].largest_sent_packet;
-                    }
-                    for _ in 0..2 {
-                        self.ctx.io.push_back(Io::Transmit {
-                            destination: self.connections[conn.0].remote,
-                            packet: self.connections[conn.0].force_transmit(&self.ctx.config, now),
-                        });
+                    if self.connections[conn.0].pto_count >= 2 {
+                        // Probes went unanswered twice over, so the path itself may have failed
+                        let old = self.connections[conn.0].remote;
+                        self.connections[conn.0].fail_over(&mut self.ctx, conn);
+                        self.update_remote(conn, old);
                     }
-                    self.connections[conn.0].reset_idle_timeout(&self.ctx.config, now);
-                    self.connections[conn.0].rto_count += 1;
+                    self.connections[conn.0].pto_count += 1;
+                    self.connections[conn.0].probes_pending = 2;
                 }
                 self.connections[conn.0].set_loss_detection_alarm(&self.ctx.config);
                 self.ctx.dirty_conns.insert(conn);
             }
+            Timer::Ack => {
+                self.connections[conn.0].ack_timeout();
+                self.ctx.dirty_conns.insert(conn);
+            }
+            Timer::PathValidation => {
+                let old = self.connections[conn.0].remote;
+                self.connections[conn.0].path_validation_timeout(&mut self.ctx, conn, now);
+                self.update_remote(conn, old);
+                self.ctx.dirty_conns.insert(conn);
+            }
+            Timer::Pacing => {
+                // Tokens have accrued for the next datagram
+                self.ctx.dirty_conns.insert(conn);
+            }
+            Timer::KeepAlive => {
+                if let Some(State::Established(_)) = self.connections[conn.0].state {
+                    trace!(self.ctx.log, "sending keep-alive"; "connection" => %self.connections[conn.0].local_id);
+                    self.ping(conn);
+                }
+            }
         }
     }
 
@@ -812,7 +1752,47 @@ impl Endpoint {
         stream: StreamId,
         data: &[u8],
     ) -> Result<usize, WriteError> {
-        let r = self.connections[conn.0].write(stream, data);
+        self.write_vectored(conn, stream, &[data])
+    }
+
+    /// Transmit data gathered from several buffers on a stream
+    ///
+    /// Behaves as if the buffers were concatenated and passed to `write`; the data is copied once, not once per buffer.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active outgoing channel
+    pub fn write_vectored(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        bufs: &[&[u8]],
+    ) -> Result<usize, WriteError> {
+        let r = self.connections[conn.0].write_vectored(stream, bufs);
+        self.wrote(conn, stream, r)
+    }
+
+    /// Transmit already shared buffers on a stream, in order
+    ///
+    /// Like `write_vectored`, but the data is sent straight from `bufs` without being copied.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active outgoing channel
+    pub fn write_chunks(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        bufs: &[Bytes],
+    ) -> Result<usize, WriteError> {
+        let r = self.connections[conn.0].write_chunks(stream, bufs);
+        self.wrote(conn, stream, r)
+    }
+
+    fn wrote(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        r: Result<usize, WriteError>,
+    ) -> Result<usize, WriteError> {
         match r {
             Ok(n) => {
                 self.ctx.dirty_conns.insert(conn);
@@ -864,6 +1844,46 @@ impl Endpoint {
         }
     }
 
+    /// Read the next contiguous chunk of data from a stream
+    ///
+    /// Like `read`, but the caller needn't supply a buffer. Data that arrived in order is returned in the pieces it
+    /// was received in, without being copied.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active incoming channel
+    /// - when applied to a stream that has been read from out of order
+    pub fn read_chunk(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+    ) -> Result<Bytes, ReadError> {
+        self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
+        match self.connections[conn.0].read_chunk(stream) {
+            x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
+                self.connections[conn.0].maybe_cleanup(stream);
+                x
+            }
+            x => x,
+        }
+    }
+
+    /// Read data from a stream without consuming it
+    ///
+    /// Returns the same data as a `read` with the same `buf` would, which remains available to later reads. Finished
+    /// and reset streams are reported as by `read`, but remain so until `read` reports them.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active incoming channel
+    /// - when applied to a stream that has been read from out of order
+    pub fn peek(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        self.connections[conn.0].peek(stream, buf)
+    }
+
     /// Read data from a stream out of order
     ///
     /// Unlike `read`, this interface is not subject to head-of-line blocking within the stream, and hence can achieve
@@ -898,6 +1918,21 @@ impl Endpoint {
         self.connections[conn.0].reset(&mut self.ctx, stream, error_code, conn)
     }
 
+    /// Set the priority of a stream's outgoing data, 0 by default
+    ///
+    /// When several streams have data ready, each is sent a share of the available capacity that doubles with each
+    /// increment of its priority relative to the others. Takes effect from the next packet sent.
+    pub fn set_priority(&mut self, conn: ConnectionHandle, stream: StreamId, priority: i32) {
+        self.connections[conn.0]
+            .scheduler
+            .set_priority(stream, priority);
+    }
+
+    /// The priority of a stream's outgoing data
+    pub fn priority(&self, conn: ConnectionHandle, stream: StreamId) -> i32 {
+        self.connections[conn.0].scheduler.priority(stream)
+    }
+
     /// Instruct the peer to abandon transmitting data on a stream
     ///
     /// # Panics
@@ -909,9 +1944,39 @@ impl Endpoint {
 
     /// Create a new stream
     ///
-    /// Returns `None` if the maximum number of streams currently permitted by the remote endpoint are already open.
+    /// Returns `None` if the maximum number of streams currently permitted by the remote endpoint are already open, in
+    /// which case the peer is told we're blocked and `Event::StreamAvailable` is emitted once it permits more.
     pub fn open(&mut self, conn: ConnectionHandle, direction: Directionality) -> Option<StreamId> {
-        self.connections[conn.0].open(&self.ctx.config, direction)
+        let id = self.connections[conn.0].open(&self.ctx.config, direction);
+        if id.is_none() {
+            self.ctx.dirty_conns.insert(conn);
+        }
+        id
+    }
+
+    /// Move a client connection to a new path
+    ///
+    /// Call this after the local address changed, e.g. when moving between networks, with `remote` the address to send
+    /// subsequent packets to, normally unchanged. The new path is validated with a PATH_CHALLENGE, and a connection ID
+    /// the server hasn't seen us use is adopted if available, so that observers can't link the two paths.
+    ///
+    /// # Panics
+    /// - when applied to a server connection or one that isn't established
+    pub fn migrate(&mut self, conn: ConnectionHandle, remote: SocketAddrV6) {
+        let old = self.connections[conn.0].remote;
+        self.connections[conn.0].migrate(&mut self.ctx, remote);
+        self.update_remote(conn, old);
+        self.ctx.dirty_conns.insert(conn);
+    }
+
+    /// Replace every connection ID issued to the peer, asking it to retire the old ones
+    ///
+    /// The peer switches to one of the replacements as soon as it learns of them, after which packets addressed to the
+    /// old IDs are no longer routed to `conn`. Has no effect if connections are identified by address alone.
+    pub fn rotate_cids(&mut self, conn: ConnectionHandle) {
+        self.connections[conn.0].cid_pool.retire_all();
+        self.issue_cids(conn);
+        self.ctx.dirty_conns.insert(conn);
     }
 
     /// Ping the remote endpoint
@@ -922,6 +1987,71 @@ impl Endpoint {
         self.ctx.dirty_conns.insert(conn);
     }
 
+    /// Update the 1-RTT keys of a connection
+    ///
+    /// Takes effect once the handshake is confirmed and the peer has acknowledged a packet protected with the current
+    /// keys. Keys are also updated automatically every `Config::key_update_interval` packets.
+    pub fn force_key_update(&mut self, conn: ConnectionHandle) {
+        self.connections[conn.0].force_key_update(&self.ctx.config);
+        self.ctx.dirty_conns.insert(conn);
+    }
+
+    /// Queue an unreliable, unordered datagram for transmission
+    ///
+    /// Datagrams are never retransmitted, and may be lost, reordered, or dropped by the peer if not read promptly.
+    pub fn send_datagram(
+        &mut self,
+        conn: ConnectionHandle,
+        data: Bytes,
+    ) -> Result<(), SendDatagramError> {
+        self.connections[conn.0].send_datagram(&self.ctx.config, data)?;
+        self.ctx.dirty_conns.insert(conn);
+        Ok(())
+    }
+
+    /// Open an additional path to the peer at `remote`, to be used alongside the active one once it's validated
+    ///
+    /// Requires `Config::multipath_policy` and peer support, and a spare connection ID from the peer so that observers
+    /// can't link the paths. Packets on every path leave from the endpoint's one socket.
+    pub fn add_path(
+        &mut self,
+        conn: ConnectionHandle,
+        remote: SocketAddrV6,
+    ) -> Result<PathId, AddPathError> {
+        let id = self.connections[conn.0].add_path(&mut self.ctx, remote)?;
+        self.ctx.dirty_conns.insert(conn);
+        Ok(id)
+    }
+
+    /// Receive a datagram sent by the peer, if any is buffered
+    pub fn recv_datagram(&mut self, conn: ConnectionHandle) -> Option<Bytes> {
+        self.connections[conn.0].recv_datagram()
+    }
+
+    /// Largest datagram payload that may currently be sent on `conn`
+    ///
+    /// None if the peer does not support datagrams.
+    pub fn get_max_datagram_size(&self, conn: ConnectionHandle) -> Option<usize> {
+        self.connections[conn.0].max_datagram_size()
+    }
+
+    /// Ask the peer to acknowledge packets less often
+    ///
+    /// The peer may then receive up to `ack_eliciting_threshold` ack-eliciting packets before acknowledging them, so
+    /// long as it acknowledges each within `max_ack_delay` μs. Fewer ACKs save work on both ends when sending a lot of
+    /// data, but slow loss recovery. Fails if the peer didn't advertise support, or if `max_ack_delay` is shorter than
+    /// it can honor.
+    pub fn request_ack_frequency(
+        &mut self,
+        conn: ConnectionHandle,
+        ack_eliciting_threshold: u64,
+        max_ack_delay: u64,
+    ) -> Result<(), AckFrequencyError> {
+        self.connections[conn.0].request_ack_frequency(ack_eliciting_threshold, max_ack_delay)?;
+        self.ctx.dirty_conns.insert(conn);
+        Ok(())
+    }
+
     /// Close a connection immediately
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility to call this only when
@@ -934,6 +2064,38 @@ impl Endpoint {
         self.connections[conn.0].close(&mut self.ctx, now, conn, error_code, reason);
     }
 
+    /// Abandon a connection immediately, without notifying the peer
+    ///
+    /// Unlike `close`, nothing more is sent, so the peer only finds out when its idle timeout expires or a stateless
+    /// reset answers its next packet. The connection is left drained, with any events not yet polled discarded, and
+    /// `close` forgets it as for any other drained connection, so handles referring to it stay valid until then. A
+    /// connection that's already closing is instead forgotten once it's drained, as after `close`, since the timer that
+    /// ends its drain period is already running.
+    pub fn force_close(&mut self, conn: ConnectionHandle) {
+        let closing = match *self.connections[conn.0].state.as_ref().unwrap() {
+            State::Drained => false,
+            ref x => x.is_closed(),
+        };
+        if closing {
+            self.connections[conn.0]
+                .state
+                .as_mut()
+                .unwrap()
+                .set_app_closed();
+            return;
+        }
+        // Timers not yet started by the application needn't be
+        self.ctx.io.retain(|x| match *x {
+            Io::TimerStart { connection, .. } => connection != conn,
+            _ => true,
+        });
+        self.stop_timers(conn);
+        self.ctx.events.retain(|&(x, _)| x != conn);
+        self.ctx.dirty_conns.remove(&conn);
+        self.ctx.readable_conns.remove(&conn);
+        self.connections[conn.0].state = Some(State::Drained);
+    }
+
     /// Look up whether we're the client or server of `conn`.
     pub fn get_side(&self, conn: ConnectionHandle) -> Side {
         self.connections[conn.0].side
@@ -950,6 +2112,20 @@ impl Endpoint {
     pub fn get_remote_address(&self, conn: ConnectionHandle) -> &SocketAddrV6 {
         &self.connections[conn.0].remote
     }
+    /// Whether `conn` has been dropped without a draining period, e.g. by timing out or being reset
+    pub fn is_drained(&self, conn: ConnectionHandle) -> bool {
+        self.connections[conn.0]
+            .state
+            .as_ref()
+            .unwrap()
+            .is_drained()
+    }
+    /// The value of the transport parameter with `id` sent by the peer of `conn`, if any
+    ///
+    /// Only parameters not defined by QUIC itself are available. None until the peer's parameters are received.
+    pub fn get_custom_param(&self, conn: ConnectionHandle, id: u64) -> Option<&Bytes> {
+        self.connections[conn.0].custom_param(id)
+    }
     pub fn get_protocol(&self, conn: ConnectionHandle) -> Option<&[u8]> {
         if let State::Established(ref state) = *self.connections[conn.0].state.as_ref().unwrap() {
             state.tls.get_alpn_protocol().map(|p| p.as_bytes())
@@ -962,10 +2138,25 @@ impl Endpoint {
         self.connections[conn.0].bytes_in_flight
     }
 
+    /// RTT, loss and throughput measurements of the path `conn` is using
+    pub fn get_path_stats(&self, conn: ConnectionHandle) -> PathStats {
+        self.connections[conn.0].path_stats()
+    }
+
+    /// Flow control stalls of `conn` in each direction
+    pub fn get_flow_control_stats(&self, conn: ConnectionHandle) -> FlowControlStats {
+        self.connections[conn.0].flow_control_stats
+    }
+
+    /// Traffic counters for the endpoint as a whole
+    pub fn stats(&self) -> EndpointStats {
+        self.ctx.stats
+    }
+
     /// Number of bytes worth of non-ack-only packets that may be sent.
     pub fn get_congestion_state(&self, conn: ConnectionHandle) -> u64 {
         let c = &self.connections[conn.0];
-        c.congestion_window.saturating_sub(c.bytes_in_flight)
+        c.congestion.window().saturating_sub(c.bytes_in_flight)
     }
 
     /// The name a client supplied via SNI.
@@ -979,6 +2170,18 @@ impl Endpoint {
         }
     }
 
+    /// The certificate chain the peer authenticated `conn` with, if any
+    ///
+    /// `None` until the handshake completes. Clients only present certificates to servers configured with
+    /// `build_server_config_with_client_auth` or `require_client_auth`. The chain remains available after the connection
+    /// is closed.
+    pub fn get_peer_certificates(&self, conn: ConnectionHandle) -> Option<&[Certificate]> {
+        self.connections[conn.0]
+            .peer_certificates
+            .as_ref()
+            .map(|x| &x[..])
+    }
+
     /// Whether a previous session was successfully resumed by `conn`.
     pub fn get_session_resumed(&self, _: ConnectionHandle) -> bool {
         false // TODO: fixme?
@@ -1021,9 +2224,37 @@ pub enum Event {
     StreamAvailable {
         directionality: Directionality,
     },
+    /// The peer reports it has data to send but is blocked by flow control at `offset`
+    ///
+    /// `stream` is `None` when the connection-level limit is the obstacle. Frequent occurrences suggest our receive
+    /// windows are too small or data isn't read promptly.
+    PeerBlocked {
+        stream: Option<StreamId>,
+        offset: u64,
+    },
+    /// The server will accept 0-RTT data on a later connection given `ticket` via `Endpoint::connect_with_ticket`
     NewSessionTicket {
         ticket: Box<[u8]>,
     },
+    /// A datagram was received and may be retrieved with `recv_datagram`
+    DatagramReceived,
+    /// The connection moved to a new path, to which subsequent packets will be sent
+    PathMigrated {
+        old: SocketAddrV6,
+        new: SocketAddrV6,
+    },
+    /// The peer answered a PATH_CHALLENGE sent to `remote`, proving that it receives packets sent there
+    PathValidated { remote: SocketAddrV6 },
+    /// The peer didn't answer PATH_CHALLENGEs sent to `remote` in time
+    ///
+    /// A server whose peer migrated to `remote` returns to the last validated path. A client that can't reach the server
+    /// from its new address may want to close the connection.
+    PathValidationFailed { remote: SocketAddrV6 },
+    /// A packet carrying a PING frame, sent at `sent` (μs), was acknowledged
+    ///
+    /// Reported for PINGs requested by `Endpoint::ping` and sent as keep-alives alike. Only the most recently sent of
+    /// those acknowledged by a single ACK frame is reported.
+    PingAcknowledged { sent: u64 },
 }
 
 /// I/O operations to be immediately executed the backend.
@@ -1031,6 +2262,8 @@ pub enum Event {
 pub enum Io {
     Transmit {
         destination: SocketAddrV6,
+        /// Codepoint to set in the IP header, if any
+        ecn: Option<EcnCodepoint>,
         packet: Box<[u8]>,
     },
     /// Start or reset a timer
@@ -1046,11 +2279,29 @@ pub enum Io {
     },
 }
 
+/// A datagram gathered by `Endpoint::poll_transmit_batch`
+#[derive(Debug)]
+pub struct Transmit {
+    pub destination: SocketAddrV6,
+    /// Codepoint to set in the IP header, if any
+    pub ecn: Option<EcnCodepoint>,
+    pub packet: Box<[u8]>,
+    /// Size of each datagram in `packet`, if it holds several to be split apart by segmentation offload
+    ///
+    /// Every datagram but the last is exactly this size; the last may be shorter. Always `None` when gathered by the
+    /// endpoint, which leaves merging datagrams to the I/O layer that knows whether the socket supports it.
+    pub segment_size: Option<u16>,
+}
+
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub enum Timer {
     Close,
     LossDetection,
     Idle,
+    Ack,
+    PathValidation,
+    Pacing,
+    KeepAlive,
 }
 
 impl slog::Value for Timer {
@@ -1076,17 +2327,18 @@ where
     R: Into<state::CloseReason>,
 {
     let mut buf = Vec::<u8>::new();
-    Header::Long {
+    let partial_encode = Header::Long {
+        version: crypto.version(),
         ty: types::HANDSHAKE,
         destination_id: remote_id.clone(),
         source_id: local_id.clone(),
-        number: packet_number,
+        number: PacketNumber::U32(packet_number),
     }.encode(&mut buf);
     let header_len = buf.len();
-    let max_len = MIN_MTU - header_len as u16 - AEAD_TAG_SIZE as u16;
+    let max_len = MIN_MTU - header_len as u16 - crypto.tag_len() as u16;
     match reason.into() {
         state::CloseReason::Application(ref x) => x.encode(&mut buf, max_len),
-        state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
+        state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len, crypto.version()),
     }
     if let Some(data) = tls_alert {
         if !data.is_empty() {
@@ -1098,7 +2350,9 @@ where
             }.encode(false, &mut buf);
         }
     }
-    set_payload_length(&mut buf, header_len);
+    set_payload_length(&mut buf, partial_encode.len_slot.unwrap(), crypto.tag_len())
+        .expect("close packets fit in the minimum MTU");
     crypto.encrypt(packet_number as u64, &mut buf, header_len);
+    Header::encrypt_header(&mut buf, header_len, crypto.local_header_key());
     buf.into()
 }
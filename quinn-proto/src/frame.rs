@@ -1,12 +1,13 @@
 use std::ops::Range;
-use std::{fmt, io, mem};
+use std::{fmt, io, mem, str};
 
 use bytes::{Buf, BufMut, Bytes};
 
 use coding::{self, BufExt, BufMutExt, UnexpectedEnd};
 use range_set::RangeSet;
 use {
-    varint, ConnectionId, StreamId, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE,
+    varint, ConnectionId, StreamId, TransportError, Version, MAX_CID_SIZE, MIN_CID_SIZE,
+    RESET_TOKEN_SIZE,
 };
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -31,6 +32,13 @@ impl Type {
             None
         }
     }
+    fn datagram(self) -> Option<DatagramInfo> {
+        if self.0 >= 0x30 && self.0 <= 0x31 {
+            Some(DatagramInfo(self.0))
+        } else {
+            None
+        }
+    }
 }
 
 impl coding::Value for Type {
@@ -53,6 +61,7 @@ macro_rules! frame_types {
                 match self.0 {
                     $($val => f.write_str(stringify!($name)),)*
                     x if x >= 0x10 && x <= 0x17 => f.write_str("STREAM"),
+                    x if x >= 0x30 && x <= 0x31 => f.write_str("DATAGRAM"),
                     _ => write!(f, "<unknown {:02x}>", self.0),
                 }
             }
@@ -75,6 +84,15 @@ impl StreamInfo {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct DatagramInfo(u8);
+
+impl DatagramInfo {
+    fn len(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+}
+
 frame_types!{
     PADDING = 0x00,
     RST_STREAM = 0x01,
@@ -92,6 +110,12 @@ frame_types!{
     ACK = 0x0d,
     PATH_CHALLENGE = 0x0e,
     PATH_RESPONSE = 0x0f,
+    NEW_TOKEN = 0x19,
+    ACK_ECN = 0x1a,
+    RETIRE_CONNECTION_ID = 0x1b,
+    HANDSHAKE_DONE = 0x1e,
+    DATAGRAM = 0x30,
+    ACK_FREQUENCY = 0xaf,
 }
 
 #[derive(Debug)]
@@ -101,36 +125,23 @@ pub enum Frame {
     ConnectionClose(ConnectionClose),
     ApplicationClose(ApplicationClose),
     MaxData(u64),
-    MaxStreamData {
-        id: StreamId,
-        offset: u64,
-    },
+    MaxStreamData { id: StreamId, offset: u64 },
     MaxStreamId(StreamId),
     Ping,
-    Blocked {
-        offset: u64,
-    },
-    StreamBlocked {
-        id: StreamId,
-        offset: u64,
-    },
-    StreamIdBlocked {
-        id: StreamId,
-    },
-    StopSending {
-        id: StreamId,
-        error_code: u16,
-    },
+    Blocked { offset: u64 },
+    StreamBlocked { id: StreamId, offset: u64 },
+    StreamIdBlocked { id: StreamId },
+    StopSending { id: StreamId, error_code: u16 },
     Ack(Ack),
     Stream(Stream),
     PathChallenge(u64),
     PathResponse(u64),
-    NewConnectionId {
-        sequence: u64,
-        id: ConnectionId,
-        reset_token: [u8; 16],
-    },
-    Invalid(Type),
+    NewConnectionId(NewConnectionId),
+    RetireConnectionId { sequence: u64 },
+    NewToken { token: Bytes },
+    HandshakeDone,
+    Datagram(Datagram),
+    AckFrequency(AckFrequency),
 }
 
 impl Frame {
@@ -149,7 +160,11 @@ impl Frame {
             StreamBlocked { .. } => Type::STREAM_BLOCKED,
             StreamIdBlocked { .. } => Type::STREAM_ID_BLOCKED,
             StopSending { .. } => Type::STOP_SENDING,
-            Ack(_) => Type::ACK,
+            Ack(ref x) => if x.ecn.is_some() {
+                Type::ACK_ECN
+            } else {
+                Type::ACK
+            },
             Stream(ref x) => {
                 let mut ty = 0x10;
                 if x.fin {
@@ -162,15 +177,151 @@ impl Frame {
             }
             PathChallenge(_) => Type::PATH_CHALLENGE,
             PathResponse(_) => Type::PATH_RESPONSE,
-            NewConnectionId { .. } => Type::NEW_CONNECTION_ID,
-            Invalid(ty) => ty,
+            NewConnectionId(_) => Type::NEW_CONNECTION_ID,
+            RetireConnectionId { .. } => Type::RETIRE_CONNECTION_ID,
+            NewToken { .. } => Type::NEW_TOKEN,
+            HandshakeDone => Type::HANDSHAKE_DONE,
+            Datagram(_) => Type(0x31),
+            AckFrequency(_) => Type::ACK_FREQUENCY,
+        }
+    }
+
+    /// Exact size of the frame as written by `encode` in packets of `version`
+    pub fn size(&self, version: Version) -> usize {
+        use self::Frame::*;
+        match *self {
+            Padding | Ping | HandshakeDone => 1,
+            RstStream(ref x) => 1 + var_size(x.id.0) + 2 + var_size(x.final_offset),
+            ConnectionClose(ref x) => {
+                let ty = if version.close_frame_type() {
+                    var_size(x.frame_type.map_or(0, |x| x.0 as u64))
+                } else {
+                    0
+                };
+                1 + 2 + ty + var_size(x.reason.len() as u64) + x.reason.len()
+            }
+            ApplicationClose(ref x) => 1 + 2 + var_size(x.reason.len() as u64) + x.reason.len(),
+            MaxData(x) | Blocked { offset: x } => 1 + var_size(x),
+            MaxStreamData { id, offset } | StreamBlocked { id, offset } => {
+                1 + var_size(id.0) + var_size(offset)
+            }
+            MaxStreamId(id) | StreamIdBlocked { id } => 1 + var_size(id.0),
+            StopSending { id, .. } => 1 + var_size(id.0) + 2,
+            Ack(ref x) => {
+                let ecn = x
+                    .ecn
+                    .map_or(0, |x| var_size(x.ect0) + var_size(x.ect1) + var_size(x.ce));
+                1 + var_size(x.largest)
+                    + var_size(x.delay)
+                    + var_size(x.iter().count() as u64 - 1)
+                    + x.additional.len()
+                    + ecn
+            }
+            Stream(ref x) => x.size(true),
+            PathChallenge(_) | PathResponse(_) => 1 + 8,
+            NewConnectionId(ref x) => {
+                1 + var_size(x.sequence)
+                    + var_size(x.retire_prior_to)
+                    + 1
+                    + x.id.len()
+                    + RESET_TOKEN_SIZE
+            }
+            RetireConnectionId { sequence } => 1 + var_size(sequence),
+            NewToken { ref token } => 1 + var_size(token.len() as u64) + token.len(),
+            Datagram(ref x) => x.size(),
+            AckFrequency(ref x) => {
+                1 + var_size(x.sequence)
+                    + var_size(x.ack_eliciting_threshold)
+                    + var_size(x.request_max_ack_delay)
+            }
+        }
+    }
+
+    /// Encode the frame such that `Iter` decodes it back from a packet of `version`, giving STREAM and DATAGRAM frames
+    /// an explicit length
+    pub fn encode<W: BufMut>(&self, out: &mut W, version: Version) {
+        use self::Frame::*;
+        match *self {
+            Padding | Ping | HandshakeDone => out.write(self.ty()),
+            RstStream(ref x) => x.encode(out),
+            // Decoded reasons are never longer than the cap, so aren't truncated here
+            ConnectionClose(ref x) => x.encode(out, u16::max_value(), version),
+            ApplicationClose(ref x) => x.encode(out, u16::max_value()),
+            MaxData(x) | Blocked { offset: x } => {
+                out.write(self.ty());
+                out.write_var(x);
+            }
+            MaxStreamData { id, offset } | StreamBlocked { id, offset } => {
+                out.write(self.ty());
+                out.write(id);
+                out.write_var(offset);
+            }
+            MaxStreamId(id) | StreamIdBlocked { id } => {
+                out.write(self.ty());
+                out.write(id);
+            }
+            StopSending { id, error_code } => {
+                out.write(self.ty());
+                out.write(id);
+                out.write(error_code);
+            }
+            Ack(ref x) => {
+                out.write(self.ty());
+                out.write_var(x.largest);
+                out.write_var(x.delay);
+                out.write_var(x.iter().count() as u64 - 1);
+                out.put_slice(&x.additional);
+                if let Some(ref ecn) = x.ecn {
+                    ecn.encode(out);
+                }
+            }
+            Stream(ref x) => x.encode(true, out),
+            PathChallenge(x) | PathResponse(x) => {
+                out.write(self.ty());
+                out.write(x);
+            }
+            NewConnectionId(ref x) => x.encode(out),
+            RetireConnectionId { sequence } => {
+                out.write(self.ty());
+                out.write_var(sequence);
+            }
+            NewToken { ref token } => {
+                out.write(self.ty());
+                out.write_var(token.len() as u64);
+                out.put_slice(token);
+            }
+            Datagram(ref x) => x.encode(true, out),
+            AckFrequency(ref x) => x.encode(out),
         }
     }
 }
 
+fn var_size(x: u64) -> usize {
+    varint::size(x).unwrap()
+}
+
+/// Longest reason phrase accepted in a close frame, in bytes
+pub const MAX_REASON_LEN: usize = 1024;
+
+/// Length of the longest prefix of `reason` of at most `max_len` bytes that's valid UTF-8
+///
+/// This both avoids splitting a character and stops short of anything the peer would reject as malformed.
+fn reason_len(reason: &[u8], max_len: usize) -> usize {
+    let len = reason.len().min(max_len);
+    match str::from_utf8(&reason[..len]) {
+        Ok(_) => len,
+        Err(e) => e.valid_up_to(),
+    }
+}
+
+/// A connection closed by the transport, because of an error or otherwise
 #[derive(Debug, Clone)]
 pub struct ConnectionClose<T = Bytes> {
     pub error_code: TransportError,
+    /// Type of the frame that caused the error, if any
+    ///
+    /// Only sent in versions whose CONNECTION_CLOSE has room for it, and always `None` when received in others.
+    pub frame_type: Option<Type>,
     pub reason: T,
 }
 
@@ -192,6 +343,7 @@ impl From<TransportError> for ConnectionClose {
     fn from(x: TransportError) -> Self {
         ConnectionClose {
             error_code: x,
+            frame_type: x.frame_type(),
             reason: Bytes::new(),
         }
     }
@@ -201,17 +353,26 @@ impl<T> ConnectionClose<T>
 where
     T: AsRef<[u8]>,
 {
-    pub fn encode<W: BufMut>(&self, out: &mut W, max_len: u16) {
+    pub fn encode<W: BufMut>(&self, out: &mut W, max_len: u16, version: Version) {
         out.write(Type::CONNECTION_CLOSE);
         out.write(self.error_code);
-        let max_len =
-            max_len as usize - 3 - varint::size(self.reason.as_ref().len() as u64).unwrap();
-        let actual_len = self.reason.as_ref().len().min(max_len);
+        let mut ty_len = 0;
+        if version.close_frame_type() {
+            let ty = self.frame_type.map_or(0, |x| x.0 as u64);
+            varint::write(ty, out).unwrap();
+            ty_len = varint::size(ty).unwrap();
+        }
+        let max_len = max_len as usize
+            - 3
+            - ty_len
+            - varint::size(self.reason.as_ref().len() as u64).unwrap();
+        let actual_len = reason_len(self.reason.as_ref(), max_len.min(MAX_REASON_LEN));
         varint::write(actual_len as u64, out).unwrap();
         out.put_slice(&self.reason.as_ref()[0..actual_len]);
     }
 }
 
+/// A connection closed by the application, which carries no frame type
 #[derive(Debug, Clone)]
 pub struct ApplicationClose<T = Bytes> {
     pub error_code: u16,
@@ -244,7 +405,7 @@ where
         out.write(self.error_code);
         let max_len =
             max_len as usize - 3 - varint::size(self.reason.as_ref().len() as u64).unwrap();
-        let actual_len = self.reason.as_ref().len().min(max_len);
+        let actual_len = reason_len(self.reason.as_ref(), max_len.min(MAX_REASON_LEN));
         varint::write(actual_len as u64, out).unwrap();
         out.put_slice(&self.reason.as_ref()[0..actual_len]);
     }
@@ -255,6 +416,7 @@ pub struct Ack {
     pub largest: u64,
     pub delay: u64,
     pub additional: Bytes,
+    pub ecn: Option<EcnCounts>,
 }
 
 impl<'a> IntoIterator for &'a Ack {
@@ -267,12 +429,21 @@ impl<'a> IntoIterator for &'a Ack {
 }
 
 impl Ack {
-    pub fn encode<W: BufMut>(delay: u64, ranges: &RangeSet, buf: &mut W) {
+    pub fn encode<W: BufMut>(
+        delay: u64,
+        ranges: &RangeSet,
+        ecn: Option<&EcnCounts>,
+        buf: &mut W,
+    ) {
         let mut rest = ranges.iter().rev();
         let first = rest.next().unwrap();
         let largest = first.end - 1;
         let first_size = first.end - first.start;
-        buf.write(Type::ACK);
+        buf.write(if ecn.is_some() {
+            Type::ACK_ECN
+        } else {
+            Type::ACK
+        });
         varint::write(largest, buf).unwrap();
         varint::write(delay, buf).unwrap();
         varint::write(ranges.len() as u64 - 1, buf).unwrap();
@@ -284,6 +455,9 @@ impl Ack {
             varint::write(size - 1, buf).unwrap();
             prev = block.start;
         }
+        if let Some(x) = ecn {
+            x.encode(buf)
+        }
     }
 
     pub fn iter(&self) -> AckIter {
@@ -291,6 +465,22 @@ impl Ack {
     }
 }
 
+/// Number of packets received with each ECN codepoint
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct EcnCounts {
+    pub ect0: u64,
+    pub ect1: u64,
+    pub ce: u64,
+}
+
+impl EcnCounts {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        varint::write(self.ect0, out).unwrap();
+        varint::write(self.ect1, out).unwrap();
+        varint::write(self.ce, out).unwrap();
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Stream<T = Bytes> {
     pub id: StreamId,
@@ -324,12 +514,112 @@ where
         }
         out.put_slice(self.data.as_ref());
     }
+
+    /// Size of the frame when encoded with or without an explicit length
+    pub fn size(&self, length: bool) -> usize {
+        let len = self.data.as_ref().len();
+        let mut size = 1 + var_size(self.id.0) + len;
+        if self.offset != 0 {
+            size += var_size(self.offset);
+        }
+        if length {
+            size += var_size(len as u64);
+        }
+        size
+    }
+}
+
+/// An unreliable, unordered application datagram (RFC 9221)
+#[derive(Debug, Clone)]
+pub struct Datagram<T = Bytes> {
+    pub data: T,
+}
+
+impl<T> Datagram<T>
+where
+    T: AsRef<[u8]>,
+{
+    pub fn encode<W: BufMut>(&self, length: bool, out: &mut W) {
+        out.put_u8(if length { 0x31 } else { 0x30 });
+        if length {
+            varint::write(self.data.as_ref().len() as u64, out).unwrap();
+        }
+        out.put_slice(self.data.as_ref());
+    }
+
+    /// Size of the frame when encoded with an explicit length
+    pub fn size(&self) -> usize {
+        let len = self.data.as_ref().len();
+        1 + varint::size(len as u64).unwrap() + len
+    }
+}
+
+/// Issues the peer a connection ID it may address packets to
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NewConnectionId {
+    /// Order of issue, starting from 0 for the ID chosen during the handshake
+    pub sequence: u64,
+    /// IDs with a smaller sequence number must be retired by the peer
+    pub retire_prior_to: u64,
+    pub id: ConnectionId,
+    /// Token the peer can recognize a stateless reset by once it has used `id`
+    pub reset_token: [u8; RESET_TOKEN_SIZE],
+}
+
+impl NewConnectionId {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::NEW_CONNECTION_ID);
+        out.write_var(self.sequence);
+        out.write_var(self.retire_prior_to);
+        out.write(self.id.len() as u8);
+        out.put_slice(&self.id);
+        out.put_slice(&self.reset_token);
+    }
+}
+
+/// Asks the peer to acknowledge packets less often than it otherwise would
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AckFrequency {
+    /// Orders requests, so that a late retransmission can't undo a newer one
+    pub sequence: u64,
+    /// Number of ack-eliciting packets the peer may receive without acknowledging them immediately
+    pub ack_eliciting_threshold: u64,
+    /// Longest the peer may delay an acknowledgement (μs)
+    pub request_max_ack_delay: u64,
+}
+
+impl AckFrequency {
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::ACK_FREQUENCY);
+        out.write_var(self.sequence);
+        out.write_var(self.ack_eliciting_threshold);
+        out.write_var(self.request_max_ack_delay);
+    }
 }
 
 pub struct Iter {
     // TODO: ditch io::Cursor after bytes 0.5
     bytes: io::Cursor<Bytes>,
     last_ty: Option<Type>,
+    /// Version of the packet the frames came in, which determines some of their layouts
+    version: Version,
+}
+
+/// A frame that couldn't be decoded
+///
+/// Nothing after it in the same packet can be decoded either, since the frame's extent is unknown.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InvalidFrame {
+    /// Type of the offending frame
+    pub ty: Type,
+    /// What was wrong with it
+    pub reason: &'static str,
+}
+
+impl From<InvalidFrame> for TransportError {
+    fn from(x: InvalidFrame) -> Self {
+        TransportError::frame(x.ty)
+    }
 }
 
 enum IterErr {
@@ -338,6 +628,16 @@ enum IterErr {
     Malformed,
 }
 
+impl IterErr {
+    fn reason(&self) -> &'static str {
+        match *self {
+            IterErr::UnexpectedEnd => "unexpected end",
+            IterErr::InvalidFrameId => "unknown frame type",
+            IterErr::Malformed => "malformed",
+        }
+    }
+}
+
 impl From<UnexpectedEnd> for IterErr {
     fn from(_: UnexpectedEnd) -> Self {
         IterErr::UnexpectedEnd
@@ -345,10 +645,11 @@ impl From<UnexpectedEnd> for IterErr {
 }
 
 impl Iter {
-    pub fn new(payload: Bytes) -> Self {
+    pub fn new(payload: Bytes, version: Version) -> Self {
         Iter {
             bytes: io::Cursor::new(payload),
             last_ty: None,
+            version,
         }
     }
 
@@ -362,6 +663,15 @@ impl Iter {
         Ok(self.bytes.get_ref().slice(start, start + len as usize))
     }
 
+    /// Take a close frame's reason phrase, which must be short UTF-8
+    fn take_reason(&mut self) -> Result<Bytes, IterErr> {
+        let reason = self.take_len()?;
+        if reason.len() > MAX_REASON_LEN || str::from_utf8(&reason).is_err() {
+            return Err(IterErr::Malformed);
+        }
+        Ok(reason)
+    }
+
     fn try_next(&mut self) -> Result<Frame, IterErr> {
         let ty = self.bytes.get::<Type>()?;
         self.last_ty = Some(ty);
@@ -374,11 +684,20 @@ impl Iter {
             }),
             Type::CONNECTION_CLOSE => Frame::ConnectionClose(ConnectionClose {
                 error_code: self.bytes.get()?,
-                reason: self.take_len()?,
+                frame_type: if !self.version.close_frame_type() {
+                    None
+                } else {
+                    match self.bytes.get_var()? {
+                        0 => None,
+                        x if x <= u8::max_value() as u64 => Some(Type(x as u8)),
+                        _ => return Err(IterErr::Malformed),
+                    }
+                },
+                reason: self.take_reason()?,
             }),
             Type::APPLICATION_CLOSE => Frame::ApplicationClose(ApplicationClose {
                 error_code: self.bytes.get()?,
-                reason: self.take_len()?,
+                reason: self.take_reason()?,
             }),
             Type::MAX_DATA => Frame::MaxData(self.bytes.get_var()?),
             Type::MAX_STREAM_DATA => Frame::MaxStreamData {
@@ -401,7 +720,7 @@ impl Iter {
                 id: self.bytes.get()?,
                 error_code: self.bytes.get()?,
             },
-            Type::ACK => {
+            Type::ACK | Type::ACK_ECN => {
                 let largest = self.bytes.get_var()?;
                 let delay = self.bytes.get_var()?;
                 let extra_blocks = self.bytes.get_var()? as usize;
@@ -409,16 +728,30 @@ impl Iter {
                 let len = scan_ack_blocks(&self.bytes.bytes()[..], largest, extra_blocks)
                     .ok_or(UnexpectedEnd)?;
                 self.bytes.advance(len);
+                let ecn = if ty == Type::ACK_ECN {
+                    Some(EcnCounts {
+                        ect0: self.bytes.get_var()?,
+                        ect1: self.bytes.get_var()?,
+                        ce: self.bytes.get_var()?,
+                    })
+                } else {
+                    None
+                };
                 Frame::Ack(Ack {
                     delay,
                     largest,
                     additional: self.bytes.get_ref().slice(start, start + len),
+                    ecn,
                 })
             }
             Type::PATH_CHALLENGE => Frame::PathChallenge(self.bytes.get()?),
             Type::PATH_RESPONSE => Frame::PathResponse(self.bytes.get()?),
             Type::NEW_CONNECTION_ID => {
                 let sequence = self.bytes.get_var()?;
+                let retire_prior_to = self.bytes.get_var()?;
+                if retire_prior_to > sequence {
+                    return Err(IterErr::Malformed);
+                }
                 let length = self.bytes.get::<u8>()? as usize;
                 if length < MIN_CID_SIZE || length > MAX_CID_SIZE {
                     return Err(IterErr::Malformed);
@@ -434,46 +767,86 @@ impl Iter {
                 }
                 let mut reset_token = [0; RESET_TOKEN_SIZE];
                 self.bytes.copy_to_slice(&mut reset_token);
-                Frame::NewConnectionId {
+                Frame::NewConnectionId(NewConnectionId {
                     sequence,
+                    retire_prior_to,
                     id,
                     reset_token,
+                })
+            }
+            Type::RETIRE_CONNECTION_ID => Frame::RetireConnectionId {
+                sequence: self.bytes.get_var()?,
+            },
+            Type::NEW_TOKEN => {
+                let token = self.take_len()?;
+                if token.is_empty() {
+                    return Err(IterErr::Malformed);
                 }
+                Frame::NewToken { token }
             }
-            _ => match ty.stream() {
-                Some(s) => Frame::Stream(Stream {
-                    id: self.bytes.get()?,
-                    offset: if s.off() { self.bytes.get_var()? } else { 0 },
-                    fin: s.fin(),
-                    data: if s.len() {
+            Type::HANDSHAKE_DONE if self.version.handshake_done() => Frame::HandshakeDone,
+            Type::ACK_FREQUENCY => Frame::AckFrequency(AckFrequency {
+                sequence: self.bytes.get_var()?,
+                ack_eliciting_threshold: self.bytes.get_var()?,
+                request_max_ack_delay: self.bytes.get_var()?,
+            }),
+            _ => {
+                if let Some(s) = ty.stream() {
+                    let id = self.bytes.get()?;
+                    let offset = if s.off() { self.bytes.get_var()? } else { 0 };
+                    let data = if s.len() {
                         self.take_len()?
                     } else {
-                        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
-                        x.advance(self.bytes.position() as usize);
-                        self.bytes.set_position(0);
-                        x
-                    },
-                }),
-                None => {
+                        self.take_remaining()
+                    };
+                    // Stream offsets must be representable as varints
+                    if offset + data.len() as u64 > 2u64.pow(62) - 1 {
+                        return Err(IterErr::Malformed);
+                    }
+                    Frame::Stream(Stream {
+                        id,
+                        offset,
+                        fin: s.fin(),
+                        data,
+                    })
+                } else if let Some(d) = ty.datagram() {
+                    Frame::Datagram(Datagram {
+                        data: if d.len() {
+                            self.take_len()?
+                        } else {
+                            self.take_remaining()
+                        },
+                    })
+                } else {
                     return Err(IterErr::InvalidFrameId);
                 }
-            },
+            }
         })
     }
+
+    fn take_remaining(&mut self) -> Bytes {
+        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
+        x.advance(self.bytes.position() as usize);
+        self.bytes.set_position(0);
+        x
+    }
 }
 
 impl Iterator for Iter {
-    type Item = Frame;
+    type Item = Result<Frame, InvalidFrame>;
     fn next(&mut self) -> Option<Self::Item> {
         if !self.bytes.has_remaining() {
             return None;
         }
         match self.try_next() {
-            Ok(x) => Some(x),
-            Err(_) => {
+            Ok(x) => Some(Ok(x)),
+            Err(e) => {
                 // Corrupt frame, skip it and everything that follows
                 self.bytes = io::Cursor::new(Bytes::new());
-                Some(Frame::Invalid(self.last_ty.unwrap()))
+                Some(Err(InvalidFrame {
+                    ty: self.last_ty.unwrap(),
+                    reason: e.reason(),
+                }))
             }
         }
     }
@@ -520,7 +893,7 @@ impl<'a> Iterator for AckIter<'a> {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct RstStream {
     pub id: StreamId,
     pub error_code: u16,
@@ -536,10 +909,109 @@ impl RstStream {
     }
 }
 
+/// Packs frames onto the end of a packet without letting it grow past a size limit
+///
+/// The limit is what the MTU leaves once the AEAD tag is accounted for.
+pub struct Builder<'a> {
+    buf: &'a mut Vec<u8>,
+    limit: usize,
+    version: Version,
+    /// Start of a final frame that runs to the end of the packet, after which nothing more may be written
+    open: Option<usize>,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(buf: &'a mut Vec<u8>, limit: usize, version: Version) -> Self {
+        Self {
+            buf,
+            limit,
+            version,
+            open: None,
+        }
+    }
+
+    /// Length of the packet so far, including anything written before the builder was created
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Number of bytes further frames may occupy
+    pub fn remaining(&self) -> usize {
+        if self.open.is_some() {
+            0
+        } else {
+            self.limit.saturating_sub(self.buf.len())
+        }
+    }
+
+    /// Write `frame` whole if it fits, returning whether it did
+    pub fn push(&mut self, frame: &Frame) -> bool {
+        if frame.size(self.version) > self.remaining() {
+            return false;
+        }
+        frame.encode(&mut *self.buf, self.version);
+        true
+    }
+
+    /// Write as much of `frame` as fits, returning the number of data bytes written
+    ///
+    /// A frame that doesn't fit whole is written without its length, which lets the data run to the end of the packet,
+    /// and truncated to fit. Nothing can be written after such a frame. Returns `None` if not even one byte would fit.
+    pub fn push_stream<T>(&mut self, frame: &Stream<T>) -> Option<usize>
+    where
+        T: AsRef<[u8]>,
+    {
+        let remaining = self.remaining();
+        let data = frame.data.as_ref();
+        if frame.size(true) <= remaining {
+            frame.encode(true, &mut *self.buf);
+            return Some(data.len());
+        }
+        let header = frame.size(false) - data.len();
+        if header >= remaining {
+            return None;
+        }
+        let len = data.len().min(remaining - header);
+        self.open = Some(self.buf.len());
+        Stream {
+            id: frame.id,
+            offset: frame.offset,
+            fin: frame.fin && len == data.len(),
+            data: &data[..len],
+        }.encode(false, &mut *self.buf);
+        Some(len)
+    }
+
+    /// Pad the packet out to `min` bytes, or to the limit if that's smaller
+    pub fn pad_to(&mut self, min: usize) {
+        let min = min.min(self.limit);
+        if self.buf.len() >= min {
+            return;
+        }
+        let padding = min - self.buf.len();
+        match self.open {
+            // Padding can't follow a frame without a length, so goes in front of it instead
+            Some(start) => {
+                let tail = self.buf.split_off(start);
+                self.buf.resize(start + padding, Type::PADDING.into());
+                self.buf.extend_from_slice(&tail);
+                self.open = Some(start + padding);
+            }
+            None => {
+                self.buf.resize(min, Type::PADDING.into());
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn frames(buf: Vec<u8>) -> Vec<Result<Frame, InvalidFrame>> {
+        Iter::new(Bytes::from(buf), Version::V2).collect()
+    }
+
     #[test]
     fn ack_coding() {
         const PACKETS: &[u64] = &[1, 2, 3, 5, 10, 11, 14];
@@ -548,15 +1020,471 @@ mod test {
             ranges.insert(packet..packet + 1);
         }
         let mut buf = Vec::new();
-        Ack::encode(42, &ranges, &mut buf);
-        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        Ack::encode(42, &ranges, None, &mut buf);
+        let frames = frames(buf);
         match frames[0] {
-            Frame::Ack(ref ack) => {
+            Ok(Frame::Ack(ref ack)) => {
                 let mut packets = ack.iter().flat_map(|x| x).collect::<Vec<_>>();
                 packets.sort_unstable();
                 assert_eq!(&packets[..], PACKETS);
+                assert_eq!(ack.ecn, None);
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn ack_roundtrip(
+            ranges in ::proptest::collection::vec((0u64..1000, 1u64..1000), 1..100),
+            delay in 0u64..2u64.pow(62)
+        ) {
+            // Separate each range from the last by a gap of at least one packet
+            let mut set = RangeSet::new();
+            let mut next = 0;
+            for (gap, len) in ranges {
+                let start = next + gap;
+                set.insert(start..start + len);
+                next = start + len + 1;
+            }
+            let mut buf = Vec::new();
+            Ack::encode(delay, &set, None, &mut buf);
+            let frames = frames(buf);
+            prop_assert_eq!(frames.len(), 1);
+            let ack = match frames[0] {
+                Ok(Frame::Ack(ref x)) => x,
+                ref x => panic!("incorrect frame {:?}", x),
+            };
+            prop_assert_eq!(ack.delay, delay);
+            prop_assert_eq!(ack.largest, set.max().unwrap());
+            let mut decoded = RangeSet::new();
+            for range in ack.iter() {
+                decoded.insert(range);
+            }
+            prop_assert_eq!(
+                decoded.iter().collect::<Vec<_>>(),
+                set.iter().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn ack_ecn_coding() {
+        let mut ranges = RangeSet::new();
+        ranges.insert(3..8);
+        ranges.insert(10..11);
+        let counts = EcnCounts {
+            ect0: 5,
+            ect1: 0,
+            ce: 1,
+        };
+        let mut buf = Vec::new();
+        Ack::encode(7, &ranges, Some(&counts), &mut buf);
+        buf.push(Type::PING.into());
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 2);
+        match frames[0] {
+            Ok(Frame::Ack(ref ack)) => {
+                assert_eq!(ack.delay, 7);
+                let mut packets = ack.iter().flat_map(|x| x).collect::<Vec<_>>();
+                packets.sort_unstable();
+                assert_eq!(&packets[..], &[3, 4, 5, 6, 7, 10]);
+                assert_eq!(ack.ecn, Some(counts));
             }
             ref x => panic!("incorrect frame {:?}", x),
         }
+        assert_matches!(frames[1], Ok(Frame::Ping));
+    }
+
+    #[test]
+    fn datagram_coding() {
+        let mut buf = Vec::new();
+        Datagram {
+            data: &b"hello"[..],
+        }.encode(true, &mut buf);
+        Datagram {
+            data: &b"world"[..],
+        }.encode(false, &mut buf);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Ok(Frame::Datagram(ref x)) if &x.data[..] == b"hello");
+        assert_matches!(frames[1], Ok(Frame::Datagram(ref x)) if &x.data[..] == b"world");
+    }
+
+    #[test]
+    fn rst_stream_coding() {
+        let frame = RstStream {
+            id: StreamId(6),
+            error_code: 42,
+            // Takes the longest varint encoding
+            final_offset: 1 << 40,
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 1);
+        assert_matches!(frames[0], Ok(Frame::RstStream(x)) if x == frame);
+    }
+
+    #[test]
+    fn ack_frequency_coding() {
+        let frame = AckFrequency {
+            sequence: 3,
+            ack_eliciting_threshold: 10,
+            request_max_ack_delay: 25_000,
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 1);
+        assert_matches!(frames[0], Ok(Frame::AckFrequency(x)) if x == frame);
+    }
+
+    #[test]
+    fn close_coding() {
+        let mut buf = Vec::new();
+        ConnectionClose::from(TransportError::frame(Type::ACK)).encode(&mut buf, 1200, Version::V2);
+        ApplicationClose {
+            error_code: 42,
+            reason: &b"bye"[..],
+        }.encode(&mut buf, 1200);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 2);
+        assert_matches!(
+            frames[0],
+            Ok(Frame::ConnectionClose(ref x))
+                if x.error_code == TransportError::frame(Type::ACK)
+                    && x.frame_type == Some(Type::ACK)
+                    && x.reason.is_empty()
+        );
+        assert_matches!(frames[1], Ok(Frame::ApplicationClose(ref x)) if x.error_code == 42 && &x.reason[..] == b"bye");
+
+        // Version 1's CONNECTION_CLOSE has no frame type
+        let mut buf = Vec::new();
+        ConnectionClose::from(TransportError::frame(Type::ACK)).encode(&mut buf, 1200, Version::V1);
+        assert_eq!(buf, [0x02, 0x01, 0x0d, 0x00]);
+        let frames = Iter::new(Bytes::from(buf), Version::V1).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 1);
+        assert_matches!(
+            frames[0],
+            Ok(Frame::ConnectionClose(ref x))
+                if x.error_code == TransportError::frame(Type::ACK) && x.frame_type.is_none()
+        );
+    }
+
+    #[test]
+    fn close_reason_len() {
+        let reason = "é".repeat(MAX_REASON_LEN);
+        for &max_len in &[100, 4000] {
+            let mut buf = Vec::new();
+            ApplicationClose {
+                error_code: 0,
+                reason: reason.as_bytes(),
+            }.encode(&mut buf, max_len);
+            assert!(buf.len() <= max_len as usize);
+            // Truncated to fit, and to the cap, without splitting a character
+            match frames(buf)[0] {
+                Ok(Frame::ApplicationClose(ref x)) => {
+                    assert!(x.reason.len() <= MAX_REASON_LEN);
+                    assert!(reason.starts_with(str::from_utf8(&x.reason).unwrap()));
+                }
+                ref x => panic!("incorrect frame {:?}", x),
+            }
+        }
+
+        // Anything from the first invalid UTF-8 on is left out
+        let mut buf = Vec::new();
+        ApplicationClose {
+            error_code: 0,
+            reason: &b"ok\xffno"[..],
+        }.encode(&mut buf, 1200);
+        assert_matches!(frames(buf)[0], Ok(Frame::ApplicationClose(ref x)) if &x.reason[..] == b"ok");
+
+        // Longer reasons are refused on receipt
+        let mut buf = vec![0x03, 0x00, 0x00];
+        varint::write(MAX_REASON_LEN as u64 + 1, &mut buf).unwrap();
+        buf.extend_from_slice(&[b'a'; MAX_REASON_LEN + 1]);
+        match frames(buf)[0] {
+            Err(InvalidFrame { ty, .. }) if ty == Type::APPLICATION_CLOSE => {}
+            ref x => panic!("decoded {:?}", x),
+        }
+    }
+
+    #[test]
+    fn new_connection_id_coding() {
+        let frame = NewConnectionId {
+            sequence: 4,
+            retire_prior_to: 2,
+            id: ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+            reset_token: [0xcd; RESET_TOKEN_SIZE],
+        };
+        let mut buf = Vec::new();
+        frame.encode(&mut buf);
+        // Asking the peer to retire the ID being issued is an encoding error
+        let invalid = NewConnectionId {
+            retire_prior_to: 5,
+            ..frame.clone()
+        };
+        invalid.encode(&mut buf);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Ok(Frame::NewConnectionId(ref x)) if *x == frame);
+        assert_matches!(
+            frames[1],
+            Err(InvalidFrame {
+                ty: Type::NEW_CONNECTION_ID,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn new_token_coding() {
+        let mut buf = Vec::new();
+        buf.write(Type::NEW_TOKEN);
+        buf.write_var(5);
+        buf.extend_from_slice(b"token");
+        // Tokens can't be empty
+        buf.write(Type::NEW_TOKEN);
+        buf.write_var(0);
+        let frames = frames(buf);
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Ok(Frame::NewToken { ref token }) if &token[..] == b"token");
+        assert_matches!(
+            frames[1],
+            Err(InvalidFrame {
+                ty: Type::NEW_TOKEN,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let cases: &[(&str, &[u8], Type)] = &[
+            ("truncated varint", &[0x04, 0x80], Type::MAX_DATA),
+            (
+                "truncated fixed field",
+                &[0x0e, 0x01, 0x02],
+                Type::PATH_CHALLENGE,
+            ),
+            // Claims 16 bytes of data, carries 2
+            (
+                "overlong stream data",
+                &[0x12, 0x01, 0x10, 0xaa, 0xbb],
+                Type(0x12),
+            ),
+            // Offset 2^62 - 1 plus a byte of data
+            (
+                "stream past the largest offset",
+                &[
+                    0x14, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xaa,
+                ],
+                Type(0x14),
+            ),
+            // Largest 2, first block of 5 packets
+            ("ack below zero", &[0x0d, 0x02, 0x00, 0x00, 0x05], Type::ACK),
+            // Largest 4, first block of 1, then a gap reaching below zero
+            (
+                "ack gap below zero",
+                &[0x0d, 0x04, 0x00, 0x01, 0x01, 0x02, 0x00],
+                Type::ACK,
+            ),
+            (
+                "ack missing blocks",
+                &[0x0d, 0x04, 0x00, 0x03, 0x00],
+                Type::ACK,
+            ),
+            (
+                "truncated connection close reason",
+                &[0x02, 0x00, 0x0a, 0x00],
+                Type::CONNECTION_CLOSE,
+            ),
+            // Frame type 0x100
+            (
+                "connection close frame type out of range",
+                &[0x02, 0x00, 0x0a, 0x41, 0x00, 0x00],
+                Type::CONNECTION_CLOSE,
+            ),
+            (
+                "application close reason not UTF-8",
+                &[0x03, 0x00, 0x2a, 0x02, 0xc3, 0x28],
+                Type::APPLICATION_CLOSE,
+            ),
+            ("unknown frame type", &[0x3f], Type(0x3f)),
+        ];
+        for &(name, data, ty) in cases {
+            // Trailing frames are never reported once decoding fails
+            let mut buf = data.to_vec();
+            buf.push(Type::PING.into());
+            let frames = frames(buf);
+            assert_eq!(frames.len(), 1, "{}", name);
+            match frames[0] {
+                Err(InvalidFrame { ty: x, .. }) if x == ty => {}
+                ref x => panic!("{}: decoded {:?}", name, x),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_frame_error() {
+        let err = Iter::new(Bytes::from(&[0x04, 0x80][..]), Version::V2)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.reason, "unexpected end");
+        assert_eq!(
+            TransportError::from(err),
+            TransportError::frame(Type::MAX_DATA)
+        );
+    }
+
+    #[test]
+    fn frame_size() {
+        let mut ranges = RangeSet::new();
+        ranges.insert(3..8);
+        ranges.insert(100_000..100_001);
+        let mut buf = Vec::new();
+        Ack::encode(
+            1 << 20,
+            &ranges,
+            Some(&EcnCounts {
+                ect0: 70,
+                ect1: 0,
+                ce: 1 << 31,
+            }),
+            &mut buf,
+        );
+        RstStream {
+            id: StreamId(1 << 14),
+            error_code: 7,
+            final_offset: 1 << 40,
+        }.encode(&mut buf);
+        ConnectionClose::from(TransportError::frame(Type::ACK)).encode(&mut buf, 1200, Version::V2);
+        ApplicationClose {
+            error_code: 42,
+            reason: &b"bye"[..],
+        }.encode(&mut buf, 1200);
+        Stream {
+            id: StreamId(4),
+            offset: 1 << 30,
+            fin: true,
+            data: &[0xab; 100][..],
+        }.encode(true, &mut buf);
+        NewConnectionId {
+            sequence: 4,
+            retire_prior_to: 2,
+            id: ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+            reset_token: [0xcd; RESET_TOKEN_SIZE],
+        }.encode(&mut buf);
+        buf.write(Type::MAX_STREAM_DATA);
+        buf.write(StreamId(64));
+        buf.write_var(1 << 16);
+        buf.write(Type::PATH_CHALLENGE);
+        buf.write(0x0123_4567_89ab_cdefu64);
+        buf.write(Type::NEW_TOKEN);
+        buf.write_var(5);
+        buf.extend_from_slice(b"token");
+        buf.write(Type::HANDSHAKE_DONE);
+        Datagram {
+            data: &[0xcd; 70][..],
+        }.encode(true, &mut buf);
+        let frames = frames(buf.clone());
+        assert_eq!(frames.len(), 11);
+
+        // Every frame encodes to exactly its claimed size, and back to the bytes it was decoded from
+        let mut reencoded = Vec::new();
+        for frame in frames {
+            let frame = frame.unwrap();
+            let start = reencoded.len();
+            frame.encode(&mut reencoded, Version::V2);
+            assert_eq!(
+                reencoded.len() - start,
+                frame.size(Version::V2),
+                "{}",
+                frame.ty()
+            );
+        }
+        assert_eq!(reencoded, buf);
+    }
+
+    #[test]
+    fn builder_pads_before_open_frame() {
+        let data = [0xab; 100];
+        let frame = Stream {
+            id: StreamId(0),
+            offset: 0,
+            fin: true,
+            data: &data[..],
+        };
+        let mut payload = Vec::new();
+        {
+            // Fits whole only without a length, leaving a byte spare
+            let mut builder = Builder::new(&mut payload, frame.size(false) + 1, Version::V2);
+            assert_eq!(builder.push_stream(&frame), Some(data.len()));
+            assert_eq!(builder.remaining(), 0);
+            assert!(!builder.push(&Frame::Ping));
+            builder.pad_to(1200);
+        }
+        assert_eq!(payload.len(), frame.size(false) + 1);
+        let frames = frames(payload);
+        assert_eq!(frames.len(), 2);
+        assert_matches!(frames[0], Ok(Frame::Padding));
+        assert_matches!(frames[1], Ok(Frame::Stream(ref x)) if x.fin && &x.data[..] == &data[..]);
+    }
+
+    proptest! {
+        #[test]
+        fn builder_budget(
+            budget in 1usize..1500,
+            lens in ::proptest::collection::vec(0usize..1000, 1..10),
+            min in 0usize..1500
+        ) {
+            let data = [0xab; 1000];
+            let mut payload = Vec::new();
+            let mut written = Vec::new();
+            {
+                let mut builder = Builder::new(&mut payload, budget, Version::V2);
+                for (i, &len) in lens.iter().enumerate() {
+                    let frame = Stream {
+                        id: StreamId(i as u64),
+                        offset: 0,
+                        fin: true,
+                        data: &data[..len],
+                    };
+                    let remaining = builder.remaining();
+                    match builder.push_stream(&frame) {
+                        Some(n) => {
+                            prop_assert!(builder.len() <= budget);
+                            written.push(n);
+                            if n < len {
+                                // Truncation fills the budget exactly
+                                prop_assert_eq!(builder.len(), budget);
+                                break;
+                            }
+                        }
+                        None => {
+                            // Only refused when not even one byte of data would fit
+                            prop_assert!(frame.size(false) - len >= remaining);
+                            break;
+                        }
+                    }
+                }
+                builder.pad_to(min);
+            }
+            prop_assert!(payload.len() <= budget);
+            prop_assert!(payload.len() >= min.min(budget));
+
+            // Everything written decodes back, in order
+            let mut decoded = Vec::new();
+            for frame in frames(payload) {
+                match frame {
+                    Ok(Frame::Padding) => {}
+                    Ok(Frame::Stream(x)) => decoded.push(x.data.len()),
+                    x => panic!("incorrect frame {:?}", x),
+                }
+            }
+            prop_assert_eq!(decoded, written);
+        }
     }
 }
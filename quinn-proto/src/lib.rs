@@ -1,7 +1,11 @@
+#[cfg(feature = "fuzzing")]
+extern crate arbitrary;
 extern crate arrayvec;
 #[cfg(test)]
 #[macro_use]
 extern crate assert_matches;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
 extern crate blake2;
 extern crate byteorder;
 extern crate bytes;
@@ -15,12 +19,21 @@ extern crate hex_literal;
 #[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 extern crate rand;
 extern crate ring;
 extern crate rustls;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(test)]
+extern crate serde_json;
 extern crate slab;
 #[macro_use]
 extern crate slog;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 #[cfg(test)]
 extern crate untrusted;
 extern crate webpki;
@@ -28,35 +41,153 @@ extern crate webpki_roots;
 
 use std::fmt;
 
+mod cid_generator;
+pub use cid_generator::{
+    ConnectionIdError, ConnectionIdGenerator, ConnectionIdGeneratorFactory,
+    RandomConnectionIdGenerator, RandomConnectionIdGeneratorFactory,
+};
+
+mod cid_pool;
 mod coding;
+mod pacing;
+mod pmtud;
+mod rack;
 mod range_set;
+mod scheduler;
 mod stream;
 #[cfg(test)]
 mod tests;
 mod transport_parameters;
 mod varint;
 
+pub mod connect_udp;
+
 mod connection;
-pub use connection::{ConnectionError, ConnectionHandle, ReadError, WriteError};
+pub use connection::{
+    AckFrequencyError, AddPathError, ConnectionError, ConnectionHandle, FlowControlStats, PathId,
+    PathStats, ReadError, SendDatagramError, WriteError,
+};
+
+mod congestion;
+pub use congestion::{
+    Bbr, BbrFactory, BbrMode, CongestionController, CongestionControllerFactory, CongestionEvent,
+    Cubic, CubicFactory, NewReno, NewRenoFactory,
+};
 
 mod crypto;
-pub use crypto::{ClientConfig, ConnectError};
+pub use crypto::{
+    build_server_config_with_client_auth, require_client_auth, set_client_cert,
+    stateless_reset_token, ClientConfig, ConnectError, StatelessResetKey,
+};
 
 mod frame;
 use frame::Frame;
 pub use frame::{ApplicationClose, ConnectionClose};
 
 mod endpoint;
-pub use endpoint::{Config, Endpoint, EndpointError, Event, Io, ListenKeys, Timer};
+pub use endpoint::{
+    Config, Endpoint, EndpointError, EndpointStats, Event, Io, ListenKeys, MultipathPolicy, Timer,
+    Transmit,
+};
 
 mod packet;
-pub use packet::ConnectionId;
+pub use packet::{ConnectionId, ParseConnectionIdError, TooLong};
+
+/// Internals exercised by the fuzz targets, which are not part of the stable API
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    pub use crypto::{Crypto, HeaderKey};
+    pub use frame::{Frame, InvalidFrame, Iter as FrameIter};
+    pub use packet::{
+        set_payload_length, Header, HeaderError, Packet, PacketNumber, PartialDecode,
+        PartialEncode, PayloadTooLong, AEAD_TAG_SIZE,
+    };
+
+    /// Largest connection ID length
+    pub const MAX_CID_SIZE: usize = ::MAX_CID_SIZE;
+}
+
+mod qlog;
+pub use qlog::QlogWriter;
+
+mod ticket_store;
+pub use ticket_store::{InMemoryTicketStore, SessionTicketStore};
+
+mod token_store;
+pub use token_store::{InMemoryTokenStore, TokenStore};
 
 mod transport_error;
 pub use transport_error::Error as TransportError;
 
-/// The QUIC protocol version implemented
-pub const VERSION: u32 = 0xff00_000b;
+/// A version of QUIC that may be negotiated
+///
+/// Versions share a wire format, but differ in the salt Initial keys are derived from, in the labels used to derive
+/// packet protection keys, and in the codepoints of long header packet types. Exercising more than one keeps
+/// middleboxes from ossifying on the details of any single version.
+///
+/// Both are private versions of this crate's pre-RFC wire format, identified by numbers outside those IANA assigns.
+/// They borrow the Initial salts of RFC 9001 and RFC 9369, and the latter's packet type codepoints, but derive keys
+/// with the draft's labels, so neither interoperates with RFC QUIC versions 1 or 2.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Version {
+    /// The draft wire format
+    V1,
+    /// The draft wire format, with the salt and packet type codepoints of QUIC version 2, the type of the offending frame
+    /// in CONNECTION_CLOSE, and HANDSHAKE_DONE
+    V2,
+}
+
+impl Version {
+    /// The number identifying the version on the wire
+    pub fn number(self) -> u32 {
+        match self {
+            Version::V1 => 0x5155_4901,
+            Version::V2 => 0x5155_4902,
+        }
+    }
+
+    /// Whether CONNECTION_CLOSE frames name the type of the frame that caused the error
+    pub(crate) fn close_frame_type(self) -> bool {
+        self == Version::V2
+    }
+
+    /// Whether the server confirms the handshake with a HANDSHAKE_DONE frame
+    ///
+    /// Otherwise the client deems it confirmed once the server acknowledges one of its 1-RTT packets.
+    pub(crate) fn handshake_done(self) -> bool {
+        self == Version::V2
+    }
+
+    /// The version identified by `number`, if it's one we implement
+    pub fn from_number(number: u32) -> Option<Self> {
+        match number {
+            0x5155_4901 => Some(Version::V1),
+            0x5155_4902 => Some(Version::V2),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl arbitrary::Arbitrary for Version {
+    fn arbitrary(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0u8..=1)? {
+            0 => Version::V1,
+            _ => Version::V2,
+        })
+    }
+}
+
+impl slog::Value for Version {
+    fn serialize(
+        &self,
+        _: &slog::Record,
+        key: slog::Key,
+        serializer: &mut slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{:?}", self))
+    }
+}
 
 /// Whether an endpoint was the initiator of a connection
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -97,6 +228,32 @@ pub enum Directionality {
     Uni = 1,
 }
 
+/// Explicit congestion notification codepoint carried in the IP header of a datagram
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EcnCodepoint {
+    /// ECN-capable transport, codepoint 0
+    ECT0 = 0b10,
+    /// ECN-capable transport, codepoint 1
+    ECT1 = 0b01,
+    /// Congestion experienced
+    CE = 0b11,
+}
+
+impl EcnCodepoint {
+    /// Decode the ECN field of an IP TOS or traffic class byte
+    pub fn from_bits(x: u8) -> Option<Self> {
+        use self::EcnCodepoint::*;
+        Some(match x & 0b11 {
+            0b10 => ECT0,
+            0b01 => ECT1,
+            0b11 => CE,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
 /// Identifier for a stream within a particular connection
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct StreamId(pub(crate) u64);
@@ -172,7 +329,8 @@ impl coding::Value for StreamId {
 //
 
 const RESET_TOKEN_SIZE: usize = 16;
-const MAX_CID_SIZE: usize = 18;
-const MIN_CID_SIZE: usize = 4;
+const MAX_CID_SIZE: usize = 20;
+/// Smallest ID that may be issued in a NEW_CONNECTION_ID frame or a preferred address
+const MIN_CID_SIZE: usize = 1;
 const MIN_INITIAL_SIZE: usize = 1200;
 const MIN_MTU: u16 = 1232;
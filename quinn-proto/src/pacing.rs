@@ -0,0 +1,142 @@
+//! Spreading transmissions out over time
+//!
+//! Sending a congestion window's worth of packets back to back can overflow shallow buffers at a bottleneck, causing
+//! loss even when the window fits the path. A pacer limits the sender to a target rate, allowing only short bursts.
+
+use std::cmp;
+
+/// Shortest interval whose worth of sending the bucket holds (μs)
+const BURST_INTERVAL: u64 = 2000;
+/// Factor by which a window is paced out faster than one per round trip, so the pacer doesn't keep it from filling
+const WINDOW_GAIN: f64 = 1.25;
+/// `WINDOW_GAIN` in slow start, where the window doubles every round trip and pacing mustn't hold that back
+const SLOW_START_GAIN: f64 = 2.0;
+
+/// Rate at which to pace out a congestion window of `window` bytes given a smoothed RTT of `rtt` μs, if known
+/// (bytes/s)
+pub fn window_rate(window: u64, rtt: u64, slow_start: bool) -> Option<u64> {
+    if rtt == 0 {
+        return None;
+    }
+    let gain = if slow_start {
+        SLOW_START_GAIN
+    } else {
+        WINDOW_GAIN
+    };
+    Some((window as f64 * gain * 1e6 / rtt as f64) as u64)
+}
+
+/// Paces datagrams out at a target rate with a token bucket
+///
+/// Tokens, measured in bytes, accrue at the pacing rate up to the bucket's capacity, and each datagram sent spends its
+/// size in tokens. Bursts are thus bounded by the capacity, while the long-term rate can't exceed the target.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    /// Bytes that may be sent immediately
+    tokens: f64,
+    /// Rate at which tokens accrue (bytes/s)
+    rate: u64,
+    /// Time at which `tokens` was last brought up to date (μs)
+    last_update: u64,
+    /// Fewest datagrams the bucket holds, so that pacing at low rates doesn't degrade into one datagram per wakeup
+    burst: u64,
+}
+
+impl Pacer {
+    /// A pacer whose bucket starts out full, holding at least `burst` datagrams, and never fewer than one
+    pub fn new(burst: u64) -> Self {
+        Self {
+            tokens: ::std::f64::INFINITY,
+            rate: 0,
+            last_update: 0,
+            // A bucket smaller than a datagram would never fill up enough to send it
+            burst: cmp::max(burst, 1),
+        }
+    }
+
+    /// Time at which a datagram of up to `size` bytes may be sent at `rate` bytes/s, if not immediately
+    pub fn delay(&mut self, now: u64, rate: u64, size: u64) -> Option<u64> {
+        let capacity = cmp::max(self.burst * size, rate * BURST_INTERVAL / 1_000_000);
+        let elapsed = now.saturating_sub(self.last_update);
+        self.tokens = (self.tokens + elapsed as f64 * self.rate as f64 / 1e6).min(capacity as f64);
+        self.last_update = now;
+        self.rate = rate;
+        if self.tokens >= size as f64 || rate == 0 {
+            return None;
+        }
+        let wait = ((size as f64 - self.tokens) * 1e6 / rate as f64).ceil() as u64;
+        Some(now + cmp::max(wait, 1))
+    }
+
+    /// Spend the tokens for a datagram of `size` bytes
+    pub fn on_sent(&mut self, size: u64) {
+        self.tokens -= size as f64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RATE: u64 = 1_000_000;
+
+    #[test]
+    fn burst_then_paced() {
+        let mut pacer = Pacer::new(2);
+        // The bucket holds 2ms of sending at 1 MB/s
+        for _ in 0..2 {
+            assert_eq!(pacer.delay(0, RATE, 1000), None);
+            pacer.on_sent(1000);
+        }
+        // Each further 1000 byte datagram waits 1ms for its tokens
+        assert_eq!(pacer.delay(0, RATE, 1000), Some(1000));
+        assert_eq!(pacer.delay(500, RATE, 1000), Some(1000));
+        assert_eq!(pacer.delay(1000, RATE, 1000), None);
+        pacer.on_sent(1000);
+        assert_eq!(pacer.delay(1000, RATE, 1000), Some(2000));
+    }
+
+    #[test]
+    fn idle_credit_capped() {
+        let mut pacer = Pacer::new(2);
+        assert_eq!(pacer.delay(0, RATE, 1000), None);
+        pacer.on_sent(1000);
+        // A long silence only refills the bucket
+        let now = 1_000_000;
+        for _ in 0..2 {
+            assert_eq!(pacer.delay(now, RATE, 1000), None);
+            pacer.on_sent(1000);
+        }
+        assert!(pacer.delay(now, RATE, 1000).is_some());
+    }
+
+    #[test]
+    fn configured_burst() {
+        let mut pacer = Pacer::new(10);
+        // Far more than 2ms of sending, but the configured burst goes out at once
+        for _ in 0..10 {
+            assert_eq!(pacer.delay(0, RATE, 1000), None);
+            pacer.on_sent(1000);
+        }
+        assert_eq!(pacer.delay(0, RATE, 1000), Some(1000));
+    }
+
+    #[test]
+    fn zero_burst() {
+        let mut pacer = Pacer::new(0);
+        // 2ms of sending at 100 kB/s is less than a datagram, but one still fits
+        assert_eq!(pacer.delay(0, 100_000, 1000), None);
+        pacer.on_sent(1000);
+        assert_eq!(pacer.delay(0, 100_000, 1000), Some(10_000));
+        assert_eq!(pacer.delay(10_000, 100_000, 1000), None);
+    }
+
+    #[test]
+    fn window_pacing_rate() {
+        assert_eq!(window_rate(10_000, 0, false), None);
+        // 10 kB per 100ms, sped up by a quarter
+        assert_eq!(window_rate(10_000, 100_000, false), Some(125_000));
+        // Twice that in slow start
+        assert_eq!(window_rate(10_000, 100_000, true), Some(200_000));
+    }
+}
@@ -1,26 +1,52 @@
+use std::convert::TryFrom;
+use std::ops::Range;
 use std::{fmt, io, str};
 
+#[cfg(feature = "fuzzing")]
+use arbitrary::{Arbitrary, Unstructured};
 use arrayvec::ArrayVec;
 use bytes::{BigEndian, Buf, BufMut, ByteOrder, Bytes, BytesMut};
 use rand::Rng;
 use slog;
 
 use coding::{self, BufExt, BufMutExt};
-use {MAX_CID_SIZE, VERSION};
+use crypto::HeaderKey;
+use {varint, Version, MAX_CID_SIZE};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Header {
+    Initial {
+        version: Version,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+        /// Address validation token supplied by a Retry, or empty
+        token: Bytes,
+        number: PacketNumber,
+    },
     Long {
+        version: Version,
         ty: u8,
         source_id: ConnectionId,
         destination_id: ConnectionId,
-        number: u32,
+        number: PacketNumber,
     },
     Short {
         id: ConnectionId,
         number: PacketNumber,
+        /// Latency spin bit, observable by on-path middleboxes to estimate RTT
+        spin: bool,
         key_phase: bool,
     },
+    /// Requests that the client retry its Initial with the token carried as the packet's payload
+    ///
+    /// Retry packets carry no packet number and aren't encrypted.
+    Retry {
+        version: Version,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+        /// The destination connection ID of the Initial that prompted this Retry
+        orig_dst_cid: ConnectionId,
+    },
     VersionNegotiate {
         ty: u8,
         source_id: ConnectionId,
@@ -32,10 +58,16 @@ impl Header {
     pub fn destination_id(&self) -> &ConnectionId {
         use self::Header::*;
         match *self {
+            Initial {
+                ref destination_id, ..
+            } => destination_id,
             Long {
                 ref destination_id, ..
             } => destination_id,
             Short { ref id, .. } => id,
+            Retry {
+                ref destination_id, ..
+            } => destination_id,
             VersionNegotiate {
                 ref destination_id, ..
             } => destination_id,
@@ -48,110 +80,312 @@ impl Header {
             _ => false,
         }
     }
+
+    /// The packet number space this packet belongs to
+    ///
+    /// Retry and Version Negotiation packets carry no packet number, but answer the client's Initial and so are
+    /// assigned to its space.
+    pub fn space(&self) -> SpaceId {
+        use self::Header::*;
+        match *self {
+            Initial { .. } | Retry { .. } | VersionNegotiate { .. } => SpaceId::Initial,
+            Long {
+                ty: types::HANDSHAKE,
+                ..
+            } => SpaceId::Handshake,
+            Long { .. } | Short { .. } => SpaceId::Data,
+        }
+    }
+
+    /// The truncated packet number, for packets that carry one
+    pub fn number(&self) -> Option<PacketNumber> {
+        use self::Header::*;
+        match *self {
+            Initial { number, .. } | Long { number, .. } | Short { number, .. } => Some(number),
+            Retry { .. } | VersionNegotiate { .. } => None,
+        }
+    }
+}
+
+/// A packet number space, within which packets are numbered, acknowledged, and recovered from loss
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SpaceId {
+    Initial,
+    Handshake,
+    /// 0-RTT and 1-RTT packets
+    Data,
 }
 
 // An encoded packet number
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PacketNumber {
     U8(u8),
     U16(u16),
+    U24(u32),
     U32(u32),
 }
 
 impl PacketNumber {
-    pub fn new(n: u64, largest_acked: u64) -> Self {
+    /// Encode `n`, failing if it's too far ahead of `largest_acked` for the peer to recover it
+    pub fn new(n: u64, largest_acked: u64) -> Result<Self, PacketNumberTooLarge> {
         if largest_acked == 0 {
-            return PacketNumber::U32(n as u32);
+            // Nothing is acknowledged yet, and the peer expands relative to 0. Initial packet numbers are random, so
+            // only the smallest fit in less than the full width.
+            return if n < 1 << 7 {
+                Ok(PacketNumber::U8(n as u8))
+            } else if n < 1 << 32 {
+                Ok(PacketNumber::U32(n as u32))
+            } else {
+                Err(PacketNumberTooLarge)
+            };
         }
-        let range = (n - largest_acked) / 2;
-        if range < 1 << 8 {
+        // The encoding must cover twice the distance from the largest acknowledged packet for the peer to be able to
+        // recover the full value unambiguously
+        let range = n.saturating_sub(largest_acked).saturating_mul(2);
+        Ok(if range < 1 << 8 {
             PacketNumber::U8(n as u8)
         } else if range < 1 << 16 {
             PacketNumber::U16(n as u16)
+        } else if range < 1 << 24 {
+            PacketNumber::U24(n as u32 & 0x00ff_ffff)
         } else if range < 1 << 32 {
             PacketNumber::U32(n as u32)
         } else {
-            panic!("packet number too large to encode")
-        }
+            return Err(PacketNumberTooLarge);
+        })
     }
 
-    fn ty(&self) -> u8 {
+    /// Number of bytes used to encode this packet number on the wire
+    pub fn len(&self) -> usize {
         use self::PacketNumber::*;
         match *self {
-            U8(_) => 0x00,
-            U16(_) => 0x01,
-            U32(_) => 0x02,
+            U8(_) => 1,
+            U16(_) => 2,
+            U24(_) => 3,
+            U32(_) => 4,
         }
     }
 
+    /// Value of the packet number length bits in the first byte of a header
+    fn tag(&self) -> u8 {
+        self.len() as u8 - 1
+    }
+
     pub fn encode<W: BufMut>(&self, w: &mut W) {
         use self::PacketNumber::*;
         match *self {
             U8(x) => w.write(x),
             U16(x) => w.write(x),
+            U24(x) => {
+                w.write((x >> 16) as u8);
+                w.write(x as u16);
+            }
             U32(x) => w.write(x),
         }
     }
 
+    pub fn decode<R: Buf>(len: usize, r: &mut R) -> coding::Result<Self> {
+        use self::PacketNumber::*;
+        Ok(match len {
+            1 => U8(r.get()?),
+            2 => U16(r.get()?),
+            3 => {
+                let high = r.get::<u8>()? as u32;
+                let low = r.get::<u16>()? as u32;
+                U24(high << 16 | low)
+            }
+            4 => U32(r.get()?),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Recover the full packet number, given the largest one received so far
+    ///
+    /// Picks the value closest to the next expected number, as in the sample algorithm of RFC 9000 appendix A.3,
+    /// without going below 0 or above 2^62 - 1.
     pub fn expand(&self, prev: u64) -> u64 {
         use self::PacketNumber::*;
-        let t = prev + 1;
-        // Compute missing bits that minimize the difference from expected
-        let d = match *self {
-            U8(_) => 1 << 8,
-            U16(_) => 1 << 16,
-            U32(_) => 1 << 32,
-        };
-        let x = match *self {
+        let truncated = match *self {
             U8(x) => x as u64,
             U16(x) => x as u64,
+            U24(x) => x as u64,
             U32(x) => x as u64,
         };
-        if t > d / 2 {
-            x + d * ((t + d / 2 - x) / d)
+        // Saturating, so that a nonsensical `prev` from a misbehaving peer yields a wrong answer rather than a panic
+        let expected = prev.saturating_add(1);
+        let win = 1 << (8 * self.len());
+        let hwin = win / 2;
+        let candidate = (expected & !(win - 1)) | truncated;
+        if candidate.saturating_add(hwin) <= expected && candidate < (1 << 62) - win {
+            candidate + win
+        } else if candidate > expected.saturating_add(hwin) && candidate >= win {
+            candidate - win
         } else {
-            x % d
+            candidate
         }
     }
 }
 
-const KEY_PHASE_BIT: u8 = 0x40;
+/// The type of a packet of `version` given its first byte, or `None` if it has a short header
+///
+/// The header form and long packet type bits are not covered by header protection.
+pub fn long_type(first: u8, version: Version) -> Option<u8> {
+    if first & LONG_HEADER_FORM == 0 {
+        return None;
+    }
+    Some(decode_long_type((first & LONG_TYPE_MASK) >> 4, version))
+}
+
+/// The long packet type bits that identify `ty`, one of `types`, in packets of `version`
+///
+/// Version 2 rotates the codepoints of version 1 so that the two can't be mistaken for one another.
+fn encode_long_type(ty: u8, version: Version) -> u8 {
+    match version {
+        Version::V1 => ty,
+        Version::V2 => (ty + 1) & 0x03,
+    }
+}
+
+/// Inverse of `encode_long_type`
+fn decode_long_type(bits: u8, version: Version) -> u8 {
+    match version {
+        Version::V1 => bits,
+        Version::V2 => (bits + 3) & 0x03,
+    }
+}
+
+/// Encode a connection ID preceded by its length in a byte of its own
+///
+/// Lengths were once packed into 4 bits offset by 3, which can't express IDs of more than 18 bytes.
+fn encode_cid<W: BufMut>(w: &mut W, id: &ConnectionId) {
+    w.write(id.len() as u8);
+    w.put_slice(id);
+}
+
+fn decode_cid<R: Buf>(r: &mut R) -> Result<ConnectionId, HeaderError> {
+    let len = r.get::<u8>()? as usize;
+    if len > MAX_CID_SIZE {
+        return Err(HeaderError::InvalidHeader("connection ID too long"));
+    }
+    if r.remaining() < len {
+        return Err(HeaderError::InvalidHeader(
+            "connection ID longer than packet",
+        ));
+    }
+    Ok(ConnectionId::from_buf(r, len))
+}
+
+fn encode_cids<W: BufMut>(w: &mut W, destination_id: &ConnectionId, source_id: &ConnectionId) {
+    encode_cid(w, destination_id);
+    encode_cid(w, source_id);
+}
+
+const LONG_HEADER_FORM: u8 = 0x80;
+const FIXED_BIT: u8 = 0x40;
+const SPIN_BIT: u8 = 0x20;
+const KEY_PHASE_BIT: u8 = 0x04;
+const LONG_TYPE_MASK: u8 = 0x30;
+const LONG_RESERVED_BITS: u8 = 0x0c;
+const SHORT_RESERVED_BITS: u8 = 0x18;
+const PACKET_NUMBER_LEN_MASK: u8 = 0x03;
 
 impl Header {
-    pub fn encode<W: BufMut>(&self, w: &mut W) {
+    /// Encode, leaving room for the payload length of a long header packet smaller than 2^14 bytes
+    pub fn encode<W: BufMut>(&self, w: &mut W) -> PartialEncode {
+        self.encode_reserving(w, 2)
+    }
+
+    /// Encode, leaving `length_width` bytes for the payload length of a long header packet
+    ///
+    /// The length is filled in by `set_payload_length` once the payload is known; see `payload_length_width`.
+    pub fn encode_reserving<W: BufMut>(&self, w: &mut W, length_width: usize) -> PartialEncode {
+        self.encode_with_quic_bit(w, length_width, true)
+    }
+
+    /// Like `encode_reserving`, but sending the QUIC bit as `quic_bit`, for a peer that lets us grease it (RFC 9287)
+    ///
+    /// Short headers keep the bit set unless the spin bit is also set, so that the first byte can't be zero and
+    /// mistaken for padding after a coalesced packet.
+    pub fn encode_with_quic_bit<W: BufMut>(
+        &self,
+        w: &mut W,
+        length_width: usize,
+        quic_bit: bool,
+    ) -> PartialEncode {
         use self::Header::*;
+        let fixed = if quic_bit { FIXED_BIT } else { 0 };
         match *self {
+            Initial {
+                version,
+                ref source_id,
+                ref destination_id,
+                ref token,
+                number,
+            } => {
+                let ty = encode_long_type(types::INITIAL, version);
+                w.write(LONG_HEADER_FORM | fixed | ty << 4 | number.tag());
+                w.write(version.number());
+                encode_cids(w, destination_id, source_id);
+                w.write_var(token.len() as u64);
+                w.put_slice(token);
+                w.put_slice(&[0; 4][..length_width]); // Placeholder; see `set_payload_length`
+                number.encode(w);
+                let slot_start = 1
+                    + 4
+                    + 2
+                    + destination_id.len()
+                    + source_id.len()
+                    + varint::size(token.len() as u64).unwrap()
+                    + token.len();
+                PartialEncode::long(slot_start, length_width, number.len())
+            }
             Long {
+                version,
                 ty,
                 ref source_id,
                 ref destination_id,
                 number,
             } => {
-                w.write(0b1000_0000 | ty);
-                w.write(VERSION);
-                let mut dcil = destination_id.len() as u8;
-                if dcil > 0 {
-                    dcil -= 3;
-                }
-                let mut scil = source_id.len() as u8;
-                if scil > 0 {
-                    scil -= 3;
-                }
-                w.write(dcil << 4 | scil);
-                w.put_slice(destination_id);
-                w.put_slice(source_id);
-                w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
-                w.write(number);
+                let ty = encode_long_type(ty, version);
+                w.write(LONG_HEADER_FORM | fixed | ty << 4 | number.tag());
+                w.write(version.number());
+                encode_cids(w, destination_id, source_id);
+                w.put_slice(&[0; 4][..length_width]); // Placeholder; see `set_payload_length`
+                number.encode(w);
+                let slot_start = 1 + 4 + 2 + destination_id.len() + source_id.len();
+                PartialEncode::long(slot_start, length_width, number.len())
             }
             Short {
                 ref id,
                 number,
+                spin,
                 key_phase,
             } => {
-                let ty = number.ty() | 0x30 | if key_phase { KEY_PHASE_BIT } else { 0 };
+                let ty = if spin { fixed | SPIN_BIT } else { FIXED_BIT };
+                let ty = ty | if key_phase { KEY_PHASE_BIT } else { 0 } | number.tag();
                 w.write(ty);
                 w.put_slice(id);
                 number.encode(w);
+                PartialEncode {
+                    header_len: 1 + id.len() + number.len(),
+                    pn_offset: 1 + id.len(),
+                    len_slot: None,
+                }
+            }
+            Retry {
+                version,
+                ref source_id,
+                ref destination_id,
+                ref orig_dst_cid,
+            } => {
+                let ty = encode_long_type(types::RETRY, version);
+                w.write(LONG_HEADER_FORM | fixed | ty << 4);
+                w.write(version.number());
+                encode_cids(w, destination_id, source_id);
+                encode_cid(w, orig_dst_cid);
+                PartialEncode::unnumbered(
+                    1 + 4 + 3 + destination_id.len() + source_id.len() + orig_dst_cid.len(),
+                )
             }
             VersionNegotiate {
                 ty,
@@ -160,20 +394,57 @@ impl Header {
             } => {
                 w.write(0x80 | ty);
                 w.write::<u32>(0);
-                let mut dcil = destination_id.len() as u8;
-                if dcil > 0 {
-                    dcil -= 3;
-                }
-                let mut scil = source_id.len() as u8;
-                if scil > 0 {
-                    scil -= 3;
-                }
-                w.write(dcil << 4 | scil);
-                w.put_slice(destination_id);
-                w.put_slice(source_id);
+                encode_cids(w, destination_id, source_id);
+                PartialEncode::unnumbered(1 + 4 + 2 + destination_id.len() + source_id.len())
             }
         }
     }
+    /// Apply header protection to an encoded and encrypted packet
+    ///
+    /// `header_len` is the length of the unprotected header, including the packet number.
+    pub fn encrypt_header(packet: &mut [u8], header_len: usize, header_key: &HeaderKey) {
+        let pn_len = (packet[0] & PACKET_NUMBER_LEN_MASK) as usize + 1;
+        let pn_offset = header_len - pn_len;
+        let sample_offset = pn_offset + 4;
+        let sample_end = sample_offset + header_key.sample_size();
+        let mask = header_key.mask(&packet[sample_offset..sample_end]);
+        packet[0] ^= mask[0] & protected_bits(packet[0]);
+        for (out, inp) in packet[pn_offset..header_len].iter_mut().zip(&mask[1..]) {
+            *out ^= inp;
+        }
+    }
+
+    /// Remove header protection from a packet whose packet number begins at `pn_offset`
+    pub fn decrypt_header(
+        packet: &mut [u8],
+        pn_offset: usize,
+        header_key: &HeaderKey,
+    ) -> Result<(), HeaderError> {
+        let sample_offset = pn_offset + 4;
+        let sample_end = sample_offset + header_key.sample_size();
+        if packet.len() < sample_end {
+            return Err(HeaderError::InvalidHeader("packet too short to sample"));
+        }
+        let mask = header_key.mask(&packet[sample_offset..sample_end]);
+        packet[0] ^= mask[0] & protected_bits(packet[0]);
+        let pn_len = (packet[0] & PACKET_NUMBER_LEN_MASK) as usize + 1;
+        for (out, inp) in packet[pn_offset..pn_offset + pn_len]
+            .iter_mut()
+            .zip(&mask[1..])
+        {
+            *out ^= inp;
+        }
+        Ok(())
+    }
+}
+
+/// Bits of the first byte of a header that are covered by header protection
+fn protected_bits(first: u8) -> u8 {
+    if first & LONG_HEADER_FORM != 0 {
+        0x0f
+    } else {
+        0x1f
+    }
 }
 
 pub struct Packet {
@@ -182,6 +453,24 @@ pub struct Packet {
     pub payload: BytesMut,
 }
 
+impl Packet {
+    /// Check that the reserved bits of the first byte, exposed by removing header protection, are zero
+    ///
+    /// Anyone can construct a packet with these bits set, so a violation can only be blamed on the peer once the
+    /// packet has been authenticated.
+    pub fn check_reserved_bits(&self) -> Result<(), HeaderError> {
+        let reserved = match self.header {
+            Header::Initial { .. } | Header::Long { .. } => LONG_RESERVED_BITS,
+            Header::Short { .. } => SHORT_RESERVED_BITS,
+            Header::Retry { .. } | Header::VersionNegotiate { .. } => return Ok(()),
+        };
+        if self.header_data[0] & reserved != 0 {
+            return Err(HeaderError::ReservedBitsSet);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum HeaderError {
     #[fail(display = "unsupported version")]
@@ -191,6 +480,21 @@ pub enum HeaderError {
     },
     #[fail(display = "invalid header: {}", _0)]
     InvalidHeader(&'static str),
+    /// A header that couldn't be parsed, with whatever context was recovered for diagnosis
+    #[fail(display = "malformed header at offset {}: {}", offset, reason)]
+    Malformed {
+        reason: &'static str,
+        /// Offset within the datagram at which decoding stopped
+        offset: usize,
+        /// The first byte of the packet, which determines the layout of the rest of the header
+        first: u8,
+        /// The destination connection ID, if it was decoded
+        destination: Option<ConnectionId>,
+        /// The source connection ID, if it was decoded
+        source: Option<ConnectionId>,
+    },
+    #[fail(display = "reserved bits set")]
+    ReservedBitsSet,
 }
 
 impl From<coding::UnexpectedEnd> for HeaderError {
@@ -199,111 +503,399 @@ impl From<coding::UnexpectedEnd> for HeaderError {
     }
 }
 
-impl Packet {
-    pub fn decode(
-        mut packet: BytesMut,
+/// A packet whose header has been decoded up to the packet number
+///
+/// The packet number and the low bits of the first byte are covered by header protection, so they can only be read
+/// once the appropriate keys have been selected based on the unprotected parts of the header.
+pub struct PartialDecode {
+    plain_header: PlainHeader,
+    packet: BytesMut,
+    pn_offset: usize,
+}
+
+enum PlainHeader {
+    Initial {
+        version: Version,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+        token: Bytes,
+    },
+    Long {
+        version: Version,
+        ty: u8,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+    },
+    Short {
+        id: ConnectionId,
+    },
+    Retry {
+        version: Version,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+        orig_dst_cid: ConnectionId,
+    },
+    VersionNegotiate {
+        ty: u8,
+        source_id: ConnectionId,
+        destination_id: ConnectionId,
+    },
+}
+
+impl PartialDecode {
+    /// Decode the unprotected portion of every packet coalesced into `datagram`
+    ///
+    /// Full decoding requires keys selected based on each partial decode. Decoding stops after any packet that fails
+    /// to parse, as the position of the next is then unknown. Zero bytes between or after packets are skipped.
+    pub fn decode_all(
+        mut datagram: BytesMut,
         dest_id_len: usize,
-    ) -> Result<(Self, BytesMut), HeaderError> {
-        let (header_len, payload_len, header) = {
-            let mut buf = io::Cursor::new(&packet[..]);
-            let ty = buf.get::<u8>()?;
-            let long = ty & 0x80 != 0;
-            let ty = ty & !0x80;
-            let mut cid_stage = [0; MAX_CID_SIZE];
-            if long {
-                let version = buf.get::<u32>()?;
-                let ci_lengths = buf.get::<u8>()?;
-                let mut dcil = ci_lengths >> 4;
-                if dcil > 0 {
-                    dcil += 3
-                };
-                let mut scil = ci_lengths & 0xF;
-                if scil > 0 {
-                    scil += 3
-                };
-                if buf.remaining() < (dcil + scil) as usize {
-                    return Err(HeaderError::InvalidHeader(
-                        "connection IDs longer than packet",
-                    ));
+    ) -> Vec<Result<PartialDecode, HeaderError>> {
+        let mut result = Vec::new();
+        // Bytes of the datagram preceding the packet being decoded
+        let mut consumed = 0;
+        loop {
+            // A first byte of zero can't begin a packet because the fixed bit is unset, so it must be padding
+            let padding = datagram.iter().take_while(|&&x| x == 0).count();
+            datagram.split_to(padding);
+            consumed += padding;
+            if datagram.is_empty() {
+                break;
+            }
+            let len = datagram.len();
+            match PartialDecode::new(datagram, dest_id_len) {
+                Ok((partial, rest)) => {
+                    result.push(Ok(partial));
+                    consumed += len - rest.len();
+                    datagram = rest;
                 }
-                buf.copy_to_slice(&mut cid_stage[0..dcil as usize]);
-                let destination_id = ConnectionId::new(cid_stage, dcil as usize);
-                buf.copy_to_slice(&mut cid_stage[0..scil as usize]);
-                let source_id = ConnectionId::new(cid_stage, scil as usize);
-                match version {
-                    0 => (
-                        buf.position() as usize,
-                        packet.len() - buf.position() as usize,
-                        Header::VersionNegotiate {
-                            ty,
-                            source_id,
-                            destination_id,
-                        },
-                    ),
-                    VERSION => {
-                        let len = buf.get_var()?;
-                        let number = buf.get()?;
-                        let header_len = buf.position() as usize;
-                        if buf.position() + len > packet.len() as u64 {
-                            return Err(HeaderError::InvalidHeader("payload longer than packet"));
-                        }
-                        (
-                            header_len,
-                            len as usize,
-                            Header::Long {
-                                ty,
-                                source_id,
-                                destination_id,
-                                number,
-                            },
-                        )
-                    }
-                    _ => {
-                        return Err(HeaderError::UnsupportedVersion {
-                            source: source_id,
-                            destination: destination_id,
-                        })
+                Err(mut e) => {
+                    if let HeaderError::Malformed { ref mut offset, .. } = e {
+                        *offset += consumed;
                     }
+                    result.push(Err(e));
+                    break;
                 }
-            } else {
-                if buf.remaining() < dest_id_len {
-                    return Err(HeaderError::InvalidHeader(
-                        "destination connection ID longer than packet",
-                    ));
+            }
+        }
+        result
+    }
+
+    /// Decode the unprotected portion of the first packet in `packet`, returning any coalesced packets that follow it
+    pub fn new(mut packet: BytesMut, dest_id_len: usize) -> Result<(Self, BytesMut), HeaderError> {
+        if packet.is_empty() {
+            return Err(HeaderError::InvalidHeader("empty packet"));
+        }
+        let (pn_offset, packet_len, plain_header) = {
+            let mut buf = io::Cursor::new(&packet[..]);
+            let mut destination = None;
+            let mut source = None;
+            let result = Self::decode_plain(&mut buf, dest_id_len, &mut destination, &mut source);
+            match result {
+                Ok(x) => x,
+                Err(HeaderError::InvalidHeader(reason)) => {
+                    return Err(HeaderError::Malformed {
+                        reason,
+                        offset: buf.position() as usize,
+                        first: packet[0],
+                        destination,
+                        source,
+                    });
                 }
-                buf.copy_to_slice(&mut cid_stage[0..dest_id_len]);
-                let id = ConnectionId::new(cid_stage, dest_id_len);
-                let key_phase = ty & KEY_PHASE_BIT != 0;
-                let number = match ty & 0b11 {
-                    0x0 => PacketNumber::U8(buf.get()?),
-                    0x1 => PacketNumber::U16(buf.get()?),
-                    0x2 => PacketNumber::U32(buf.get()?),
-                    _ => {
-                        return Err(HeaderError::InvalidHeader("unknown packet type"));
-                    }
-                };
-                (
-                    buf.position() as usize,
-                    packet.len() - buf.position() as usize,
-                    Header::Short {
-                        id,
-                        number,
-                        key_phase,
-                    },
-                )
+                Err(e) => return Err(e),
             }
         };
-        let header_data = packet.split_to(header_len).freeze();
-        let payload = packet.split_to(payload_len);
+        let this = packet.split_to(packet_len);
         Ok((
-            Packet {
-                header,
-                header_data,
-                payload,
+            PartialDecode {
+                plain_header,
+                packet: this,
+                pn_offset,
             },
             packet,
         ))
     }
+
+    /// The destination connection ID of the packet at the start of `datagram`, which may be truncated after the ID
+    ///
+    /// `dest_id_len` is the length of the ID if the packet has a short header, which doesn't record it.
+    pub fn peek_destination_id(datagram: &[u8], dest_id_len: usize) -> Option<ConnectionId> {
+        let mut destination = None;
+        let _ = Self::decode_plain(
+            &mut io::Cursor::new(datagram),
+            dest_id_len,
+            &mut destination,
+            &mut None,
+        );
+        destination
+    }
+
+    /// Decode the header of the packet `buf` reads up to the packet number, returning the packet number's offset,
+    /// the length of the packet, and the header
+    ///
+    /// Connection IDs are stored in `destination` and `source` as they're decoded, so they can be reported even if a
+    /// later field is malformed.
+    fn decode_plain(
+        buf: &mut io::Cursor<&[u8]>,
+        dest_id_len: usize,
+        destination: &mut Option<ConnectionId>,
+        source: &mut Option<ConnectionId>,
+    ) -> Result<(usize, usize, PlainHeader), HeaderError> {
+        let packet = *buf.get_ref();
+        let first = buf.get::<u8>()?;
+        if first & LONG_HEADER_FORM == 0 {
+            if buf.remaining() < dest_id_len {
+                return Err(HeaderError::InvalidHeader(
+                    "destination connection ID longer than packet",
+                ));
+            }
+            let id = ConnectionId::from_buf(buf, dest_id_len);
+            *destination = Some(id.clone());
+            return Ok((
+                buf.position() as usize,
+                packet.len(),
+                PlainHeader::Short { id },
+            ));
+        }
+
+        let version = buf.get::<u32>()?;
+        let destination_id = decode_cid(buf)?;
+        *destination = Some(destination_id.clone());
+        let source_id = decode_cid(buf)?;
+        *source = Some(source_id.clone());
+        if version == 0 {
+            return Ok((
+                buf.position() as usize,
+                packet.len(),
+                PlainHeader::VersionNegotiate {
+                    ty: first & !LONG_HEADER_FORM,
+                    source_id,
+                    destination_id,
+                },
+            ));
+        }
+        let version = match Version::from_number(version) {
+            Some(x) => x,
+            None => {
+                return Err(HeaderError::UnsupportedVersion {
+                    source: source_id,
+                    destination: destination_id,
+                })
+            }
+        };
+        let ty = decode_long_type((first & LONG_TYPE_MASK) >> 4, version);
+        if ty == types::RETRY {
+            // The token extends to the end of the datagram
+            let orig_dst_cid = decode_cid(buf)?;
+            if orig_dst_cid.is_empty() {
+                return Err(HeaderError::InvalidHeader(
+                    "empty original destination connection ID",
+                ));
+            }
+            return Ok((
+                buf.position() as usize,
+                packet.len(),
+                PlainHeader::Retry {
+                    version,
+                    source_id,
+                    destination_id,
+                    orig_dst_cid,
+                },
+            ));
+        }
+
+        let plain_header = if ty == types::INITIAL {
+            let token_len = buf.get_var()?;
+            if token_len > buf.remaining() as u64 {
+                return Err(HeaderError::InvalidHeader("token longer than packet"));
+            }
+            let start = buf.position() as usize;
+            let token = Bytes::from(&packet[start..start + token_len as usize]);
+            buf.advance(token_len as usize);
+            PlainHeader::Initial {
+                version,
+                source_id,
+                destination_id,
+                token,
+            }
+        } else {
+            PlainHeader::Long {
+                version,
+                ty,
+                source_id,
+                destination_id,
+            }
+        };
+        // Covers the packet number and the protected payload
+        let len = buf.get_var()?;
+        if buf.position() + len > packet.len() as u64 {
+            return Err(HeaderError::InvalidHeader("payload longer than packet"));
+        }
+        Ok((
+            buf.position() as usize,
+            (buf.position() + len) as usize,
+            plain_header,
+        ))
+    }
+
+    pub fn dst_cid(&self) -> &ConnectionId {
+        match self.plain_header {
+            PlainHeader::Initial {
+                ref destination_id, ..
+            } => destination_id,
+            PlainHeader::Long {
+                ref destination_id, ..
+            } => destination_id,
+            PlainHeader::Short { ref id } => id,
+            PlainHeader::Retry {
+                ref destination_id, ..
+            } => destination_id,
+            PlainHeader::VersionNegotiate {
+                ref destination_id, ..
+            } => destination_id,
+        }
+    }
+
+    /// The version of QUIC a long header packet belongs to, other than Version Negotiation
+    pub fn version(&self) -> Option<Version> {
+        match self.plain_header {
+            PlainHeader::Initial { version, .. }
+            | PlainHeader::Long { version, .. }
+            | PlainHeader::Retry { version, .. } => Some(version),
+            PlainHeader::Short { .. } | PlainHeader::VersionNegotiate { .. } => None,
+        }
+    }
+
+    /// The source connection ID of a long header packet
+    pub fn source_id(&self) -> Option<&ConnectionId> {
+        match self.plain_header {
+            PlainHeader::Initial { ref source_id, .. }
+            | PlainHeader::Long { ref source_id, .. }
+            | PlainHeader::Retry { ref source_id, .. }
+            | PlainHeader::VersionNegotiate { ref source_id, .. } => Some(source_id),
+            PlainHeader::Short { .. } => None,
+        }
+    }
+
+    pub fn has_long_header(&self) -> bool {
+        match self.plain_header {
+            PlainHeader::Short { .. } => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_initial(&self) -> bool {
+        match self.plain_header {
+            PlainHeader::Initial { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The type of a long header packet, which is not covered by header protection
+    pub fn long_type(&self) -> Option<u8> {
+        match self.plain_header {
+            PlainHeader::Initial { .. } => Some(types::INITIAL),
+            PlainHeader::Long { ty, .. } => Some(ty),
+            PlainHeader::Retry { .. } => Some(types::RETRY),
+            _ => None,
+        }
+    }
+
+    /// Offset of the ciphertext sample used to compute the header protection mask
+    pub fn sample_offset(&self) -> usize {
+        self.pn_offset + 4
+    }
+
+    /// The raw, still protected, packet
+    pub fn data(&self) -> &[u8] {
+        &self.packet
+    }
+
+    /// Remove header protection using `header_key` and decode the remainder of the header
+    pub fn finish(self, header_key: &HeaderKey) -> Result<Packet, HeaderError> {
+        let PartialDecode {
+            plain_header,
+            mut packet,
+            pn_offset,
+        } = self;
+        let (header_len, header) = match plain_header {
+            PlainHeader::VersionNegotiate {
+                ty,
+                source_id,
+                destination_id,
+            } => (
+                pn_offset,
+                Header::VersionNegotiate {
+                    ty,
+                    source_id,
+                    destination_id,
+                },
+            ),
+            PlainHeader::Retry {
+                version,
+                source_id,
+                destination_id,
+                orig_dst_cid,
+            } => (
+                pn_offset,
+                Header::Retry {
+                    version,
+                    source_id,
+                    destination_id,
+                    orig_dst_cid,
+                },
+            ),
+            plain_header => {
+                Header::decrypt_header(&mut packet, pn_offset, header_key)?;
+                let first = packet[0];
+                let pn_len = (first & PACKET_NUMBER_LEN_MASK) as usize + 1;
+                let number =
+                    PacketNumber::decode(pn_len, &mut io::Cursor::new(&packet[pn_offset..]))?;
+                let header = match plain_header {
+                    PlainHeader::Initial {
+                        version,
+                        source_id,
+                        destination_id,
+                        token,
+                    } => Header::Initial {
+                        version,
+                        source_id,
+                        destination_id,
+                        token,
+                        number,
+                    },
+                    PlainHeader::Long {
+                        version,
+                        ty,
+                        source_id,
+                        destination_id,
+                    } => Header::Long {
+                        version,
+                        ty,
+                        source_id,
+                        destination_id,
+                        number,
+                    },
+                    PlainHeader::Short { id } => Header::Short {
+                        id,
+                        number,
+                        spin: first & SPIN_BIT != 0,
+                        key_phase: first & KEY_PHASE_BIT != 0,
+                    },
+                    PlainHeader::Retry { .. } | PlainHeader::VersionNegotiate { .. } => {
+                        unreachable!()
+                    }
+                };
+                (pn_offset + pn_len, header)
+            }
+        };
+        let header_data = packet.split_to(header_len).freeze();
+        Ok(Packet {
+            header,
+            header_data,
+            payload: packet,
+        })
+    }
 }
 
 /// Protocol-level identifier for a connection.
@@ -326,12 +918,48 @@ impl ::std::ops::DerefMut for ConnectionId {
 }
 
 impl ConnectionId {
+    /// Construct from the first `len` bytes of `data`, which may be 0
+    ///
+    /// # Panics
+    /// - if `len` exceeds `MAX_CID_SIZE`
     pub fn new(data: [u8; MAX_CID_SIZE], len: usize) -> Self {
+        assert!(len <= MAX_CID_SIZE, "connection ID too long");
         let mut x = ConnectionId(data.into());
         x.0.truncate(len);
         x
     }
 
+    /// Copy an ID from a slice, failing if it's longer than `MAX_CID_SIZE`
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, TooLong> {
+        if bytes.len() > MAX_CID_SIZE {
+            return Err(TooLong);
+        }
+        let mut data = [0; MAX_CID_SIZE];
+        data[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self::new(data, bytes.len()))
+    }
+
+    /// Take an ID of `len` bytes, which may be 0, from the front of `buf` without staging it elsewhere first
+    ///
+    /// # Panics
+    /// - if `len` exceeds `MAX_CID_SIZE`
+    /// - if fewer than `len` bytes remain in `buf`
+    pub fn from_buf<B: Buf>(buf: &mut B, len: usize) -> Self {
+        assert!(len <= MAX_CID_SIZE, "connection ID too long");
+        let mut v = ArrayVec::from([0; MAX_CID_SIZE]);
+        v.truncate(len);
+        buf.copy_to_slice(&mut v);
+        ConnectionId(v)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
     pub fn random<R: Rng>(rng: &mut R, len: u8) -> Self {
         debug_assert!(len as usize <= MAX_CID_SIZE);
         let mut v = ArrayVec::from([0; MAX_CID_SIZE]);
@@ -341,6 +969,28 @@ impl ConnectionId {
     }
 }
 
+impl<'a> TryFrom<&'a [u8]> for ConnectionId {
+    type Error = TooLong;
+    fn try_from(bytes: &'a [u8]) -> Result<Self, TooLong> {
+        Self::from_slice(bytes)
+    }
+}
+
+/// Error indicating that too many packets are unacknowledged to encode the next packet number
+#[derive(Fail, Debug, Copy, Clone, Eq, PartialEq)]
+#[fail(display = "packet number too far ahead of the largest acknowledged")]
+pub struct PacketNumberTooLarge;
+
+/// Error indicating that a long header packet's payload is too long for the space reserved for its length
+#[derive(Fail, Debug, Copy, Clone, Eq, PartialEq)]
+#[fail(display = "payload length exceeds reserved space")]
+pub struct PayloadTooLong;
+
+/// Error indicating that a connection ID would exceed `MAX_CID_SIZE` bytes
+#[derive(Fail, Debug, Copy, Clone, Eq, PartialEq)]
+#[fail(display = "connection ID too long")]
+pub struct TooLong;
+
 impl fmt::Display for ConnectionId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for byte in &self.0 {
@@ -350,6 +1000,83 @@ impl fmt::Display for ConnectionId {
     }
 }
 
+impl str::FromStr for ConnectionId {
+    type Err = ParseConnectionIdError;
+
+    /// Parse the hex form produced by `Display`
+    fn from_str(s: &str) -> Result<Self, ParseConnectionIdError> {
+        if s.len() % 2 != 0 {
+            return Err(ParseConnectionIdError::OddLength);
+        }
+        let len = s.len() / 2;
+        if len > MAX_CID_SIZE {
+            return Err(ParseConnectionIdError::TooLong);
+        }
+        let mut data = [0; MAX_CID_SIZE];
+        for (byte, digits) in data.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digit = |x: u8| {
+                (x as char)
+                    .to_digit(16)
+                    .ok_or(ParseConnectionIdError::InvalidDigit)
+            };
+            *byte = (digit(digits[0])? << 4 | digit(digits[1])?) as u8;
+        }
+        Ok(Self::new(data, len))
+    }
+}
+
+/// Error indicating that a string isn't the hex form of a `ConnectionId`
+#[derive(Fail, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseConnectionIdError {
+    #[fail(display = "odd number of hex digits")]
+    OddLength,
+    #[fail(display = "connection ID too long")]
+    TooLong,
+    #[fail(display = "invalid hex digit")]
+    InvalidDigit,
+}
+
+/// Hex, like `Display`, in human-readable formats such as JSON, and raw bytes otherwise
+#[cfg(feature = "serde")]
+impl serde::Serialize for ConnectionId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ConnectionId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ConnectionId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a connection ID of at most {} bytes", MAX_CID_SIZE)
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<ConnectionId, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<ConnectionId, E> {
+                ConnectionId::from_slice(v).map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(Visitor)
+        } else {
+            deserializer.deserialize_bytes(Visitor)
+        }
+    }
+}
+
 impl slog::Value for ConnectionId {
     fn serialize(
         &self,
@@ -361,17 +1088,1187 @@ impl slog::Value for ConnectionId {
     }
 }
 
-pub fn set_payload_length(packet: &mut [u8], header_len: usize) {
-    let len = packet.len() - header_len + AEAD_TAG_SIZE;
-    assert!(len < 2usize.pow(14)); // Fits in reserved space
-    BigEndian::write_u16(&mut packet[header_len - 6..], len as u16 | 0b01 << 14);
+#[cfg(feature = "fuzzing")]
+impl Arbitrary for ConnectionId {
+    fn arbitrary(u: &mut Unstructured) -> arbitrary::Result<Self> {
+        let len = u.int_in_range(0..=MAX_CID_SIZE)?;
+        let mut stage = [0; MAX_CID_SIZE];
+        stage[..len].copy_from_slice(u.bytes(len)?);
+        Ok(ConnectionId::new(stage, len))
+    }
+}
+
+#[cfg(feature = "fuzzing")]
+impl Arbitrary for PacketNumber {
+    fn arbitrary(u: &mut Unstructured) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0u8..=3)? {
+            0 => PacketNumber::U8(u8::arbitrary(u)?),
+            1 => PacketNumber::U16(u16::arbitrary(u)?),
+            2 => PacketNumber::U24(u32::arbitrary(u)? & 0x00ff_ffff),
+            _ => PacketNumber::U32(u32::arbitrary(u)?),
+        })
+    }
+}
+
+/// Generates only headers that `PartialDecode` should accept, so that encoding can be checked to round-trip
+#[cfg(feature = "fuzzing")]
+impl Arbitrary for Header {
+    fn arbitrary(u: &mut Unstructured) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0u8..=4)? {
+            0 => {
+                let version = Version::arbitrary(u)?;
+                let source_id = ConnectionId::arbitrary(u)?;
+                let destination_id = ConnectionId::arbitrary(u)?;
+                let token_len = u.int_in_range(0..=64)?;
+                Header::Initial {
+                    version,
+                    source_id,
+                    destination_id,
+                    token: Bytes::from(u.bytes(token_len)?),
+                    number: PacketNumber::arbitrary(u)?,
+                }
+            }
+            1 => Header::Long {
+                version: Version::arbitrary(u)?,
+                ty: if bool::arbitrary(u)? {
+                    types::ZERO_RTT
+                } else {
+                    types::HANDSHAKE
+                },
+                source_id: ConnectionId::arbitrary(u)?,
+                destination_id: ConnectionId::arbitrary(u)?,
+                number: PacketNumber::arbitrary(u)?,
+            },
+            2 => Header::Short {
+                id: ConnectionId::arbitrary(u)?,
+                number: PacketNumber::arbitrary(u)?,
+                spin: bool::arbitrary(u)?,
+                key_phase: bool::arbitrary(u)?,
+            },
+            3 => {
+                let version = Version::arbitrary(u)?;
+                let source_id = ConnectionId::arbitrary(u)?;
+                let destination_id = ConnectionId::arbitrary(u)?;
+                let orig_dst_cid = ConnectionId::arbitrary(u)?;
+                if orig_dst_cid.is_empty() {
+                    return Err(arbitrary::Error::IncorrectFormat);
+                }
+                Header::Retry {
+                    version,
+                    source_id,
+                    destination_id,
+                    orig_dst_cid,
+                }
+            }
+            _ => Header::VersionNegotiate {
+                ty: u8::arbitrary(u)? & !LONG_HEADER_FORM,
+                source_id: ConnectionId::arbitrary(u)?,
+                destination_id: ConnectionId::arbitrary(u)?,
+            },
+        })
+    }
+}
+
+/// Encode a version negotiation packet listing `versions` and one randomly chosen reserved version
+///
+/// The reserved version is of the form 0x?a?a?a?a, which no real version uses, so that peers which can't tolerate unknown
+/// versions are caught early.
+pub fn version_negotiate<R: Rng>(
+    rng: &mut R,
+    source_id: ConnectionId,
+    destination_id: ConnectionId,
+    versions: &[u32],
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    Header::VersionNegotiate {
+        ty: rng.gen(),
+        source_id,
+        destination_id,
+    }.encode(&mut buf);
+    let grease = rng.gen_range(0, versions.len() + 1);
+    for (i, &version) in versions.iter().enumerate() {
+        if i == grease {
+            buf.write(reserved_version(rng));
+        }
+        buf.write(version);
+    }
+    if grease == versions.len() {
+        buf.write(reserved_version(rng));
+    }
+    buf
+}
+
+/// A random version of the form 0x?a?a?a?a, reserved for exercising version negotiation
+pub fn reserved_version<R: Rng>(rng: &mut R) -> u32 {
+    rng.gen::<u32>() & 0xf0f0_f0f0 | 0x0a0a_0a0a
+}
+
+/// Positions of the fields in an encoded header that are filled in or protected once the payload is known
+///
+/// Offsets are relative to the start of the header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartialEncode {
+    /// Length of the header, including the packet number
+    pub header_len: usize,
+    /// Offset of the packet number, or `header_len` for packets that don't have one
+    pub pn_offset: usize,
+    /// Varint reserved for the payload length, for long header packets that have one
+    pub len_slot: Option<Range<usize>>,
 }
 
+impl PartialEncode {
+    fn long(slot_start: usize, length_width: usize, pn_len: usize) -> Self {
+        let pn_offset = slot_start + length_width;
+        Self {
+            header_len: pn_offset + pn_len,
+            pn_offset,
+            len_slot: Some(slot_start..pn_offset),
+        }
+    }
+
+    fn unnumbered(header_len: usize) -> Self {
+        Self {
+            header_len,
+            pn_offset: header_len,
+            len_slot: None,
+        }
+    }
+}
+
+/// Fill in the payload length of a long header packet, in the `slot` reserved by `Header::encode_reserving`
+///
+/// The length covers everything following the slot, including the `tag_len`-byte AEAD tag that encryption will
+/// append. Fails, leaving the slot untouched, if the length doesn't fit; reserving `payload_length_width` of the
+/// largest size the packet may grow to rules that out.
+pub fn set_payload_length(
+    packet: &mut [u8],
+    slot: Range<usize>,
+    tag_len: usize,
+) -> Result<(), PayloadTooLong> {
+    let len = packet.len() - slot.end + tag_len;
+    let length_width = slot.end - slot.start;
+    if len >= 2usize.pow(8 * length_width as u32 - 2) {
+        return Err(PayloadTooLong);
+    }
+    let slot = &mut packet[slot];
+    match length_width {
+        2 => BigEndian::write_u16(slot, len as u16 | 0b01 << 14),
+        4 => BigEndian::write_u32(slot, len as u32 | 0b10 << 30),
+        _ => panic!("unsupported payload length width {}", length_width),
+    }
+    Ok(())
+}
+
+/// Number of bytes to reserve for the payload length of a long header packet no larger than `max_len`
+pub fn payload_length_width(max_len: usize) -> usize {
+    if max_len < 2usize.pow(14) {
+        2
+    } else {
+        4
+    }
+}
+
+/// Length of the AEAD tag of the standard cipher suites; see `Crypto::tag_len` for that of a negotiated suite
 pub const AEAD_TAG_SIZE: usize = 16;
 
+/// Long header packet types
 pub mod types {
-    pub const INITIAL: u8 = 0x7F;
-    pub const RETRY: u8 = 0x7E;
-    //pub const ZERO_RTT: u8 = 0x7C;
-    pub const HANDSHAKE: u8 = 0x7D;
+    pub const INITIAL: u8 = 0x0;
+    pub const ZERO_RTT: u8 = 0x1;
+    pub const HANDSHAKE: u8 = 0x2;
+    pub const RETRY: u8 = 0x3;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::{Crypto, SessionTicket, SESSION_TICKET_ID_SIZE};
+    use transport_parameters::TransportParameters;
+    use Side;
+
+    fn check_pn(number: u64, largest_acked: u64, len: usize) {
+        let pn = PacketNumber::new(number, largest_acked).unwrap();
+        assert_eq!(pn.len(), len);
+        let mut buf = Vec::new();
+        pn.encode(&mut buf);
+        assert_eq!(buf.len(), len);
+        let decoded = PacketNumber::decode(len, &mut io::Cursor::new(&buf[..])).unwrap();
+        assert_eq!(decoded, pn);
+        assert_eq!(decoded.expand(largest_acked), number);
+    }
+
+    #[test]
+    fn packet_number_widths() {
+        for &base in &[1, 0xdead_beef, 2u64.pow(62) - 2u64.pow(32)] {
+            check_pn(base + 1, base, 1);
+            check_pn(base + 127, base, 1);
+            check_pn(base + 128, base, 2);
+            check_pn(base + 2u64.pow(15) - 1, base, 2);
+            check_pn(base + 2u64.pow(15), base, 3);
+            check_pn(base + 2u64.pow(23) - 1, base, 3);
+            check_pn(base + 2u64.pow(23), base, 4);
+            check_pn(base + 2u64.pow(31) - 1, base, 4);
+        }
+        check_pn(2u64.pow(62) - 1, 2u64.pow(62) - 2, 1);
+    }
+
+    #[test]
+    fn packet_number_nothing_acked() {
+        check_pn(0, 0, 1);
+        check_pn(127, 0, 1);
+        check_pn(128, 0, 4);
+        check_pn(2u64.pow(32) - 1, 0, 4);
+        assert_eq!(
+            PacketNumber::new(2u64.pow(32), 0),
+            Err(PacketNumberTooLarge)
+        );
+    }
+
+    #[test]
+    fn packet_number_expand_edges() {
+        // Example from RFC 9000 appendix A.3
+        assert_eq!(PacketNumber::U16(0x9b32).expand(0xa82f_30ea), 0xa82f_9b32);
+        assert_eq!(PacketNumber::U8(0).expand(0), 0);
+        assert_eq!(PacketNumber::U8(0xff).expand(0), 0xff);
+        // Just below and above a window boundary
+        assert_eq!(PacketNumber::U8(0xff).expand(0x100), 0xff);
+        assert_eq!(PacketNumber::U8(0x01).expand(0xff), 0x101);
+        // The candidates straddling the expected value are equally distant; the later one wins
+        assert_eq!(PacketNumber::U8(0x81).expand(0x100), 0x181);
+        assert_eq!(PacketNumber::U8(0x82).expand(0x100), 0x82);
+        // Never past the largest packet number
+        assert_eq!(
+            PacketNumber::U8(0).expand(2u64.pow(62) - 2),
+            2u64.pow(62) - 0x100
+        );
+        // Out of range values of `prev` don't overflow
+        assert_eq!(
+            PacketNumber::U8(0xff).expand(u64::max_value()),
+            0xffff_ffff_ffff_ffff
+        );
+        assert_eq!(
+            PacketNumber::U32(0).expand(u64::max_value() - 1),
+            0xffff_ffff_0000_0000
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn packet_number_roundtrip(
+            largest_acked in 1u64..2u64.pow(62) - 2u64.pow(31),
+            distance in 1u64..2u64.pow(31),
+            received in 0u64..2u64.pow(31)
+        ) {
+            let number = largest_acked + distance;
+            let pn = PacketNumber::new(number, largest_acked).unwrap();
+            // The peer may have received more than we know it has
+            let prev = largest_acked + received % distance;
+            prop_assert_eq!(pn.expand(prev), number);
+        }
+    }
+
+    #[test]
+    fn packet_number_too_large() {
+        // The peer stopped acknowledging packets long ago
+        let largest_acked = 1;
+        assert_eq!(
+            PacketNumber::new(largest_acked + 2u64.pow(31), largest_acked),
+            Err(PacketNumberTooLarge)
+        );
+        assert_eq!(
+            PacketNumber::new(2u64.pow(62) - 1, largest_acked),
+            Err(PacketNumberTooLarge)
+        );
+    }
+
+    fn protect(crypto: &Crypto, header: Header, number: u64, payload: &[u8]) -> Vec<u8> {
+        protect_reserving(crypto, header, number, payload, 2)
+    }
+
+    fn protect_reserving(
+        crypto: &Crypto,
+        header: Header,
+        number: u64,
+        payload: &[u8],
+        length_width: usize,
+    ) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let partial = header.encode_reserving(&mut buf, length_width);
+        let header_len = buf.len();
+        assert_eq!(partial.header_len, header_len);
+        buf.extend_from_slice(payload);
+        if let Some(slot) = partial.len_slot {
+            set_payload_length(&mut buf, slot, AEAD_TAG_SIZE).unwrap();
+        }
+        crypto.encrypt(number, &mut buf, header_len);
+        Header::encrypt_header(&mut buf, header_len, crypto.local_header_key());
+        buf
+    }
+
+    fn unprotect(crypto: &Crypto, packet: Vec<u8>) -> Option<(Header, BytesMut)> {
+        let (partial, rest) = PartialDecode::new(BytesMut::from(packet), 8).unwrap();
+        assert!(rest.is_empty());
+        let mut packet = partial.finish(crypto.remote_header_key()).ok()?;
+        let number = match packet.header {
+            Header::Initial { number, .. }
+            | Header::Long { number, .. }
+            | Header::Short { number, .. } => number.expand(0),
+            _ => unreachable!(),
+        };
+        crypto
+            .decrypt(number, &packet.header_data, &mut packet.payload)
+            .ok()?;
+        Some((packet.header, packet.payload))
+    }
+
+    #[test]
+    fn header_protection_roundtrip() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        for &(number, full) in &[
+            (PacketNumber::U8(0x12), 0x12),
+            (PacketNumber::U16(0x1234), 0x1234),
+            (PacketNumber::U24(0x12_3456), 0x12_3456),
+            (PacketNumber::U32(0x1234_5678), 0x1234_5678),
+        ] {
+            let spin = number.len() % 2 == 0;
+            let header = Header::Short {
+                id: id.clone(),
+                number,
+                spin,
+                key_phase: true,
+            };
+            let packet = protect(&client, header, full, b"payload");
+            let (header, payload) = unprotect(&server, packet).unwrap();
+            assert_eq!(&payload[..], b"payload");
+            match header {
+                Header::Short {
+                    number: decoded,
+                    spin: decoded_spin,
+                    key_phase,
+                    ..
+                } => {
+                    assert_eq!(decoded, number);
+                    assert_eq!(decoded_spin, spin);
+                    assert!(key_phase);
+                }
+                ref x => panic!("unexpected header {:?}", x),
+            }
+
+            let header = Header::Long {
+                version: Version::V1,
+                ty: types::HANDSHAKE,
+                source_id: id.clone(),
+                destination_id: id.clone(),
+                number,
+            };
+            let packet = protect(&client, header, full, b"payload");
+            let (header, payload) = unprotect(&server, packet).unwrap();
+            assert_eq!(&payload[..], b"payload");
+            match header {
+                Header::Long {
+                    ty, number: decoded, ..
+                } => {
+                    assert_eq!(ty, types::HANDSHAKE);
+                    assert_eq!(decoded, number);
+                }
+                ref x => panic!("unexpected header {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn greased_quic_bit() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        let short = |spin| Header::Short {
+            id: id.clone(),
+            number: PacketNumber::U8(7),
+            spin,
+            key_phase: false,
+        };
+        let long = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number: PacketNumber::U32(7),
+        };
+        // Without a spin bit, the QUIC bit is all that keeps a short header from beginning with zero
+        for &(ref header, cleared) in &[(short(true), true), (short(false), false), (long, true)] {
+            let mut buf = Vec::new();
+            let partial = header.encode_with_quic_bit(&mut buf, 2, false);
+            assert_eq!(buf[0] & FIXED_BIT == 0, cleared);
+            let header_len = buf.len();
+            buf.extend_from_slice(b"payload");
+            if let Some(slot) = partial.len_slot {
+                set_payload_length(&mut buf, slot, AEAD_TAG_SIZE).unwrap();
+            }
+            client.encrypt(7, &mut buf, header_len);
+            Header::encrypt_header(&mut buf, header_len, client.local_header_key());
+            let (decoded, payload) = unprotect(&server, buf).unwrap();
+            assert_eq!(decoded, *header);
+            assert_eq!(&payload[..], b"payload");
+        }
+    }
+
+    #[test]
+    fn cid_lengths() {
+        for &len in &[0, 8, MAX_CID_SIZE] {
+            let id = ConnectionId::new([0xab; MAX_CID_SIZE], len);
+            for header in vec![
+                Header::Long {
+                    version: Version::V1,
+                    ty: types::HANDSHAKE,
+                    source_id: ConnectionId::new([0xcd; MAX_CID_SIZE], MAX_CID_SIZE - len),
+                    destination_id: id.clone(),
+                    number: PacketNumber::U8(1),
+                },
+                Header::Short {
+                    id: id.clone(),
+                    number: PacketNumber::U8(1),
+                    spin: false,
+                    key_phase: false,
+                },
+            ] {
+                let mut buf = Vec::new();
+                let partial = header.encode(&mut buf);
+                buf.extend_from_slice(&[0; 32]);
+                if let Some(slot) = partial.len_slot {
+                    set_payload_length(&mut buf, slot, AEAD_TAG_SIZE).unwrap();
+                }
+                buf.extend_from_slice(&[0; AEAD_TAG_SIZE]);
+                let (partial, rest) = PartialDecode::new(BytesMut::from(buf), len).unwrap();
+                assert!(rest.is_empty());
+                assert_eq!(partial.dst_cid(), &id);
+            }
+        }
+
+        // Lengths beyond the limit are rejected rather than truncated
+        let mut buf = Vec::new();
+        Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: ConnectionId::new([0xcd; MAX_CID_SIZE], 8),
+            destination_id: ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+            number: PacketNumber::U8(1),
+        }.encode(&mut buf);
+        buf[5] = MAX_CID_SIZE as u8 + 1;
+        buf.extend_from_slice(&[0; 32]);
+        let first = buf[0];
+        assert_eq!(
+            PartialDecode::new(BytesMut::from(buf), 8).err(),
+            Some(HeaderError::Malformed {
+                reason: "connection ID too long",
+                offset: 6,
+                first,
+                destination: None,
+                source: None,
+            })
+        );
+    }
+
+    #[test]
+    fn header_spaces() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let number = PacketNumber::U8(1);
+        let long = |ty| Header::Long {
+            version: Version::V1,
+            ty,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number,
+        };
+        for &(ref header, space, has_number) in &[
+            (
+                Header::Initial {
+                    version: Version::V1,
+                    source_id: id.clone(),
+                    destination_id: id.clone(),
+                    token: Bytes::new(),
+                    number,
+                },
+                SpaceId::Initial,
+                true,
+            ),
+            (long(types::HANDSHAKE), SpaceId::Handshake, true),
+            (long(types::ZERO_RTT), SpaceId::Data, true),
+            (
+                Header::Short {
+                    id: id.clone(),
+                    number,
+                    spin: false,
+                    key_phase: false,
+                },
+                SpaceId::Data,
+                true,
+            ),
+            (
+                Header::Retry {
+                    version: Version::V1,
+                    source_id: id.clone(),
+                    destination_id: id.clone(),
+                    orig_dst_cid: id.clone(),
+                },
+                SpaceId::Initial,
+                false,
+            ),
+            (
+                Header::VersionNegotiate {
+                    ty: 0,
+                    source_id: id.clone(),
+                    destination_id: id.clone(),
+                },
+                SpaceId::Initial,
+                false,
+            ),
+        ] {
+            assert_eq!(header.space(), space, "{:?}", header);
+            let expected = if has_number { Some(number) } else { None };
+            assert_eq!(header.number(), expected, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        let short = Header::Short {
+            id: id.clone(),
+            number: PacketNumber::U8(1),
+            spin: false,
+            key_phase: false,
+        };
+        let long = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number: PacketNumber::U8(1),
+        };
+        for &(ref header, reserved) in &[(short, SHORT_RESERVED_BITS), (long, LONG_RESERVED_BITS)] {
+            for bit in (0..8).map(|i| 1 << i).filter(|&x| reserved & x != 0) {
+                let mut buf = Vec::new();
+                let partial = header.encode(&mut buf);
+                buf[0] |= bit;
+                let header_len = buf.len();
+                buf.extend_from_slice(b"payload");
+                if let Some(slot) = partial.len_slot {
+                    set_payload_length(&mut buf, slot, AEAD_TAG_SIZE).unwrap();
+                }
+                client.encrypt(1, &mut buf, header_len);
+                Header::encrypt_header(&mut buf, header_len, client.local_header_key());
+
+                let (partial, _) = PartialDecode::new(BytesMut::from(buf), 8).unwrap();
+                let mut packet = partial.finish(server.remote_header_key()).unwrap();
+                // The reserved bits are authenticated like the rest of the header
+                server
+                    .decrypt(1, &packet.header_data, &mut packet.payload)
+                    .unwrap();
+                assert_eq!(
+                    packet.check_reserved_bits(),
+                    Err(HeaderError::ReservedBitsSet)
+                );
+            }
+
+            let packet = protect(&client, header.clone(), 1, b"payload");
+            let (partial, _) = PartialDecode::new(BytesMut::from(packet), 8).unwrap();
+            let packet = partial.finish(server.remote_header_key()).unwrap();
+            assert_eq!(packet.check_reserved_bits(), Ok(()));
+        }
+    }
+
+    /// Payload sizes whose length field is just below and just above the largest value 2 bytes can encode
+    const LARGEST_SHORT_LENGTH_PAYLOAD: usize = 2usize.pow(14) - 1 - 4 - AEAD_TAG_SIZE;
+
+    #[test]
+    fn payload_length_widths() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        for &(payload_len, length_width) in &[
+            (10, 2),
+            (10, 4),
+            (LARGEST_SHORT_LENGTH_PAYLOAD, 2),
+            (LARGEST_SHORT_LENGTH_PAYLOAD, 4),
+            (LARGEST_SHORT_LENGTH_PAYLOAD + 1, 4),
+            (60000, 4),
+        ] {
+            let header = Header::Long {
+                version: Version::V1,
+                ty: types::HANDSHAKE,
+                source_id: id.clone(),
+                destination_id: id.clone(),
+                number: PacketNumber::U32(0x1234_5678),
+            };
+            let payload = vec![0x42; payload_len];
+            let mut packet =
+                protect_reserving(&client, header, 0x1234_5678, &payload, length_width);
+            // Trailing data must be identified as a separate, coalesced packet
+            let packet_len = packet.len();
+            packet.extend_from_slice(&[0; 32]);
+            let (partial, rest) = PartialDecode::new(BytesMut::from(packet), 8).unwrap();
+            assert_eq!(rest.len(), 32);
+            let mut packet = partial.finish(server.remote_header_key()).unwrap();
+            assert_eq!(packet.header_data.len() + packet.payload.len(), packet_len);
+            server
+                .decrypt(0x1234_5678, &packet.header_data, &mut packet.payload)
+                .unwrap();
+            assert_eq!(&packet.payload[..], &payload[..]);
+        }
+        assert_eq!(payload_length_width(1200), 2);
+        assert_eq!(payload_length_width(2usize.pow(14) - 1), 2);
+        assert_eq!(payload_length_width(2usize.pow(14)), 4);
+    }
+
+    #[test]
+    fn payload_length_tag_lens() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        for &tag_len in &[AEAD_TAG_SIZE, 8] {
+            let header = Header::Long {
+                version: Version::V1,
+                ty: types::HANDSHAKE,
+                source_id: id.clone(),
+                destination_id: id.clone(),
+                number: PacketNumber::U32(0x1234_5678),
+            };
+            let mut buf = Vec::new();
+            let slot = header.encode(&mut buf).len_slot.unwrap();
+            // The most that fits in the 2 bytes reserved once the tag is accounted for
+            let largest = 2usize.pow(14) - 1;
+            buf.resize(slot.end + largest - tag_len, 0);
+            set_payload_length(&mut buf, slot.clone(), tag_len).unwrap();
+            assert_eq!(
+                BigEndian::read_u16(&buf[slot]) as usize,
+                0b01 << 14 | largest
+            );
+        }
+    }
+
+    #[test]
+    fn payload_length_overflow() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number: PacketNumber::U32(0),
+        };
+        let mut buf = Vec::new();
+        let slot = header.encode(&mut buf).len_slot.unwrap();
+        buf.extend_from_slice(&[0; LARGEST_SHORT_LENGTH_PAYLOAD + 1]);
+        let before = buf.clone();
+        assert_eq!(
+            set_payload_length(&mut buf, slot, AEAD_TAG_SIZE),
+            Err(PayloadTooLong)
+        );
+        assert_eq!(buf, before);
+    }
+
+    #[test]
+    fn encode_offsets() {
+        let dcid = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let scid = ConnectionId::new([0xcd; MAX_CID_SIZE], 4);
+        let cases = vec![
+            (
+                Header::Initial {
+                    version: Version::V1,
+                    source_id: scid.clone(),
+                    destination_id: dcid.clone(),
+                    token: Bytes::from(&b"token"[..]),
+                    number: PacketNumber::U32(0x1234_5678),
+                },
+                2,
+                PartialEncode {
+                    header_len: 31,
+                    pn_offset: 27,
+                    len_slot: Some(25..27),
+                },
+            ),
+            (
+                Header::Long {
+                    version: Version::V1,
+                    ty: types::HANDSHAKE,
+                    source_id: scid.clone(),
+                    destination_id: dcid.clone(),
+                    number: PacketNumber::U32(0x1234_5678),
+                },
+                4,
+                PartialEncode {
+                    header_len: 27,
+                    pn_offset: 23,
+                    len_slot: Some(19..23),
+                },
+            ),
+            (
+                Header::Short {
+                    id: dcid.clone(),
+                    number: PacketNumber::U16(0x1234),
+                    spin: false,
+                    key_phase: false,
+                },
+                2,
+                PartialEncode {
+                    header_len: 11,
+                    pn_offset: 9,
+                    len_slot: None,
+                },
+            ),
+            (
+                Header::Retry {
+                    version: Version::V1,
+                    source_id: scid.clone(),
+                    destination_id: dcid.clone(),
+                    orig_dst_cid: dcid.clone(),
+                },
+                2,
+                PartialEncode {
+                    header_len: 28,
+                    pn_offset: 28,
+                    len_slot: None,
+                },
+            ),
+            (
+                Header::VersionNegotiate {
+                    ty: 0x2a,
+                    source_id: scid.clone(),
+                    destination_id: dcid.clone(),
+                },
+                2,
+                PartialEncode {
+                    header_len: 19,
+                    pn_offset: 19,
+                    len_slot: None,
+                },
+            ),
+        ];
+        for (header, length_width, expected) in cases {
+            let mut buf = Vec::new();
+            let partial = header.encode_reserving(&mut buf, length_width);
+            assert_eq!(partial, expected, "{:?}", header);
+            assert_eq!(buf.len(), partial.header_len);
+            if let Some(number) = header.number() {
+                let mut pn = Vec::new();
+                number.encode(&mut pn);
+                assert_eq!(&buf[partial.pn_offset..], &pn[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_rtt_roundtrip() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let ticket = SessionTicket {
+            id: [0xcd; SESSION_TICKET_ID_SIZE],
+            secret: vec![0xef; 32],
+            params: TransportParameters::default(),
+        };
+        let crypto = Crypto::new_0rtt(&ticket, Version::V1);
+        let number = PacketNumber::U32(0x1234_5678);
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::ZERO_RTT,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number,
+        };
+        let packet = protect(&crypto, header, 0x1234_5678, b"payload");
+        let partial = PartialDecode::new(BytesMut::from(&packet[..]), 8).unwrap().0;
+        assert_eq!(partial.long_type(), Some(types::ZERO_RTT));
+        let (header, payload) = unprotect(&crypto, packet).unwrap();
+        assert_eq!(&payload[..], b"payload");
+        match header {
+            Header::Long {
+                ty, number: decoded, ..
+            } => {
+                assert_eq!(ty, types::ZERO_RTT);
+                assert_eq!(decoded, number);
+            }
+            ref x => panic!("unexpected header {:?}", x),
+        }
+    }
+
+    #[test]
+    fn header_protection_tampered_sample() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        let header = Header::Short {
+            id: id.clone(),
+            number: PacketNumber::U16(0x1234),
+            spin: false,
+            key_phase: false,
+        };
+        let mut packet = protect(&client, header, 0x1234, b"payload");
+        let offset = PartialDecode::new(BytesMut::from(&packet[..]), 8)
+            .unwrap()
+            .0
+            .sample_offset();
+        packet[offset] ^= 0x01;
+        assert!(unprotect(&server, packet).is_none());
+    }
+
+    #[test]
+    fn initial_token_roundtrip() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V1);
+        // Empty on the first flight, a short token, and one long enough to need a two-byte length
+        for expected in &[&[][..], &b"token"[..], &[0x5a; 100][..]] {
+            let header = Header::Initial {
+                version: Version::V1,
+                source_id: id.clone(),
+                destination_id: id.clone(),
+                token: Bytes::from(*expected),
+                number: PacketNumber::U32(0x1234_5678),
+            };
+            let packet = protect(&client, header, 0x1234_5678, b"payload");
+            let (header, payload) = unprotect(&server, packet).unwrap();
+            assert_eq!(&payload[..], b"payload");
+            match header {
+                Header::Initial { token, number, .. } => {
+                    assert_eq!(&token[..], *expected);
+                    assert_eq!(number, PacketNumber::U32(0x1234_5678));
+                }
+                ref x => panic!("unexpected header {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn initial_token_truncated() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let mut buf = Vec::new();
+        Header::Initial {
+            version: Version::V1,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            token: Bytes::from(&[0x5a; 100][..]),
+            number: PacketNumber::U32(0),
+        }.encode(&mut buf);
+        // Flags, version, CID lengths, and both CIDs precede the token length
+        let token_start = 1 + 4 + 1 + 8 + 1 + 8;
+        assert_eq!(&buf[token_start..token_start + 2], &[0x40, 100]);
+        let malformed = |reason, offset| {
+            Some(HeaderError::Malformed {
+                reason,
+                offset,
+                first: buf[0],
+                destination: Some(id.clone()),
+                source: Some(id.clone()),
+            })
+        };
+        // The token length itself is cut short
+        assert_eq!(
+            PartialDecode::new(BytesMut::from(&buf[..token_start + 1]), 8).err(),
+            malformed("unexpected end of packet", token_start + 1)
+        );
+        // The token extends past the end of the packet
+        assert_eq!(
+            PartialDecode::new(BytesMut::from(&buf[..token_start + 2 + 50]), 8).err(),
+            malformed("token longer than packet", token_start + 2)
+        );
+        // A length for a token that isn't there at all
+        let mut missing = buf[..token_start].to_vec();
+        missing.push(1);
+        assert_eq!(
+            PartialDecode::new(BytesMut::from(missing), 8).err(),
+            malformed("token longer than packet", token_start + 1)
+        );
+    }
+
+    #[test]
+    fn retry_coding() {
+        let orig = ConnectionId::new([0xcd; MAX_CID_SIZE], MAX_CID_SIZE);
+        let mut buf = Vec::new();
+        Header::Retry {
+            version: Version::V1,
+            source_id: ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+            destination_id: ConnectionId::new([0xef; MAX_CID_SIZE], 8),
+            orig_dst_cid: orig.clone(),
+        }.encode(&mut buf);
+        buf.extend_from_slice(b"token");
+        let (partial, rest) = PartialDecode::new(BytesMut::from(&buf[..]), 8).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(partial.long_type(), Some(types::RETRY));
+        // Retry packets aren't protected, so any key will do
+        let key = Crypto::new_handshake(&orig, Side::Client, Version::V1);
+        let packet = partial.finish(key.remote_header_key()).unwrap();
+        assert_eq!(&packet.payload[..], b"token");
+        match packet.header {
+            Header::Retry {
+                version,
+                source_id,
+                destination_id,
+                orig_dst_cid,
+            } => {
+                assert_eq!(version, Version::V1);
+                assert_eq!(&source_id[..], &[0xab; 8]);
+                assert_eq!(&destination_id[..], &[0xef; 8]);
+                assert_eq!(orig_dst_cid, orig);
+            }
+            ref x => panic!("unexpected header {:?}", x),
+        }
+
+        // Truncated original destination connection ID
+        let header_len = buf.len() - b"token".len();
+        assert!(PartialDecode::new(BytesMut::from(&buf[..header_len - 1]), 8).is_err());
+        // Zero length original destination connection ID
+        let mut empty = buf[..header_len - orig.len()].to_vec();
+        *empty.last_mut().unwrap() = 0;
+        empty.extend_from_slice(b"token");
+        assert!(PartialDecode::new(BytesMut::from(empty), 8).is_err());
+    }
+
+    #[test]
+    fn v2_initial_roundtrip() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let client = Crypto::new_handshake(&id, Side::Client, Version::V2);
+        let server = Crypto::new_handshake(&id, Side::Server, Version::V2);
+        let header = Header::Initial {
+            version: Version::V2,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            token: Bytes::new(),
+            number: PacketNumber::U32(0x1234_5678),
+        };
+        let packet = protect(&client, header, 0x1234_5678, b"payload");
+        // Version 2 moves Initial packets to the codepoint version 1 uses for 0-RTT
+        assert_eq!((packet[0] & LONG_TYPE_MASK) >> 4, 0b01);
+        assert_eq!(&packet[1..5], &[0x51, 0x55, 0x49, 0x02]);
+        assert_eq!(long_type(packet[0], Version::V2), Some(types::INITIAL));
+        assert_eq!(long_type(packet[0], Version::V1), Some(types::ZERO_RTT));
+
+        let (partial, _) = PartialDecode::new(BytesMut::from(&packet[..]), 8).unwrap();
+        assert_eq!(partial.version(), Some(Version::V2));
+        assert!(partial.is_initial());
+        let (header, payload) = unprotect(&server, packet).unwrap();
+        assert_matches!(
+            header,
+            Header::Initial {
+                version: Version::V2,
+                ..
+            }
+        );
+        assert_eq!(&payload[..], b"payload");
+    }
+
+    #[test]
+    fn long_type_codepoints() {
+        for &ty in &[
+            types::INITIAL,
+            types::ZERO_RTT,
+            types::HANDSHAKE,
+            types::RETRY,
+        ] {
+            assert_eq!(encode_long_type(ty, Version::V1), ty);
+            assert_ne!(encode_long_type(ty, Version::V2), ty);
+            for &version in &[Version::V1, Version::V2] {
+                assert_eq!(decode_long_type(encode_long_type(ty, version), version), ty);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_coalesced() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 8);
+        let crypto = Crypto::new_handshake(&id, Side::Client, Version::V1);
+        let long = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: id.clone(),
+            destination_id: id.clone(),
+            number: PacketNumber::U32(1),
+        };
+        let short = Header::Short {
+            id: id.clone(),
+            number: PacketNumber::U8(3),
+            spin: false,
+            key_phase: false,
+        };
+        let mut datagram = protect(&crypto, long.clone(), 1, b"first");
+        datagram.extend_from_slice(&[0; 3]);
+        datagram.extend_from_slice(&protect(&crypto, long, 2, b"second"));
+        // The short header packet absorbs any trailing bytes, including what would otherwise be padding
+        datagram.extend_from_slice(&protect(&crypto, short, 3, b"third"));
+        let packets = PartialDecode::decode_all(BytesMut::from(&datagram[..]), 8)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].long_type(), Some(types::HANDSHAKE));
+        assert_eq!(packets[1].long_type(), Some(types::HANDSHAKE));
+        assert!(!packets[2].has_long_header());
+
+        // Trailing padding after a long header packet
+        let mut datagram = protect(
+            &crypto,
+            Header::Long {
+                version: Version::V1,
+                ty: types::HANDSHAKE,
+                source_id: id.clone(),
+                destination_id: id.clone(),
+                number: PacketNumber::U32(4),
+            },
+            4,
+            b"payload",
+        );
+        let len = datagram.len();
+        datagram.extend_from_slice(&[0; 32]);
+        let packets = PartialDecode::decode_all(BytesMut::from(&datagram[..]), 8);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].as_ref().unwrap().data().len(), len);
+
+        // A truncated packet ends decoding
+        let whole = datagram[..len].to_vec();
+        datagram.truncate(len - 1);
+        let packets = PartialDecode::decode_all(BytesMut::from(&datagram[..]), 8);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].is_err());
+
+        // Errors are located within the datagram as a whole
+        let mut coalesced = whole;
+        coalesced.extend_from_slice(&[0; 3]);
+        coalesced.extend_from_slice(&datagram);
+        let packets = PartialDecode::decode_all(BytesMut::from(&coalesced[..]), 8);
+        assert_eq!(packets.len(), 2);
+        // Decoding stops after the length field, which claims more data than remains
+        let pn_offset = 1 + 4 + 1 + 8 + 1 + 8 + 2;
+        assert_matches!(
+            packets[1],
+            Err(HeaderError::Malformed {
+                reason: "payload longer than packet",
+                offset,
+                ..
+            }) if offset == len + 3 + pn_offset
+        );
+    }
+
+    #[test]
+    fn empty_short_id() {
+        let id = ConnectionId::new([0xab; MAX_CID_SIZE], 0);
+        assert!(id.is_empty());
+        assert_eq!(id.to_string(), "");
+        let crypto = Crypto::new_handshake(
+            &ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+            Side::Client,
+            Version::V1,
+        );
+        let header = Header::Short {
+            id: id.clone(),
+            number: PacketNumber::U16(0x1234),
+            spin: true,
+            key_phase: false,
+        };
+        let packet = protect(&crypto, header, 0x1234, b"payload");
+        let (partial, rest) = PartialDecode::new(BytesMut::from(packet), 0).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(partial.dst_cid(), &id);
+        let mut packet = partial.finish(crypto.remote_header_key()).unwrap();
+        match packet.header {
+            Header::Short {
+                id: ref decoded,
+                number,
+                spin: true,
+                key_phase: false,
+            } => {
+                assert_eq!(decoded, &id);
+                assert_eq!(number, PacketNumber::U16(0x1234));
+            }
+            ref x => panic!("unexpected header {:?}", x),
+        }
+        crypto
+            .decrypt(0x1234, &packet.header_data, &mut packet.payload)
+            .unwrap();
+        assert_eq!(&packet.payload[..], b"payload");
+    }
+
+    #[test]
+    fn cid_from_slice() {
+        for &len in &[0, 3, MAX_CID_SIZE] {
+            let bytes = (0..len as u8).collect::<Vec<_>>();
+            let id = ConnectionId::from_slice(&bytes).unwrap();
+            assert_eq!(id.len(), len);
+            assert_eq!(id.is_empty(), len == 0);
+            assert_eq!(&id[..], &bytes[..]);
+            assert_eq!(ConnectionId::try_from(&bytes[..]), Ok(id));
+        }
+        let bytes = [0xab; MAX_CID_SIZE + 1];
+        assert_eq!(ConnectionId::from_slice(&bytes), Err(TooLong));
+        assert_eq!(ConnectionId::try_from(&bytes[..]), Err(TooLong));
+    }
+
+    #[test]
+    fn cid_from_buf() {
+        let data = [1, 2, 3, 4, 5];
+        let mut buf = io::Cursor::new(&data[..]);
+        assert_eq!(
+            ConnectionId::from_buf(&mut buf, 0),
+            ConnectionId::from_slice(&[]).unwrap()
+        );
+        assert_eq!(
+            ConnectionId::from_buf(&mut buf, 3),
+            ConnectionId::from_slice(&[1, 2, 3]).unwrap()
+        );
+        assert_eq!(buf.remaining(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "connection ID too long")]
+    fn cid_from_buf_too_long() {
+        let data = [0; MAX_CID_SIZE + 1];
+        ConnectionId::from_buf(&mut io::Cursor::new(&data[..]), MAX_CID_SIZE + 1);
+    }
+
+    #[test]
+    fn cid_hex_roundtrip() {
+        for &len in &[0, 3, MAX_CID_SIZE] {
+            // Bytes from 0xe0 up exercise letters in both digits without wrapping for any length
+            let id =
+                ConnectionId::from_slice(&(0xe0..0xe0 + len as u8).collect::<Vec<_>>()).unwrap();
+            assert_eq!(id.to_string().parse::<ConnectionId>(), Ok(id));
+        }
+        assert_eq!(
+            "00ff1a".parse::<ConnectionId>(),
+            Ok(ConnectionId::from_slice(&[0x00, 0xff, 0x1a]).unwrap())
+        );
+        assert_eq!(
+            "abc".parse::<ConnectionId>(),
+            Err(ParseConnectionIdError::OddLength)
+        );
+        assert_eq!(
+            "ab".repeat(MAX_CID_SIZE + 1).parse::<ConnectionId>(),
+            Err(ParseConnectionIdError::TooLong)
+        );
+        assert_eq!(
+            "0g".parse::<ConnectionId>(),
+            Err(ParseConnectionIdError::InvalidDigit)
+        );
+        assert_eq!(
+            "\u{e9}".parse::<ConnectionId>(),
+            Err(ParseConnectionIdError::InvalidDigit)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn cid_serde_roundtrip() {
+        let id = ConnectionId::from_slice(&[0xde, 0xad, 0xbe, 0xef]).unwrap();
+
+        let json = ::serde_json::to_string(&id).unwrap();
+        assert_eq!(json, "\"deadbeef\"");
+        assert_eq!(::serde_json::from_str::<ConnectionId>(&json).unwrap(), id);
+        assert!(::serde_json::from_str::<ConnectionId>("\"dea\"").is_err());
+
+        let binary = ::bincode::serialize(&id).unwrap();
+        // Length prefix, then the bytes themselves
+        assert_eq!(&binary[binary.len() - 4..], &id[..]);
+        assert_eq!(::bincode::deserialize::<ConnectionId>(&binary).unwrap(), id);
+        let long = ::bincode::serialize(&[0u8; MAX_CID_SIZE + 1][..]).unwrap();
+        assert!(::bincode::deserialize::<ConnectionId>(&long).is_err());
+    }
 }
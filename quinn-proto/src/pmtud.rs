@@ -0,0 +1,191 @@
+//! Datagram Packetization Layer Path MTU Discovery, as described in RFC 8899
+//!
+//! Starting from a size every QUIC path must support, we send PING frames padded to a larger probe size. An
+//! acknowledged probe proves the path carries datagrams that large; probes that are repeatedly lost, or an ICMP Packet
+//! Too Big message, narrow the range of sizes searched.
+
+use std::cmp;
+
+/// Number of times a probe of one size is sent before concluding that the path can't carry it
+const MAX_PROBES: u8 = 3;
+/// Search precision: probing stops once the upper bound is within this many bytes of the confirmed size
+const MIN_STEP: u16 = 20;
+
+/// Progress of path MTU discovery on a single path
+#[derive(Debug, Clone)]
+pub struct PmtudState {
+    /// Largest UDP payload known to be deliverable on the path
+    pub plpmtu: u16,
+    /// Size of the next probe to send, or of the one in flight
+    pub probe_size: u16,
+    /// Number of probes of `probe_size` that have been sent without being acknowledged
+    pub probe_count: u8,
+    /// Smallest UDP payload the path is guaranteed to support
+    base: u16,
+    /// Largest UDP payload the path might support
+    max: u16,
+    /// Packet number of the probe in flight, if any
+    in_flight: Option<u64>,
+}
+
+impl PmtudState {
+    /// Search sizes between `base` and `max`, or stick to `base` if `enabled` is false
+    pub fn new(base: u16, max: u16, enabled: bool) -> Self {
+        let max = if enabled { cmp::max(base, max) } else { base };
+        Self {
+            plpmtu: base,
+            // Paths commonly support the largest size, so try it first
+            probe_size: max,
+            probe_count: 0,
+            base,
+            max,
+            in_flight: None,
+        }
+    }
+
+    /// Whether the largest supported size has been found to within the search precision
+    pub fn is_complete(&self) -> bool {
+        self.max - self.plpmtu < MIN_STEP
+    }
+
+    /// Size of the probe to send now, if any
+    pub fn next_probe(&self) -> Option<u16> {
+        if self.in_flight.is_some() || self.is_complete() {
+            return None;
+        }
+        Some(self.probe_size)
+    }
+
+    pub fn on_probe_sent(&mut self, packet: u64) {
+        self.in_flight = Some(packet);
+        self.probe_count += 1;
+    }
+
+    /// Handle acknowledgement of `packet`, returning whether it was our probe
+    pub fn on_acked(&mut self, packet: u64) -> bool {
+        if self.in_flight != Some(packet) {
+            return false;
+        }
+        self.in_flight = None;
+        self.plpmtu = self.probe_size;
+        self.probe_count = 0;
+        self.probe_size = self.midpoint();
+        true
+    }
+
+    /// Handle loss of `packet`, returning whether it was our probe
+    pub fn on_lost(&mut self, packet: u64) -> bool {
+        if self.in_flight != Some(packet) {
+            return false;
+        }
+        self.in_flight = None;
+        if self.probe_count >= MAX_PROBES {
+            // Not a fluke; the path can't carry datagrams this large
+            self.max = self.probe_size - 1;
+            self.probe_count = 0;
+            self.probe_size = self.midpoint();
+        }
+        true
+    }
+
+    /// Handle an ICMP Packet Too Big message reporting that the path carries UDP payloads of at most `mtu` bytes
+    pub fn on_ptb(&mut self, mtu: u16) {
+        // Every path carries the guaranteed minimum, so smaller reports are mistaken or forged
+        let mtu = cmp::max(mtu, self.base);
+        if mtu >= self.max {
+            return;
+        }
+        self.max = mtu;
+        self.plpmtu = cmp::min(self.plpmtu, mtu);
+        if self.probe_size > mtu {
+            // The probe in flight, if any, is doomed
+            self.in_flight = None;
+            self.probe_count = 0;
+            self.probe_size = if self.is_complete() { self.plpmtu } else { mtu };
+        }
+    }
+
+    fn midpoint(&self) -> u16 {
+        self.plpmtu + (self.max - self.plpmtu + 1) / 2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn probe_succeeds() {
+        let mut state = PmtudState::new(1232, 1452, true);
+        assert_eq!(state.next_probe(), Some(1452));
+        state.on_probe_sent(1);
+        assert_eq!(state.next_probe(), None);
+        assert!(!state.on_acked(2));
+        assert!(state.on_acked(1));
+        assert_eq!(state.plpmtu, 1452);
+        assert!(state.is_complete());
+        assert_eq!(state.next_probe(), None);
+    }
+
+    #[test]
+    fn binary_search() {
+        // The path carries at most 1300 bytes, which probes discover by repeated loss
+        let mut state = PmtudState::new(1232, 1452, true);
+        let mut packet = 0;
+        while let Some(size) = state.next_probe() {
+            packet += 1;
+            state.on_probe_sent(packet);
+            if size <= 1300 {
+                assert!(state.on_acked(packet));
+            } else {
+                assert!(state.on_lost(packet));
+            }
+            assert!(packet < 100, "search failed to converge");
+        }
+        assert!(state.plpmtu <= 1300);
+        assert!(state.plpmtu > 1300 - MIN_STEP);
+    }
+
+    #[test]
+    fn lost_probes_retried() {
+        let mut state = PmtudState::new(1232, 1452, true);
+        for packet in 0..u64::from(MAX_PROBES) - 1 {
+            state.on_probe_sent(packet);
+            state.on_lost(packet);
+            assert_eq!(state.next_probe(), Some(1452));
+        }
+        state.on_probe_sent(10);
+        state.on_lost(10);
+        assert!(state.next_probe().unwrap() < 1452);
+        assert_eq!(state.plpmtu, 1232);
+    }
+
+    #[test]
+    fn packet_too_big() {
+        let mut state = PmtudState::new(1232, 1452, true);
+        state.on_probe_sent(1);
+        assert!(state.on_acked(1));
+        assert_eq!(state.plpmtu, 1452);
+        // Routing changed and the path shrank
+        state.on_ptb(1400);
+        assert_eq!(state.plpmtu, 1400);
+        assert!(state.is_complete());
+        // Implausibly small reports go no lower than the guaranteed minimum
+        state.on_ptb(500);
+        assert_eq!(state.plpmtu, 1232);
+
+        // The in-flight probe is abandoned in favor of the reported size
+        let mut state = PmtudState::new(1232, 1452, true);
+        state.on_probe_sent(1);
+        state.on_ptb(1350);
+        assert_eq!(state.next_probe(), Some(1350));
+    }
+
+    #[test]
+    fn disabled() {
+        let state = PmtudState::new(1232, 1452, false);
+        assert!(state.is_complete());
+        assert_eq!(state.next_probe(), None);
+        assert_eq!(state.plpmtu, 1232);
+    }
+}
@@ -0,0 +1,288 @@
+//! Structured event logging in the qlog format
+//!
+//! qlog, described in draft-ietf-quic-qlog-main-schema and draft-ietf-quic-qlog-quic-events, is understood by tools
+//! like qvis that visualize what happened on a connection. A trace is written as a sequence of JSON records, each
+//! prefixed by a record separator as in RFC 7464: a header describing the trace, followed by one record per event.
+//! Events are grouped by the ID of the client's first Initial packet, which both endpoints of a connection know it by.
+
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{self, Write};
+use std::net::SocketAddrV6;
+
+use connection::SentPacket;
+use packet::{ConnectionId, SpaceId};
+use Side;
+
+/// Kind of packet an event concerns, as qlog names them
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketType {
+    Initial,
+    Handshake,
+    ZeroRtt,
+    OneRtt,
+}
+
+impl PacketType {
+    /// The type of a packet in `space`, which holds 0-RTT packets if `zero_rtt`
+    pub fn new(space: SpaceId, zero_rtt: bool) -> Self {
+        match space {
+            SpaceId::Initial => PacketType::Initial,
+            SpaceId::Handshake => PacketType::Handshake,
+            SpaceId::Data if zero_rtt => PacketType::ZeroRtt,
+            SpaceId::Data => PacketType::OneRtt,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PacketType::Initial => "initial",
+            PacketType::Handshake => "handshake",
+            PacketType::ZeroRtt => "0RTT",
+            PacketType::OneRtt => "1RTT",
+        }
+    }
+}
+
+/// Writes a qlog trace of the connections of an endpoint to a sink, e.g. a file
+///
+/// Attach a writer with `Endpoint::with_qlog`. Events are timestamped in milliseconds by the clock that drives the
+/// endpoint, i.e. the `now` passed to `handle` and `timeout`. Writing stops at the first I/O error.
+pub struct QlogWriter {
+    sink: Option<Box<Write + Send>>,
+}
+
+impl QlogWriter {
+    /// Begin a trace titled `title` from the point of view of `vantage`, writing its header to `sink`
+    pub fn new(mut sink: Box<Write + Send>, title: &str, vantage: Side) -> io::Result<Self> {
+        let vantage = match vantage {
+            Side::Client => "client",
+            Side::Server => "server",
+        };
+        write!(
+            sink,
+            "\x1e{{\"qlog_version\":\"0.3\",\"qlog_format\":\"JSON-SEQ\",\"title\":{},\"trace\":{{\
+             \"vantage_point\":{{\"type\":\"{}\"}},\"common_fields\":{{\"time_format\":\"absolute\",\
+             \"reference_time\":0}}}}}}\n",
+            JsonStr(title),
+            vantage
+        )?;
+        Ok(Self { sink: Some(sink) })
+    }
+
+    /// Write out any buffered events
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.sink {
+            Some(ref mut sink) => sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn connection_started(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        remote: &SocketAddrV6,
+        local_id: &ConnectionId,
+        remote_id: &ConnectionId,
+    ) {
+        self.event(
+            now,
+            group,
+            "connectivity:connection_started",
+            format_args!(
+                "\"ip_version\":\"ipv6\",\"dst_ip\":\"{}\",\"dst_port\":{},\"src_cid\":\"{}\",\"dst_cid\":\"{}\"",
+                remote.ip(),
+                remote.port(),
+                local_id,
+                remote_id
+            ),
+        );
+    }
+
+    pub(crate) fn connection_state_updated(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        new: &'static str,
+    ) {
+        self.event(
+            now,
+            group,
+            "connectivity:connection_state_updated",
+            format_args!("\"new\":\"{}\"", new),
+        );
+    }
+
+    pub(crate) fn packet_sent(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        ty: PacketType,
+        number: u64,
+        packet: &SentPacket,
+    ) {
+        let mut frames = String::new();
+        if !packet.acks.is_empty() {
+            frames.push_str("{\"frame_type\":\"ack\",\"acked_ranges\":[");
+            for (i, range) in packet.acks.iter().enumerate() {
+                if i != 0 {
+                    frames.push(',');
+                }
+                write!(frames, "[{},{}]", range.start, range.end - 1).unwrap();
+            }
+            frames.push_str("]}");
+        }
+        for stream in &packet.retransmits.stream {
+            if !frames.is_empty() {
+                frames.push(',');
+            }
+            write!(
+                frames,
+                "{{\"frame_type\":\"stream\",\"stream_id\":{},\"offset\":{},\"length\":{},\"fin\":{}}}",
+                stream.id.0,
+                stream.offset,
+                stream.data.len(),
+                stream.fin
+            ).unwrap();
+        }
+        self.event(
+            now,
+            group,
+            "transport:packet_sent",
+            format_args!(
+                "\"packet_type\":\"{}\",\"header\":{{\"packet_number\":{}}},\"frames\":[{}]",
+                ty.name(),
+                number,
+                frames
+            ),
+        );
+    }
+
+    pub(crate) fn packet_received(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        ty: PacketType,
+        number: u64,
+    ) {
+        self.event(
+            now,
+            group,
+            "transport:packet_received",
+            format_args!(
+                "\"packet_type\":\"{}\",\"header\":{{\"packet_number\":{}}}",
+                ty.name(),
+                number
+            ),
+        );
+    }
+
+    pub(crate) fn packet_lost(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        ty: PacketType,
+        number: u64,
+    ) {
+        self.event(
+            now,
+            group,
+            "recovery:packet_lost",
+            format_args!(
+                "\"packet_type\":\"{}\",\"header\":{{\"packet_number\":{}}}",
+                ty.name(),
+                number
+            ),
+        );
+    }
+
+    /// Record the state of congestion control and RTT estimation, with RTTs in μs
+    pub(crate) fn metrics_updated(
+        &mut self,
+        now: u64,
+        group: &ConnectionId,
+        congestion_window: u64,
+        bytes_in_flight: u64,
+        smoothed_rtt: u64,
+        latest_rtt: u64,
+    ) {
+        self.event(
+            now,
+            group,
+            "recovery:metrics_updated",
+            format_args!(
+                "\"congestion_window\":{},\"bytes_in_flight\":{},\"smoothed_rtt\":{},\"latest_rtt\":{}",
+                congestion_window,
+                bytes_in_flight,
+                Millis(smoothed_rtt),
+                Millis(latest_rtt)
+            ),
+        );
+    }
+
+    fn event(&mut self, now: u64, group: &ConnectionId, name: &str, data: fmt::Arguments) {
+        let result = match self.sink {
+            Some(ref mut sink) => write!(
+                sink,
+                "\x1e{{\"time\":{},\"name\":\"{}\",\"group_id\":\"{}\",\"data\":{{{}}}}}\n",
+                Millis(now),
+                name,
+                group,
+                data
+            ),
+            None => return,
+        };
+        if result.is_err() {
+            self.sink = None;
+        }
+    }
+}
+
+/// Formats a time in μs as milliseconds, without loss of precision
+struct Millis(u64);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+/// Formats a string as a JSON string literal
+struct JsonStr<'a>(&'a str);
+
+impl<'a> fmt::Display for JsonStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn millis() {
+        assert_eq!(Millis(0).to_string(), "0.000");
+        assert_eq!(Millis(1_234_567).to_string(), "1234.567");
+    }
+
+    #[test]
+    fn json_str() {
+        assert_eq!(
+            JsonStr("a \"quoted\"\\path\n\x01").to_string(),
+            "\"a \\\"quoted\\\"\\\\path\\n\\u0001\""
+        );
+    }
+}
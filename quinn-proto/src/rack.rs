@@ -0,0 +1,97 @@
+//! Loss detection by the time a packet was sent (RACK, RFC 8985)
+//!
+//! Rather than counting how many later packets have been acknowledged, RACK declares a packet lost once a packet sent
+//! sufficiently later than it has been acknowledged. The allowance for reordering is measured in time, so a loss near
+//! the end of a flight, which few packets follow, is found as quickly as any other.
+
+/// Largest the reordering window may grow to, in eighths of the smoothed RTT
+const MAX_REORDER_WINDOW: u64 = 8;
+
+/// What RACK knows of the most recently sent packet to have been acknowledged
+#[derive(Debug, Clone)]
+pub struct RackState {
+    /// Packet number of that packet, if any has been acknowledged
+    pub packet: Option<u64>,
+    /// Time at which it was sent (μs)
+    pub xmit_ts: u64,
+    /// Round trip time measured from its acknowledgement (μs)
+    pub rtt: u64,
+    /// Time allowed for reordering, in eighths of the smoothed RTT
+    reorder_window: u64,
+    /// When the reordering window was last widened (μs)
+    widened_at: Option<u64>,
+}
+
+impl RackState {
+    pub fn new() -> Self {
+        Self {
+            packet: None,
+            xmit_ts: 0,
+            rtt: 0,
+            reorder_window: 1,
+            widened_at: None,
+        }
+    }
+
+    /// Account for an ACK received at `now`
+    ///
+    /// `newest` is the number and send time of the most recently sent packet it newly acknowledged, and `reordered`
+    /// whether it newly acknowledged any packet sent before the one we were already tracking.
+    pub fn on_ack(&mut self, now: u64, newest: (u64, u64), reordered: bool, smoothed_rtt: u64) {
+        if reordered {
+            // Widen the window by at most one step per round trip, so a single burst of reordering doesn't blow it up
+            let due = self.widened_at.map_or(true, |t| now >= t + smoothed_rtt);
+            if due && self.reorder_window < MAX_REORDER_WINDOW {
+                self.reorder_window += 1;
+                self.widened_at = Some(now);
+            }
+        }
+        let (packet, sent) = newest;
+        if self.packet.map_or(true, |x| packet > x) {
+            self.packet = Some(packet);
+            self.xmit_ts = sent;
+            self.rtt = now - sent;
+        }
+    }
+
+    /// Whether `packet` was sent before the most recently sent packet known to have been acknowledged
+    pub fn is_older(&self, packet: u64) -> bool {
+        self.packet.map_or(false, |x| packet < x)
+    }
+
+    /// Time at which a packet sent at `sent` and not since acknowledged is deemed lost
+    pub fn loss_time(&self, sent: u64, smoothed_rtt: u64) -> u64 {
+        sent + self.rtt + self.reorder_window * smoothed_rtt / 8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RTT: u64 = 80_000;
+
+    #[test]
+    fn loss_time() {
+        let mut rack = RackState::new();
+        assert!(!rack.is_older(0));
+        rack.on_ack(RTT + 1000, (5, 1000), false, RTT);
+        assert!(rack.is_older(4));
+        assert!(!rack.is_older(5));
+        // A round trip plus an eighth of one for reordering
+        assert_eq!(rack.loss_time(500, RTT), 500 + RTT + RTT / 8);
+    }
+
+    #[test]
+    fn reorder_window_widens() {
+        let mut rack = RackState::new();
+        rack.on_ack(RTT, (5, 0), false, RTT);
+        rack.on_ack(RTT + 10, (6, 10), true, RTT);
+        assert_eq!(rack.loss_time(0, RTT), RTT + 2 * RTT / 8);
+        // Not again within the same round trip
+        rack.on_ack(RTT + 20, (7, 20), true, RTT);
+        assert_eq!(rack.loss_time(0, RTT), RTT + 2 * RTT / 8);
+        rack.on_ack(3 * RTT, (8, 2 * RTT), true, RTT);
+        assert_eq!(rack.loss_time(0, RTT), RTT + 3 * RTT / 8);
+    }
+}
@@ -0,0 +1,186 @@
+//! Weighted fair queueing of stream data
+//!
+//! Streams with data queued share the connection's capacity in proportion to weights derived from their priorities,
+//! using start-time fair queueing: each stream's transmissions are stamped with a virtual time that advances by the
+//! size of each transmission divided by the stream's weight, and the stream with the earliest virtual start time sends
+//! next. A stream that falls idle doesn't bank credit for later, since its start time is never behind the virtual time
+//! of the most recent transmission.
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use fnv::FnvHashMap;
+
+use frame;
+use StreamId;
+
+/// Range of priorities that affect scheduling; others are clamped to it
+const MAX_PRIORITY: i32 = 32;
+
+/// Scheduling state of the streams of a connection
+#[derive(Debug, Default)]
+pub struct StreamScheduler {
+    streams: FnvHashMap<StreamId, Entry>,
+    /// Start time of the most recent transmission
+    virtual_time: f64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Entry {
+    priority: i32,
+    /// Virtual time at which the stream's most recent transmission finished
+    finish: f64,
+}
+
+impl Entry {
+    /// Share of capacity relative to a stream of priority 0, which doubles with each increment of priority
+    fn weight(&self) -> f64 {
+        2f64.powi(self.priority.max(-MAX_PRIORITY).min(MAX_PRIORITY))
+    }
+}
+
+impl StreamScheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Priority of `stream`, 0 unless otherwise set
+    pub fn priority(&self, stream: StreamId) -> i32 {
+        self.streams.get(&stream).map_or(0, |x| x.priority)
+    }
+
+    /// Change the priority of `stream`, affecting all subsequent choices
+    pub fn set_priority(&mut self, stream: StreamId, priority: i32) {
+        self.streams
+            .entry(stream)
+            .or_insert_with(Entry::default)
+            .priority = priority;
+    }
+
+    /// Choose which of `candidates`, the streams with data ready to send, should send next
+    ///
+    /// Ties go to the higher priority, then the lower stream ID, so choices are deterministic.
+    pub fn select<I>(&self, candidates: I) -> Option<StreamId>
+    where
+        I: IntoIterator<Item = StreamId>,
+    {
+        let mut best: Option<(StreamId, f64, i32)> = None;
+        for id in candidates {
+            let entry = self.streams.get(&id).cloned().unwrap_or_default();
+            let start = entry.finish.max(self.virtual_time);
+            let better = match best {
+                None => true,
+                Some((best_id, best_start, best_priority)) => {
+                    match start.partial_cmp(&best_start).unwrap() {
+                        Ordering::Less => true,
+                        Ordering::Greater => false,
+                        Ordering::Equal => (best_priority, id) < (entry.priority, best_id),
+                    }
+                }
+            };
+            if better {
+                best = Some((id, start, entry.priority));
+            }
+        }
+        best.map(|(id, _, _)| id)
+    }
+
+    /// Index in `queue` of the frame to send next, if any
+    ///
+    /// Handshake data on stream 0 always goes first. Each stream's frames are queued in order, so a stream sends its
+    /// first.
+    pub fn next_frame(&self, queue: &VecDeque<frame::Stream>) -> Option<usize> {
+        if let Some(i) = queue.iter().position(|x| x.id == StreamId(0)) {
+            return Some(i);
+        }
+        let id = self.select(queue.iter().map(|x| x.id))?;
+        queue.iter().position(|x| x.id == id)
+    }
+
+    /// Record the transmission of `bytes` of data from `stream`
+    pub fn on_sent(&mut self, stream: StreamId, bytes: usize) {
+        let entry = self.streams.entry(stream).or_insert_with(Entry::default);
+        let start = entry.finish.max(self.virtual_time);
+        entry.finish = start + bytes as f64 / entry.weight();
+        self.virtual_time = start;
+    }
+
+    /// Forget a stream that will send no more data
+    pub fn remove(&mut self, stream: StreamId) {
+        self.streams.remove(&stream);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Bytes sent by each of `streams`, all continuously ready, over `rounds` transmissions of `size` bytes
+    fn share(
+        scheduler: &mut StreamScheduler,
+        streams: &[StreamId],
+        rounds: usize,
+        size: usize,
+    ) -> Vec<usize> {
+        let mut sent = vec![0; streams.len()];
+        for _ in 0..rounds {
+            let id = scheduler.select(streams.iter().cloned()).unwrap();
+            scheduler.on_sent(id, size);
+            sent[streams.iter().position(|&x| x == id).unwrap()] += size;
+        }
+        sent
+    }
+
+    #[test]
+    fn equal_priorities_alternate() {
+        let mut scheduler = StreamScheduler::new();
+        let streams = [StreamId(4), StreamId(8)];
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            let id = scheduler.select(streams.iter().cloned()).unwrap();
+            scheduler.on_sent(id, 1000);
+            order.push(id);
+        }
+        assert_eq!(order, [StreamId(4), StreamId(8), StreamId(4), StreamId(8)]);
+    }
+
+    #[test]
+    fn weighted() {
+        let mut scheduler = StreamScheduler::new();
+        let streams = [StreamId(4), StreamId(8), StreamId(12)];
+        scheduler.set_priority(StreamId(4), 2);
+        scheduler.set_priority(StreamId(8), 1);
+        assert_eq!(scheduler.priority(StreamId(4)), 2);
+        assert_eq!(scheduler.priority(StreamId(12)), 0);
+        assert_eq!(
+            share(&mut scheduler, &streams, 700, 100),
+            [40_000, 20_000, 10_000]
+        );
+    }
+
+    #[test]
+    fn priority_change() {
+        let mut scheduler = StreamScheduler::new();
+        let streams = [StreamId(4), StreamId(8)];
+        assert_eq!(share(&mut scheduler, &streams, 10, 100), [500, 500]);
+        scheduler.set_priority(StreamId(8), 1);
+        assert_eq!(share(&mut scheduler, &streams, 30, 100), [1000, 2000]);
+    }
+
+    #[test]
+    fn idle_streams_bank_no_credit() {
+        let mut scheduler = StreamScheduler::new();
+        assert_eq!(share(&mut scheduler, &[StreamId(4)], 100, 100), [10_000]);
+        // The newly ready stream gets its fair share from now on, not a burst making up for lost time
+        let streams = [StreamId(4), StreamId(8)];
+        assert_eq!(share(&mut scheduler, &streams, 10, 100), [500, 500]);
+    }
+
+    #[test]
+    fn removed_streams_forgotten() {
+        let mut scheduler = StreamScheduler::new();
+        scheduler.set_priority(StreamId(4), 3);
+        scheduler.remove(StreamId(4));
+        assert_eq!(scheduler.priority(StreamId(4)), 0);
+    }
+}
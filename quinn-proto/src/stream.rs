@@ -72,6 +72,8 @@ pub struct Send {
     pub state: SendState,
     /// Number of bytes sent but unacked
     pub bytes_in_flight: u64,
+    /// The limit at which we last told the peer this stream was blocked
+    pub blocked_at: Option<u64>,
 }
 
 impl Send {
@@ -81,6 +83,7 @@ impl Send {
             max_data: 0,
             state: SendState::Ready,
             bytes_in_flight: 0,
+            blocked_at: None,
         }
     }
 
@@ -106,6 +109,8 @@ pub struct Recv {
     pub assembler: Assembler,
     /// Whether the application is aware of this stream yet
     pub fresh: bool,
+    /// Whether we've asked the peer to stop sending
+    pub stopped: bool,
 }
 
 impl Recv {
@@ -118,6 +123,7 @@ impl Recv {
             unordered: false,
             assembler: Assembler::new(),
             fresh: true,
+            stopped: false,
         }
     }
 
@@ -208,6 +214,25 @@ impl Assembler {
         self.written.front().map_or(true, |x| x & mask == mask)
     }
 
+    /// Offset in the stream of the next byte to be read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether no data past `offset` is held
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Consume `n` bytes that were delivered without passing through the assembler
+    pub fn skip(&mut self, n: u64) {
+        debug_assert!(self.is_empty());
+        self.offset += n;
+        // Every remaining bit marks a byte not yet written, so there's nothing to shift
+        self.written.clear();
+        self.written_offset = 0;
+    }
+
     /// Leading written bytes
     fn prefix_len(&self) -> usize {
         for i in 0..self.written.len() {
@@ -221,19 +246,7 @@ impl Assembler {
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        let n;
-        {
-            let (a, b) = self.data.as_slices();
-            let available = self.prefix_len();
-            let a_len = a.len().min(available);
-            let (a, b) = (&a[0..a_len], &b[0..(available - a_len)]);
-            let a_n = a.len().min(buf.len());
-            buf[0..a_n].copy_from_slice(&a[0..a_n]);
-            let b_n = b.len().min(buf.len().saturating_sub(a.len()));
-            buf[a_n..(a_n + b_n)].copy_from_slice(&b[0..b_n]);
-            n = a_n + b_n;
-        }
-
+        let n = self.peek(buf);
         self.offset += n as u64;
         self.data.drain(0..n);
         let q = n / 8;
@@ -245,8 +258,21 @@ impl Assembler {
         n
     }
 
-    #[cfg(test)]
-    fn next(&mut self) -> Option<Box<[u8]>> {
+    /// Copy leading written bytes into `buf` without consuming them
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let (a, b) = self.data.as_slices();
+        let available = self.prefix_len();
+        let a_len = a.len().min(available);
+        let (a, b) = (&a[0..a_len], &b[0..(available - a_len)]);
+        let a_n = a.len().min(buf.len());
+        buf[0..a_n].copy_from_slice(&a[0..a_n]);
+        let b_n = b.len().min(buf.len().saturating_sub(a.len()));
+        buf[a_n..(a_n + b_n)].copy_from_slice(&b[0..b_n]);
+        a_n + b_n
+    }
+
+    /// Consume all leading written bytes
+    pub fn next(&mut self) -> Option<Box<[u8]>> {
         let mut buf = Vec::new();
         buf.resize(self.prefix_len(), 0);
         self.read(&mut buf);
@@ -365,4 +391,22 @@ mod test {
         x.insert(0, b"1234");
         assert_matches!(x.next(), None);
     }
+
+    #[test]
+    fn assemble_peek() {
+        let mut x = Assembler::new();
+        x.insert(0, b"123");
+        x.insert(4, b"5");
+        let mut buf = [0; 8];
+        assert_eq!(x.peek(&mut buf[..2]), 2);
+        assert_eq!(&buf[..2], b"12");
+        // Stops at the gap
+        assert_eq!(x.peek(&mut buf), 3);
+        assert_eq!(&buf[..3], b"123");
+        assert_matches!(x.next(), Some(ref y) if &y[..] == b"123");
+        x.insert(3, b"4");
+        assert_eq!(x.peek(&mut buf), 2);
+        assert_eq!(&buf[..2], b"45");
+        assert_matches!(x.next(), Some(ref y) if &y[..] == b"45");
+    }
 }
@@ -2,16 +2,23 @@ use std::collections::VecDeque;
 use std::io::{self, Read, Write};
 use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
 use std::ops::RangeFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{fmt, fs, str};
+use std::{cmp, fmt, fs, mem, str};
 
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
 use rustls::internal::pemfile;
 use slog::{Drain, Logger, KV};
 use untrusted::Input;
 
 use super::*;
+use connection::{EcnState, State, ECN_PROBE_PACKETS, ISSUED_CIDS};
+use frame;
+use packet::{self, set_payload_length, types, Header, PacketNumber, PartialDecode};
 
 struct TestDrain;
 
@@ -62,8 +69,27 @@ struct Pair {
     time: u64,
     // One-way
     latency: u64,
+    /// Largest random delay added to `latency`, which reorders datagrams sent close together (μs)
+    jitter: u64,
+    /// Probability that a datagram is lost
+    loss_rate: f64,
+    /// Probability that a datagram is held back by `REORDER_DELAY`, letting those sent after it overtake it
+    reorder_rate: f64,
+    /// Capacity of the link in each direction in bytes per second, or 0 if unlimited
+    bandwidth: u64,
+    /// Datagrams sent before this time are lost
+    partition_end: u64,
+    /// Whether the simulated network clears ECN codepoints
+    strip_ecn: bool,
+    /// Whether the simulated network marks every ECN-capable datagram as having experienced congestion
+    mark_ce: bool,
+    /// Decides the fate of datagrams subject to random impairments, seeded so that runs are repeatable
+    rng: XorShiftRng,
 }
 
+/// Extra delay of datagrams the simulated network reorders (μs)
+const REORDER_DELAY: u64 = 1000;
+
 impl Default for Pair {
     fn default() -> Self {
         let mut server_config = server_config();
@@ -74,34 +100,37 @@ impl Default for Pair {
 }
 
 fn server_config() -> Config {
-    let certs = {
-        let f =
-            fs::File::open("../certs/server.chain").expect("cannot open '../certs/server.chain'");
-        let mut reader = io::BufReader::new(f);
-        pemfile::certs(&mut reader).expect("cannot read certificates")
-    };
-
-    let keys = {
-        let f = fs::File::open("../certs/server.rsa").expect("cannot open '../certs/server.rsa'");
-        let mut reader = io::BufReader::new(f);
-        pemfile::rsa_private_keys(&mut reader).expect("cannot read private keys")
-    };
-
+    let (certs, key) = cert_and_key("server");
     let mut tls_server_config = crypto::build_server_config();
-    tls_server_config
-        .set_single_cert(certs, keys[0].clone())
-        .unwrap();
+    tls_server_config.set_single_cert(certs, key).unwrap();
     Config {
         tls_server_config: Arc::new(tls_server_config),
         ..Default::default()
     }
 }
 
-fn client_config() -> Config {
+/// Load the certificate chain `../certs/<name>.chain` and the private key `../certs/<name>.rsa`
+fn cert_and_key(name: &str) -> (Vec<crypto::Certificate>, crypto::PrivateKey) {
+    let path = format!("../certs/{}.chain", name);
+    let f = fs::File::open(&path).unwrap_or_else(|_| panic!("cannot open '{}'", path));
+    let certs = pemfile::certs(&mut io::BufReader::new(f)).expect("cannot read certificates");
+
+    let path = format!("../certs/{}.rsa", name);
+    let f = fs::File::open(&path).unwrap_or_else(|_| panic!("cannot open '{}'", path));
+    let mut keys =
+        pemfile::rsa_private_keys(&mut io::BufReader::new(f)).expect("cannot read private keys");
+    (certs, keys.remove(0))
+}
+
+fn ca_der() -> Vec<u8> {
     let mut f = fs::File::open("../certs/ca.der").expect("cannot open '../certs/ca.der'");
     let mut bytes = Vec::new();
     f.read_to_end(&mut bytes).expect("error while reading");
+    bytes
+}
 
+fn client_config() -> Config {
+    let bytes = ca_der();
     let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(Input::from(&bytes)).unwrap();
     let anchor_vec = vec![anchor];
 
@@ -124,7 +153,10 @@ impl Pair {
             Some(*LISTEN_KEYS),
         ).unwrap();
         let client = Endpoint::new(log.new(o!("side" => "Client")), client_config, None).unwrap();
+        Self::with_endpoints(log, server, client)
+    }
 
+    fn with_endpoints(log: Logger, server: Endpoint, client: Endpoint) -> Self {
         let localhost = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
         let server_addr = SocketAddrV6::new(
             localhost,
@@ -144,6 +176,14 @@ impl Pair {
             client: TestEndpoint::new(Side::Client, client, client_addr),
             time: 0,
             latency: 0,
+            jitter: 0,
+            loss_rate: 0.0,
+            reorder_rate: 0.0,
+            bandwidth: 0,
+            partition_end: 0,
+            strip_ecn: false,
+            mark_ce: false,
+            rng: XorShiftRng::from_seed([0xab; 16]),
         }
     }
 
@@ -153,7 +193,10 @@ impl Pair {
         self.drive_server();
         let client_t = self.client.next_wakeup();
         let server_t = self.server.next_wakeup();
-        if client_t == self.client.idle && server_t == self.server.idle {
+        // Keep-alives would otherwise keep the connection busy forever
+        if client_t == self.client.idle.min(self.client.keep_alive)
+            && server_t == self.server.idle.min(self.server.keep_alive)
+        {
             return false;
         }
         if client_t < server_t {
@@ -178,29 +221,74 @@ impl Pair {
     fn drive_client(&mut self) {
         trace!(self.log, "client running");
         self.client.drive(&self.log, self.time, self.server.addr);
-        for packet in self.client.outbound.drain(..) {
+        let outbound = mem::replace(&mut self.client.outbound, VecDeque::new());
+        for (ecn, packet) in outbound {
             self.client
                 .socket
                 .send_to(&packet, self.server.addr)
                 .unwrap();
-            self.server
-                .inbound
-                .push_back((self.time + self.latency, packet));
+            self.transmit(Side::Server, ecn, packet);
         }
     }
 
     fn drive_server(&mut self) {
         trace!(self.log, "server running");
         self.server.drive(&self.log, self.time, self.client.addr);
-        for packet in self.server.outbound.drain(..) {
+        let outbound = mem::replace(&mut self.server.outbound, VecDeque::new());
+        for (ecn, packet) in outbound {
             self.server
                 .socket
                 .send_to(&packet, self.client.addr)
                 .unwrap();
-            self.client
-                .inbound
-                .push_back((self.time + self.latency, packet));
+            self.transmit(Side::Client, ecn, packet);
+        }
+    }
+
+    /// Carry a datagram across the simulated network to the endpoint on side `to`, subject to its impairments
+    fn transmit(&mut self, to: Side, ecn: Option<EcnCodepoint>, packet: Box<[u8]>) {
+        if self.time < self.partition_end
+            || self.loss_rate > 0.0 && self.rng.gen::<f64>() < self.loss_rate
+        {
+            trace!(self.log, "network dropped datagram"; "len" => packet.len());
+            return;
+        }
+        let endpoint = match to {
+            Side::Server => &mut self.server,
+            Side::Client => &mut self.client,
+        };
+        let mut arrival = self.time;
+        if self.bandwidth != 0 {
+            // Datagrams queue for the link, then take time to cross it in proportion to their size
+            let start = arrival.max(endpoint.link_busy);
+            endpoint.link_busy = start + packet.len() as u64 * 1_000_000 / self.bandwidth;
+            arrival = endpoint.link_busy;
         }
+        arrival += self.latency;
+        if self.jitter != 0 {
+            arrival += self.rng.gen_range(0, self.jitter + 1);
+        }
+        if self.reorder_rate > 0.0 && self.rng.gen::<f64>() < self.reorder_rate {
+            arrival += REORDER_DELAY;
+        }
+        let ecn = if self.strip_ecn {
+            None
+        } else if self.mark_ce && ecn.is_some() {
+            Some(EcnCodepoint::CE)
+        } else {
+            ecn
+        };
+        // Keep the queue ordered by time of arrival
+        let i = endpoint
+            .inbound
+            .iter()
+            .position(|x| x.0 > arrival)
+            .unwrap_or_else(|| endpoint.inbound.len());
+        endpoint.inbound.insert(i, (arrival, ecn, packet));
+    }
+
+    /// Lose every datagram sent in the next `duration` μs, as in a network outage
+    fn partition(&mut self, duration: u64) {
+        self.partition_end = self.time + duration;
     }
 
     fn connect(&mut self) -> (ConnectionHandle, ConnectionHandle) {
@@ -215,6 +303,21 @@ impl Pair {
         assert_matches!(self.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
         (client_conn, server_conn)
     }
+
+    /// Replace the server with a fresh endpoint that has forgotten all its connections but kept its keys
+    fn restart_server(&mut self) {
+        info!(self.log, "restarting server");
+        self.server.endpoint = Endpoint::new(
+            self.log.new(o!("side" => "Server")),
+            server_config(),
+            Some(*LISTEN_KEYS),
+        )
+        .unwrap();
+        self.server.conn = None;
+        self.server.idle = u64::max_value();
+        self.server.loss = u64::max_value();
+        self.server.close = u64::max_value();
+    }
 }
 
 struct TestEndpoint {
@@ -225,9 +328,17 @@ struct TestEndpoint {
     idle: u64,
     loss: u64,
     close: u64,
+    ack: u64,
+    path_validation: u64,
+    pacing: u64,
+    keep_alive: u64,
     conn: Option<ConnectionHandle>,
-    outbound: VecDeque<Box<[u8]>>,
-    inbound: VecDeque<(u64, Box<[u8]>)>,
+    outbound: VecDeque<(Option<EcnCodepoint>, Box<[u8]>)>,
+    inbound: VecDeque<(u64, Option<EcnCodepoint>, Box<[u8]>)>,
+    /// When the simulated link towards this endpoint finishes carrying the datagrams queued on it
+    link_busy: u64,
+    /// Address to which every datagram sent is lost, as if the path to it failed
+    unreachable: Option<SocketAddrV6>,
 }
 
 impl TestEndpoint {
@@ -244,9 +355,15 @@ impl TestEndpoint {
             idle: u64::max_value(),
             loss: u64::max_value(),
             close: u64::max_value(),
+            ack: u64::max_value(),
+            path_validation: u64::max_value(),
+            pacing: u64::max_value(),
+            keep_alive: u64::max_value(),
             conn: None,
             outbound: VecDeque::new(),
             inbound: VecDeque::new(),
+            link_busy: 0,
+            unreachable: None,
         }
     }
 
@@ -288,18 +405,69 @@ impl TestEndpoint {
                 self.close = u64::max_value();
                 self.endpoint.timeout(now, conn, Timer::Close);
             }
+            if self.ack <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::Ack
+                );
+                self.ack = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::Ack);
+            }
+            if self.path_validation <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::PathValidation
+                );
+                self.path_validation = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::PathValidation);
+            }
+            if self.pacing <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::Pacing
+                );
+                self.pacing = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::Pacing);
+            }
+            if self.keep_alive <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::KeepAlive
+                );
+                self.keep_alive = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::KeepAlive);
+            }
         }
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
-            self.endpoint.handle(
-                now,
-                remote,
-                Vec::from(self.inbound.pop_front().unwrap().1).into(),
-            );
+            let (_, ecn, packet) = self.inbound.pop_front().unwrap();
+            self.endpoint
+                .handle(now, remote, ecn, Vec::from(packet).into());
         }
         while let Some(x) = self.endpoint.poll_io(now) {
             match x {
-                Io::Transmit { packet, .. } => {
-                    self.outbound.push_back(packet);
+                Io::Transmit {
+                    packet,
+                    ecn,
+                    destination,
+                } => {
+                    if self.unreachable == Some(destination) {
+                        trace!(
+                            log,
+                            "{side:?} datagram to {destination} lost",
+                            side = self.side,
+                            destination = destination
+                        );
+                    } else {
+                        self.outbound.push_back((ecn, packet));
+                    }
                 }
                 Io::TimerStart {
                     timer,
@@ -324,6 +492,18 @@ impl TestEndpoint {
                         Timer::Close => {
                             self.close = time;
                         }
+                        Timer::Ack => {
+                            self.ack = time;
+                        }
+                        Timer::PathValidation => {
+                            self.path_validation = time;
+                        }
+                        Timer::Pacing => {
+                            self.pacing = time;
+                        }
+                        Timer::KeepAlive => {
+                            self.keep_alive = time;
+                        }
                     }
                 }
                 Io::TimerStop { timer, .. } => {
@@ -343,6 +523,18 @@ impl TestEndpoint {
                         Timer::Close => {
                             self.close = u64::max_value();
                         }
+                        Timer::Ack => {
+                            self.ack = u64::max_value();
+                        }
+                        Timer::PathValidation => {
+                            self.path_validation = u64::max_value();
+                        }
+                        Timer::Pacing => {
+                            self.pacing = u64::max_value();
+                        }
+                        Timer::KeepAlive => {
+                            self.keep_alive = u64::max_value();
+                        }
                     }
                 }
             }
@@ -353,6 +545,10 @@ impl TestEndpoint {
         self.idle
             .min(self.loss)
             .min(self.close)
+            .min(self.ack)
+            .min(self.path_validation)
+            .min(self.pacing)
+            .min(self.keep_alive)
             .min(self.inbound.front().map_or(u64::max_value(), |x| x.0))
     }
 }
@@ -380,10 +576,11 @@ fn version_negotiate() {
     server.handle(
         0,
         client_addr,
+        None,
         // Long-header packet with reserved version number
         hex!(
             "80 0a1a2a3a
-                        11 00000000 00000000
+                        04 00000000 04 00000000
                         00"
         )[..]
             .into(),
@@ -392,17 +589,235 @@ fn version_negotiate() {
     assert_matches!(io, Some(Io::Transmit { .. }));
     if let Some(Io::Transmit { packet, .. }) = io {
         assert!(packet[0] | 0x80 != 0);
-        assert!(&packet[1..14] == hex!("00000000 11 00000000 00000000"));
+        assert!(&packet[1..15] == hex!("00000000 04 00000000 04 00000000"));
+        assert!(
+            packet[15..]
+                .chunks(4)
+                .any(|x| BigEndian::read_u32(x) == Version::V1.number())
+        );
         assert!(
-            packet[14..]
+            packet[15..]
                 .chunks(4)
-                .any(|x| BigEndian::read_u32(x) == VERSION)
+                .any(|x| BigEndian::read_u32(x) == Version::V2.number())
+        );
+        // Greased with a reserved version
+        assert!(
+            packet[15..]
+                .chunks(4)
+                .any(|x| BigEndian::read_u32(x) & 0x0f0f_0f0f == 0x0a0a_0a0a)
         );
     }
     assert_matches!(server.poll_io(0), None);
     assert_matches!(server.poll(), None);
 }
 
+#[test]
+fn version_negotiate_rate_limit() {
+    let log = logger();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let mut config = server_config();
+    config.max_version_negotiations = 2;
+    let mut server =
+        Endpoint::new(log.new(o!("peer" => "server")), config, Some(*LISTEN_KEYS)).unwrap();
+    let packet = hex!(
+        "80 0a1a2a3a
+                    04 00000000 04 00000000
+                    00"
+    );
+    for &now in &[0, 1, 2, 1000 * 1000] {
+        server.handle(now, client_addr, None, packet[..].into());
+    }
+    for _ in 0..3 {
+        assert_matches!(server.poll_io(0), Some(Io::Transmit { .. }));
+    }
+    assert_matches!(server.poll_io(0), None);
+}
+
+#[test]
+fn version_mismatch() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    let packet = {
+        let conn = &pair.client.connections[client_conn.0];
+        packet::version_negotiate(
+            &mut rand::thread_rng(),
+            conn.remote_id.clone(),
+            conn.local_id.clone(),
+            // A draft version we no longer implement
+            &[0xff00_000b],
+        )
+    };
+    let server_addr = pair.server.addr;
+    pair.client.handle(0, server_addr, None, packet[..].into());
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::VersionMismatch })) if conn == client_conn);
+}
+
+#[test]
+fn version_2() {
+    let mut client_config = client_config();
+    client_config.versions = vec![Version::V2];
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.connections[client_conn.0].version, Version::V2);
+    assert_eq!(pair.server.connections[server_conn.0].version, Version::V2);
+}
+
+#[test]
+fn version_downgrade() {
+    // The client's first choice isn't supported by the server
+    let mut server_config = server_config();
+    server_config.versions = vec![Version::V1];
+    let mut client_config = client_config();
+    client_config.versions = vec![Version::V2, Version::V1];
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    let conn = &pair.client.connections[client_conn.0];
+    assert_eq!(conn.version, Version::V1);
+    assert_eq!(conn.initial_version, Version::V2);
+    assert_eq!(pair.server.connections[server_conn.0].version, Version::V1);
+}
+
+#[test]
+fn version_no_downgrade() {
+    let mut server_config = server_config();
+    server_config.versions = vec![Version::V1];
+    let mut client_config = client_config();
+    client_config.versions = vec![Version::V2];
+    let mut pair = Pair::new(server_config, client_config);
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::VersionMismatch })) if conn == client_conn);
+    assert_matches!(pair.server.accept(), None);
+}
+
+#[test]
+fn version_negotiation_to_v2() {
+    // The server only speaks the client's second choice
+    let mut server_config = server_config();
+    server_config.versions = vec![Version::V2];
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+    let conn = &pair.client.connections[client_conn.0];
+    assert_eq!(conn.version, Version::V2);
+    assert_eq!(conn.initial_version, Version::V1);
+    assert_eq!(pair.server.connections[server_conn.0].version, Version::V2);
+}
+
+#[test]
+fn version_negotiation_only_once() {
+    let mut server_config = server_config();
+    server_config.versions = vec![Version::V2];
+    let mut pair = Pair::new(server_config, client_config());
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    // The server answers the client's first Initial with a version negotiation, prompting a new one in version 2
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    assert_eq!(pair.client.connections[client_conn.0].version, Version::V2);
+
+    // A second version negotiation offering nothing we support would end the attempt if it were acted on
+    let packet = {
+        let conn = &pair.client.connections[client_conn.0];
+        packet::version_negotiate(
+            &mut rand::thread_rng(),
+            conn.remote_id.clone(),
+            conn.local_id.clone(),
+            &[0x1a2b_3c4d],
+        )
+    };
+    let server_addr = pair.server.addr;
+    pair.client
+        .handle(pair.time, server_addr, None, packet[..].into());
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_eq!(pair.client.connections[client_conn.0].version, Version::V2);
+}
+
+#[test]
+fn send_version_negotiation() {
+    let mut server = Endpoint::new(logger(), server_config(), Some(*LISTEN_KEYS)).unwrap();
+    let dcid = ConnectionId::from_slice(&[0xab; 8]).unwrap();
+    let scid = ConnectionId::from_slice(&[0xcd; 8]).unwrap();
+    let remote = "[::2]:7890".parse().unwrap();
+    let packet = server.send_version_negotiation(remote, &dcid, &scid);
+    // Addressed back to the packet's sender
+    let (partial, _) = PartialDecode::new(packet[..].into(), 8).unwrap();
+    assert_eq!(partial.dst_cid(), &scid);
+    assert_eq!(partial.source_id(), Some(&dcid));
+    // Lists every version we support, among reserved ones
+    let versions = packet[23..]
+        .chunks(4)
+        .map(BigEndian::read_u32)
+        .filter(|x| x & 0x0f0f_0f0f != 0x0a0a_0a0a)
+        .collect::<Vec<_>>();
+    assert_eq!(versions, [Version::V1.number(), Version::V2.number()]);
+}
+
+#[test]
+fn version_downgrade_attack() {
+    let mut client_config = client_config();
+    client_config.versions = vec![Version::V2, Version::V1];
+    let mut pair = Pair::new(server_config(), client_config);
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    // An attacker claims the server only supports our second choice
+    let packet = {
+        let conn = &pair.client.connections[client_conn.0];
+        packet::version_negotiate(
+            &mut rand::thread_rng(),
+            conn.remote_id.clone(),
+            conn.local_id.clone(),
+            &[Version::V1.number()],
+        )
+    };
+    let server_addr = pair.server.addr;
+    pair.client.handle(0, server_addr, None, packet[..].into());
+    pair.drive();
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == client_conn && error_code == TransportError::VERSION_NEGOTIATION_ERROR
+    );
+}
+
+#[test]
+fn no_versions() {
+    let mut config = server_config();
+    config.versions = Vec::new();
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::NoVersions)
+    );
+    // Servers list every version in their transport parameters, which has room for only so many
+    let mut config = server_config();
+    config.versions = vec![Version::V1; 63];
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::TooManyVersions(63))
+    );
+}
+
+#[test]
+fn invalid_ack_config() {
+    let mut config = server_config();
+    config.ack_delay_exponent = 21;
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::AckDelayExponentTooLarge(21))
+    );
+    let mut config = server_config();
+    config.max_ack_ranges = 0;
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::NoAckRanges)
+    );
+    let mut config = server_config();
+    config.max_ack_delay = (1 << 14) * 1000;
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::MaxAckDelayTooLarge(_))
+    );
+}
+
 #[test]
 fn lifecycle() {
     let mut pair = Pair::default();
@@ -420,40 +835,303 @@ fn lifecycle() {
     assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionDrained)) if conn == client_conn);
 }
 
-/*
+#[test]
+fn handshake_reordered() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    // The server's first flight spans several packets, delivered in reverse order
+    assert!(pair.client.inbound.len() > 1);
+    let reversed = pair.client.inbound.drain(..).rev().collect::<VecDeque<_>>();
+    pair.client.inbound = reversed;
+    pair.drive_client();
+    // Handshake data reassembled from the reordered packets suffices to complete the handshake
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    pair.drive();
+    assert!(pair.server.accept().is_some());
+}
+
+#[test]
+fn close_retransmitted() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.client.close(pair.time, client_conn, 42, Bytes::new());
+    pair.drive_client();
+    info!(pair.log, "dropping CONNECTION_CLOSE");
+    pair.server.inbound.clear();
+
+    // A closing endpoint answers further packets with its close until its drain period ends
+    pair.server.ping(server_conn);
+    let mut closed = false;
+    while pair.step() {
+        if !closed {
+            if let Some(State::Draining(_)) = pair.server.connections[server_conn.0].state {
+                // The client is still within its drain period
+                assert!(pair.client.connections.contains(client_conn.0));
+                closed = true;
+            }
+        }
+    }
+    assert!(closed);
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ApplicationClosed {
+                        reason: ApplicationClose { error_code: 42, .. }
+                    }})) if conn == server_conn);
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionDrained)) if conn == client_conn);
+}
+
+#[test]
+fn force_close() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    pair.client.force_close(client_conn);
+    pair.drive_client();
+    assert!(pair.server.inbound.is_empty());
+    // The handle stays valid, but nothing more is reported for it
+    assert!(pair.client.is_drained(client_conn));
+    assert_matches!(pair.client.poll(), None);
+    assert_eq!(pair.client.next_wakeup(), u64::max_value());
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    assert!(!pair.client.connections.contains(client_conn.0));
+}
+
 #[test]
 fn stateless_retry() {
-    let mut pair = Pair::new(
-        Config {
-            use_stateless_retry: true,
-            ..Config::default()
-        },
-        Config::default(),
+    let mut server_config = server_config();
+    server_config.use_stateless_retry = true;
+    let mut pair = Pair::new(server_config, client_config());
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive_client();
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    // The first Initial is answered with a Retry rather than a connection
+    assert!(pair.server.connections.is_empty());
+    assert_eq!(pair.server.outbound.len(), 1);
+    assert_eq!(
+        packet::long_type(pair.server.outbound[0].1[0], Version::V1),
+        Some(types::RETRY)
+    );
+
+    // The second carries the token from the Retry, which the server accepts
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].retried);
+    assert!(!pair.client.connections[client_conn.0].retry_token.is_empty());
+    assert!(pair.server.accept().is_some());
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_eq!(pair.server.stats().dropped_packets, 0);
+}
+
+#[test]
+fn new_token() {
+    let mut server_config = server_config();
+    server_config.use_stateless_retry = true;
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, _) = pair.connect();
+    assert!(pair.client.connections[client_conn.0].retried);
+    pair.drive();
+    while pair.server.poll().is_some() {}
+    while pair.client.poll().is_some() {}
+    let stored = pair
+        .client
+        .ctx
+        .config
+        .token_store
+        .lock()
+        .unwrap()
+        .load("localhost");
+    assert!(stored.is_some());
+
+    // The token from the first connection validates the client's address without a Retry
+    let (client_conn, _) = pair.connect();
+    assert!(!pair.client.connections[client_conn.0].retried);
+    assert_eq!(
+        pair.client.connections[client_conn.0].retry_token,
+        stored.unwrap()
     );
-    pair.connect();
 }
-*/
 
-/*
 #[test]
 fn stateless_reset() {
     let mut pair = Pair::default();
     let (client_conn, _) = pair.connect();
-    assert_matches!(pair.client.poll(), Some((conn, Event::NewSessionTicket { .. })) if conn == client_conn);
-    let mut config = Config::default();
-    set_server_certificate(&mut config);
-    pair.server.endpoint = Endpoint::new(
-        pair.log.new(o!("peer" => "server")),
-        config,
-        Some(*LISTEN_KEYS),
-    ).unwrap();
+    while pair.client.poll().is_some() {}
+
+    pair.restart_server();
+
     pair.client.ping(client_conn);
-    info!(pair.log, "resetting");
     pair.drive();
-    assert_matches!(pair.client.poll(), Some((conn, Event::NewSessionTicket { .. })) if conn == client_conn);
     assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == client_conn);
+    let state = pair.client.connections[client_conn.0].state.as_ref();
+    assert!(state.unwrap().is_drained());
+}
+
+#[test]
+fn stateless_reset_zero_length_cid() {
+    // Resets carry no usable connection ID, so they're routed to the connection by address and must be recognized
+    // when they fail to decrypt
+    let mut client_config = client_config();
+    client_config.local_cid_len = 0;
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, _) = pair.connect();
+    while pair.client.poll().is_some() {}
+
+    pair.restart_server();
+
+    pair.client.ping(client_conn);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), None);
+    let state = pair.client.connections[client_conn.0].state.as_ref();
+    assert!(state.unwrap().is_drained());
+}
+
+#[test]
+fn custom_transport_parameters() {
+    let mut server_config = server_config();
+    server_config
+        .set_custom_param(0xff42, Bytes::from(&b"hello"[..]))
+        .unwrap();
+    let mut client_config = client_config();
+    client_config
+        .set_custom_param(0xff43, Bytes::from(&b"world"[..]))
+        .unwrap();
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.get_custom_param(client_conn, 0xff42),
+        Some(&Bytes::from(&b"hello"[..]))
+    );
+    assert_eq!(
+        pair.server.get_custom_param(server_conn, 0xff43),
+        Some(&Bytes::from(&b"world"[..]))
+    );
+    // Our own parameters are not the peer's
+    assert_eq!(pair.client.get_custom_param(client_conn, 0xff43), None);
+    assert_eq!(pair.server.get_custom_param(server_conn, 0xff42), None);
+}
+
+#[test]
+fn custom_transport_parameter_range() {
+    let mut config = Config::default();
+    // Collides with initial_max_data
+    assert_matches!(
+        config.set_custom_param(0x0001, Bytes::new()),
+        Err(EndpointError::IllegalCustomParameter(0x0001))
+    );
+    assert_matches!(
+        config.set_custom_param(0x1_0000, Bytes::new()),
+        Err(EndpointError::IllegalCustomParameter(0x1_0000))
+    );
+    assert!(config.custom_transport_parameters.is_empty());
+
+    // Together they must leave room for QUIC's own parameters
+    config
+        .set_custom_param(0xff42, Bytes::from(vec![0; 60_000]))
+        .unwrap();
+    assert_matches!(
+        config.set_custom_param(0xff43, Bytes::from(vec![0; 5000])),
+        Err(EndpointError::CustomParametersTooLong(65_008))
+    );
+    // Replacing a parameter only counts the new value
+    config
+        .set_custom_param(0xff42, Bytes::from(vec![0; 60_000]))
+        .unwrap();
+    config.custom_transport_parameters.clear();
+
+    // Parameters inserted directly are checked too
+    config
+        .custom_transport_parameters
+        .insert(0x0001, Bytes::new());
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::IllegalCustomParameter(0x0001))
+    );
+}
+
+#[test]
+fn cid_lengths() {
+    for &len in &[0, 8, MAX_CID_SIZE] {
+        let mut server_config = server_config();
+        server_config.max_remote_bi_streams = 1;
+        server_config.local_cid_len = len;
+        let mut client_config = client_config();
+        client_config.local_cid_len = len;
+        let mut pair = Pair::new(server_config, client_config);
+        let (client_conn, server_conn) = pair.connect();
+        assert_eq!(pair.client.get_local_id(client_conn).len(), len);
+        assert_eq!(pair.server.get_local_id(server_conn).len(), len);
+        assert_eq!(pair.client.get_remote_id(client_conn).len(), len);
+
+        let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+        const REQUEST: &[u8] = b"hello";
+        pair.client.write(client_conn, s, REQUEST).unwrap();
+        pair.drive();
+        assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+        assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == REQUEST);
+
+        const RESPONSE: &[u8] = b"world";
+        pair.server.write(server_conn, s, RESPONSE).unwrap();
+        pair.drive();
+        assert_matches!(pair.client.poll(), Some((conn, Event::StreamReadable { stream, fresh: false })) if conn == client_conn && stream == s);
+        assert_matches!(pair.client.read_unordered(client_conn, s), Ok((ref data, 0)) if data == RESPONSE);
+    }
+}
+
+#[test]
+fn cid_too_long() {
+    let mut config = server_config();
+    config.local_cid_len = MAX_CID_SIZE + 1;
+    assert_matches!(
+        Endpoint::new(logger(), config, None).err(),
+        Some(EndpointError::ConnectionIdTooLong(len)) if len == MAX_CID_SIZE + 1
+    );
+}
+
+#[test]
+fn zero_length_cid() {
+    let mut server_config = server_config();
+    server_config.max_remote_bi_streams = 1;
+    let mut client_config = client_config();
+    client_config.local_cid_len = 0;
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    assert!(pair.client.connections[client_conn.0].local_id.is_empty());
+    assert!(pair.server.connections[server_conn.0].remote_id.is_empty());
+
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    const REQUEST: &[u8] = b"hello";
+    pair.client.write(client_conn, s, REQUEST).unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == REQUEST);
+
+    // Short header packets to the client carry no connection ID at all
+    const RESPONSE: &[u8] = b"world";
+    pair.server.write(server_conn, s, RESPONSE).unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamReadable { stream, fresh: false })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.read_unordered(client_conn, s), Ok((ref data, 0)) if data == RESPONSE);
+}
+
+#[test]
+fn transmit_batch() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, &[0; 8000]).unwrap();
+
+    let mut bufs = (0..4).map(|_| None).collect::<Vec<_>>();
+    assert_eq!(pair.client.poll_transmit_batch(pair.time, &mut bufs), 4);
+    assert!(bufs.iter().all(|x| x.is_some()));
+    let mut bufs = (0..32).map(|_| None).collect::<Vec<_>>();
+    let n = pair.client.poll_transmit_batch(pair.time, &mut bufs);
+    assert!(n > 0 && n < bufs.len());
+    assert!(bufs[n..].iter().all(|x| x.is_none()));
+    // Everything left is timers
+    while let Some(io) = pair.client.poll_io(pair.time) {
+        assert_matches!(io, Io::TimerStart { .. } | Io::TimerStop { .. });
+    }
 }
-*/
 
 #[test]
 fn finish_stream() {
@@ -479,7 +1157,116 @@ fn finish_stream() {
 }
 
 #[test]
-fn reset_stream() {
+fn peek_stream() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = [0; 3];
+    assert_eq!(pair.server.peek(server_conn, s, &mut buf), Ok(3));
+    assert_eq!(&buf, b"hel");
+    let mut buf = [0; 16];
+    // Peeking past the buffered data is no different from reading
+    assert_eq!(pair.server.peek(server_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+    assert_eq!(
+        pair.server.peek(server_conn, s, &mut buf),
+        Err(ReadError::Finished)
+    );
+    assert_eq!(
+        pair.server.read(server_conn, s, &mut buf),
+        Err(ReadError::Finished)
+    );
+}
+
+#[test]
+fn write_vectored() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    // Only as much as stream flow control allows is taken, in order
+    let window = pair.server.ctx.config.stream_receive_window as usize;
+    let body = vec![0xAB; window];
+    assert_eq!(
+        pair.client
+            .write_vectored(client_conn, s, &[b"head", b"", &body[..]]),
+        Ok(window)
+    );
+    assert_eq!(
+        pair.client.write_vectored(client_conn, s, &[b"more"]),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+
+    let mut buf = vec![0; window];
+    let mut n = 0;
+    while n < window {
+        n += pair.server.read(server_conn, s, &mut buf[n..]).unwrap();
+    }
+    assert_eq!(&buf[..4], b"head");
+    assert!(buf[4..].iter().all(|&x| x == 0xAB));
+}
+
+#[test]
+fn write_chunks() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let chunks = [
+        Bytes::from(&b"hello"[..]),
+        Bytes::new(),
+        Bytes::from(&b" world"[..]),
+    ];
+    assert_eq!(pair.client.write_chunks(client_conn, s, &chunks), Ok(11));
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = [0; 11];
+    let mut n = 0;
+    while n < buf.len() {
+        n += pair.server.read(server_conn, s, &mut buf[n..]).unwrap();
+    }
+    assert_eq!(&buf, b"hello world");
+}
+
+#[test]
+fn read_chunk() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.client.write(client_conn, s, b" world").unwrap();
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut chunks = Vec::new();
+    loop {
+        match pair.server.read_chunk(server_conn, s) {
+            Ok(chunk) => chunks.push(chunk),
+            Err(ReadError::Finished) => break,
+            Err(e) => panic!("unexpected read error: {}", e),
+        }
+    }
+    // Each write arrived in its own frame, so is returned as is
+    assert_eq!(
+        chunks,
+        vec![Bytes::from(&b"hello"[..]), Bytes::from(&b" world"[..])]
+    );
+}
+
+#[test]
+fn reset_stream() {
     let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
 
@@ -533,6 +1320,423 @@ fn stop_stream() {
     );
 }
 
+#[test]
+fn reset_stream_twice() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+
+    const ERROR: u16 = 42;
+    pair.client.reset(client_conn, s, ERROR);
+    pair.client.reset(client_conn, s, ERROR + 1);
+    assert_eq!(
+        pair.client.connections[client_conn.0].pending.rst_stream,
+        [(s, ERROR)]
+    );
+    assert_matches!(
+        pair.client.write(client_conn, s, b"foo"),
+        Err(WriteError::Reset)
+    );
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Reset { error_code: ERROR })
+    );
+}
+
+#[test]
+fn reset_stream_credit() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const LEN: usize = 1000;
+    pair.client.write(client_conn, s, &[0xab; LEN]).unwrap();
+    pair.drive();
+    let max_data = pair.server.connections[server_conn.0].local_max_data;
+
+    // Abandon data that was never sent
+    pair.client.write(client_conn, s, &[0xcd; LEN]).unwrap();
+    pair.client.reset(client_conn, s, 42);
+    pair.drive();
+    {
+        let conn = &pair.server.connections[server_conn.0];
+        assert_eq!(conn.data_recvd, 2 * LEN as u64);
+        // The server returned the credit the unsent data consumed, which the application can never read
+        assert_eq!(conn.local_max_data, max_data + LEN as u64);
+    }
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data.len() == LEN);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Reset { error_code: 42 })
+    );
+}
+
+/// Reset a stream that sent 5 bytes, claiming a final size of `final_size`, and return the error it caused, if any
+fn reset_with_final_size(fin: bool, final_size: u64) -> Option<TransportError> {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    if fin {
+        pair.client.finish(client_conn, s);
+    }
+    pair.drive_client();
+    // Misrepresent how much was written
+    pair.client.connections[client_conn.0]
+        .streams
+        .get_mut(&s)
+        .unwrap()
+        .send_mut()
+        .unwrap()
+        .offset = final_size;
+    pair.client.reset(client_conn, s, 42);
+    pair.drive();
+    let mut error = None;
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::ConnectionLost {
+            reason: ConnectionError::TransportError { error_code },
+        } = event
+        {
+            assert_eq!(conn, server_conn);
+            error = Some(error_code);
+        }
+    }
+    error
+}
+
+#[test]
+fn reset_stream_final_size() {
+    assert_eq!(reset_with_final_size(false, 5), None);
+    // Data written but not yet sent when the stream was reset
+    assert_eq!(reset_with_final_size(false, 8), None);
+    // Less than was already received
+    assert_eq!(
+        reset_with_final_size(false, 3),
+        Some(TransportError::FINAL_OFFSET_ERROR)
+    );
+    // Once a FIN has fixed the final size, a reset must agree with it
+    assert_eq!(reset_with_final_size(true, 5), None);
+    assert_eq!(
+        reset_with_final_size(true, 8),
+        Some(TransportError::FINAL_OFFSET_ERROR)
+    );
+}
+
+#[test]
+fn stop_stream_twice() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    const ERROR: u16 = 42;
+    pair.server.stop_sending(server_conn, s, ERROR);
+    pair.server.stop_sending(server_conn, s, ERROR + 1);
+    assert_eq!(
+        pair.server.connections[server_conn.0].pending.stop_sending,
+        [(s, ERROR)]
+    );
+    pair.drive();
+
+    // The client's application is told to write, so it can learn the stream was stopped
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamWritable { stream })) if conn == client_conn && stream == s);
+    assert_matches!(
+        pair.client.write(client_conn, s, b"foo"),
+        Err(WriteError::Stopped { error_code: ERROR })
+    );
+    assert_matches!(
+        pair.client.write(client_conn, s, b"foo"),
+        Err(WriteError::Reset)
+    );
+}
+
+#[test]
+fn stop_push() {
+    // Let a single stream exhaust the connection-level window
+    const WINDOW: u32 = 64 * 1024;
+    let mut server_config = server_config();
+    server_config.max_remote_bi_streams = 1;
+    let mut client_config = client_config();
+    client_config.max_remote_uni_streams = 1;
+    client_config.receive_window = WINDOW;
+    client_config.stream_receive_window = WINDOW;
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    pair.client.write(client_conn, s, b"GET").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == b"GET");
+
+    // The server queues far more than congestion control lets it send at once
+    const CHUNK: &[u8] = &[0xab; 4096];
+    loop {
+        match pair.server.write(server_conn, s, CHUNK) {
+            Ok(_) => {}
+            Err(WriteError::Blocked) => break,
+            Err(e) => panic!("unexpected write error: {}", e),
+        }
+    }
+    pair.drive_server();
+
+    info!(pair.log, "stopping stream");
+    const ERROR: u16 = 42;
+    pair.client.stop_sending(client_conn, s, ERROR);
+    pair.drive();
+    assert_matches!(
+        pair.server.write(server_conn, s, CHUNK),
+        Err(WriteError::Stopped { error_code: ERROR })
+    );
+    {
+        // Nothing more of the stream will be sent
+        let pending = &pair.server.connections[server_conn.0].pending;
+        assert!(pending.stream.iter().all(|x| x.id != s));
+    }
+
+    // The client returned the connection-level credit for the data it will never receive
+    let s2 = pair.server.open(server_conn, Directionality::Uni).unwrap();
+    assert_matches!(pair.server.write(server_conn, s2, CHUNK), Ok(n) if n == CHUNK.len());
+}
+
+#[test]
+fn stream_id_blocked() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 1;
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+    {
+        let pending = &pair.client.connections[client_conn.0].pending;
+        assert!(pending.uni_stream_id_blocked);
+    }
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s);
+    pair.drive();
+    {
+        let pending = &pair.client.connections[client_conn.0].pending;
+        assert!(!pending.uni_stream_id_blocked);
+    }
+    assert_eq!(
+        pair.server
+            .get_flow_control_stats(server_conn)
+            .stream_id_blocked_received,
+        1
+    );
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+
+    // Closing the stream lets the client open another in its place
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
+    pair.drive();
+    let mut available = false;
+    while let Some((conn, event)) = pair.client.poll() {
+        if let Event::StreamAvailable { directionality } = event {
+            assert_eq!((conn, directionality), (client_conn, Directionality::Uni));
+            available = true;
+        }
+    }
+    assert!(available);
+    let s2 = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_matches!(pair.client.open(client_conn, Directionality::Uni), None);
+
+    pair.client.write(client_conn, s2, MSG).unwrap();
+    pair.drive();
+    assert_matches!(pair.server.read_unordered(server_conn, s2), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn flow_control_blocked() {
+    const WINDOW: u32 = 8192;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 2;
+    server_config.stream_receive_window = WINDOW;
+    server_config.receive_window = WINDOW + WINDOW / 2;
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+
+    // Exhaust the credit of one stream, then the rest of the connection's on another
+    const CHUNK: &[u8] = &[0xab; 1024];
+    let s1 = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let s2 = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    for &s in &[s1, s2] {
+        loop {
+            match pair.client.write(client_conn, s, CHUNK) {
+                Ok(_) => {}
+                Err(WriteError::Blocked) => break,
+                Err(e) => panic!("unexpected write error: {}", e),
+            }
+        }
+        // Further attempts at the same limit aren't reported again
+        assert_matches!(
+            pair.client.write(client_conn, s, CHUNK),
+            Err(WriteError::Blocked)
+        );
+    }
+    // Reports are counted once they're sent, not when they're queued
+    assert_eq!(
+        pair.client.get_flow_control_stats(client_conn),
+        FlowControlStats::default()
+    );
+    pair.drive();
+    assert_matches!(
+        pair.client.write(client_conn, s2, CHUNK),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+
+    let mut blocked = Vec::new();
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::PeerBlocked { stream, offset } = event {
+            assert_eq!(conn, server_conn);
+            blocked.push((stream, offset));
+        }
+    }
+    blocked.sort();
+    assert_eq!(
+        blocked,
+        [
+            (None, u64::from(WINDOW + WINDOW / 2)),
+            (Some(s1), u64::from(WINDOW))
+        ]
+    );
+    assert_eq!(
+        pair.client.get_flow_control_stats(client_conn),
+        FlowControlStats {
+            blocked_sent: 1,
+            stream_blocked_sent: 1,
+            blocked_received: 0,
+            stream_blocked_received: 0,
+            stream_id_blocked_received: 0,
+        }
+    );
+    assert_eq!(
+        pair.server.get_flow_control_stats(server_conn),
+        FlowControlStats {
+            blocked_sent: 0,
+            stream_blocked_sent: 0,
+            blocked_received: 1,
+            stream_blocked_received: 1,
+            stream_id_blocked_received: 0,
+        }
+    );
+}
+
+#[test]
+fn stream_priority() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let low = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let high = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.set_priority(client_conn, high, 1);
+    assert_eq!(pair.client.priority(client_conn, high), 1);
+    assert_eq!(pair.client.priority(client_conn, low), 0);
+
+    // Equal amounts queued at once, the low priority stream's first
+    const LEN: usize = 64 * 1024;
+    let data = vec![0xab; LEN];
+    for &s in &[low, high] {
+        assert_eq!(pair.client.write(client_conn, s, &data), Ok(LEN));
+        pair.client.finish(client_conn, s);
+    }
+    pair.drive();
+
+    let mut finished = Vec::new();
+    while let Some((_, event)) = pair.client.poll() {
+        if let Event::StreamFinished { stream } = event {
+            finished.push(stream);
+        }
+    }
+    assert_eq!(finished, [high, low]);
+}
+
+/// A server that only accepts clients with certificates issued by the test CA, and a client presenting
+/// `../certs/<client_cert>.chain`
+fn client_auth_pair(client_cert: &str) -> Pair {
+    let mut roots = crypto::RootCertStore::empty();
+    roots.add(&crypto::Certificate(ca_der())).unwrap();
+    // Added after the server's certificate, which must survive
+    let mut server = server_config();
+    require_client_auth(Arc::get_mut(&mut server.tls_server_config).unwrap(), roots);
+
+    let mut client = client_config();
+    let (certs, key) = cert_and_key(client_cert);
+    set_client_cert(
+        Arc::get_mut(&mut client.tls_client_config).unwrap(),
+        certs,
+        key,
+    )
+    .unwrap();
+    Pair::new(server, client)
+}
+
+#[test]
+fn client_certificate() {
+    let mut pair = client_auth_pair("server");
+    let (client_conn, server_conn) = pair.connect();
+    let (certs, _) = cert_and_key("server");
+    assert_eq!(
+        pair.server.get_peer_certificates(server_conn),
+        Some(&certs[..])
+    );
+    assert_eq!(
+        pair.client.get_peer_certificates(client_conn),
+        Some(&certs[..])
+    );
+
+    // Still known once the TLS session is gone
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    assert_eq!(
+        pair.client.get_peer_certificates(client_conn),
+        Some(&certs[..])
+    );
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((_, Event::ConnectionLost { .. })));
+    assert_eq!(
+        pair.server.get_peer_certificates(server_conn),
+        Some(&certs[..])
+    );
+}
+
+#[test]
+fn reject_untrusted_client_certificate() {
+    let mut pair = client_auth_pair("untrusted");
+    info!(pair.log, "connecting");
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.accept(), None);
+    assert_matches!(
+        pair.server.poll(),
+        Some((
+            _,
+            Event::ConnectionLost {
+                reason: ConnectionError::TransportError {
+                    error_code: TransportError::TLS_HANDSHAKE_FAILED
+                }
+            }
+        ))
+    );
+    // The client finishes its side of the handshake before the server checks its certificate
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed { ref reason } }))
+                    if conn == client_conn && reason.error_code == TransportError::TLS_HANDSHAKE_FAILED);
+}
+
 /*
 #[test]
 fn reject_self_signed_cert() {
@@ -573,52 +1777,2423 @@ fn congestion() {
     pair.client.write(client_conn, s, &[42; 1024]).unwrap();
 }
 
-#[test]
-fn high_latency_handshake() {
-    let mut pair = Pair::default();
-    pair.latency = 200 * 1000;
-    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+/// Rate at which `FixedRate` paces (bytes/s)
+const PACING_RATE: u64 = 1_000_000;
+
+/// Holds a wide window and paces at `PACING_RATE`, so that only the pacer limits transmission
+struct FixedRate;
+
+impl CongestionController for FixedRate {
+    fn on_packet_sent(&mut self, _: u64, _: u64, _: u64) {}
+    fn on_ack_received(&mut self, _: u64, _: u64, _: u64, _: u64) {}
+    fn on_congestion_event(&mut self, _: u64, _: CongestionEvent) {}
+    fn on_persistent_congestion(&mut self) {}
+    fn window(&self) -> u64 {
+        4 * 1024 * 1024
+    }
+    fn pacing_rate(&self) -> Option<u64> {
+        Some(PACING_RATE)
+    }
+}
+
+struct FixedRateFactory;
+
+impl CongestionControllerFactory for FixedRateFactory {
+    fn build(&self, _: &Config) -> Box<CongestionController> {
+        Box::new(FixedRate)
+    }
+}
+
+/// Write `len` bytes from a client paced by `FixedRate`, returning the number of datagrams sent immediately and the
+/// time taken to deliver them all
+fn paced_transfer(enable_pacing: bool, len: usize) -> (usize, u64) {
+    let mut client_config = client_config();
+    client_config.congestion_controller_factory = Arc::new(FixedRateFactory);
+    client_config.enable_pacing = enable_pacing;
+    // Probes aren't paced
+    client_config.mtu_discovery = false;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &vec![42; len]), Ok(len));
+    let start = pair.time;
+    pair.drive_client();
+    let burst = pair.server.inbound.len();
     pair.drive();
-    let server_conn = if let Some(c) = pair.server.accept() {
-        c
-    } else {
-        panic!("server didn't connect");
-    };
-    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
-    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
-    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+    (burst, pair.time - start)
+}
+
+#[test]
+fn pacing() {
+    const LEN: usize = 100 * 1000;
+    let (burst, time) = paced_transfer(true, LEN);
+    // Only the pacer's bucket, holding two datagrams, is sent at once
+    assert!(burst <= 2);
+    // The rest of the data leaves at the pacing rate
+    let min_time = (LEN as u64 - 2 * MIN_MTU as u64) * 1_000_000 / PACING_RATE;
+    assert!(time >= min_time);
+
+    let (burst, time) = paced_transfer(false, LEN);
+    assert!(burst > 2);
+    assert!(time < min_time);
+}
+
+/// Holds the window fixed at `WINDOW` bytes, reporting no pacing rate of its own
+struct FixedWindow;
+
+const WINDOW: u64 = 64 * 1024;
+
+impl CongestionController for FixedWindow {
+    fn on_packet_sent(&mut self, _: u64, _: u64, _: u64) {}
+    fn on_ack_received(&mut self, _: u64, _: u64, _: u64, _: u64) {}
+    fn on_congestion_event(&mut self, _: u64, _: CongestionEvent) {}
+    fn on_persistent_congestion(&mut self) {}
+    fn window(&self) -> u64 {
+        WINDOW
+    }
+}
+
+struct FixedWindowFactory;
+
+impl CongestionControllerFactory for FixedWindowFactory {
+    fn build(&self, _: &Config) -> Box<CongestionController> {
+        Box::new(FixedWindow)
+    }
+}
+
+#[test]
+fn pacing_by_window() {
+    const LEN: usize = 32 * 1024;
+    let mut client_config = client_config();
+    client_config.congestion_controller_factory = Arc::new(FixedWindowFactory);
+    client_config.mtu_discovery = false;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    let mut pair = Pair::new(server_config, client_config);
+    pair.latency = 10 * 1000;
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; LEN]), Ok(LEN));
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    // The window is spread over the RTT, so only the pacer's bucket leaves at once
+    let burst = pair.client.outbound.len();
+    assert!(burst >= 2 && burst < LEN / MIN_MTU as usize);
+    for _ in 0..4 {
+        // The pacing timer wakes the sender as soon as the next datagram may go
+        assert!(pair.client.pacing > pair.time && pair.client.pacing != u64::max_value());
+        let sent = pair.client.outbound.len();
+        pair.time = pair.client.pacing;
+        pair.client.drive(&pair.log, pair.time, pair.server.addr);
+        assert!(pair.client.outbound.len() > sent);
+    }
+}
+
+#[test]
+fn pacing_in_slow_start() {
+    const LEN: usize = 32 * 1024;
+    let mut client_config = client_config();
+    client_config.mtu_discovery = false;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    let mut pair = Pair::new(server_config, client_config);
+    pair.latency = 10 * 1000;
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[42; LEN]), Ok(LEN));
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let congestion = &pair.client.connections[client_conn.0].congestion;
+    assert!(congestion.in_slow_start());
+    // The window would allow more, but it's paced out over the RTT
+    let window = congestion.window() as usize;
+    assert!(pair.client.outbound.len() < window / MIN_MTU as usize);
+    assert!(pair.client.pacing != u64::max_value());
+}
+
+#[test]
+fn send_buffer() {
+    const BUFFER: u64 = 1024 * 1024;
+    const TOTAL: usize = 16 * 1024 * 1024;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    // Flow control never limits the writer, so only the send buffer can
+    server_config.stream_receive_window = 2 * TOTAL as u32;
+    server_config.receive_window = 2 * TOTAL as u32;
+    let mut client_config = client_config();
+    client_config.send_buffer_size = BUFFER as u32;
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let mut written = 0;
+    let mut suspensions = 0;
+    while written < TOTAL {
+        match pair.client.write(client_conn, s, &[42; 64 * 1024]) {
+            Ok(n) => {
+                written += n;
+            }
+            Err(WriteError::Blocked) => {
+                assert_eq!(pair.client.connections[client_conn.0].unacked_data, BUFFER);
+                suspensions += 1;
+                pair.drive();
+                // Acknowledgement of the buffered data wakes the writer
+                assert_eq!(pair.client.connections[client_conn.0].unacked_data, 0);
+                assert_matches!(pair.client.poll(), Some((conn, Event::StreamWritable { stream })) if conn == client_conn && stream == s);
+            }
+            Err(e) => {
+                panic!("unexpected write error: {}", e);
+            }
+        }
+        assert!(pair.client.connections[client_conn.0].unacked_data <= BUFFER);
+    }
+    assert_eq!(suspensions, TOTAL / BUFFER as usize - 1);
+}
+
+#[test]
+fn path_stats() {
+    let mut pair = Pair::default();
+    // Give the clock something to measure
+    pair.latency = 10 * 1000;
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let mut sent = 0;
+    while sent < 1024 * 1024 {
+        match pair.client.write(client_conn, s, &[42; 1024]) {
+            Ok(n) => {
+                sent += n;
+                pair.drive_client();
+            }
+            Err(WriteError::Blocked) => {
+                pair.drive();
+            }
+            Err(e) => {
+                panic!("unexpected write error: {}", e);
+            }
+        }
+    }
+    pair.drive();
+    let stats = pair.client.get_path_stats(client_conn);
+    // No sample is shorter than the round trip through the simulated network
+    assert!(stats.rtt >= Duration::from_micros(2 * pair.latency));
+    assert!(stats.rtt < Duration::from_millis(100));
+    assert_eq!(stats.loss_rate, 0.0);
+    assert_eq!(stats.bytes_in_flight, 0);
+    assert!(stats.cwnd > 0);
+    assert!(stats.send_rate > 0);
+}
+
+#[test]
+fn datagram() {
+    let mut server_config = server_config();
+    server_config.max_datagram_frame_size = Some(1200);
+    let mut client_config = client_config();
+    client_config.max_datagram_frame_size = Some(1200);
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.send_datagram(client_conn, MSG.into()).unwrap();
+    assert_matches!(
+        pair.client.send_datagram(client_conn, vec![0; 2000].into()),
+        Err(SendDatagramError::TooLarge)
+    );
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::DatagramReceived)) if conn == server_conn);
+    assert_matches!(pair.server.recv_datagram(server_conn), Some(ref data) if data == MSG);
+    assert_matches!(pair.server.recv_datagram(server_conn), None);
+}
+
+#[test]
+fn datagram_drop_oldest() {
+    let mut server_config = server_config();
+    server_config.max_datagram_frame_size = Some(1200);
+    let mut client_config = client_config();
+    client_config.max_datagram_frame_size = Some(1200);
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+
+    // Queue one more datagram than fits without giving the connection a chance to send any
+    for i in 0..=128u32 {
+        pair.client
+            .send_datagram(client_conn, i.to_string().into())
+            .unwrap();
+    }
+    pair.drive();
+    let mut received = Vec::new();
+    while let Some(data) = pair.server.recv_datagram(server_conn) {
+        received.push(str::from_utf8(&data).unwrap().parse::<u32>().unwrap());
+    }
+    assert_eq!(received, (1..=128).collect::<Vec<_>>());
+}
+
+#[test]
+fn datagram_lossy() {
+    let mut server_config = server_config();
+    server_config.max_datagram_frame_size = Some(1200);
+    let mut client_config = client_config();
+    client_config.max_datagram_frame_size = Some(1200);
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    pair.loss_rate = 0.2;
+
+    for i in 0..100u32 {
+        pair.client
+            .send_datagram(client_conn, i.to_string().into())
+            .unwrap();
+        pair.drive();
+    }
+    pair.loss_rate = 0.0;
+    pair.drive();
+    let mut received = Vec::new();
+    while let Some(data) = pair.server.recv_datagram(server_conn) {
+        received.push(str::from_utf8(&data).unwrap().parse::<u32>().unwrap());
+    }
+    // Lost datagrams are gone for good, and nothing arrives twice
+    assert!(!received.is_empty() && received.len() < 100);
+    let mut deduped = received.clone();
+    deduped.sort();
+    deduped.dedup();
+    assert_eq!(deduped.len(), received.len());
+    // Datagrams are subject to congestion control, so the losses were accounted for
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+}
+
+#[test]
+fn datagram_unsupported() {
+    let mut client_config = client_config();
+    client_config.max_datagram_frame_size = Some(1200);
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, _) = pair.connect();
+    assert_matches!(
+        pair.client.send_datagram(client_conn, (&b"hello"[..]).into()),
+        Err(SendDatagramError::UnsupportedByPeer)
+    );
+}
+
+/// Count the packets the server sends while receiving 1000 ack-eliciting packets
+fn ack_frequency_acks(threshold: Option<u64>) -> usize {
+    let mut server_config = server_config();
+    server_config.max_datagram_frame_size = Some(1200);
+    server_config.min_ack_delay = Some(1000);
+    let mut client_config = client_config();
+    client_config.max_datagram_frame_size = Some(1200);
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, _) = pair.connect();
+    if let Some(threshold) = threshold {
+        pair.client
+            .request_ack_frequency(client_conn, threshold, 25 * 1000)
+            .unwrap();
+        pair.drive();
+    }
+
+    // Time stands still, so ACKs are only sent once the threshold is exceeded
+    let mut acks = 0;
+    for i in 0..1000u64 {
+        pair.client
+            .send_datagram(client_conn, i.to_string().into())
+            .unwrap();
+        pair.drive_client();
+        pair.drive_server();
+        acks += pair.client.inbound.len();
+        pair.drive_client();
+    }
+    pair.drive();
+    acks
+}
+
+#[test]
+fn ack_frequency() {
+    let immediate = ack_frequency_acks(None);
+    assert!(immediate >= 1000);
+    let delayed = ack_frequency_acks(Some(9));
+    assert!(delayed <= 101, "sent {} ACKs", delayed);
+}
+
+#[test]
+fn ack_frequency_delay() {
+    let mut server_config = server_config();
+    server_config.min_ack_delay = Some(1000);
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+    pair.client
+        .request_ack_frequency(client_conn, 9, 25 * 1000)
+        .unwrap();
+    pair.drive();
+
+    // A lone packet is acknowledged once the requested delay has passed
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    pair.drive_server();
+    assert_eq!(pair.client.inbound.len(), 0);
+    let start = pair.time;
+    pair.drive();
+    assert!(pair.time - start >= 25 * 1000);
+    assert_eq!(pair.server.connections[server_conn.0].unacked_ack_eliciting, 0);
+}
+
+#[test]
+fn max_ack_delay_negotiated() {
+    let mut server_config = server_config();
+    server_config.max_ack_delay = 40 * 1000;
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.connections[client_conn.0].max_ack_delay,
+        40 * 1000
+    );
+    assert_eq!(
+        pair.server.connections[server_conn.0].max_ack_delay,
+        25 * 1000
+    );
+}
+
+#[test]
+fn rtt_excludes_ack_delay_within_max() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let conn = &mut pair.client.connections[client_conn.0];
+    conn.min_rtt = 10 * 1000;
+    conn.smoothed_rtt = 0;
+
+    // A delay the peer was allowed is discounted in full
+    conn.latest_rtt = 100 * 1000;
+    conn.update_rtt(20 * 1000);
+    assert_eq!(conn.smoothed_rtt, 80 * 1000);
+
+    // Beyond max_ack_delay, the rest is counted as part of the round trip
+    conn.smoothed_rtt = 0;
+    conn.latest_rtt = 100 * 1000;
+    conn.update_rtt(50 * 1000);
+    assert_eq!(conn.smoothed_rtt, 75 * 1000);
+}
+
+#[test]
+fn ack_frequency_unsupported() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    assert_matches!(
+        pair.client.request_ack_frequency(client_conn, 9, 25 * 1000),
+        Err(AckFrequencyError::UnsupportedByPeer)
+    );
+
+    let mut server_config = server_config();
+    server_config.min_ack_delay = Some(1000);
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, _) = pair.connect();
+    assert_matches!(
+        pair.client.request_ack_frequency(client_conn, 9, 500),
+        Err(AckFrequencyError::DelayTooShort)
+    );
+
+    // Our own support says nothing about the peer's, which isn't known until the handshake completes
+    let mut client_config = client_config();
+    client_config.min_ack_delay = Some(1000);
+    let mut pair = Pair::new(server_config(), client_config);
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    assert_matches!(
+        pair.client.request_ack_frequency(client_conn, 9, 25 * 1000),
+        Err(AckFrequencyError::UnsupportedByPeer)
+    );
+}
+
+#[test]
+fn ack_ranges_capped() {
+    const RANGES: usize = 16;
+    let mut server_config = server_config();
+    server_config.max_ack_ranges = RANGES;
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+
+    // Every other packet is lost, so each one received starts a new range
+    for i in 0..4000 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+        if i % 2 == 0 {
+            pair.server.inbound.clear();
+        }
+        pair.drive_server();
+        pair.drive_client();
+    }
+    let conn = &pair.server.connections[server_conn.0];
+    assert_eq!(conn.pending_acks.len(), RANGES);
+    // The most recent ranges are the ones kept
+    assert_eq!(conn.pending_acks.max(), Some(conn.rx_packet));
+}
+
+#[test]
+fn ecn_validated() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Capable
+    );
+    assert_eq!(
+        pair.server.connections[server_conn.0].ecn_state,
+        EcnState::Capable
+    );
+}
+
+#[test]
+fn ecn_bleached() {
+    let mut pair = Pair::default();
+    pair.strip_ecn = true;
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Failed
+    );
+    assert_eq!(
+        pair.server.connections[server_conn.0].ecn_state,
+        EcnState::Failed
+    );
+    assert_eq!(pair.client.connections[client_conn.0].ecn_codepoint(), None);
+}
+
+#[test]
+fn ecn_congestion() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, &[42; 1024]).unwrap();
+    pair.drive();
+    let window = pair.client.get_path_stats(client_conn).cwnd;
+
+    pair.mark_ce = true;
+    pair.client.write(client_conn, s, &[42; 1024]).unwrap();
+    pair.drive();
+    // Congestion reported by ECN is responded to as if a packet were lost, though none were
+    assert!(pair.client.get_path_stats(client_conn).cwnd < window);
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Capable
+    );
+}
+
+/// Write enough data to a fresh stream to need several packets, and restart ECN validation
+fn ecn_probe_setup(pair: &mut Pair) -> ConnectionHandle {
+    let (client_conn, _) = pair.connect();
+    pair.client.connections[client_conn.0].ecn_state = EcnState::Testing(0);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, &[0; 12000]).unwrap();
+    pair.drive_client();
+    client_conn
+}
+
+#[test]
+fn ecn_probes() {
+    let mut pair = Pair::default();
+    let client_conn = ecn_probe_setup(&mut pair);
+    let marks = pair.server.inbound.iter().map(|x| x.1).collect::<Vec<_>>();
+    let probes = ECN_PROBE_PACKETS as usize;
+    assert!(marks.len() > probes);
+    assert!(marks[..probes].iter().all(|&x| x == Some(EcnCodepoint::ECT0)));
+    assert!(marks[probes..].iter().all(|x| x.is_none()));
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Unknown
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Capable
+    );
+}
+
+#[test]
+fn ecn_probes_lost() {
+    let mut pair = Pair::default();
+    let client_conn = ecn_probe_setup(&mut pair);
+    for _ in 0..ECN_PROBE_PACKETS {
+        pair.server.inbound.pop_front();
+    }
+    pair.drive();
+    assert_eq!(
+        pair.client.connections[client_conn.0].ecn_state,
+        EcnState::Failed
+    );
+}
+
+/// Spin bits of the short-header packets queued for delivery to `endpoint`
+fn inbound_spins(endpoint: &TestEndpoint) -> Vec<bool> {
+    endpoint
+        .inbound
+        .iter()
+        .filter(|x| x.2[0] & 0x80 == 0)
+        .map(|x| x.2[0] & 0x20 != 0)
+        .collect()
+}
+
+#[test]
+fn spin_bit() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let mut last = None;
+    for _ in 0..4 {
+        let spin = pair.client.connections[client_conn.0].spin;
+        assert_ne!(Some(spin), last);
+        last = Some(spin);
+        pair.client.write(client_conn, s, b"hello").unwrap();
+        pair.drive_client();
+        let spins = inbound_spins(&pair.server);
+        assert!(!spins.is_empty());
+        assert!(spins.iter().all(|&x| x == spin));
+        pair.drive();
+    }
+}
+
+#[test]
+fn spin_bit_disabled() {
+    let mut client_config = client_config();
+    client_config.enable_spin_bit = false;
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, server_conn) = pair.connect();
+    for _ in 0..4 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+        assert!(inbound_spins(&pair.server).iter().all(|&x| !x));
+        pair.drive_server();
+        assert!(inbound_spins(&pair.client).iter().all(|&x| !x));
+        pair.drive();
+    }
+    assert!(!pair.client.connections[client_conn.0].spin);
+    assert!(!pair.server.connections[server_conn.0].spin);
+}
+
+#[test]
+fn initial_padding() {
+    let mut pair = Pair::default();
+    pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert!(pair.client.outbound.front().unwrap().1.len() >= MIN_INITIAL_SIZE);
+    pair.drive();
+    assert!(pair.server.accept().is_some());
+    assert_eq!(pair.server.stats().small_initials_dropped, 0);
+}
+
+/// Encode `header`, append the frames written by `payload`, then encrypt and protect the header with `crypto`
+fn protect<F>(header: Header, number: u64, crypto: &crypto::Crypto, payload: F) -> Vec<u8>
+where
+    F: FnOnce(&mut Vec<u8>),
+{
+    let mut buf = Vec::new();
+    let partial_encode = header.encode(&mut buf);
+    let header_len = buf.len();
+    payload(&mut buf);
+    set_payload_length(&mut buf, partial_encode.len_slot.unwrap(), crypto.tag_len()).unwrap();
+    crypto.encrypt(number, &mut buf, header_len);
+    Header::encrypt_header(&mut buf, header_len, crypto.local_header_key());
+    buf
+}
+
+#[test]
+fn small_initial_ignored() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    pair.client.outbound.clear();
+
+    // A well-formed Initial in a datagram of only 300 bytes
+    let initial = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let number = conn.get_tx_number();
+        let header = Header::Initial {
+            version: Version::V1,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            token: Bytes::new(),
+            number: PacketNumber::U32(number as u32),
+        };
+        let tag_len = conn.handshake_crypto.tag_len();
+        protect(header, number, &conn.handshake_crypto, |buf| {
+            buf.push(frame::Type::PING.into());
+            buf.resize(300 - tag_len, frame::Type::PADDING.into());
+        })
+    };
+    assert_eq!(initial.len(), 300);
+    pair.server
+        .inbound
+        .push_back((pair.time, None, initial.into()));
+    pair.drive_server();
+
+    assert_eq!(pair.server.stats().small_initials_dropped, 1);
+    assert!(pair.server.connections.is_empty());
+    assert!(pair.client.inbound.is_empty());
+}
+
+#[test]
+fn coalesced_initial_handshake() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let (_, initial) = pair.client.outbound.pop_front().unwrap();
+    pair.client.outbound.clear();
+
+    // A Handshake packet for the same connection, which shares the Initial's keys
+    let (number, handshake) = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let number = conn.get_tx_number();
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            number: PacketNumber::U32(number as u32),
+        };
+        let buf = protect(header, number, &conn.handshake_crypto, |buf| {
+            buf.push(frame::Type::PING.into())
+        });
+        (number, buf)
+    };
+
+    let mut datagram = initial.to_vec();
+    datagram.extend_from_slice(&handshake);
+    // Trailing padding must be ignored
+    datagram.extend_from_slice(&[0; 16]);
+    pair.server
+        .inbound
+        .push_back((pair.time, None, datagram.into()));
+    pair.drive_server();
+
+    let (_, conn) = pair.server.connections.iter().next().unwrap();
+    assert!(conn.pending_acks.contains(number - 1));
+    assert!(conn.pending_acks.contains(number));
+}
+
+/// Check that a client using `version` learns that the handshake is confirmed
+fn confirm_handshake(version: Version) {
+    let mut client_config = client_config();
+    client_config.versions = vec![version];
+    let mut pair = Pair::new(server_config(), client_config);
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+    {
+        // The client has sent its Finished, but doesn't yet know whether the server accepts it
+        let conn = &pair.client.connections[client_conn.0];
+        assert!(!conn.handshake_confirmed);
+        assert!(conn
+            .sent_packets
+            .values()
+            .any(|x| x.space != packet::SpaceId::Data));
+    }
+    // Give the server a 1-RTT packet to acknowledge
+    pair.client.ping(client_conn);
+    pair.drive();
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(conn.handshake_confirmed);
+    assert!(!conn.awaiting_handshake);
+    assert!(conn
+        .sent_packets
+        .values()
+        .all(|x| x.space == packet::SpaceId::Data));
+}
+
+#[test]
+fn handshake_done() {
+    confirm_handshake(Version::V2);
+}
+
+#[test]
+fn handshake_confirmed_by_ack() {
+    // Version 1 has no HANDSHAKE_DONE, so the client waits for one of its 1-RTT packets to be acknowledged
+    confirm_handshake(Version::V1);
+}
+
+#[test]
+fn handshake_done_from_client() {
+    let mut client_config = client_config();
+    client_config.versions = vec![Version::V2];
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, server_conn) = pair.connect();
+    pair.client.connections[client_conn.0]
+        .pending
+        .handshake_done = true;
+    pair.client.ping(client_conn);
+    pair.drive();
+    let mut lost = false;
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::ConnectionLost {
+            reason: ConnectionError::TransportError { error_code },
+        } = event
+        {
+            assert_eq!(conn, server_conn);
+            assert_eq!(error_code, TransportError::PROTOCOL_VIOLATION);
+            lost = true;
+        }
+    }
+    assert!(lost);
+}
+
+#[test]
+fn handshake_application_close() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let (_, initial) = pair.client.outbound.pop_front().unwrap();
+    pair.client.outbound.clear();
+
+    // An APPLICATION_CLOSE, which mustn't be sent before the handshake completes
+    let handshake = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let number = conn.get_tx_number();
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            number: PacketNumber::U32(number as u32),
+        };
+        protect(header, number, &conn.handshake_crypto, |buf| {
+            frame::ApplicationClose {
+                error_code: 42,
+                reason: Bytes::new(),
+            }.encode(buf, 1200)
+        })
+    };
+
+    let mut datagram = initial.to_vec();
+    datagram.extend_from_slice(&handshake);
+    pair.server
+        .inbound
+        .push_back((pair.time, None, datagram.into()));
+    pair.drive_server();
+
+    let mut lost = false;
+    while let Some((_, event)) = pair.server.poll() {
+        if let Event::ConnectionLost {
+            reason: ConnectionError::TransportError { error_code },
+        } = event
+        {
+            assert_eq!(error_code, TransportError::PROTOCOL_VIOLATION);
+            lost = true;
+        }
+    }
+    assert!(lost);
+}
+
+#[test]
+fn coalesced_undecryptable() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let (_, initial) = pair.client.outbound.pop_front().unwrap();
+    pair.client.outbound.clear();
+
+    let (number, handshake, zero_rtt) = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let number = conn.get_tx_number();
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::HANDSHAKE,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            number: PacketNumber::U32(number as u32),
+        };
+        let buf = protect(header, number, &conn.handshake_crypto, |buf| {
+            buf.push(frame::Type::PING.into())
+        });
+
+        // A 0-RTT packet, which the server has no keys for
+        let mut zero_rtt = Vec::new();
+        let partial_encode = Header::Long {
+            version: Version::V1,
+            ty: types::ZERO_RTT,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            number: PacketNumber::U32(0),
+        }.encode(&mut zero_rtt);
+        zero_rtt.extend_from_slice(&[0xab; 64]);
+        set_payload_length(
+            &mut zero_rtt,
+            partial_encode.len_slot.unwrap(),
+            packet::AEAD_TAG_SIZE,
+        )
+        .unwrap();
+        (number, buf, zero_rtt)
+    };
+
+    // The packet is dropped on its own, without disrupting those around it
+    let mut datagram = initial.to_vec();
+    datagram.extend_from_slice(&zero_rtt);
+    datagram.extend_from_slice(&handshake);
+    pair.server
+        .inbound
+        .push_back((pair.time, None, datagram.into()));
+    pair.drive_server();
+
+    let (_, conn) = pair.server.connections.iter().next().unwrap();
+    assert!(conn.pending_acks.contains(number - 1));
+    assert!(conn.pending_acks.contains(number));
+    assert_eq!(pair.server.stats().dropped_packets, 1);
+}
+
+#[test]
+fn reserved_bits_set() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let packet = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let number = conn.get_tx_number();
+        let mut buf = Vec::new();
+        Header::Short {
+            id: conn.remote_id.clone(),
+            number: PacketNumber::U32(number as u32),
+            spin: false,
+            key_phase: conn.key_phase,
+        }
+        .encode(&mut buf);
+        // Set under header protection, so only an authenticated peer could have done it
+        buf[0] |= 0x08;
+        let header_len = buf.len();
+        buf.push(frame::Type::PING.into());
+        let crypto = conn.crypto.as_ref().unwrap();
+        crypto.encrypt(number, &mut buf, header_len);
+        Header::encrypt_header(&mut buf, header_len, crypto.local_header_key());
+        buf
+    };
+    pair.server
+        .inbound
+        .push_back((pair.time, None, packet.into()));
+    pair.drive_server();
+    assert_matches!(
+        pair.server.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == server_conn && error_code == TransportError::PROTOCOL_VIOLATION
+    );
+}
+
+#[test]
+fn coalesce_handshake_and_protected() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let conn = &mut pair.client.connections[client_conn.0];
+    // Pretend the handshake is unconfirmed while application data is also ready to send
+    conn.awaiting_handshake = true;
+    conn.handshake_pending.ping = true;
+    conn.pending.ping = true;
+    let (datagram, _) = conn
+        .next_datagram(&pair.log, &Config::default(), &mut pair.rng, pair.time)
+        .unwrap()
+        .unwrap();
+    let packets = PartialDecode::decode_all(datagram[..].into(), conn.remote_id.len())
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].long_type(), Some(types::HANDSHAKE));
+    assert!(!packets[1].has_long_header());
+}
+
+#[test]
+fn coalesce_only_matching_ecn() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let conn = &mut pair.client.connections[client_conn.0];
+    conn.awaiting_handshake = true;
+    conn.handshake_pending.ping = true;
+    conn.pending.ping = true;
+    // Only one ECN probe remains, so the packets after it go unmarked
+    conn.ecn_state = EcnState::Testing(ECN_PROBE_PACKETS - 1);
+    let (datagram, ecn) = conn
+        .next_datagram(&pair.log, &Config::default(), &mut pair.rng, pair.time)
+        .unwrap()
+        .unwrap();
+    assert_eq!(ecn, Some(EcnCodepoint::ECT0));
+    let packets = PartialDecode::decode_all(datagram[..].into(), conn.remote_id.len());
+    assert_eq!(packets.len(), 1);
+    let (datagram, ecn) = conn
+        .next_datagram(&pair.log, &Config::default(), &mut pair.rng, pair.time)
+        .unwrap()
+        .unwrap();
+    assert_eq!(ecn, None);
+    assert!(!datagram.is_empty());
+}
+
+#[test]
+fn grease_quic_bit_after_peer_permits() {
+    let mut pair = Pair::default();
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    // Our own parameters permit greasing, but the server hasn't said whether it does yet
+    let greased = pair.client.connections[client_conn.0]
+        .params
+        .grease_quic_bit;
+    assert!(!greased);
+    pair.drive();
+    let server_conn = pair.server.accept().expect("server didn't connect");
+    let greased = pair.client.connections[client_conn.0]
+        .params
+        .grease_quic_bit;
+    assert!(greased);
+    let greased = pair.server.connections[server_conn.0]
+        .params
+        .grease_quic_bit;
+    assert!(greased);
+}
+
+#[test]
+fn high_latency_handshake() {
+    let mut pair = Pair::default();
+    pair.latency = 200 * 1000;
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive();
+    let server_conn = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't connect");
+    };
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+}
+
+/// Send `len` bytes on a fresh stream from client to server, checking that they all arrive intact
+fn transfer(
+    pair: &mut Pair,
+    client_conn: ConnectionHandle,
+    server_conn: ConnectionHandle,
+    len: usize,
+) {
+    let msg = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(len));
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = vec![0; len + 1];
+    let mut n = 0;
+    loop {
+        match pair.server.read(server_conn, s, &mut buf[n..]) {
+            Ok(x) => n += x,
+            Err(ReadError::Finished) => break,
+            Err(e) => panic!("unexpected read error: {}", e),
+        }
+    }
+    assert_eq!(&buf[..n], &msg[..]);
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+}
+
+#[test]
+fn lossy_network() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.latency = 10 * 1000;
+    pair.jitter = 5 * 1000;
+    pair.loss_rate = 0.05;
+    pair.reorder_rate = 0.05;
+    pair.bandwidth = 1000 * 1000;
+    transfer(&mut pair, client_conn, server_conn, 256 * 1024);
+}
+
+/// Send `len` bytes on a fresh bidirectional stream from client to server and back, checking they arrive intact
+fn echo(pair: &mut Pair, client_conn: ConnectionHandle, server_conn: ConnectionHandle, len: usize) {
+    let msg = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(len));
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = vec![0; len + 1];
+    let mut n = 0;
+    loop {
+        match pair.server.read(server_conn, s, &mut buf[n..]) {
+            Ok(x) => n += x,
+            Err(ReadError::Finished) => break,
+            Err(e) => panic!("unexpected read error: {}", e),
+        }
+    }
+    assert_eq!(&buf[..n], &msg[..]);
+    assert_eq!(pair.server.write(server_conn, s, &msg), Ok(len));
+    pair.server.finish(server_conn, s);
+    pair.drive();
+
+    let mut n = 0;
+    loop {
+        match pair.client.read(client_conn, s, &mut buf[n..]) {
+            Ok(x) => n += x,
+            Err(ReadError::Finished) => break,
+            Err(e) => panic!("unexpected read error: {}", e),
+        }
+    }
+    assert_eq!(&buf[..n], &msg[..]);
+}
+
+#[test]
+fn key_update() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    for i in 0..4 {
+        // Each side initiates every other update
+        if i % 2 == 0 {
+            pair.client.force_key_update(client_conn);
+        } else {
+            pair.server.force_key_update(server_conn);
+        }
+        echo(&mut pair, client_conn, server_conn, 64 * 1024);
+        assert_eq!(pair.client.connections[client_conn.0].key_updates, i + 1);
+        assert_eq!(pair.server.connections[server_conn.0].key_updates, i + 1);
+    }
+    // Packets sent around each update were all authenticated by the peer, so none was deemed lost
+    assert_eq!(pair.client.get_path_stats(client_conn).loss_rate, 0.0);
+    assert_eq!(pair.server.get_path_stats(server_conn).loss_rate, 0.0);
+}
+
+#[test]
+fn key_update_interval() {
+    let mut server_config = server_config();
+    server_config.max_remote_bi_streams = 32;
+    server_config.key_update_interval = Some(32);
+    let mut client_config = client_config();
+    client_config.key_update_interval = Some(32);
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    echo(&mut pair, client_conn, server_conn, 256 * 1024);
+    let updates = pair.client.connections[client_conn.0].key_updates;
+    assert!(updates >= 4);
+    assert_eq!(pair.server.connections[server_conn.0].key_updates, updates);
+    assert_eq!(pair.client.get_path_stats(client_conn).loss_rate, 0.0);
+    assert_eq!(pair.server.get_path_stats(server_conn).loss_rate, 0.0);
+}
+
+#[test]
+fn network_partition() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    pair.latency = 10 * 1000;
+    // Shorter than the idle timeout, so the connection survives
+    pair.partition(2 * 1000 * 1000);
+    transfer(&mut pair, client_conn, server_conn, 64 * 1024);
+    assert!(pair.time > pair.partition_end);
+}
+
+#[test]
+fn idle_timeout() {
+    // The shorter of the two limits applies to both sides
+    let server_config = Config {
+        max_idle_timeout: 2 * 1000 * 1000,
+        ..server_config()
+    };
+    let client_config = Config {
+        max_idle_timeout: 5 * 1000 * 1000,
+        ..client_config()
+    };
+    let mut pair = Pair::new(server_config, client_config);
+    let (client_conn, server_conn) = pair.connect();
+    while pair.client.poll().is_some() {}
+    while pair.server.poll().is_some() {}
+    let start = pair.time;
+    assert!(pair.client.idle <= start + 2 * 1000 * 1000);
+    assert!(pair.server.idle <= start + 2 * 1000 * 1000);
+
+    pair.time = pair.client.idle;
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    assert!(
+        pair.client.outbound.is_empty(),
+        "sent a packet on idle timeout"
+    );
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::TimedOut })) if conn == client_conn);
+    assert!(pair.client.is_drained(client_conn));
+    assert_eq!(pair.client.next_wakeup(), u64::max_value());
+
+    pair.time = pair.server.idle;
+    pair.server.drive(&pair.log, pair.time, pair.client.addr);
+    assert!(
+        pair.server.outbound.is_empty(),
+        "sent a packet on idle timeout"
+    );
+    assert_matches!(pair.server.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::TimedOut })) if conn == server_conn);
+    assert!(pair.server.is_drained(server_conn));
+}
+
+#[test]
+fn idle_timeout_disabled() {
+    let mut pair = Pair::new(
+        Config {
+            max_idle_timeout: 0,
+            ..server_config()
+        },
+        Config {
+            max_idle_timeout: 0,
+            ..client_config()
+        },
+    );
+    pair.connect();
+    assert_eq!(pair.client.idle, u64::max_value());
+    assert_eq!(pair.server.idle, u64::max_value());
+
+    // Either side's limit suffices on its own
+    let mut pair = Pair::new(
+        Config {
+            max_idle_timeout: 0,
+            ..server_config()
+        },
+        client_config(),
+    );
+    pair.connect();
+    assert!(pair.server.idle <= pair.time + 10 * 1000 * 1000);
+}
+
+fn keep_alive_config() -> Config {
+    Config {
+        keep_alive_interval: Some(1000 * 1000),
+        ..client_config()
+    }
+}
+
+#[test]
+fn keep_alive() {
+    let mut pair = Pair::new(server_config(), keep_alive_config());
+    let (client_conn, server_conn) = pair.connect();
+    let start = pair.time;
+    // Outlast the idle timeout several times over
+    while pair.time < start + 60 * 1000 * 1000 {
+        assert!(pair.client.keep_alive != u64::max_value(), "keep-alive timer stopped");
+        pair.time = pair.client.keep_alive;
+        pair.drive();
+    }
+    assert!(!pair.client.connections[client_conn.0].state.as_ref().unwrap().is_closed());
+    assert!(!pair.server.connections[server_conn.0].state.as_ref().unwrap().is_closed());
+    while let Some((_, event)) = pair.client.poll() {
+        assert_matches!(event, Event::PingAcknowledged { .. });
+    }
+}
+
+#[test]
+fn keep_alive_unanswered() {
+    let mut pair = Pair::new(server_config(), keep_alive_config());
+    let (client_conn, _) = pair.connect();
+    // The server vanishes, which the client must notice in spite of its own keep-alives
+    pair.partition(u64::max_value() / 2);
+    let start = pair.time;
+    loop {
+        assert!(pair.time < start + 12 * 1000 * 1000, "keep-alives delayed the idle timeout");
+        pair.time = pair.time.max(pair.client.keep_alive.min(pair.client.idle));
+        pair.drive();
+        if let Some((conn, event)) = pair.client.poll() {
+            assert_eq!(conn, client_conn);
+            assert_matches!(event, Event::ConnectionLost { reason: ConnectionError::TimedOut });
+            break;
+        }
+    }
+}
+
+#[test]
+fn ping_acknowledged() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    pair.latency = 10 * 1000;
+    let sent = pair.time;
+    pair.client.ping(client_conn);
+    pair.drive();
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::PingAcknowledged { sent: x })) if conn == client_conn && x == sent
+    );
+    assert!(pair.time >= sent + 2 * pair.latency);
+}
+
+/// Time taken to deliver a short message whose `lost`th packet from the end is lost
+fn tail_loss_recovery(mut client_config: Config, lost: usize) -> u64 {
+    // Keep MTU probes from trailing the data
+    client_config.mtu_discovery = false;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    let mut pair = Pair::new(server_config, client_config);
+    pair.latency = 50 * 1000;
+    let (client_conn, server_conn) = pair.connect();
+
+    let msg = vec![42; 8 * 1000];
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &msg), Ok(msg.len()));
+    pair.client.finish(client_conn, s);
+    let start = pair.time;
+    pair.drive_client();
+    assert!(pair.server.inbound.len() > lost);
+    let index = pair.server.inbound.len() - lost;
+    pair.server.inbound.remove(index);
+
+    let mut buf = vec![0; msg.len() + 1];
+    let mut n = 0;
+    loop {
+        assert!(pair.step(), "went idle before delivery");
+        loop {
+            match pair.server.read(server_conn, s, &mut buf[n..]) {
+                Ok(x) => n += x,
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Finished) => {
+                    assert_eq!(&buf[..n], &msg[..]);
+                    return pair.time - start;
+                }
+                Err(e) => panic!("unexpected read error: {}", e),
+            }
+        }
+    }
+}
+
+#[test]
+fn rack_tail_loss() {
+    // RACK declares the packet lost an eighth of an RTT after the last one is acknowledged, where the time threshold
+    // here waits a quarter of an RTT beyond one RTT from when it was sent
+    let mut without_rack = client_config();
+    without_rack.using_rack = false;
+    without_rack.time_reordering_fraction = 0x4000;
+    assert!(tail_loss_recovery(client_config(), 2) < tail_loss_recovery(without_rack, 2));
+}
+
+#[test]
+fn tail_loss_probe() {
+    // With no later packet to be acknowledged, the loss goes unnoticed until a probe elicits an ACK
+    assert!(tail_loss_recovery(client_config(), 1) > tail_loss_recovery(client_config(), 2));
+}
+
+/// Send a flight of 1-RTT packets from the client, the first of which is lost, returning that packet's number
+fn lose_first_of_flight(pair: &mut Pair, conn: ConnectionHandle) -> u64 {
+    let s = pair.client.open(conn, Directionality::Uni).unwrap();
+    let msg = vec![42; 8 * 1000];
+    assert_eq!(pair.client.write(conn, s, &msg), Ok(msg.len()));
+    let first = pair.client.connections[conn.0].largest_sent_packet + 1;
+    pair.drive_client();
+    assert!(pair.server.inbound.len() > 4);
+    pair.server.inbound.pop_front();
+    first
+}
+
+fn loss_detection_pair(mut client_config: Config) -> Pair {
+    client_config.using_rack = false;
+    // Keep MTU probes from joining the flight
+    client_config.mtu_discovery = false;
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    let mut pair = Pair::new(server_config, client_config);
+    pair.latency = 50 * 1000;
+    pair
+}
+
+#[test]
+fn packet_threshold_loss() {
+    // Wait nearly two RTTs before time threshold loss detection applies, so only the packet threshold can act sooner
+    let mut pair = loss_detection_pair(Config {
+        time_reordering_fraction: u16::max_value(),
+        ..client_config()
+    });
+    let (client_conn, _) = pair.connect();
+    let first = lose_first_of_flight(&mut pair, client_conn);
+    let start = pair.time;
+    let data = packet::SpaceId::Data as usize;
+    loop {
+        assert!(pair.step(), "went idle before loss was detected");
+        let conn = &pair.client.connections[client_conn.0];
+        if conn.largest_acked[data].map_or(false, |x| x >= first + 3) {
+            // An ACK for the third later packet suffices
+            assert!(!conn.sent_packets.contains_key(&first));
+            assert!(pair.time < start + 3 * pair.latency);
+            break;
+        }
+    }
+}
+
+#[test]
+fn time_threshold_loss() {
+    // Wait a whole extra RTT, so the deadline comes well after the first ACK
+    let mut pair = loss_detection_pair(Config {
+        reordering_threshold: u32::max_value(),
+        time_reordering_fraction: 0x8000,
+        ..client_config()
+    });
+    let (client_conn, _) = pair.connect();
+    let first = lose_first_of_flight(&mut pair, client_conn);
+    let data = packet::SpaceId::Data as usize;
+    while pair.client.connections[client_conn.0].largest_acked[data] <= Some(first) {
+        assert!(pair.step(), "went idle before an ACK arrived");
+    }
+    let deadline = {
+        let conn = &pair.client.connections[client_conn.0];
+        let sent = conn.sent_packets[&first].time;
+        let rtt = cmp::max(conn.latest_rtt, conn.smoothed_rtt);
+        assert_eq!(conn.loss_time[data], sent + rtt + rtt / 2);
+        conn.loss_time[data]
+    };
+    assert_eq!(pair.client.loss, deadline);
+
+    pair.time = deadline;
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(!conn.sent_packets.contains_key(&first));
+    assert!(
+        !pair.client.outbound.is_empty(),
+        "lost data wasn't retransmitted"
+    );
+}
+
+#[test]
+fn probe_timeout() {
+    let mut pair = loss_detection_pair(client_config());
+    let (client_conn, _) = pair.connect();
+    pair.partition(u64::max_value() / 2);
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    let mut interval = pair.client.connections[client_conn.0].pto(&pair.client.ctx.config);
+    assert_eq!(pair.client.loss, pair.time + interval);
+    for count in 1..4 {
+        pair.time = pair.client.loss;
+        pair.client.drive(&pair.log, pair.time, pair.server.addr);
+        // Two probes, which are PINGs as there's no new data to send, then a timeout twice as long
+        assert_eq!(pair.client.outbound.len(), 2);
+        pair.client.outbound.clear();
+        assert_eq!(pair.client.connections[client_conn.0].pto_count, count);
+        interval *= 2;
+        assert_eq!(pair.client.loss, pair.time + interval);
+    }
 }
 
-/*
 #[test]
 fn zero_rtt() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    let mut pair = Pair::new(server_config, client_config());
+    let (c, _) = pair.connect();
+    let ticket = match pair.client.poll() {
+        Some((conn, Event::NewSessionTicket { ticket })) if conn == c => ticket,
+        e => panic!("unexpected poll result: {:?}", e),
+    };
+    info!(pair.log, "closing"; "ticket size" => ticket.len());
+    pair.client.close(pair.time, c, 42, Bytes::new());
+    pair.drive();
+    info!(pair.log, "resuming");
+    let cc = pair
+        .client
+        .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+        .unwrap();
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(cc, s, MSG).unwrap();
+    // Deliver the client's first flight, but nothing further
+    pair.drive_client();
+    pair.drive_server();
+    let sc = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't accept 0-RTT");
+    };
+    assert_matches!(
+        pair.server.connections[sc.0].state,
+        Some(State::Handshake(_))
+    );
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == cc);
+    // Tickets can't be reused
+    let mut id = [0; crypto::SESSION_TICKET_ID_SIZE];
+    id.copy_from_slice(&ticket[..crypto::SESSION_TICKET_ID_SIZE]);
+    assert!(!pair.server.ctx.session_tickets.contains_key(&id));
+}
+
+#[test]
+fn zero_rtt_rejected() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    let mut pair = Pair::new(server_config, client_config());
+    let (c, _) = pair.connect();
+    let ticket = match pair.client.poll() {
+        Some((conn, Event::NewSessionTicket { ticket })) if conn == c => ticket,
+        e => panic!("unexpected poll result: {:?}", e),
+    };
+    pair.client.close(pair.time, c, 42, Bytes::new());
+    pair.drive();
+    // The server forgets the ticket
+    pair.server.ctx.session_tickets.clear();
+    let cc = pair
+        .client
+        .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+        .unwrap();
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(cc, s, MSG).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    assert_matches!(pair.server.accept(), None);
+    // The early data arrives once it's retransmitted after the handshake
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == cc);
+    let sc = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't connect");
+    };
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+}
+
+/// Connect once to obtain a session ticket, then close the connection
+fn ticketed_pair(server_config: Config) -> (Pair, Box<[u8]>) {
+    let mut pair = Pair::new(server_config, client_config());
+    let (c, _) = pair.connect();
+    let ticket = match pair.client.poll() {
+        Some((conn, Event::NewSessionTicket { ticket })) if conn == c => ticket,
+        e => panic!("unexpected poll result: {:?}", e),
+    };
+    pair.client.close(pair.time, c, 42, Bytes::new());
+    pair.drive();
+    while pair.server.poll().is_some() {}
+    while pair.client.poll().is_some() {}
+    (pair, ticket)
+}
+
+#[test]
+fn zero_rtt_stored_ticket() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    let (mut pair, ticket) = ticketed_pair(server_config);
+    let stored = pair
+        .client
+        .ctx
+        .config
+        .session_ticket_store
+        .lock()
+        .unwrap()
+        .load("localhost");
+    assert_eq!(stored.as_ref().map(|x| &x[..]), Some(&ticket[..]));
+    // A plain connect to the same server resumes the session
+    let cc = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    // and uses up the ticket
+    let stored = pair
+        .client
+        .ctx
+        .config
+        .session_ticket_store
+        .lock()
+        .unwrap()
+        .load("localhost");
+    assert!(stored.is_none());
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(cc, s, MSG).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    let sc = pair.server.accept().expect("server didn't accept 0-RTT");
+    assert_matches!(
+        pair.server.connections[sc.0].state,
+        Some(State::Handshake(_))
+    );
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == cc);
+}
+
+#[test]
+fn zero_rtt_limit() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    server_config.max_early_data = 8;
+    let (mut pair, ticket) = ticketed_pair(server_config);
+    let cc = pair
+        .client
+        .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+        .unwrap();
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    // Only as much as the server will accept is sent early
+    assert_eq!(pair.client.write(cc, s, MSG), Ok(8));
+    assert_matches!(
+        pair.client.write(cc, s, &MSG[8..]),
+        Err(WriteError::Blocked)
+    );
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == cc);
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamWritable { stream })) if conn == cc && stream == s);
+    assert_eq!(pair.client.write(cc, s, &MSG[8..]), Ok(MSG.len() - 8));
+    pair.drive();
+    let sc = pair.server.accept().expect("server didn't accept");
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn zero_rtt_excess() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    server_config.max_early_data = 8;
+    let (mut pair, ticket) = ticketed_pair(server_config);
+    let cc = pair
+        .client
+        .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+        .unwrap();
+    // Disregard the server's limit
+    pair.client.connections[cc.0].max_data = u64::max_value();
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    pair.client.write(cc, s, b"Hello, 0-RTT!").unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    let sc = pair.server.accept().expect("server didn't accept 0-RTT");
+    assert_matches!(
+        pair.server.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == sc && error_code == TransportError::PROTOCOL_VIOLATION
+    );
+}
+
+#[test]
+fn zero_rtt_off_path_challenge() {
+    let mut server_config = server_config();
+    server_config.max_session_tickets = 16;
+    let (mut pair, ticket) = ticketed_pair(server_config);
+    let cc = pair
+        .client
+        .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    let sc = pair.server.accept().expect("server didn't accept 0-RTT");
+
+    // A PATH_CHALLENGE in 0-RTT from another address, which can't be answered without 1-RTT keys
+    let packet = {
+        let conn = &mut pair.client.connections[cc.0];
+        let number = conn.get_tx_number();
+        let header = Header::Long {
+            version: Version::V1,
+            ty: types::ZERO_RTT,
+            source_id: conn.local_id.clone(),
+            destination_id: conn.remote_id.clone(),
+            number: PacketNumber::U32(number as u32),
+        };
+        protect(header, number, conn.zero_rtt_crypto.as_ref().unwrap(), |buf| {
+            buf.push(frame::Type::PATH_CHALLENGE.into());
+            buf.extend_from_slice(&[0x5a; 8]);
+        })
+    };
+    let other = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let time = pair.time;
+    pair.server.handle(time, other, None, (&packet[..]).into());
+    assert!(pair.server.connections[sc.0].off_path_frames.is_empty());
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == cc);
+}
+
+#[test]
+fn zero_rtt_replay_permitted() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 32;
+    server_config.max_session_tickets = 16;
+    server_config.zero_rtt_anti_replay = false;
+    let (mut pair, ticket) = ticketed_pair(server_config);
+    for _ in 0..2 {
+        let cc = pair
+            .client
+            .connect_with_ticket(pair.server.addr, "localhost", &ticket)
+            .unwrap();
+        let s = pair.client.open(cc, Directionality::Uni).unwrap();
+        const MSG: &[u8] = b"Hello, 0-RTT!";
+        pair.client.write(cc, s, MSG).unwrap();
+        pair.drive_client();
+        pair.drive_server();
+        // Accepted in 0-RTT both times
+        let sc = pair.server.accept().expect("server didn't accept 0-RTT");
+        assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+        pair.drive();
+    }
+}
+
+#[test]
+fn migration() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let old_addr = pair.client.addr;
+    let client_remote_id = pair.client.get_remote_id(client_conn).clone();
+    let server_remote_id = pair.server.get_remote_id(server_conn).clone();
+
+    // The client's address changes, e.g. on moving to a different network
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let server_addr = pair.server.addr;
+    pair.client.migrate(client_conn, server_addr);
+    assert_ne!(*pair.client.get_remote_id(client_conn), client_remote_id);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello from a new address";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+
+    let mut migrated = false;
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::PathMigrated { old, new } = event {
+            assert_eq!(conn, server_conn);
+            assert_eq!(old, old_addr);
+            assert_eq!(new, pair.client.addr);
+            migrated = true;
+        }
+    }
+    assert!(migrated);
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    assert_ne!(*pair.server.get_remote_id(server_conn), server_remote_id);
+    // Each side validated the new path with a PATH_CHALLENGE
+    assert!(pair.server.connections[server_conn.0].paths[&pair.client.addr].validated);
+    assert!(pair.client.connections[client_conn.0].paths[&server_addr].validated);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn path_validation() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let old_addr = pair.client.addr;
+
+    // A NAT rebinding changes the client's address without its knowledge
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let new_addr = pair.client.addr;
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    const MSG: &[u8] = &[0xab; 256];
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    {
+        // The server follows the client, but challenges the new path rather than trusting it
+        let path = &pair.server.connections[server_conn.0].paths[&new_addr];
+        assert!(!path.validated);
+        assert!(path.validation_deadline.is_some());
+        assert!(path.total_sent <= 3 * path.total_recvd);
+    }
+    pair.drive();
+
+    let mut migrated = false;
+    let mut validated = false;
+    while let Some((conn, event)) = pair.server.poll() {
+        assert_eq!(conn, server_conn);
+        match event {
+            Event::PathMigrated { old, new } => {
+                assert_eq!(old, old_addr);
+                assert_eq!(new, new_addr);
+                migrated = true;
+            }
+            Event::PathValidated { remote } => {
+                assert!(migrated);
+                assert_eq!(remote, new_addr);
+                validated = true;
+            }
+            Event::PathValidationFailed { .. } => panic!("path validation failed"),
+            _ => {}
+        }
+    }
+    assert!(validated);
+    assert!(pair.server.connections[server_conn.0].paths[&new_addr].validated);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    pair.server.write(server_conn, s, MSG).unwrap();
+    pair.drive();
+    assert_matches!(pair.client.read_unordered(client_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn path_validation_failure() {
     let mut pair = Pair::default();
-    let (c, _) = pair.connect();
-    let ticket = match pair.client.poll() {
-        Some((conn, Event::NewSessionTicket { ref ticket })) if conn == c => ticket.clone(),
-        e => panic!("unexpected poll result: {:?}", e),
+    let (client_conn, server_conn) = pair.connect();
+    let old_addr = pair.client.addr;
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let new_addr = pair.client.addr;
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+
+    // Nothing the server sends to the new address arrives
+    let mut failed = false;
+    for _ in 0..100 {
+        pair.drive_client();
+        pair.server.drive(&pair.log, pair.time, new_addr);
+        pair.server.outbound.clear();
+        while let Some((conn, event)) = pair.server.poll() {
+            assert_eq!(conn, server_conn);
+            if let Event::PathValidationFailed { remote } = event {
+                assert_eq!(remote, new_addr);
+                failed = true;
+            }
+        }
+        if failed {
+            break;
+        }
+        pair.time = pair.client.next_wakeup().min(pair.server.next_wakeup());
+    }
+    assert!(failed);
+    // The server fell back to the address the client was last seen to receive packets at
+    assert_eq!(*pair.server.get_remote_address(server_conn), old_addr);
+    assert!(!pair.server.connections[server_conn.0].paths[&new_addr].validated);
+}
+
+#[test]
+fn path_validation_failure_after_migration() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    // Migrate successfully once, so the server knows two validated addresses
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let second_addr = pair.client.addr;
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+    assert_eq!(*pair.server.get_remote_address(server_conn), second_addr);
+    assert!(pair.server.connections[server_conn.0].paths[&second_addr].validated);
+    while pair.server.poll().is_some() {}
+
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let third_addr = pair.client.addr;
+    pair.client.write(client_conn, s, b" world").unwrap();
+    let mut failed = false;
+    for _ in 0..100 {
+        pair.drive_client();
+        pair.server.drive(&pair.log, pair.time, third_addr);
+        pair.server.outbound.clear();
+        while let Some((_, event)) = pair.server.poll() {
+            if let Event::PathValidationFailed { remote } = event {
+                assert_eq!(remote, third_addr);
+                failed = true;
+            }
+        }
+        if failed {
+            break;
+        }
+        pair.time = pair.client.next_wakeup().min(pair.server.next_wakeup());
+    }
+    assert!(failed);
+    // Of the validated addresses, the server returns to the one it moved off, not the oldest
+    assert_eq!(*pair.server.get_remote_address(server_conn), second_addr);
+}
+
+/// First byte of every connection ID issued by `SequentialGenerator`
+const CID_MARKER: u8 = 0xab;
+
+/// Issues connection IDs consisting of a marker byte followed by a counter
+struct SequentialGenerator {
+    next: u32,
+    generated: Arc<AtomicUsize>,
+}
+
+impl ConnectionIdGenerator for SequentialGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        self.generated.fetch_add(1, Ordering::Relaxed);
+        let mut id = [CID_MARKER; 8];
+        BigEndian::write_u32(&mut id[4..], self.next);
+        self.next += 1;
+        ConnectionId::from_slice(&id).unwrap()
+    }
+
+    fn validate_cid(&self, cid: &ConnectionId) -> bool {
+        cid[0] == CID_MARKER
+    }
+}
+
+struct SequentialGeneratorFactory(Arc<AtomicUsize>);
+
+impl ConnectionIdGeneratorFactory for SequentialGeneratorFactory {
+    fn build(&self, _: &Config) -> Box<ConnectionIdGenerator> {
+        Box::new(SequentialGenerator {
+            next: 0,
+            generated: self.0.clone(),
+        })
+    }
+}
+
+#[test]
+fn custom_cid_generator() {
+    let generated = Arc::new(AtomicUsize::new(0));
+    let mut server_config = server_config();
+    server_config.connection_id_generator_factory =
+        Arc::new(SequentialGeneratorFactory(generated.clone()));
+    let mut pair = Pair::new(server_config, client_config());
+    let (client_conn, server_conn) = pair.connect();
+    // One ID for the handshake, and the spares issued once it completed
+    assert_eq!(generated.load(Ordering::Relaxed), ISSUED_CIDS + 1);
+    assert!(
+        pair.server.connections[server_conn.0]
+            .cid_pool
+            .iter()
+            .all(|x| x[0] == CID_MARKER)
+    );
+    assert_eq!(pair.client.get_remote_id(client_conn)[0], CID_MARKER);
+}
+
+/// Issues the same connection ID every time
+struct ConstantGeneratorFactory;
+
+impl ConnectionIdGeneratorFactory for ConstantGeneratorFactory {
+    fn build(&self, _: &Config) -> Box<ConnectionIdGenerator> {
+        Box::new(ConstantGenerator)
+    }
+}
+
+struct ConstantGenerator;
+
+impl ConnectionIdGenerator for ConstantGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        ConnectionId::from_slice(&[CID_MARKER; 8]).unwrap()
+    }
+}
+
+#[test]
+fn cid_collision() {
+    let mut server_config = server_config();
+    server_config.connection_id_generator_factory = Arc::new(ConstantGeneratorFactory);
+    let mut pair = Pair::new(server_config, client_config());
+    let (_, server_conn) = pair.connect();
+    // No spares could be issued without reusing the ID chosen during the handshake
+    assert_eq!(
+        pair.server.connections[server_conn.0]
+            .cid_pool
+            .iter()
+            .count(),
+        1
+    );
+
+    // Nor can another connection be given an ID of its own
+    let client_conn = pair.client.connect(pair.server.addr, "localhost").unwrap();
+    pair.drive();
+    assert_matches!(pair.server.accept(), None);
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == client_conn && error_code == TransportError::SERVER_BUSY
+    );
+}
+
+#[test]
+fn invalid_cid_not_reset() {
+    let log = logger();
+    let client_addr = "[::2]:7890".parse().unwrap();
+    let mut config = server_config();
+    config.connection_id_generator_factory =
+        Arc::new(SequentialGeneratorFactory(Arc::new(AtomicUsize::new(0))));
+    let mut server =
+        Endpoint::new(log.new(o!("peer" => "server")), config, Some(*LISTEN_KEYS)).unwrap();
+    // Short header packets for unknown connections
+    let mut packet = vec![0x40];
+    packet.extend_from_slice(&[CID_MARKER; 8]);
+    packet.extend_from_slice(&[0; 32]);
+    server.handle(0, client_addr, None, packet[..].into());
+    // Might be addressed to a connection we've forgotten
+    assert_matches!(server.poll_io(0), Some(Io::Transmit { .. }));
+    packet[1] = !CID_MARKER;
+    server.handle(0, client_addr, None, packet[..].into());
+    assert_matches!(server.poll_io(0), None);
+}
+
+/// Issues connection IDs whose first byte identifies the server, as a load balancer might require
+struct ShardGeneratorFactory(u8);
+
+impl ConnectionIdGeneratorFactory for ShardGeneratorFactory {
+    fn build(&self, _: &Config) -> Box<ConnectionIdGenerator> {
+        Box::new(ShardGenerator(self.0))
+    }
+}
+
+struct ShardGenerator(u8);
+
+impl ConnectionIdGenerator for ShardGenerator {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut id = ConnectionId::random(&mut rand::thread_rng(), 8);
+        id[0] = self.0;
+        id
+    }
+}
+
+/// Config for a server whose connection IDs start with `shard`, ignoring packets for any other
+fn shard_config(shard: u8) -> Config {
+    let mut config = server_config();
+    config.connection_id_generator_factory = Arc::new(ShardGeneratorFactory(shard));
+    config.connection_id_filter = Some(Arc::new(move |cid: &[u8]| cid[0] == shard));
+    config
+}
+
+#[test]
+fn connection_id_filter() {
+    let mut pair = Pair::new(shard_config(0xa0), client_config());
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.get_remote_id(client_conn)[0], 0xa0);
+    let log = pair.log.new(o!("peer" => "other server"));
+    let mut other = Endpoint::new(log, shard_config(0xb0), Some(*LISTEN_KEYS)).unwrap();
+
+    // Deliver the client's packets to both servers, as if the load balancer didn't route by connection ID
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.client.drive(&pair.log, pair.time, pair.server.addr);
+    let mut sent = 0;
+    for (ecn, packet) in pair.client.outbound.drain(..) {
+        other.handle(pair.time, pair.client.addr, ecn, packet[..].into());
+        pair.server.inbound.push_back((pair.time, ecn, packet));
+        sent += 1;
+    }
+    assert!(sent > 0);
+    // The other server neither created a connection nor reset this one
+    assert_matches!(other.poll_io(pair.time), None);
+    assert_eq!(other.stats().filtered_packets, sent);
+    pair.drive();
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if &data[..] == b"hello");
+    assert_eq!(pair.server.stats().filtered_packets, 0);
+}
+
+#[test]
+fn cid_retirement() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let old_id = pair.client.get_remote_id(client_conn).clone();
+    assert_eq!(
+        pair.server.connections[server_conn.0]
+            .cid_pool
+            .iter()
+            .count(),
+        ISSUED_CIDS + 1
+    );
+    assert_eq!(
+        pair.client.connections[client_conn.0].remote_cids.len(),
+        ISSUED_CIDS
+    );
+
+    pair.client.addr = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        CLIENT_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let server_addr = pair.server.addr;
+    pair.client.migrate(client_conn, server_addr);
+    pair.drive();
+
+    // The server forgot the ID the client stopped using, and issued a replacement
+    let server_cids = pair.server.connections[server_conn.0]
+        .cid_pool
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(server_cids.len(), ISSUED_CIDS + 1);
+    assert!(!server_cids.contains(&old_id));
+    assert!(server_cids.contains(pair.client.get_remote_id(client_conn)));
+    assert_eq!(
+        pair.client.connections[client_conn.0].remote_cids.len(),
+        ISSUED_CIDS
+    );
+}
+
+#[test]
+fn cid_retire_in_use() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Retire the ID the client's packets are still addressed to
+    {
+        let conn = &mut pair.client.connections[client_conn.0];
+        let sequence = conn.remote_cid_sequence;
+        conn.pending.retire_cids.push(sequence);
+    }
+    pair.drive_client();
+    pair.drive_server();
+    assert_matches!(
+        pair.server.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == server_conn && error_code == TransportError::PROTOCOL_VIOLATION
+    );
+}
+
+#[test]
+fn cid_exhaustion() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Migrate more often than there are connection IDs issued at any one time
+    for i in 0..2 * (ISSUED_CIDS + 1) {
+        let old_id = pair.client.get_remote_id(client_conn).clone();
+        pair.client.addr = SocketAddrV6::new(
+            Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+            CLIENT_PORTS.lock().unwrap().next().unwrap(),
+            0,
+            0,
+        );
+        let server_addr = pair.server.addr;
+        pair.client.migrate(client_conn, server_addr);
+        assert_ne!(*pair.client.get_remote_id(client_conn), old_id);
+        let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+        let msg = format!("hello from address {}", i);
+        pair.client.write(client_conn, s, msg.as_bytes()).unwrap();
+        pair.drive();
+        assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if &data[..] == msg.as_bytes());
+    }
+}
+
+#[test]
+fn cid_rotation() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let old_cids = pair.server.connections[server_conn.0]
+        .cid_pool
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    // Data sent before the client learns of the new IDs is still routed by the old one
+    pair.server.rotate_cids(server_conn);
+    pair.client.write(client_conn, s, b" world").unwrap();
+    pair.drive();
+    pair.client.write(client_conn, s, b"!").unwrap();
+    pair.client.finish(client_conn, s);
+    pair.drive();
+
+    let mut buf = [0; 12];
+    let mut n = 0;
+    while n < buf.len() {
+        n += pair.server.read(server_conn, s, &mut buf[n..]).unwrap();
+    }
+    assert_eq!(&buf, b"hello world!");
+
+    // The client moved to a replacement and retired every old ID, leaving the server a full set of new ones
+    let new_cids = pair.server.connections[server_conn.0]
+        .cid_pool
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(new_cids.len(), ISSUED_CIDS + 1);
+    assert!(new_cids.iter().all(|x| !old_cids.contains(x)));
+    assert!(new_cids.contains(pair.client.get_remote_id(client_conn)));
+    assert_eq!(
+        pair.client.connections[client_conn.0].remote_cids.len(),
+        ISSUED_CIDS
+    );
+}
+
+/// A sink whose contents remain readable after it's handed to a `QlogWriter`
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn qlog() {
+    let sink = SharedBuf::default();
+    let qlog = QlogWriter::new(Box::new(sink.clone()), "qlog test", Side::Client).unwrap();
+    let log = logger();
+    let server_log = log.new(o!("side" => "Server"));
+    let server = Endpoint::new(server_log, server_config(), Some(*LISTEN_KEYS)).unwrap();
+    let client = Endpoint::new(log.new(o!("side" => "Client")), client_config(), None)
+        .unwrap()
+        .with_qlog(Arc::new(Mutex::new(qlog)));
+    let mut pair = Pair::with_endpoints(log, server, client);
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+    let now = pair.time;
+    pair.client.close(now, client_conn, 0, Bytes::new());
+    pair.drive();
+
+    let output = sink.0.lock().unwrap();
+    let records = str::from_utf8(&output)
+        .unwrap()
+        .split('\x1e')
+        .skip(1)
+        .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records[0]["qlog_format"], "JSON-SEQ");
+    assert_eq!(records[0]["trace"]["vantage_point"]["type"], "client");
+    let events = &records[1..];
+    let names = events
+        .iter()
+        .map(|x| x["name"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    let first = |name: &str| {
+        names
+            .iter()
+            .position(|&x| x == name)
+            .unwrap_or_else(|| panic!("no {} event", name))
     };
-    info!(pair.log, "closing"; "ticket size" => ticket.len());
-    pair.client.close(pair.time, c, 42, (&[][..]).into());
+
+    // The connection starts by sending the ClientHello
+    assert_eq!(names[0], "connectivity:connection_started");
+    assert_eq!(names[1], "transport:packet_sent");
+    assert_eq!(events[1]["data"]["packet_type"], "initial");
+    assert!(first("transport:packet_received") < first("recovery:metrics_updated"));
+    let states = events
+        .iter()
+        .filter(|x| x["name"] == "connectivity:connection_state_updated")
+        .map(|x| x["data"]["new"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(states, ["handshake_complete", "closing", "closed"]);
+    // Stream data shows up in the frames of the packets that carried it
+    let stream_sent = events
+        .iter()
+        .filter(|x| x["name"] == "transport:packet_sent")
+        .flat_map(|x| x["data"]["frames"].as_array().unwrap())
+        .any(|f| f["frame_type"] == "stream" && f["stream_id"] == s.0 && f["length"] == 5);
+    assert!(stream_sent);
+
+    // Every event belongs to the one connection, and is timestamped by the endpoint's clock
+    let group = &events[0]["group_id"];
+    assert!(events.iter().all(|x| x["group_id"] == *group));
+    let times = events
+        .iter()
+        .map(|x| x["time"].as_f64().unwrap())
+        .collect::<Vec<_>>();
+    assert!(times.windows(2).all(|x| x[0] <= x[1]));
+}
+
+#[test]
+fn mtu_discovery() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Nothing between the endpoints limits datagram size, so the largest probe succeeds
+    let max = pair.client.ctx.config.max_mtu;
+    assert_eq!(pair.client.connections[client_conn.0].mtu(), max);
+    assert_eq!(pair.server.connections[server_conn.0].mtu(), max);
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+
+    // Reports that don't quote one of the connection's packets could come from anyone
+    let server_addr = pair.server.addr;
+    let mut quoted = vec![0x40];
+    quoted.extend_from_slice(&[0xAB; 8]);
+    pair.client.handle_icmp_ptb(server_addr, 1300, &quoted);
+    assert_eq!(pair.client.connections[client_conn.0].mtu(), max);
+
+    // A router on the path reports that it can't forward datagrams that large
+    let mut quoted = vec![0x40];
+    quoted.extend_from_slice(&pair.client.connections[client_conn.0].remote_id);
+    pair.client.handle_icmp_ptb(server_addr, 1300, &quoted);
+    assert_eq!(pair.client.connections[client_conn.0].mtu(), 1300);
+    // Implausibly small reports go no lower than every path must support
+    pair.client.handle_icmp_ptb(server_addr, 500, &quoted);
+    assert_eq!(pair.client.connections[client_conn.0].mtu(), MIN_MTU);
+}
+
+#[test]
+fn mtu_discovery_disabled() {
+    let mut client_config = client_config();
+    client_config.mtu_discovery = false;
+    let mut pair = Pair::new(server_config(), client_config);
+    let (client_conn, _) = pair.connect();
+    assert_eq!(pair.client.connections[client_conn.0].mtu(), MIN_MTU);
+}
+
+#[test]
+fn preferred_address() {
+    let mut server_config = server_config();
+    let preferred = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    server_config.preferred_address_v6 = Some(preferred);
+    let mut pair = Pair::new(server_config, client_config());
+    let server_addr = pair.server.addr;
+    let (client_conn, server_conn) = pair.connect();
+
+    let mut migrated = false;
+    while let Some((conn, event)) = pair.client.poll() {
+        if let Event::PathMigrated { old, new } = event {
+            assert_eq!(conn, client_conn);
+            assert_eq!(old, server_addr);
+            assert_eq!(new, preferred);
+            migrated = true;
+        }
+    }
+    assert!(migrated);
+    assert_eq!(*pair.client.get_remote_address(client_conn), preferred);
+    assert!(pair.client.connections[client_conn.0].paths[&preferred].validated);
+    // The client switched to the connection ID issued for the preferred address
+    assert_eq!(
+        *pair.client.get_remote_id(client_conn),
+        *pair.server.connections[server_conn.0]
+            .cid_pool
+            .iter()
+            .nth(1)
+            .unwrap()
+    );
+
+    // The connection is still usable
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello from the preferred address";
+    pair.client.write(client_conn, s, MSG).unwrap();
     pair.drive();
-    info!(pair.log, "resuming");
-    let cc = pair
-        .client
-        .connect(
-            pair.server.addr,
-            "localhost",
-        )
-        .unwrap();
-    let s = pair.client.open(cc, Directionality::Uni).unwrap();
-    const MSG: &[u8] = b"Hello, 0-RTT!";
-    pair.client.write(cc, s, MSG).unwrap();
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn preferred_address_ignored() {
+    let mut server_config = server_config();
+    server_config.preferred_address_v6 = Some(SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    ));
+    let mut client_config = client_config();
+    client_config.use_preferred_address = false;
+    let mut pair = Pair::new(server_config, client_config);
+    let server_addr = pair.server.addr;
+    let (client_conn, _) = pair.connect();
+    assert_eq!(*pair.client.get_remote_address(client_conn), server_addr);
+    assert_eq!(pair.client.connections[client_conn.0].paths.len(), 1);
+}
+
+fn multipath_pair(server_policy: Option<MultipathPolicy>) -> Pair {
+    let mut server_config = server_config();
+    server_config.multipath_policy = server_policy;
+    let mut client_config = client_config();
+    client_config.multipath_policy = Some(MultipathPolicy::RoundRobin);
+    Pair::new(server_config, client_config)
+}
+
+#[test]
+fn multipath_round_robin() {
+    let mut pair = multipath_pair(Some(MultipathPolicy::RoundRobin));
+    let server_addr = pair.server.addr;
+    let (client_conn, server_conn) = pair.connect();
+    let extra = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let id = pair.client.add_path(client_conn, extra).unwrap();
+    assert_ne!(id, PathId(0));
+    assert_eq!(
+        pair.client.add_path(client_conn, extra),
+        Err(AddPathError::AlreadyOpen)
+    );
     pair.drive();
-    assert!(pair.client.get_session_resumed(c));
-    let sc = if let Some(c) = pair.server.accept() {
-        c
-    } else {
-        panic!("server didn't connect");
+    assert!(pair.client.connections[client_conn.0].paths[&extra].validated);
+
+    let sent = |pair: &Pair, addr: SocketAddrV6| {
+        pair.client.connections[client_conn.0].paths[&addr].total_sent
     };
-    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+    let (active_before, extra_before) = (sent(&pair, server_addr), sent(&pair, extra));
+    transfer(&mut pair, client_conn, server_conn, 32 * 1024);
+    // Traffic was spread over both paths, while the active one stayed put
+    assert!(sent(&pair, server_addr) > active_before);
+    assert!(sent(&pair, extra) > extra_before);
+    assert_eq!(*pair.client.get_remote_address(client_conn), server_addr);
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+}
+
+#[test]
+fn multipath_fail_over() {
+    let mut server_config = server_config();
+    server_config.multipath_policy = Some(MultipathPolicy::ActiveStandby);
+    let mut client_config = client_config();
+    client_config.multipath_policy = Some(MultipathPolicy::ActiveStandby);
+    let mut pair = Pair::new(server_config, client_config);
+    let server_addr = pair.server.addr;
+    let (client_conn, server_conn) = pair.connect();
+    let standby = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    pair.client.add_path(client_conn, standby).unwrap();
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].paths[&standby].validated);
+    // Only the active path is used while it works
+    assert_eq!(*pair.client.get_remote_address(client_conn), server_addr);
+    while pair.client.poll().is_some() {}
+
+    // The active path stops answering
+    pair.client.unreachable = Some(server_addr);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    let mut failed_over = false;
+    while let Some((conn, event)) = pair.client.poll() {
+        if let Event::PathMigrated { old, new } = event {
+            assert_eq!(conn, client_conn);
+            assert_eq!(old, server_addr);
+            assert_eq!(new, standby);
+            failed_over = true;
+        }
+    }
+    assert!(failed_over);
+    assert_eq!(*pair.client.get_remote_address(client_conn), standby);
+    // The old path is kept as a standby in turn
+    assert!(pair.client.connections[client_conn.0].paths[&server_addr].extra);
+    // Traffic got through on the standby
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if &data[..] == b"hello");
+}
+
+#[test]
+fn multipath_unsupported() {
+    let extra = SocketAddrV6::new(
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1),
+        SERVER_PORTS.lock().unwrap().next().unwrap(),
+        0,
+        0,
+    );
+    let mut pair = multipath_pair(None);
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.add_path(client_conn, extra),
+        Err(AddPathError::UnsupportedByPeer)
+    );
+    assert_eq!(
+        pair.server.add_path(server_conn, extra),
+        Err(AddPathError::Disabled)
+    );
+}
+
+#[test]
+fn packet_number_gap_too_large() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    while pair.client.poll().is_some() {}
+    // Pretend the server stopped acknowledging packets so long ago that the next one can't be encoded
+    pair.client.connections[client_conn.0].largest_sent_packet += 2u64.pow(31);
+    pair.client.ping(client_conn);
+    pair.drive();
+    // Only the affected connection is lost
+    assert_matches!(
+        pair.client.poll(),
+        Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError { error_code } }))
+            if conn == client_conn && error_code == TransportError::INTERNAL_ERROR
+    );
+    let state = pair.client.connections[client_conn.0].state.as_ref();
+    assert!(state.unwrap().is_drained());
 }
-*/
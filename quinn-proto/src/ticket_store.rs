@@ -0,0 +1,44 @@
+//! Storage for the session tickets clients receive, so later connections to the same server can resume with 0-RTT
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+/// Remembers session tickets by the name of the server that issued them
+///
+/// Each ticket is in the format accepted by `Endpoint::connect_with_ticket`, and includes the transport parameters of
+/// the connection that obtained it. Those parameters bound what a resumed connection sends before its handshake
+/// completes.
+pub trait SessionTicketStore: Send {
+    /// Record `ticket`, replacing any saved earlier for `server_name`
+    fn save(&mut self, server_name: &str, ticket: Bytes);
+
+    /// The most recent ticket saved for `server_name`, if any
+    fn load(&self, server_name: &str) -> Option<Bytes>;
+
+    /// Remove and return the most recent ticket saved for `server_name`, if any
+    ///
+    /// Used when resuming a connection, since reusing a ticket lets observers link the connections and gives an
+    /// attacker another chance to replay 0-RTT data.
+    fn take(&mut self, server_name: &str) -> Option<Bytes>;
+}
+
+/// Keeps the latest ticket from each server in memory
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryTicketStore {
+    tickets: HashMap<String, Bytes>,
+}
+
+impl SessionTicketStore for InMemoryTicketStore {
+    fn save(&mut self, server_name: &str, ticket: Bytes) {
+        self.tickets.insert(server_name.into(), ticket);
+    }
+
+    fn load(&self, server_name: &str) -> Option<Bytes> {
+        self.tickets.get(server_name).cloned()
+    }
+
+    fn take(&mut self, server_name: &str) -> Option<Bytes> {
+        self.tickets.remove(server_name)
+    }
+}
@@ -0,0 +1,33 @@
+//! Storage for the address validation tokens clients receive, so later connections to the same server can skip Retry
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+/// Remembers tokens from NEW_TOKEN frames by the name of the server that issued them
+///
+/// A server that requires address validation accepts such a token in place of a round trip spent on Retry, if it's
+/// presented from the same IP address before it expires.
+pub trait TokenStore: Send {
+    /// Record `token`, replacing any saved earlier for `server_name`
+    fn save(&mut self, server_name: &str, token: Bytes);
+
+    /// The most recent token saved for `server_name`, if any
+    fn load(&self, server_name: &str) -> Option<Bytes>;
+}
+
+/// Keeps the latest token from each server in memory
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryTokenStore {
+    tokens: HashMap<String, Bytes>,
+}
+
+impl TokenStore for InMemoryTokenStore {
+    fn save(&mut self, server_name: &str, token: Bytes) {
+        self.tokens.insert(server_name.into(), token);
+    }
+
+    fn load(&self, server_name: &str) -> Option<Bytes> {
+        self.tokens.get(server_name).cloned()
+    }
+}
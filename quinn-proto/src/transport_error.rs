@@ -12,6 +12,15 @@ impl Error {
     pub fn frame(ty: frame::Type) -> Self {
         Error(0x100 | u8::from(ty) as u16)
     }
+
+    /// The type of frame that caused this error, if it was made by `frame`
+    pub fn frame_type(self) -> Option<frame::Type> {
+        if self.0 >= 0x100 && self.0 <= 0x1ff {
+            Some(frame::Type::from(self.0 as u8))
+        } else {
+            None
+        }
+    }
 }
 
 impl coding::Value for Error {
@@ -62,6 +71,7 @@ errors!{
     VERSION_NEGOTIATION_ERROR(0x9) "an endpoint received transport parameters that contained version negotiation parameters that disagreed with the version negotiation that it performed, constituting a potential version downgrade attack";
     PROTOCOL_VIOLATION(0xA) "an endpoint detected an error with protocol compliance that was not covered by more specific error codes";
     UNSOLICITED_PATH_RESPONSE(0xB) "an endpoint received a PATH_RESPONSE frame that did not correspond to any PATH_CHALLENGE frame that it previously sent";
+    KEY_UPDATE_ERROR(0xE) "an endpoint detected errors in performing key updates";
 
     TLS_HANDSHAKE_FAILED(0x201) "the TLS handshake failed";
     TLS_FATAL_ALERT_GENERATED(0x202) "a TLS fatal alert was sent, causing the TLS connection to end prematurely";
@@ -1,51 +1,206 @@
-use bytes::{Buf, BufMut};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use bytes::{Buf, BufMut, Bytes};
 
 use coding::{BufExt, BufMutExt};
+use connection::MAX_REMOTE_CIDS;
 use endpoint::Config;
-use {Side, VERSION};
+use packet::ConnectionId;
+use {Side, Version, MAX_CID_SIZE, MIN_CID_SIZE, RESET_TOKEN_SIZE};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TransportParameters {
+    /// The version the client first attempted to connect with, or the version the server negotiated
+    ///
+    /// Reflecting these through the authenticated handshake lets each side detect a forged Version Negotiation packet.
+    pub version: u32,
+    /// Versions the server supports, in order of preference
+    pub supported_versions: Vec<u32>,
     pub initial_max_stream_data: u32,
     pub initial_max_data: u32,
-    pub idle_timeout: u16,
+    /// Longest the sender of these parameters will let the connection sit idle (ms), or 0 for no limit
+    pub max_idle_timeout: u32,
     pub stateless_reset_token: Option<[u8; 16]>,
     pub initial_max_streams_bidi: u16,
     pub initial_max_streams_uni: u16,
     pub max_packet_size: Option<u16>,
     pub ack_delay_exponent: u8,
+    /// Longest the sender of these parameters will delay acknowledging an ack-eliciting packet (ms)
+    pub max_ack_delay: u16,
+    /// Largest DATAGRAM frame the sender of these parameters is willing to receive, if any
+    pub max_datagram_frame_size: Option<u16>,
+    /// Whether the server will accept 0-RTT packets from a later connection resuming this one
+    ///
+    /// This and the next two are quinn's own parameters (0x0021, 0x0023 and 0x0022), not QUIC's, for its private 0-RTT
+    /// scheme; other implementations will ignore them.
+    pub issues_tickets: bool,
+    /// Bytes of stream data the server will accept in 0-RTT packets from a later connection resuming this one
+    pub max_early_data: u32,
+    /// Identifies the session ticket whose keys protect the client's 0-RTT packets, if any
+    pub resumption_ticket: Option<[u8; 16]>,
+    /// Address the server would like the client to migrate to once the handshake completes
+    pub preferred_address: Option<PreferredAddress>,
+    /// Number of connection IDs from the peer that the sender of these parameters is willing to store, including the
+    /// one in use
+    pub active_connection_id_limit: u16,
+    /// Shortest delay the sender of these parameters can delay acknowledgements by (μs), if it supports being asked to
+    /// acknowledge less often with ACK_FREQUENCY frames
+    pub min_ack_delay: Option<u32>,
+    /// Whether the sender of these parameters can use several paths at once
+    pub enable_multipath: bool,
+    /// Whether the sender of these parameters accepts packets with the QUIC bit cleared (RFC 9287)
+    pub grease_quic_bit: bool,
+    /// Parameters not defined by QUIC itself, e.g. by an application protocol, by ID
+    pub custom: HashMap<u64, Bytes>,
 }
 
 impl TransportParameters {
     pub fn new(config: &Config) -> Self {
         TransportParameters {
+            version: config.versions[0].number(),
+            supported_versions: config.versions.iter().map(|x| x.number()).collect(),
             initial_max_streams_bidi: config.max_remote_bi_streams,
             initial_max_streams_uni: config.max_remote_uni_streams,
             initial_max_data: config.receive_window,
             initial_max_stream_data: config.stream_receive_window,
+            max_idle_timeout: ((config.max_idle_timeout + 999) / 1000) as u32,
+            max_datagram_frame_size: config.max_datagram_frame_size,
+            active_connection_id_limit: MAX_REMOTE_CIDS as u16 + 1,
+            min_ack_delay: config.min_ack_delay,
+            ack_delay_exponent: config.ack_delay_exponent,
+            // Round up, so the peer never mistakes an ACK we delayed as long as we're allowed to for a lost one
+            max_ack_delay: ((config.max_ack_delay + 999) / 1000) as u16,
+            enable_multipath: config.multipath_policy.is_some(),
+            grease_quic_bit: config.grease_quic_bit,
+            custom: config.custom_transport_parameters.clone(),
             ..Default::default()
         }
     }
 }
 
+/// Smallest ID in the range reserved for private use, to which custom parameters are confined so they can't collide
+/// with parameters defined by QUIC
+pub const MIN_CUSTOM_PARAMETER: u64 = 0xff00;
+/// Largest ID a parameter can have
+pub const MAX_CUSTOM_PARAMETER: u64 = 0xffff;
+/// Most bytes custom parameters can take up between them, leaving QUIC's own parameters room in the 65535 bytes the
+/// parameter list is limited to
+pub const MAX_CUSTOM_PARAMETERS_LEN: usize = 0xffff - 1024;
+
+/// Bytes `custom` parameters take up when written, including each one's ID and length
+pub fn custom_params_len(custom: &HashMap<u64, Bytes>) -> usize {
+    custom.values().map(|x| 4 + x.len()).sum()
+}
+
+/// Most versions a server can list alongside the reserved one, in the 252 bytes clients accept
+pub const MAX_SUPPORTED_VERSIONS: usize = 62;
+
 const DEFAULT_ACK_DELAY_EXPONENT: u8 = 3;
+const DEFAULT_MAX_ACK_DELAY: u16 = 25;
+/// Values of `max_ack_delay` from here up are invalid
+pub const MAX_ACK_DELAY_LIMIT: u16 = 1 << 14;
+/// Version listed by servers alongside those they support, to keep clients from choking on unknown versions
+const RESERVED_VERSION: u32 = 0x0a1a_2a3a;
+const DEFAULT_ACTIVE_CONNECTION_ID_LIMIT: u16 = 2;
 
 impl Default for TransportParameters {
     fn default() -> Self {
         Self {
+            version: Version::V1.number(),
+            supported_versions: vec![Version::V1.number()],
             // TODO: Sanity check all
             initial_max_stream_data: 64 * 1024,
             initial_max_data: 64 * 1024,
-            idle_timeout: 10,
+            max_idle_timeout: 0,
             stateless_reset_token: None,
             initial_max_streams_bidi: 0,
             initial_max_streams_uni: 0,
             max_packet_size: None,
             ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            max_ack_delay: DEFAULT_MAX_ACK_DELAY,
+            max_datagram_frame_size: None,
+            issues_tickets: false,
+            max_early_data: 0,
+            resumption_ticket: None,
+            preferred_address: None,
+            active_connection_id_limit: DEFAULT_ACTIVE_CONNECTION_ID_LIMIT,
+            min_ack_delay: None,
+            enable_multipath: false,
+            grease_quic_bit: false,
+            custom: HashMap::new(),
         }
     }
 }
 
+/// An alternative server address, and the connection ID and stateless reset token for use with it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PreferredAddress {
+    pub address_v4: Option<SocketAddrV4>,
+    pub address_v6: Option<SocketAddrV6>,
+    pub connection_id: ConnectionId,
+    pub stateless_reset_token: [u8; RESET_TOKEN_SIZE],
+}
+
+/// Encoded size of a `PreferredAddress`, less its connection ID
+const PREFERRED_ADDRESS_BASE_LEN: usize = 4 + 2 + 16 + 2 + 1 + RESET_TOKEN_SIZE;
+
+impl PreferredAddress {
+    fn write<W: BufMut>(&self, w: &mut W) {
+        // Absent addresses are encoded as all zeroes
+        let v4 = self
+            .address_v4
+            .unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+        w.put_slice(&v4.ip().octets());
+        w.write::<u16>(v4.port());
+        let v6 = self
+            .address_v6
+            .unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0, 0, 0));
+        w.put_slice(&v6.ip().octets());
+        w.write::<u16>(v6.port());
+        w.write::<u8>(self.connection_id.len() as u8);
+        w.put_slice(&self.connection_id);
+        w.put_slice(&self.stateless_reset_token);
+    }
+
+    fn read<R: Buf>(r: &mut R, len: usize) -> Result<Self, Error> {
+        if len < PREFERRED_ADDRESS_BASE_LEN {
+            return Err(Error::Malformed);
+        }
+        let mut ip_v4 = [0; 4];
+        r.copy_to_slice(&mut ip_v4);
+        let address_v4 = SocketAddrV4::new(ip_v4.into(), r.get::<u16>().unwrap());
+        let mut ip_v6 = [0; 16];
+        r.copy_to_slice(&mut ip_v6);
+        let address_v6 = SocketAddrV6::new(ip_v6.into(), r.get::<u16>().unwrap(), 0, 0);
+        let cid_len = r.get::<u8>().unwrap() as usize;
+        if cid_len < MIN_CID_SIZE
+            || cid_len > MAX_CID_SIZE
+            || len != PREFERRED_ADDRESS_BASE_LEN + cid_len
+        {
+            return Err(Error::Malformed);
+        }
+        let mut stage = [0; MAX_CID_SIZE];
+        r.copy_to_slice(&mut stage[0..cid_len]);
+        let mut stateless_reset_token = [0; RESET_TOKEN_SIZE];
+        r.copy_to_slice(&mut stateless_reset_token);
+        Ok(Self {
+            address_v4: if address_v4.ip().is_unspecified() && address_v4.port() == 0 {
+                None
+            } else {
+                Some(address_v4)
+            },
+            address_v6: if address_v6.ip().is_unspecified() && address_v6.port() == 0 {
+                None
+            } else {
+                Some(address_v6)
+            },
+            connection_id: ConnectionId::new(stage, cid_len),
+            stateless_reset_token,
+        })
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Fail)]
 pub enum Error {
     #[fail(display = "version negotiation was tampered with")]
@@ -58,13 +213,19 @@ pub enum Error {
 
 impl TransportParameters {
     pub fn write<W: BufMut>(&self, side: Side, w: &mut W) {
+        w.write::<u32>(self.version); // Negotiated or initially requested version
         if side == Side::Server {
-            w.write::<u32>(VERSION); // Negotiated version
-            w.write::<u8>(8); // Bytes of supported versions
-            w.write::<u32>(0x0a1a_2a3a); // Reserved version
-            w.write::<u32>(VERSION); // Real supported version
-        } else {
-            w.write::<u32>(VERSION); // Initially requested version
+            // Endpoint::new rejects configurations listing more
+            assert!(
+                self.supported_versions.len() <= MAX_SUPPORTED_VERSIONS,
+                "too many supported versions"
+            );
+            let supported_bytes = 4 * (1 + self.supported_versions.len());
+            w.write::<u8>(supported_bytes as u8); // Bytes of supported versions
+            w.write::<u32>(RESERVED_VERSION);
+            for &x in &self.supported_versions {
+                w.write::<u32>(x);
+            }
         }
 
         let mut buf = Vec::with_capacity(22);
@@ -77,9 +238,11 @@ impl TransportParameters {
         buf.write::<u16>(4);
         buf.write::<u32>(self.initial_max_data);
 
-        buf.write::<u16>(0x0003);
-        buf.write::<u16>(2);
-        buf.write::<u16>(self.idle_timeout);
+        if self.max_idle_timeout != 0 {
+            buf.write::<u16>(0x0003);
+            buf.write::<u16>(4);
+            buf.write::<u32>(self.max_idle_timeout);
+        }
 
         if let Some(ref x) = self.stateless_reset_token {
             buf.write::<u16>(0x0006);
@@ -111,45 +274,124 @@ impl TransportParameters {
             buf.write::<u8>(self.ack_delay_exponent);
         }
 
+        if self.max_ack_delay != DEFAULT_MAX_ACK_DELAY {
+            buf.write::<u16>(0x000b);
+            buf.write::<u16>(2);
+            buf.write::<u16>(self.max_ack_delay);
+        }
+
+        if let Some(x) = self.max_datagram_frame_size {
+            buf.write::<u16>(0x0020);
+            buf.write::<u16>(2);
+            buf.write::<u16>(x);
+        }
+
+        if self.issues_tickets {
+            buf.write::<u16>(0x0021);
+            buf.write::<u16>(0);
+        }
+
+        if self.max_early_data != 0 {
+            buf.write::<u16>(0x0023);
+            buf.write::<u16>(4);
+            buf.write::<u32>(self.max_early_data);
+        }
+
+        if let Some(ref x) = self.resumption_ticket {
+            buf.write::<u16>(0x0022);
+            buf.write::<u16>(16);
+            buf.put_slice(x);
+        }
+
+        if let Some(ref x) = self.preferred_address {
+            buf.write::<u16>(0x0004);
+            buf.write::<u16>((PREFERRED_ADDRESS_BASE_LEN + x.connection_id.len()) as u16);
+            x.write(&mut buf);
+        }
+
+        if self.active_connection_id_limit != DEFAULT_ACTIVE_CONNECTION_ID_LIMIT {
+            buf.write::<u16>(0x000e);
+            buf.write::<u16>(2);
+            buf.write::<u16>(self.active_connection_id_limit);
+        }
+
+        if let Some(x) = self.min_ack_delay {
+            buf.write::<u16>(0x0024);
+            buf.write::<u16>(4);
+            buf.write::<u32>(x);
+        }
+
+        if self.enable_multipath {
+            buf.write::<u16>(0x0025);
+            buf.write::<u16>(0);
+        }
+
+        if self.grease_quic_bit {
+            buf.write::<u16>(0x2ab2);
+            buf.write::<u16>(0);
+        }
+
+        for (&id, value) in &self.custom {
+            buf.write::<u16>(id as u16);
+            buf.write::<u16>(value.len() as u16);
+            buf.put_slice(value);
+        }
+
+        // Endpoint::new rejects custom parameters taking more than MAX_CUSTOM_PARAMETERS_LEN
+        assert!(
+            buf.len() <= u16::max_value() as usize,
+            "transport parameters too long"
+        );
         w.write::<u16>(buf.len() as u16);
         w.put_slice(&buf);
     }
 
     pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
+        let params_version;
+        let mut supported_versions = Vec::new();
         if side == Side::Server {
             if r.remaining() < 26 {
                 return Err(Error::Malformed);
             }
-            // We only support one version, so there is no validation to do here.
-            r.get::<u32>().unwrap();
+            // Whether this is the version the client first attempted can only be judged by the connection
+            params_version = r.get::<u32>().unwrap();
         } else {
             if r.remaining() < 31 {
                 return Err(Error::Malformed);
             }
-            let negotiated = r.get::<u32>().unwrap();
-            if negotiated != VERSION {
-                return Err(Error::VersionNegotiation);
-            }
+            params_version = r.get::<u32>().unwrap();
             let supported_bytes = r.get::<u8>().unwrap();
-            if supported_bytes < 4 || supported_bytes > 252 || supported_bytes % 4 != 0 {
+            if supported_bytes < 4
+                || supported_bytes > 252
+                || supported_bytes % 4 != 0
+                || r.remaining() < supported_bytes as usize + 2
+            {
                 return Err(Error::Malformed);
             }
-            let mut found = false;
             for _ in 0..(supported_bytes / 4) {
-                found |= r.get::<u32>().unwrap() == negotiated;
+                let x = r.get::<u32>().unwrap();
+                if x & 0x0f0f_0f0f != 0x0a0a_0a0a {
+                    supported_versions.push(x);
+                }
             }
-            if !found {
+            if !supported_versions.contains(&params_version) {
                 return Err(Error::VersionNegotiation);
             }
         }
 
         let mut initial_max_stream_data = false;
         let mut initial_max_data = false;
-        let mut idle_timeout = false;
+        let mut max_idle_timeout = false;
         let mut initial_max_streams_bidi = false;
         let mut initial_max_streams_uni = false;
         let mut ack_delay_exponent = false;
+        let mut max_ack_delay = false;
+        let mut active_connection_id_limit = false;
         let mut params = Self::default();
+        params.version = params_version;
+        if side == Side::Client {
+            params.supported_versions = supported_versions;
+        }
         let params_len = r.get::<u16>().unwrap();
         if params_len as usize != r.remaining() {
             return Err(Error::Malformed);
@@ -179,11 +421,11 @@ impl TransportParameters {
                     initial_max_data = true;
                 }
                 0x0003 => {
-                    if len != 2 || idle_timeout {
+                    if len != 4 || max_idle_timeout {
                         return Err(Error::Malformed);
                     }
-                    params.idle_timeout = r.get::<u16>().unwrap();
-                    idle_timeout = true;
+                    params.max_idle_timeout = r.get::<u32>().unwrap();
+                    max_idle_timeout = true;
                 }
                 0x0006 => {
                     if len != 16 || params.stateless_reset_token.is_some() {
@@ -223,11 +465,102 @@ impl TransportParameters {
                         return Err(Error::IllegalValue);
                     }
                 }
+                0x000b => {
+                    if len != 2 || max_ack_delay {
+                        return Err(Error::Malformed);
+                    }
+                    params.max_ack_delay = r.get::<u16>().unwrap();
+                    max_ack_delay = true;
+                    if params.max_ack_delay >= MAX_ACK_DELAY_LIMIT {
+                        return Err(Error::IllegalValue);
+                    }
+                }
+                0x0020 => {
+                    if len != 2 || params.max_datagram_frame_size.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    params.max_datagram_frame_size = Some(r.get::<u16>().unwrap());
+                }
+                0x0021 => {
+                    if len != 0 || params.issues_tickets {
+                        return Err(Error::Malformed);
+                    }
+                    params.issues_tickets = true;
+                }
+                0x0023 => {
+                    if len != 4 || params.max_early_data != 0 {
+                        return Err(Error::Malformed);
+                    }
+                    // Only servers accept 0-RTT
+                    if side == Side::Server {
+                        return Err(Error::IllegalValue);
+                    }
+                    params.max_early_data = r.get::<u32>().unwrap();
+                }
+                0x0022 => {
+                    if len != 16 || params.resumption_ticket.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    let mut tok = [0; 16];
+                    r.copy_to_slice(&mut tok);
+                    params.resumption_ticket = Some(tok);
+                }
+                0x0004 => {
+                    if params.preferred_address.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    // Only servers have addresses to prefer
+                    if side == Side::Server {
+                        return Err(Error::IllegalValue);
+                    }
+                    params.preferred_address = Some(PreferredAddress::read(r, len as usize)?);
+                }
+                0x000e => {
+                    if len != 2 || active_connection_id_limit {
+                        return Err(Error::Malformed);
+                    }
+                    params.active_connection_id_limit = r.get::<u16>().unwrap();
+                    active_connection_id_limit = true;
+                    // Peers must be able to store a spare to migrate with
+                    if params.active_connection_id_limit < 2 {
+                        return Err(Error::IllegalValue);
+                    }
+                }
+                0x0024 => {
+                    if len != 4 || params.min_ack_delay.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    let x = r.get::<u32>().unwrap();
+                    if x >= 1 << 24 {
+                        return Err(Error::IllegalValue);
+                    }
+                    params.min_ack_delay = Some(x);
+                }
+                0x0025 => {
+                    if len != 0 || params.enable_multipath {
+                        return Err(Error::Malformed);
+                    }
+                    params.enable_multipath = true;
+                }
+                0x2ab2 => {
+                    if len != 0 || params.grease_quic_bit {
+                        return Err(Error::Malformed);
+                    }
+                    params.grease_quic_bit = true;
+                }
+                _ if u64::from(id) >= MIN_CUSTOM_PARAMETER => {
+                    let mut value = vec![0; len as usize];
+                    r.copy_to_slice(&mut value);
+                    if params.custom.insert(u64::from(id), value.into()).is_some() {
+                        return Err(Error::Malformed);
+                    }
+                }
+                // Parameters we don't know of must be ignored
                 _ => r.advance(len as usize),
             }
         }
 
-        if initial_max_stream_data && initial_max_data && idle_timeout {
+        if initial_max_stream_data && initial_max_data {
             Ok(params)
         } else {
             Err(Error::IllegalValue)
@@ -246,14 +579,165 @@ mod test {
         let params = TransportParameters {
             initial_max_streams_bidi: 16,
             initial_max_streams_uni: 16,
+            max_idle_timeout: 30_000,
             ack_delay_exponent: 2,
+            max_ack_delay: 40,
             max_packet_size: Some(1200),
+            max_datagram_frame_size: Some(1200),
+            resumption_ticket: Some([0xab; 16]),
+            active_connection_id_limit: 9,
+            min_ack_delay: Some(1000),
+            enable_multipath: true,
+            grease_quic_bit: true,
+            custom: [(0xff42, Bytes::from(&b"hello"[..])), (0xff43, Bytes::new())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Client, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn ack_delay_limits() {
+        let mut buf = Vec::new();
+        TransportParameters {
+            ack_delay_exponent: 21,
+            ..TransportParameters::default()
+        }.write(Side::Client, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+
+        let mut buf = Vec::new();
+        TransportParameters {
+            max_ack_delay: MAX_ACK_DELAY_LIMIT,
+            ..TransportParameters::default()
+        }.write(Side::Client, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn max_early_data_coding() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            issues_tickets: true,
+            max_early_data: 16 * 1024,
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
+            params
+        );
+
+        // Clients don't accept 0-RTT
+        let mut buf = Vec::new();
+        params.write(Side::Client, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn duplicate_custom() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            custom: [(0xff42, Bytes::from(&b"hello"[..]))]
+                .iter()
+                .cloned()
+                .collect(),
             ..TransportParameters::default()
         };
         params.write(Side::Client, &mut buf);
+        // Repeat the parameter, fixing up the length of the parameter list
+        let param = buf[buf.len() - 9..].to_vec();
+        buf.extend_from_slice(&param);
+        let len = buf.len() as u16 - 6;
+        buf[4] = (len >> 8) as u8;
+        buf[5] = len as u8;
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn unknown_ignored() {
+        let mut buf = Vec::new();
+        let params = TransportParameters::default();
+        params.write(Side::Client, &mut buf);
+        // Append a parameter outside the private use range, fixing up the length of the parameter list
+        buf.extend_from_slice(&[0x12, 0x34, 0x00, 0x02, 0xab, 0xcd]);
+        let len = buf.len() as u16 - 6;
+        buf[4] = (len >> 8) as u8;
+        buf[5] = len as u8;
         assert_eq!(
             TransportParameters::read(Side::Server, &mut buf.into_buf()).unwrap(),
             params
         );
     }
+
+    #[test]
+    fn preferred_address_coding() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            preferred_address: Some(PreferredAddress {
+                address_v4: Some(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 4433)),
+                address_v6: None,
+                connection_id: ConnectionId::new([0xab; MAX_CID_SIZE], 8),
+                stateless_reset_token: [0xcd; RESET_TOKEN_SIZE],
+            }),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
+            params
+        );
+
+        // Clients may not send a preferred address
+        let mut buf = Vec::new();
+        params.write(Side::Client, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Server, &mut buf.into_buf()),
+            Err(Error::IllegalValue)
+        );
+    }
+
+    #[test]
+    fn version_coding() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            version: Version::V2.number(),
+            supported_versions: vec![Version::V2.number(), Version::V1.number()],
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
+            params
+        );
+
+        // The negotiated version must be among those supported
+        let mut buf = Vec::new();
+        TransportParameters {
+            version: Version::V2.number(),
+            supported_versions: vec![Version::V1.number()],
+            ..TransportParameters::default()
+        }.write(Side::Server, &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, &mut buf.into_buf()),
+            Err(Error::VersionNegotiation)
+        );
+    }
 }
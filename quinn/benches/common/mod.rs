@@ -0,0 +1,70 @@
+//! Setup shared by the benchmarks
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use quinn;
+use rustls::internal::pemfile;
+use tokio::runtime::current_thread::Runtime;
+use tokio_current_thread;
+
+/// Connect a client to a server on the loopback interface that reads and discards up to `size` bytes from each
+/// unidirectional stream it's sent
+///
+/// `server` and `client` may adjust each endpoint before it's bound.
+pub fn connect<S, C>(
+    runtime: &mut Runtime,
+    size: usize,
+    server: S,
+    client: C,
+) -> quinn::NewClientConnection
+where
+    S: FnOnce(&mut quinn::EndpointBuilder),
+    C: FnOnce(&mut quinn::EndpointBuilder),
+{
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .config(quinn::Config {
+            max_remote_uni_streams: 1,
+            ..Default::default()
+        })
+        .listen();
+    server(&mut builder);
+    let certs = pemfile::certs(&mut &include_bytes!("../../../certs/server.chain")[..]).unwrap();
+    let keys =
+        pemfile::rsa_private_keys(&mut &include_bytes!("../../../certs/server.rsa")[..]).unwrap();
+    builder.set_certificate(certs, keys[0].clone()).unwrap();
+    let socket = UdpSocket::bind("[::1]:0").unwrap();
+    let server_addr = socket.local_addr().unwrap();
+    let (_server, driver, incoming) = builder.from_socket(socket).unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("server I/O error: {}", e)));
+    runtime.spawn(incoming.for_each(move |conn| {
+        // Drain the stream so flow control doesn't stall the sender
+        tokio_current_thread::spawn(conn.incoming.map_err(|_| ()).for_each(move |stream| {
+            match stream {
+                quinn::NewStream::Uni(stream) => {
+                    quinn::read_to_end(stream, size).map(|_| ()).map_err(|_| ())
+                }
+                quinn::NewStream::Bi(_) => unreachable!(),
+            }
+        }));
+        Ok(())
+    }));
+
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .add_certificate_authority(include_bytes!("../../../certs/ca.der"))
+        .unwrap();
+    client(&mut builder);
+    let (client, driver, _) = builder.bind("[::1]:0").unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("client I/O error: {}", e)));
+    runtime
+        .block_on(client.connect(&server_addr, "localhost").unwrap())
+        .unwrap()
+}
+
+/// `d` in seconds
+pub fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
@@ -0,0 +1,59 @@
+//! Loopback transfer rate at several UDP batch sizes
+//!
+//! Sends a stream from a client to a server on the same host, and reports how quickly datagrams were moved. Run with
+//! `cargo bench --bench udp_batch`.
+
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+extern crate tokio_current_thread;
+
+use std::time::{Duration, Instant};
+
+use tokio::runtime::current_thread::Runtime;
+
+mod common;
+
+/// Bytes sent per run
+const SIZE: usize = 32 * 1024 * 1024;
+/// Approximate stream data carried per datagram, to turn the transfer rate into a datagram rate
+const DATAGRAM_PAYLOAD: usize = 1200;
+
+fn main() {
+    for &batch in &[1, 8, 32] {
+        let elapsed = run(batch);
+        let secs = common::secs(elapsed);
+        println!(
+            "batch size {:2}: {:8.0} datagrams/s ({:6.1} MiB/s)",
+            batch,
+            (SIZE / DATAGRAM_PAYLOAD) as f64 / secs,
+            SIZE as f64 / (1024.0 * 1024.0) / secs
+        );
+    }
+}
+
+/// Time taken to deliver `SIZE` bytes with `batch` datagrams moved per system call
+fn run(batch: usize) -> Duration {
+    let mut runtime = Runtime::new().unwrap();
+
+    let conn = common::connect(
+        &mut runtime,
+        SIZE,
+        |x| {
+            x.udp_batch_size(batch);
+        },
+        |x| {
+            x.udp_batch_size(batch);
+        },
+    );
+
+    let start = Instant::now();
+    let stream = runtime.block_on(conn.connection.open_uni()).unwrap();
+    let (stream, _) = runtime
+        .block_on(tokio::io::write_all(stream, vec![0; SIZE]))
+        .unwrap();
+    // Completes once the server has acknowledged everything
+    runtime.block_on(tokio::io::shutdown(stream)).unwrap();
+    start.elapsed()
+}
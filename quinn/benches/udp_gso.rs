@@ -0,0 +1,61 @@
+//! Loopback transfer rate with and without UDP segmentation offload
+//!
+//! Sends a stream from a client to a server on the same host, and reports how quickly datagrams were moved. Offload is
+//! only available on Linux, so elsewhere both runs should perform alike. Run with `cargo bench --bench udp_gso`.
+
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+extern crate tokio_current_thread;
+
+use std::time::{Duration, Instant};
+
+use tokio::runtime::current_thread::Runtime;
+
+mod common;
+
+/// Bytes sent per run
+const SIZE: usize = 32 * 1024 * 1024;
+/// Approximate stream data carried per datagram, to turn the transfer rate into a datagram rate
+const DATAGRAM_PAYLOAD: usize = 1200;
+/// Datagrams, or merged buffers of datagrams, moved per system call
+const BATCH: usize = 32;
+
+fn main() {
+    for &gso in &[false, true] {
+        let elapsed = run(gso);
+        let secs = common::secs(elapsed);
+        println!(
+            "offload {:3}: {:8.0} datagrams/s ({:6.1} MiB/s)",
+            if gso { "on" } else { "off" },
+            (SIZE / DATAGRAM_PAYLOAD) as f64 / secs,
+            SIZE as f64 / (1024.0 * 1024.0) / secs
+        );
+    }
+}
+
+/// Time taken to deliver `SIZE` bytes, with the sender merging datagrams for segmentation offload if `gso`
+fn run(gso: bool) -> Duration {
+    let mut runtime = Runtime::new().unwrap();
+
+    let conn = common::connect(
+        &mut runtime,
+        SIZE,
+        |x| {
+            x.udp_batch_size(BATCH);
+        },
+        |x| {
+            x.udp_batch_size(BATCH).udp_segmentation_offload(gso);
+        },
+    );
+
+    let start = Instant::now();
+    let stream = runtime.block_on(conn.connection.open_uni()).unwrap();
+    let (stream, _) = runtime
+        .block_on(tokio::io::write_all(stream, vec![0; SIZE]))
+        .unwrap();
+    // Completes once the server has acknowledged everything
+    runtime.block_on(tokio::io::shutdown(stream)).unwrap();
+    start.elapsed()
+}
@@ -0,0 +1,141 @@
+//! Compare ways of sending messages made up of a small header and a large body
+//!
+//! A client streams messages to a server on the same host, either writing each header and body separately, copying
+//! them into one buffer first, or passing both to `poll_write_vectored` or `poll_write_chunks`, and reports how long
+//! each took until the server acknowledged everything. Run with `cargo bench --bench write_vectored`.
+
+extern crate bytes;
+#[macro_use]
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+extern crate tokio_current_thread;
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll};
+use quinn::Write;
+use tokio::runtime::current_thread::Runtime;
+
+mod common;
+
+/// Number of messages sent per run
+const MESSAGES: usize = 2000;
+/// Size of each message's header (bytes)
+const HEADER_LEN: usize = 16;
+/// Size of each message's body (bytes)
+const BODY_LEN: usize = 16 * 1024;
+/// Bytes sent per run
+const SIZE: usize = MESSAGES * (HEADER_LEN + BODY_LEN);
+
+#[derive(Debug, Copy, Clone)]
+enum Mode {
+    /// One write for the header and another for the body
+    Separate,
+    /// Copy the header and body into a single buffer and write that
+    Concatenated,
+    /// Pass the header and body to `poll_write_vectored` together
+    Vectored,
+    /// Pass the header and body to `poll_write_chunks` together, so neither is copied
+    Chunks,
+}
+
+fn main() {
+    println!(
+        "# {} messages of {} + {} bytes",
+        MESSAGES, HEADER_LEN, BODY_LEN
+    );
+    for &mode in &[
+        Mode::Separate,
+        Mode::Concatenated,
+        Mode::Vectored,
+        Mode::Chunks,
+    ] {
+        let elapsed = run(mode);
+        let secs = common::secs(elapsed);
+        println!(
+            "{:?}: {:.3}s ({:6.1} MiB/s)",
+            mode,
+            secs,
+            SIZE as f64 / (1024.0 * 1024.0) / secs
+        );
+    }
+}
+
+/// Time taken to deliver every message using `mode`
+fn run(mode: Mode) -> Duration {
+    let mut runtime = Runtime::new().unwrap();
+
+    let conn = common::connect(&mut runtime, SIZE, |_| {}, |_| {});
+
+    let start = Instant::now();
+    let stream = runtime.block_on(conn.connection.open_uni()).unwrap();
+    let stream = runtime.block_on(Messages::new(stream, mode)).unwrap();
+    // Completes once the server has acknowledged everything
+    runtime.block_on(tokio::io::shutdown(stream)).unwrap();
+    start.elapsed()
+}
+
+/// Writes `MESSAGES` messages to a stream, yielding the stream when done
+struct Messages {
+    stream: Option<quinn::SendStream>,
+    mode: Mode,
+    header: Bytes,
+    body: Bytes,
+    /// The current message, in `Mode::Concatenated`
+    joined: Vec<u8>,
+    sent: usize,
+    /// Bytes of the current message written so far
+    pos: usize,
+}
+
+impl Messages {
+    fn new(stream: quinn::SendStream, mode: Mode) -> Self {
+        let header = Bytes::from(vec![0x42; HEADER_LEN]);
+        let body = Bytes::from(vec![0xAB; BODY_LEN]);
+        let mut joined = header.to_vec();
+        joined.extend_from_slice(&body);
+        Self {
+            stream: Some(stream),
+            mode,
+            header,
+            body,
+            joined,
+            sent: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl Future for Messages {
+    type Item = quinn::SendStream;
+    type Error = quinn::WriteError;
+
+    fn poll(&mut self) -> Poll<quinn::SendStream, quinn::WriteError> {
+        while self.sent < MESSAGES {
+            let n = {
+                let stream = self.stream.as_mut().unwrap();
+                let (header, body, pos) = (&self.header[..], &self.body[..], self.pos);
+                try_ready!(match self.mode {
+                    Mode::Separate if pos < HEADER_LEN => stream.poll_write(&header[pos..]),
+                    Mode::Concatenated => stream.poll_write(&self.joined[pos..]),
+                    Mode::Vectored if pos < HEADER_LEN => {
+                        stream.poll_write_vectored(&[&header[pos..], body])
+                    }
+                    Mode::Chunks if pos < HEADER_LEN => {
+                        stream.poll_write_chunks(&[self.header.slice_from(pos), self.body.clone()])
+                    }
+                    _ => stream.poll_write(&body[pos - HEADER_LEN..]),
+                })
+            };
+            self.pos += n;
+            if self.pos == HEADER_LEN + BODY_LEN {
+                self.pos = 0;
+                self.sent += 1;
+            }
+        }
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
@@ -0,0 +1,126 @@
+//! Echo the data on every stream a client opens back to it
+//!
+//! Streams are used purely through `AsyncRead` and `AsyncWrite`, as generic Tokio code would use them.
+
+extern crate quinn;
+extern crate tokio;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate slog;
+extern crate futures;
+extern crate rustls;
+extern crate slog_term;
+#[macro_use]
+extern crate structopt;
+extern crate tokio_current_thread;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use failure::{err_msg, Error, ResultExt};
+use futures::{Future, Stream};
+use rustls::internal::pemfile;
+use slog::{Drain, Logger};
+use structopt::StructOpt;
+use tokio::io::AsyncRead;
+use tokio::runtime::current_thread::Runtime;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "echo")]
+struct Opt {
+    /// TLS private key in PEM format
+    #[structopt(parse(from_os_str), short = "k", long = "key")]
+    key: PathBuf,
+    /// TLS certificate in PEM format
+    #[structopt(parse(from_os_str), short = "c", long = "cert")]
+    cert: PathBuf,
+    /// Address to listen on
+    #[structopt(long = "listen", default_value = "[::]:4433")]
+    listen: SocketAddr,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let code = {
+        let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
+        let drain = slog_term::FullFormat::new(decorator)
+            .use_original_order()
+            .build()
+            .fuse();
+        if let Err(e) = run(Logger::root(drain, o!()), opt) {
+            eprintln!("ERROR: {}", e);
+            1
+        } else {
+            0
+        }
+    };
+    ::std::process::exit(code);
+}
+
+fn run(log: Logger, options: Opt) -> Result<()> {
+    let mut runtime = Runtime::new()?;
+
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .logger(log.clone())
+        .config(quinn::Config {
+            max_remote_bi_streams: 64,
+            ..Default::default()
+        })
+        .listen();
+
+    let keys = {
+        let mut reader =
+            io::BufReader::new(fs::File::open(&options.key).context("failed to read private key")?);
+        pemfile::rsa_private_keys(&mut reader).map_err(|_| err_msg("failed to read private key"))?
+    };
+    let cert_chain = {
+        let mut reader = io::BufReader::new(
+            fs::File::open(&options.cert).context("failed to read certificates")?,
+        );
+        pemfile::certs(&mut reader).map_err(|_| err_msg("failed to read certificates"))?
+    };
+    builder.set_certificate(cert_chain, keys[0].clone())?;
+
+    let (_, driver, incoming) = builder.bind(options.listen)?;
+    runtime.spawn(incoming.for_each(move |conn| {
+        let log = log.new(o!("local_id" => format!("{}", conn.connection.local_id())));
+        info!(log, "got connection"; "address" => %conn.connection.remote_address());
+        let log2 = log.clone();
+        tokio_current_thread::spawn(
+            conn.incoming
+                .map_err(move |e| info!(log2, "connection terminated"; "reason" => %e))
+                .for_each(move |stream| {
+                    echo(&log, stream);
+                    Ok(())
+                }),
+        );
+        Ok(())
+    }));
+    runtime.block_on(driver)?;
+
+    Ok(())
+}
+
+fn echo(log: &Logger, stream: quinn::NewStream) {
+    let stream = match stream {
+        quinn::NewStream::Bi(stream) => stream,
+        quinn::NewStream::Uni(_) => unreachable!(), // config.max_remote_uni_streams is defaulted to 0
+    };
+    let log = log.clone();
+    let log2 = log.clone();
+    // Copying completes when the client finishes its side of the stream, which reads as end of file
+    let (recv, send) = stream.split();
+    tokio_current_thread::spawn(
+        tokio::io::copy(recv, send)
+            .and_then(|(n, _, send)| tokio::io::shutdown(send).map(move |_| n))
+            .map_err(|e| format_err!("failed to echo: {}", e))
+            .map(move |n| info!(log, "stream complete"; "bytes" => n))
+            .map_err(move |e: Error| error!(log2, "stream failed"; "reason" => %e)),
+    );
+}
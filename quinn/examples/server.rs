@@ -144,6 +144,7 @@ fn handle_connection(root: &PathBuf, log: &Logger, conn: quinn::NewConnection) {
     let quinn::NewConnection {
         incoming,
         connection,
+        ..
     } = conn;
     let log = log.new(o!("local_id" => format!("{}", connection.local_id())));
     info!(log, "got connection";
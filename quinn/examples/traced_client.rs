@@ -0,0 +1,79 @@
+//! Connect to a server and close the connection again, reporting what happened through `tracing`
+//!
+//! Run with `--features tracing`. Setting `RUST_LOG=quinn_proto=trace` shows every packet sent, received and lost;
+//! `RUST_LOG=quinn_proto=debug` shows just the handshake and any connection errors.
+
+extern crate quinn;
+extern crate tokio;
+#[macro_use]
+extern crate failure;
+extern crate futures;
+#[macro_use]
+extern crate structopt;
+extern crate tracing_subscriber;
+extern crate url;
+
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+
+use failure::Error;
+use futures::Future;
+use structopt::StructOpt;
+use tokio::runtime::current_thread::Runtime;
+use url::Url;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "traced_client")]
+struct Opt {
+    url: Url,
+
+    #[structopt(parse(from_os_str), long = "ca")]
+    ca: Option<PathBuf>,
+}
+
+fn main() {
+    tracing_subscriber::fmt::init();
+    let code = if let Err(e) = run(Opt::from_args()) {
+        eprintln!("ERROR: {}", e);
+        1
+    } else {
+        0
+    };
+    ::std::process::exit(code);
+}
+
+fn run(options: Opt) -> Result<()> {
+    let url = options.url;
+    let remote = url
+        .with_default_port(|_| Ok(4433))?
+        .to_socket_addrs()?
+        .next()
+        .ok_or(format_err!("couldn't resolve to an address"))?;
+
+    let mut builder = quinn::Endpoint::new();
+    if let Some(ca_path) = options.ca {
+        builder.add_certificate_authority(&fs::read(&ca_path)?)?;
+    }
+
+    let (endpoint, driver, _) = builder.bind("[::]:0")?;
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(driver.map_err(|e| eprintln!("IO error: {}", e)));
+
+    runtime.block_on(
+        endpoint
+            .connect(
+                &remote,
+                url.host_str().ok_or(format_err!("URL missing host"))?,
+            )?.map_err(|e| format_err!("failed to connect: {}", e))
+            .and_then(|conn| {
+                conn.connection
+                    .close(0, b"done")
+                    .map_err(|_| unreachable!())
+            }),
+    )?;
+
+    Ok(())
+}
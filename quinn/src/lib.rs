@@ -52,6 +52,8 @@ extern crate bytes;
 extern crate failure;
 extern crate fnv;
 extern crate futures;
+extern crate libc;
+extern crate mio;
 extern crate quinn_proto as quinn;
 extern crate rand;
 extern crate rustls;
@@ -69,9 +71,9 @@ use std::cell::RefCell;
 use std::collections::{hash_map, VecDeque};
 use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{io, mem};
+use std::{cmp, io, mem};
 
 use bytes::Bytes;
 use fnv::FnvHashMap;
@@ -80,15 +82,26 @@ use futures::task::{self, Task};
 use futures::unsync::{mpsc, oneshot};
 use futures::Stream as FuturesStream;
 use futures::{Async, Future, Poll};
-use rustls::{Certificate, KeyLogFile, PrivateKey, TLSError};
+use rustls::{Certificate, KeyLogFile, PrivateKey, RootCertStore, TLSError};
 use slog::Logger;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_timer::Delay;
+use tokio_timer::{clock, Delay};
 use tokio_udp::UdpSocket;
 
 use quinn::{ConnectionHandle, Directionality, Side, StreamId};
 
-pub use quinn::{ClientConfig, Config, ConnectError, ConnectionError, ConnectionId, ListenKeys};
+mod udp;
+use udp::{RecvMeta, UdpExt};
+
+/// Room reserved for each datagram of a receive batch, enough for any UDP payload
+const RECV_SLOT_SIZE: usize = 64 * 1024;
+
+pub use quinn::{
+    AckFrequencyError, AddPathError, ClientConfig, Config, ConnectError, ConnectionError,
+    ConnectionId, EcnCodepoint, FlowControlStats, InMemoryTicketStore, InMemoryTokenStore,
+    ListenKeys, MultipathPolicy, PathId, PathStats, QlogWriter, SendDatagramError,
+    SessionTicketStore, TokenStore, Version,
+};
 
 /// Errors that can occur during the construction of an `Endpoint`.
 #[derive(Debug, Fail)]
@@ -108,6 +121,39 @@ pub enum Error {
     /// The DNS name was invalid for use in TLS
     #[fail(display = "invalid DNS name: {}", _0)]
     InvalidDnsName(String),
+    /// The configured local connection ID length exceeds the maximum QUIC permits
+    #[fail(display = "connection ID length {} is too long", _0)]
+    ConnectionIdTooLong(usize),
+    /// A custom transport parameter's ID was outside the range reserved for private use
+    #[fail(
+        display = "custom transport parameter {:#x} is outside the private use range",
+        _0
+    )]
+    IllegalCustomParameter(u64),
+    /// Custom transport parameters took up more than 64511 bytes between them
+    #[fail(
+        display = "custom transport parameters take {} bytes, more than 64511",
+        _0
+    )]
+    CustomParametersTooLong(usize),
+    /// The configuration enabled no versions of QUIC
+    #[fail(display = "no QUIC versions enabled")]
+    NoVersions,
+    /// The configuration listed more versions of QUIC than the maximum of 62
+    #[fail(display = "{} QUIC versions enabled, more than 62", _0)]
+    TooManyVersions(usize),
+    /// The configured ack delay exponent exceeds the maximum of 20
+    #[fail(display = "ack delay exponent {} exceeds 20", _0)]
+    AckDelayExponentTooLarge(u8),
+    /// The configured max ack delay is 2^14ms or more
+    #[fail(display = "max ack delay of {}μs is 2^14ms or more", _0)]
+    MaxAckDelayTooLarge(u64),
+    /// The configured max idle timeout is 2^32ms or more
+    #[fail(display = "max idle timeout of {}μs is 2^32ms or more", _0)]
+    IdleTimeoutTooLarge(u64),
+    /// The configuration allowed ACK frames no ranges
+    #[fail(display = "ACK frames must be allowed at least one range")]
+    NoAckRanges,
     /// Errors relating to web PKI infrastructure
     #[fail(display = "webpki failed: {:?}", _0)]
     WebPki(webpki::Error),
@@ -121,6 +167,15 @@ impl From<quinn::EndpointError> for Error {
             Keylog(x) => Error::Keylog(x),
             ProtocolTooLong(x) => Error::ProtocolTooLong(x),
             InvalidDnsName(x) => Error::InvalidDnsName(x),
+            ConnectionIdTooLong(x) => Error::ConnectionIdTooLong(x),
+            IllegalCustomParameter(x) => Error::IllegalCustomParameter(x),
+            CustomParametersTooLong(x) => Error::CustomParametersTooLong(x),
+            NoVersions => Error::NoVersions,
+            TooManyVersions(x) => Error::TooManyVersions(x),
+            AckDelayExponentTooLarge(x) => Error::AckDelayExponentTooLarge(x),
+            MaxAckDelayTooLarge(x) => Error::MaxAckDelayTooLarge(x),
+            IdleTimeoutTooLarge(x) => Error::IdleTimeoutTooLarge(x),
+            NoAckRanges => Error::NoAckRanges,
         }
     }
 }
@@ -135,7 +190,14 @@ struct EndpointInner {
     log: Logger,
     socket: UdpSocket,
     inner: quinn::Endpoint,
-    outgoing: VecDeque<(SocketAddrV6, Box<[u8]>)>,
+    /// Datagrams the socket wasn't ready for, in the order they're to be sent
+    outgoing: VecDeque<quinn::Transmit>,
+    /// Most datagrams to move per system call
+    batch_size: usize,
+    /// Most datagrams to merge into one buffer for segmentation offload, 1 if it's disabled
+    gso_segments: usize,
+    recv_buf: Box<[u8]>,
+    recv_meta: Vec<RecvMeta>,
     epoch: Instant,
     pending: FnvHashMap<ConnectionHandle, Pending>,
     // TODO: Replace this with something custom that avoids using oneshots to cancel
@@ -149,6 +211,54 @@ impl EndpointInner {
     fn notify(&self) {
         self.driver.as_ref().map(|x| x.notify());
     }
+
+    /// Send queued datagrams, then whatever the protocol has ready, a batch at a time
+    ///
+    /// Returns whether the socket stopped accepting datagrams before we ran out.
+    fn flush_outgoing(&mut self, now: u64) -> io::Result<bool> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        loop {
+            if self.outgoing.len() < self.batch_size {
+                let room = self.batch_size - self.outgoing.len();
+                batch.clear();
+                batch.extend((0..room).map(|_| None));
+                let n = self.inner.poll_transmit_batch(now, &mut batch);
+                self.outgoing.extend(batch.drain(..n).map(Option::unwrap));
+            }
+            if self.gso_segments > 1 {
+                udp::coalesce(&mut self.outgoing, self.gso_segments);
+            }
+            if self.outgoing.is_empty() {
+                return Ok(false);
+            }
+            let result = {
+                let (front, _) = self.outgoing.as_slices();
+                let len = cmp::min(front.len(), self.batch_size);
+                self.socket.poll_send_batch_ext(&front[..len])
+            };
+            let sent = match result {
+                Ok(Async::Ready(n)) => n,
+                Ok(Async::NotReady) => {
+                    return Ok(true);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    return Ok(true);
+                }
+                Err(ref e) if self.outgoing[0].segment_size.is_some() && udp::is_gso_error(e) => {
+                    // The first datagram, whose failure sendmmsg reports, was segmented: the path or device can't take
+                    // what the socket claimed to support, so send datagrams individually
+                    warn!(self.log, "disabling UDP segmentation offload"; "reason" => %e);
+                    self.gso_segments = 1;
+                    udp::split(&mut self.outgoing);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            self.outgoing.drain(..sent);
+        }
+    }
 }
 
 struct Pending {
@@ -159,14 +269,23 @@ struct Pending {
     bi_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
     cancel_loss_detect: Option<oneshot::Sender<()>>,
     cancel_idle: Option<oneshot::Sender<()>>,
+    cancel_ack: Option<oneshot::Sender<()>>,
+    cancel_path_validation: Option<oneshot::Sender<()>>,
+    cancel_pacing: Option<oneshot::Sender<()>>,
+    cancel_keep_alive: Option<oneshot::Sender<()>>,
     incoming_streams: VecDeque<StreamId>,
     incoming_streams_reader: Option<Task>,
     finishing: FnvHashMap<StreamId, oneshot::Sender<Option<ConnectionError>>>,
+    /// Callers of `Connection::ping`, oldest first, with the time at which each asked
+    pinging: VecDeque<(u64, oneshot::Sender<Option<ConnectionError>>)>,
     error: Option<ConnectionError>,
     draining: Option<oneshot::Sender<()>>,
     drained: bool,
+    /// Whether the connection was abandoned by `Connection::force_close`, leaving it to be forgotten on drop
+    force_closed: bool,
     incoming_session_tickets: VecDeque<Box<[u8]>>,
     incoming_session_tickets_reader: Option<Task>,
+    incoming_datagrams_reader: Option<Task>,
 }
 
 impl Pending {
@@ -179,14 +298,21 @@ impl Pending {
             bi_opening: VecDeque::new(),
             cancel_loss_detect: None,
             cancel_idle: None,
+            cancel_ack: None,
+            cancel_path_validation: None,
+            cancel_pacing: None,
+            cancel_keep_alive: None,
             incoming_streams: VecDeque::new(),
             incoming_streams_reader: None,
             finishing: FnvHashMap::default(),
+            pinging: VecDeque::new(),
             error: None,
             draining: None,
             drained: false,
+            force_closed: false,
             incoming_session_tickets: VecDeque::new(),
             incoming_session_tickets_reader: None,
+            incoming_datagrams_reader: None,
         }
     }
 
@@ -213,9 +339,15 @@ impl Pending {
         for (_, x) in self.finishing.drain() {
             let _ = x.send(Some(reason.clone()));
         }
+        for (_, x) in self.pinging.drain(..) {
+            let _ = x.send(Some(reason.clone()));
+        }
         if let Some(x) = self.incoming_session_tickets_reader.take() {
             x.notify();
         }
+        if let Some(x) = self.incoming_datagrams_reader.take() {
+            x.notify();
+        }
     }
 }
 
@@ -237,6 +369,10 @@ pub struct EndpointBuilder<'a> {
     logger: Logger,
     listen: Option<ListenKeys>,
     config: Config,
+    qlog: Option<Arc<Mutex<QlogWriter>>>,
+    udp_batch_size: usize,
+    udp_gso: bool,
+    reuse_port: bool,
 }
 
 #[allow(missing_docs)]
@@ -266,6 +402,46 @@ impl<'a> EndpointBuilder<'a> {
         self
     }
 
+    /// Record a qlog trace of every connection in `writer`, timestamped relative to when the endpoint is bound
+    pub fn qlog(&mut self, writer: Arc<Mutex<QlogWriter>>) -> &mut Self {
+        self.qlog = Some(writer);
+        self
+    }
+
+    /// Move up to `n` datagrams between the socket and the endpoint per system call, 8 by default
+    ///
+    /// Batches are sent with `sendmmsg` and received with `recvmmsg` on Linux, and a datagram at a time elsewhere. Each
+    /// datagram of a receive batch reserves 64KiB of buffer.
+    pub fn udp_batch_size(&mut self, n: usize) -> &mut Self {
+        assert!(n > 0, "batches must hold at least one datagram");
+        self.udp_batch_size = n;
+        self
+    }
+
+    /// Whether to merge runs of equally sized datagrams for segmentation offload where supported, true by default
+    ///
+    /// Only Linux supports this. If the kernel refuses a merged buffer, the endpoint reverts to sending datagrams
+    /// individually for the rest of its life.
+    pub fn udp_segmentation_offload(&mut self, enabled: bool) -> &mut Self {
+        self.udp_gso = enabled;
+        self
+    }
+
+    /// Whether `bind` should let other sockets bind the same port, so the kernel spreads incoming datagrams between
+    /// them, false by default
+    ///
+    /// This lets a server scale across threads or processes: each binds its own `Endpoint` to the same address with this
+    /// set. Linux picks the socket for a datagram by hashing its source and destination addresses, so a client normally
+    /// sticks to one endpoint, but a client that migrates to a new address may be steered to a different one, which
+    /// won't know its connection. To keep such connections working, give each endpoint a `ConnectionIdGenerator` that
+    /// embeds a value identifying it in every connection ID, so that packets can be steered to the endpoint owning their
+    /// ID, e.g. by a BPF program attached to the sockets, and a `connection_id_filter` so that endpoints don't reset
+    /// connections belonging to their neighbours. Only supported on Linux.
+    pub fn reuse_port(&mut self, enabled: bool) -> &mut Self {
+        self.reuse_port = enabled;
+        self
+    }
+
     pub fn enable_keylog(&mut self) -> &mut Self {
         {
             let tls_client_config = Arc::get_mut(&mut self.config.tls_client_config).unwrap();
@@ -288,6 +464,35 @@ impl<'a> EndpointBuilder<'a> {
         Ok(self)
     }
 
+    /// Present `cert_chain` when connecting to servers that authenticate their clients
+    pub fn set_client_certificate(
+        &mut self,
+        cert_chain: Vec<Certificate>,
+        key: PrivateKey,
+    ) -> Result<&mut Self, TLSError> {
+        quinn::set_client_cert(
+            Arc::get_mut(&mut self.config.tls_client_config).unwrap(),
+            cert_chain,
+            key,
+        )?;
+        Ok(self)
+    }
+
+    /// Only accept clients that present a certificate issued by the authority with DER certificate `der`
+    ///
+    /// The rest of the server's TLS configuration is kept, so this may be called before or after `set_certificate`.
+    /// Custom client certificate verifiers aren't supported: rustls only lets them be named behind its
+    /// `dangerous_configuration` feature.
+    pub fn require_client_certificate(&mut self, der: &[u8]) -> Result<&mut Self, Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(der.into()))?;
+        {
+            let tls_server_config = Arc::get_mut(&mut self.config.tls_server_config).unwrap();
+            quinn::require_client_auth(tls_server_config, roots);
+        }
+        Ok(self)
+    }
+
     pub fn add_certificate_authority(&mut self, der: &[u8]) -> Result<&mut Self, Error> {
         {
             let tls_client_config = Arc::get_mut(&mut self.config.tls_client_config).unwrap();
@@ -310,13 +515,29 @@ impl<'a> EndpointBuilder<'a> {
             Cow::Owned(tokio_reactor::Handle::current())
         };
         let socket = UdpSocket::from_std(socket, &reactor).map_err(Error::Socket)?;
+        socket.init_ext().map_err(Error::Socket)?;
+        let batch_size = self.udp_batch_size;
+        let gso_segments = if self.udp_gso {
+            socket.max_gso_segments()
+        } else {
+            1
+        };
+        debug!(self.logger, "UDP segmentation offload"; "segments" => gso_segments);
         let (send, recv) = mpsc::unbounded();
+        let mut inner = quinn::Endpoint::new(self.logger.clone(), self.config, self.listen)?;
+        if let Some(qlog) = self.qlog {
+            inner = inner.with_qlog(qlog);
+        }
         let rc = Rc::new(RefCell::new(EndpointInner {
-            log: self.logger.clone(),
+            log: self.logger,
             socket: socket,
-            inner: quinn::Endpoint::new(self.logger, self.config, self.listen)?,
+            inner,
             outgoing: VecDeque::new(),
-            epoch: Instant::now(),
+            batch_size,
+            gso_segments,
+            recv_buf: vec![0; batch_size * RECV_SLOT_SIZE].into_boxed_slice(),
+            recv_meta: vec![RecvMeta::default(); batch_size],
+            epoch: clock::now(),
             pending: FnvHashMap::default(),
             timers: FuturesUnordered::new(),
             incoming: send,
@@ -326,7 +547,21 @@ impl<'a> EndpointBuilder<'a> {
     }
 
     pub fn bind<T: ToSocketAddrs>(self, addr: T) -> Result<(Endpoint, Driver, Incoming), Error> {
-        let socket = std::net::UdpSocket::bind(addr).map_err(Error::Socket)?;
+        let socket = if self.reuse_port {
+            let mut result = Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            ));
+            for addr in addr.to_socket_addrs().map_err(Error::Socket)? {
+                result = udp::bind_reuse_port(&addr);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        } else {
+            std::net::UdpSocket::bind(addr)
+        }.map_err(Error::Socket)?;
         self.from_socket(socket)
     }
 }
@@ -345,9 +580,18 @@ impl Endpoint {
             logger: Logger::root(slog::Discard, o!()),
             listen: None,
             config: Config::default(),
+            qlog: None,
+            udp_batch_size: 8,
+            udp_gso: true,
+            reuse_port: false,
         }
     }
 
+    /// The local address the endpoint's socket is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.borrow().socket.local_addr()
+    }
+
     /// Connect to a remote endpoint.
     ///
     /// May fail immediately due to configuration errors, or in the future if the connection could not be established.
@@ -357,7 +601,7 @@ impl Endpoint {
         server_name: &str,
     ) -> Result<impl Future<Item = NewClientConnection, Error = ConnectionError>, ConnectError>
     {
-        let (fut, conn) = self.connect_inner(addr, server_name)?;
+        let (fut, conn) = self.connect_inner(addr, server_name, None)?;
         Ok(fut.map_err(|_| unreachable!()).and_then(move |err| {
             if let Some(err) = err {
                 Err(err)
@@ -367,24 +611,24 @@ impl Endpoint {
         }))
     }
 
-    /*
     /// Connect to a remote endpoint, with support for transmitting data before the connection is established
     ///
     /// Returns a connection that may be used for sending immediately, and a future that will complete when the
     /// connection is established.
     ///
+    /// `ticket` must have been obtained from the `session_tickets` of an earlier connection to the same server. Tickets
+    /// are only issued by quinn servers, whose 0-RTT scheme is their own rather than the one QUIC specifies.
+    ///
     /// Data transmitted this way may be replayed by an attacker until the session ticket expires. Never send non-idempotent
     /// commands as 0-RTT data.
     ///
     /// Servers may reject 0-RTT data, in which case anything sent will be retransmitted after the connection is
     /// established.
-    ///
-    /// # Panics
-    /// - If `config.session_ticket` is `None`. A session ticket is necessary for 0-RTT to be possible.
     pub fn connect_zero_rtt(
         &self,
         addr: &SocketAddr,
-        config: ClientConfig,
+        server_name: &str,
+        ticket: &[u8],
     ) -> Result<
         (
             NewClientConnection,
@@ -392,11 +636,7 @@ impl Endpoint {
         ),
         ConnectError,
     > {
-        assert!(
-            config.session_ticket.is_some(),
-            "a session ticket must be supplied for zero-rtt transmits to be possible"
-        );
-        let (fut, conn) = self.connect_inner(addr, config)?;
+        let (fut, conn) = self.connect_inner(addr, server_name, Some(ticket))?;
         let conn = NewClientConnection::new(Rc::new(conn));
         Ok((
             conn,
@@ -404,12 +644,12 @@ impl Endpoint {
                 .and_then(move |err| err.map_or(Ok(()), Err)),
         ))
     }
-    */
 
     fn connect_inner(
         &self,
         addr: &SocketAddr,
         server_name: &str,
+        ticket: Option<&[u8]>,
     ) -> Result<
         (
             impl Future<Item = Option<ConnectionError>, Error = futures::Canceled>,
@@ -420,7 +660,13 @@ impl Endpoint {
         let (send, recv) = oneshot::channel();
         let handle = {
             let mut endpoint = self.0.borrow_mut();
-            let handle = endpoint.inner.connect(normalize(*addr), server_name)?;
+            let handle = if let Some(ticket) = ticket {
+                endpoint
+                    .inner
+                    .connect_with_ticket(normalize(*addr), server_name, ticket)?
+            } else {
+                endpoint.inner.connect(normalize(*addr), server_name)?
+            };
             endpoint.pending.insert(handle, Pending::new(Some(send)));
             handle
         };
@@ -439,6 +685,8 @@ pub struct NewConnection {
     pub connection: Connection,
     /// The stream of QUIC streams initiated by the client.
     pub incoming: IncomingStreams,
+    /// The stream of datagrams sent by the client.
+    pub datagrams: IncomingDatagrams,
 }
 
 impl NewConnection {
@@ -450,7 +698,8 @@ impl NewConnection {
         });
         NewConnection {
             connection: Connection(conn.clone()),
-            incoming: IncomingStreams(conn),
+            incoming: IncomingStreams(conn.clone()),
+            datagrams: IncomingDatagrams(conn),
         }
     }
 }
@@ -463,6 +712,8 @@ pub struct NewClientConnection {
     pub incoming: IncomingStreams,
     /// The stream of session tickets provided by the server.
     pub session_tickets: IncomingSessionTickets,
+    /// The stream of datagrams sent by the server.
+    pub datagrams: IncomingDatagrams,
 }
 
 impl NewClientConnection {
@@ -470,7 +721,8 @@ impl NewClientConnection {
         Self {
             connection: Connection(conn.clone()),
             incoming: IncomingStreams(conn.clone()),
-            session_tickets: IncomingSessionTickets(conn),
+            session_tickets: IncomingSessionTickets(conn.clone()),
+            datagrams: IncomingDatagrams(conn),
         }
     }
 }
@@ -479,20 +731,18 @@ impl Future for Driver {
     type Item = ();
     type Error = io::Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut buf = [0; 64 * 1024];
         let endpoint = &mut *self.0.borrow_mut();
         if endpoint.driver.is_none() {
             endpoint.driver = Some(task::current());
         }
-        let now = micros_from(endpoint.epoch.elapsed());
+        let now = micros_since(endpoint.epoch);
         loop {
             loop {
-                match endpoint.socket.poll_recv_from(&mut buf) {
-                    Ok(Async::Ready((n, addr))) => {
-                        endpoint
-                            .inner
-                            .handle(now, normalize(addr), (&buf[0..n]).into());
-                    }
+                let n = match endpoint
+                    .socket
+                    .poll_recv_batch_ext(&mut endpoint.recv_buf, &mut endpoint.recv_meta)
+                {
+                    Ok(Async::Ready(n)) => n,
                     Ok(Async::NotReady) => {
                         break;
                     }
@@ -503,6 +753,17 @@ impl Future for Driver {
                     Err(e) => {
                         return Err(e);
                     }
+                };
+                let datagrams = endpoint.recv_meta[..n]
+                    .iter()
+                    .zip(endpoint.recv_buf.chunks(RECV_SLOT_SIZE));
+                for (meta, buf) in datagrams {
+                    endpoint.inner.handle(
+                        now,
+                        normalize(meta.addr),
+                        meta.ecn,
+                        (&buf[..meta.len]).into(),
+                    );
                 }
             }
             while let Some((connection, event)) = endpoint.inner.poll() {
@@ -592,53 +853,44 @@ impl Future for Driver {
                             x.notify();
                         }
                     }
-                }
-            }
-            let mut blocked = false;
-            while !endpoint.outgoing.is_empty() {
-                {
-                    let front = endpoint.outgoing.front().unwrap();
-                    match endpoint.socket.poll_send_to(&front.1, &front.0.into()) {
-                        Ok(Async::Ready(_)) => {}
-                        Ok(Async::NotReady) => {
-                            blocked = true;
-                            break;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                            blocked = true;
-                            break;
+                    DatagramReceived => {
+                        if let Some(x) = endpoint
+                            .pending
+                            .get_mut(&connection)
+                            .and_then(|p| p.incoming_datagrams_reader.take())
+                        {
+                            x.notify();
                         }
-                        Err(e) => {
-                            return Err(e);
+                    }
+                    PingAcknowledged { sent } => {
+                        // Every PING sent after a request was made is as good as its own
+                        let pending = endpoint.pending.get_mut(&connection).unwrap();
+                        while pending.pinging.front().map_or(false, |&(t, _)| t <= sent) {
+                            let (_, x) = pending.pinging.pop_front().unwrap();
+                            let _ = x.send(None);
                         }
                     }
+                    // Outgoing packets are addressed by the protocol state machine, so there's nothing to update
+                    PathMigrated { .. } | PathValidated { .. } | PathValidationFailed { .. } => {}
+                    // Surfaced through `Connection::flow_control_stats` instead
+                    PeerBlocked { .. } => {}
                 }
-                endpoint.outgoing.pop_front();
             }
+            let blocked = endpoint.flush_outgoing(now)?;
             while let Some(io) = endpoint.inner.poll_io(now) {
                 use quinn::Io::*;
                 match io {
                     Transmit {
                         destination,
+                        ecn,
                         packet,
                     } => {
-                        if !blocked {
-                            match endpoint.socket.poll_send_to(&packet, &destination.into()) {
-                                Ok(Async::Ready(_)) => {}
-                                Ok(Async::NotReady) => {
-                                    blocked = true;
-                                }
-                                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                                    blocked = true;
-                                }
-                                Err(e) => {
-                                    return Err(e);
-                                }
-                            }
-                        }
-                        if blocked {
-                            endpoint.outgoing.push_front((destination, packet));
-                        }
+                        endpoint.outgoing.push_back(quinn::Transmit {
+                            destination,
+                            ecn,
+                            packet,
+                            segment_size: None,
+                        });
                     }
                     TimerStart {
                         connection,
@@ -667,6 +919,10 @@ impl Future for Driver {
                         let mut cancel = match timer {
                             LossDetection => &mut pending.cancel_loss_detect,
                             Idle => &mut pending.cancel_idle,
+                            Ack => &mut pending.cancel_ack,
+                            PathValidation => &mut pending.cancel_path_validation,
+                            Pacing => &mut pending.cancel_pacing,
+                            KeepAlive => &mut pending.cancel_keep_alive,
                             Close => unreachable!(),
                         };
                         let instant = endpoint.epoch + duration_micros(time);
@@ -697,12 +953,27 @@ impl Future for Driver {
                                 Idle => {
                                     pending.cancel_idle.take().map(|x| x.send(()));
                                 }
+                                Ack => {
+                                    pending.cancel_ack.take().map(|x| x.send(()));
+                                }
+                                PathValidation => {
+                                    pending.cancel_path_validation.take().map(|x| x.send(()));
+                                }
+                                Pacing => {
+                                    pending.cancel_pacing.take().map(|x| x.send(()));
+                                }
+                                KeepAlive => {
+                                    pending.cancel_keep_alive.take().map(|x| x.send(()));
+                                }
                                 Close => {} // Arises from stateless reset
                             }
                         }
                     }
                 }
             }
+            if !blocked && !endpoint.outgoing.is_empty() {
+                endpoint.flush_outgoing(now)?;
+            }
             while let Some(x) = endpoint.inner.accept() {
                 let _ = endpoint
                     .incoming
@@ -738,6 +1009,14 @@ fn micros_from(x: Duration) -> u64 {
     x.as_secs() * 1000 * 1000 + (x.subsec_nanos() / 1000) as u64
 }
 
+/// Time elapsed since `epoch` in μs, as told by the Tokio timer's clock
+///
+/// Timers follow the same clock, so substituting a mock with `tokio_timer::clock::with_default` makes an endpoint's
+/// behavior deterministic.
+fn micros_since(epoch: Instant) -> u64 {
+    micros_from(clock::now() - epoch)
+}
+
 fn normalize(x: SocketAddr) -> SocketAddrV6 {
     match x {
         SocketAddr::V6(x) => x,
@@ -766,9 +1045,14 @@ impl Connection {
             if let Some(x) = endpoint.inner.open(self.0.conn, Directionality::Uni) {
                 let _ = send.send(Ok(x));
             } else {
-                let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-                pending.uni_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more streams
+                endpoint
+                    .pending
+                    .get_mut(&self.0.conn)
+                    .unwrap()
+                    .uni_opening
+                    .push_back(send);
+                // Let the driver tell the peer we're blocked
+                endpoint.notify();
             }
         }
         let conn = self.0.clone();
@@ -785,9 +1069,14 @@ impl Connection {
             if let Some(x) = endpoint.inner.open(self.0.conn, Directionality::Bi) {
                 let _ = send.send(Ok(x));
             } else {
-                let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-                pending.bi_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more streams
+                endpoint
+                    .pending
+                    .get_mut(&self.0.conn)
+                    .unwrap()
+                    .bi_opening
+                    .push_back(send);
+                // Let the driver tell the peer we're blocked
+                endpoint.notify();
             }
         }
         let conn = self.0.clone();
@@ -796,6 +1085,45 @@ impl Connection {
             .map(move |stream| Stream::new(conn.clone(), stream))
     }
 
+    /// Send a PING to the peer, completing once a packet carrying it has been acknowledged.
+    ///
+    /// Useful to check that the peer is still reachable, or to measure the round trip time on demand.
+    pub fn ping(&self) -> impl Future<Item = (), Error = ConnectionError> {
+        let (send, recv) = oneshot::channel();
+        {
+            let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+            let now = micros_since(endpoint.epoch);
+            let error = endpoint.pending[&self.0.conn].error.clone();
+            if let Some(e) = error {
+                let _ = send.send(Some(e));
+            } else {
+                endpoint.inner.ping(self.0.conn);
+                endpoint
+                    .pending
+                    .get_mut(&self.0.conn)
+                    .unwrap()
+                    .pinging
+                    .push_back((now, send));
+                endpoint.notify();
+            }
+        }
+        recv.map_err(|_| unreachable!())
+            .and_then(|result| match result {
+                None => Ok(()),
+                Some(e) => Err(e),
+            })
+    }
+
+    /// Update the keys protecting the connection's packets
+    ///
+    /// Takes effect once the handshake is confirmed and the peer has acknowledged a packet protected with the current
+    /// keys. Keys are also updated automatically every `Config::key_update_interval` packets.
+    pub fn force_key_update(&self) {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        endpoint.inner.force_key_update(self.0.conn);
+        endpoint.notify();
+    }
+
     /// Close the connection immediately.
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility to call this only when
@@ -810,14 +1138,21 @@ impl Connection {
         let (send, recv) = oneshot::channel();
         {
             let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+            let drained = endpoint.inner.is_drained(self.0.conn);
             endpoint.inner.close(
-                micros_from(endpoint.epoch.elapsed()),
+                micros_since(endpoint.epoch),
                 self.0.conn,
                 error_code,
                 reason.into(),
             );
             let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-            pending.draining = Some(send);
+            if drained {
+                // Timed out or reset, so already forgotten, with no ConnectionDrained to wait for
+                pending.drained = true;
+                let _ = send.send(());
+            } else {
+                pending.draining = Some(send);
+            }
         }
         recv.then(move |_| {
             let _ = self;
@@ -825,6 +1160,19 @@ impl Connection {
         })
     }
 
+    /// Abandon the connection immediately, without notifying the peer.
+    ///
+    /// Unlike `close`, nothing more is sent, so the peer only finds out when its idle timeout expires or a stateless
+    /// reset answers its next packet. Prefer `close` unless the peer is known to be unreachable.
+    pub fn force_close(self) {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        endpoint.inner.force_close(self.0.conn);
+        // Streams see the connection as drained, and the endpoint forgets it once the last of them is dropped
+        let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
+        pending.drained = true;
+        pending.force_closed = true;
+    }
+
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
         (*self
@@ -868,6 +1216,48 @@ impl Connection {
             .map(|x| x.into())
     }
 
+    /// The value of the transport parameter with `id` sent by the peer, if any
+    ///
+    /// Only parameters not defined by QUIC itself, e.g. those set with `quinn_proto::Config::set_custom_param`, are
+    /// available.
+    pub fn custom_param(&self, id: u64) -> Option<Bytes> {
+        self.0
+            .endpoint
+            .0
+            .borrow()
+            .inner
+            .get_custom_param(self.0.conn, id)
+            .cloned()
+    }
+
+    /// The certificate chain the peer authenticated with, if any
+    ///
+    /// Clients only present certificates to servers that call `EndpointBuilder::require_client_certificate`.
+    pub fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        self.0
+            .endpoint
+            .0
+            .borrow()
+            .inner
+            .get_peer_certificates(self.0.conn)
+            .map(|x| x.to_vec())
+    }
+
+    /// RTT, loss and throughput measurements of the path the connection is using
+    pub fn path_stats(&self) -> PathStats {
+        self.0.endpoint.0.borrow().inner.get_path_stats(self.0.conn)
+    }
+
+    /// Counts of flow control stalls on the connection in each direction
+    pub fn flow_control_stats(&self) -> FlowControlStats {
+        self.0
+            .endpoint
+            .0
+            .borrow()
+            .inner
+            .get_flow_control_stats(self.0.conn)
+    }
+
     /// Whether the cryptographic session was resumed
     pub fn session_resumed(&self) -> bool {
         self.0
@@ -877,19 +1267,70 @@ impl Connection {
             .inner
             .get_session_resumed(self.0.conn)
     }
+
+    /// Transmit `data` as an unreliable, unordered application datagram.
+    ///
+    /// Fails if the peer does not support datagrams or `data` exceeds `max_datagram_size`.
+    pub fn send_datagram(&self, data: Bytes) -> Result<(), SendDatagramError> {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        endpoint.inner.send_datagram(self.0.conn, data)?;
+        endpoint.notify();
+        Ok(())
+    }
+
+    /// Open an additional path to the peer at `remote`, to be used alongside the current one once it's validated.
+    ///
+    /// Fails unless `Config::multipath_policy` is set and the peer supports multipath.
+    pub fn add_path(&self, remote: SocketAddr) -> Result<PathId, AddPathError> {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        let id = endpoint.inner.add_path(self.0.conn, normalize(remote))?;
+        endpoint.notify();
+        Ok(id)
+    }
+
+    /// Ask the peer to acknowledge packets less often.
+    ///
+    /// The peer may receive up to `ack_eliciting_threshold` ack-eliciting packets before acknowledging them, provided it
+    /// acknowledges each within `max_ack_delay`. Fails if the peer does not support this.
+    pub fn request_ack_frequency(
+        &self,
+        ack_eliciting_threshold: u64,
+        max_ack_delay: Duration,
+    ) -> Result<(), AckFrequencyError> {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        endpoint.inner.request_ack_frequency(
+            self.0.conn,
+            ack_eliciting_threshold,
+            micros_from(max_ack_delay),
+        )?;
+        endpoint.notify();
+        Ok(())
+    }
+
+    /// The largest datagram that may currently be sent, or `None` if the peer does not support datagrams.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.0
+            .endpoint
+            .0
+            .borrow()
+            .inner
+            .get_max_datagram_size(self.0.conn)
+    }
 }
 
 impl Drop for ConnectionInner {
     fn drop(&mut self) {
         let endpoint = &mut *self.endpoint.0.borrow_mut();
         if let hash_map::Entry::Occupied(pending) = endpoint.pending.entry(self.conn) {
-            if pending.get().draining.is_none() && !pending.get().drained {
-                endpoint.inner.close(
-                    micros_from(endpoint.epoch.elapsed()),
-                    self.conn,
-                    0,
-                    (&[][..]).into(),
-                );
+            if pending.get().force_closed {
+                // Closing a drained connection just forgets it
+                endpoint
+                    .inner
+                    .close(micros_since(endpoint.epoch), self.conn, 0, (&[][..]).into());
+            } else if pending.get().draining.is_none() && !pending.get().drained {
+                endpoint
+                    .inner
+                    .close(micros_since(endpoint.epoch), self.conn, 0, (&[][..]).into());
                 endpoint.driver.as_ref().map(|x| x.notify());
             }
             pending.remove_entry();
@@ -921,9 +1362,27 @@ pub trait Read {
     ///   of the receive buffer, making it impossible for future ordered reads to proceed.
     fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError>;
 
+    /// Read the next contiguous chunk of data from the stream.
+    ///
+    /// Like `poll_read`, but without a caller-supplied buffer. Data that arrived in order is returned in the pieces it
+    /// was sent in, without being copied.
+    ///
+    /// # Panics
+    /// - If called after `poll_read_unordered` was called on the same stream.
+    fn poll_read_chunk(&mut self) -> Poll<Bytes, ReadError>;
+
+    /// Read data contiguously from the stream without consuming it.
+    ///
+    /// Completes with the data that `poll_read` would, which is returned again by the next `poll_peek` or `poll_read`.
+    /// Useful for deciding how to handle a stream based on its first few bytes.
+    ///
+    /// # Panics
+    /// - If called after `poll_read_unordered` was called on the same stream.
+    fn poll_peek(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError>;
+
     /// Abandon receiving data on this stream.
     ///
-    /// The peer is notified and will reset this stream in response.
+    /// The peer is notified and will reset this stream in response. Stopping again has no effect.
     fn stop(&mut self, error_code: u16);
 }
 
@@ -932,6 +1391,20 @@ pub trait Write {
     /// Write some bytes to the stream.
     fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError>;
 
+    /// Write bytes gathered from several buffers to the stream, in order.
+    ///
+    /// Equivalent to writing the concatenation of `bufs`, but avoids copying them into one buffer first. As with
+    /// `poll_write`, fewer bytes than were supplied may be written.
+    fn poll_write_vectored(&mut self, bufs: &[&[u8]]) -> Poll<usize, WriteError>;
+
+    /// Write already shared buffers to the stream, in order.
+    ///
+    /// Like `poll_write_vectored`, but streams that can hold on to `Bytes` send the data without copying it.
+    fn poll_write_chunks(&mut self, bufs: &[Bytes]) -> Poll<usize, WriteError> {
+        let bufs = bufs.iter().map(|x| &x[..]).collect::<Vec<_>>();
+        self.poll_write_vectored(&bufs)
+    }
+
     /// Indicate that no more data will be written.
     ///
     /// Completes when the peer has acknowledged all sent data.
@@ -939,11 +1412,27 @@ pub trait Write {
 
     /// Abandon transmitting data on this stream.
     ///
-    /// No new data may be transmitted, and no previously transmitted data will be retransmitted if lost.
+    /// No new data may be transmitted, and no previously transmitted data will be retransmitted if lost. Later writes
+    /// fail with `WriteError::Reset`, and resetting again has no effect.
     fn reset(&mut self, error_code: u16);
+
+    /// Set the priority of the stream's outgoing data, 0 by default.
+    ///
+    /// When several streams have data ready, each is sent a share of the connection's capacity that doubles with each
+    /// increment of its priority relative to the others. Takes effect from the next packet sent. Streams that can't be
+    /// prioritized ignore this.
+    fn set_priority(&mut self, _priority: i32) {}
+
+    /// The priority of the stream's outgoing data.
+    fn priority(&self) -> i32 {
+        0
+    }
 }
 
 /// A stream that supports both sending and receiving data
+///
+/// Usable anywhere an `AsyncRead + AsyncWrite` is expected. Reads return 0 bytes once the peer has finished the
+/// stream, and writes wait for flow control credit.
 pub struct Stream {
     conn: Rc<ConnectionInner>,
     stream: StreamId,
@@ -951,6 +1440,8 @@ pub struct Stream {
     // Send only
     finishing: Option<oneshot::Receiver<Option<ConnectionError>>>,
     finished: bool,
+    // Whether no more data may be written (due to sending reset or the peer stopping the stream)
+    reset: bool,
 
     // Recv only
     // Whether data reception is complete (due to receiving finish or reset or sending stop)
@@ -964,16 +1455,27 @@ impl Stream {
             stream,
             finishing: None,
             finished: false,
+            reset: false,
             recvd: false,
         }
     }
-}
 
-impl Write for Stream {
-    fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
+    /// Write with `write`, translating the outcome
+    fn poll_write_with<F>(&mut self, write: F) -> Poll<usize, WriteError>
+    where
+        F: FnOnce(
+            &mut quinn::Endpoint,
+            ConnectionHandle,
+            StreamId,
+        ) -> Result<usize, quinn::WriteError>,
+    {
+        if self.reset {
+            return Err(WriteError::Reset);
+        }
         let mut endpoint = self.conn.endpoint.0.borrow_mut();
         use quinn::WriteError::*;
-        let n = match endpoint.inner.write(self.conn.conn, self.stream, buf) {
+        let r = write(&mut endpoint.inner, self.conn.conn, self.stream);
+        let n = match r {
             Ok(n) => n,
             Err(Blocked) => {
                 let pending = endpoint.pending.get_mut(&self.conn.conn).unwrap();
@@ -984,12 +1486,31 @@ impl Write for Stream {
                 return Ok(Async::NotReady);
             }
             Err(Stopped { error_code }) => {
+                self.reset = true;
                 return Err(WriteError::Stopped { error_code });
             }
+            Err(Reset) => {
+                self.reset = true;
+                return Err(WriteError::Reset);
+            }
         };
         endpoint.notify();
         Ok(Async::Ready(n))
     }
+}
+
+impl Write for Stream {
+    fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
+        self.poll_write_vectored(&[buf])
+    }
+
+    fn poll_write_vectored(&mut self, bufs: &[&[u8]]) -> Poll<usize, WriteError> {
+        self.poll_write_with(|endpoint, conn, stream| endpoint.write_vectored(conn, stream, bufs))
+    }
+
+    fn poll_write_chunks(&mut self, bufs: &[Bytes]) -> Poll<usize, WriteError> {
+        self.poll_write_with(|endpoint, conn, stream| endpoint.write_chunks(conn, stream, bufs))
+    }
 
     fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
         let mut endpoint = self.conn.endpoint.0.borrow_mut();
@@ -1022,6 +1543,25 @@ impl Write for Stream {
             .inner
             .reset(self.conn.conn, self.stream, error_code);
         endpoint.notify();
+        self.reset = true;
+    }
+
+    fn set_priority(&mut self, priority: i32) {
+        self.conn
+            .endpoint
+            .0
+            .borrow_mut()
+            .inner
+            .set_priority(self.conn.conn, self.stream, priority);
+    }
+
+    fn priority(&self) -> i32 {
+        self.conn
+            .endpoint
+            .0
+            .borrow()
+            .inner
+            .priority(self.conn.conn, self.stream)
     }
 }
 
@@ -1074,6 +1614,48 @@ impl Read for Stream {
         }
     }
 
+    fn poll_read_chunk(&mut self) -> Poll<Bytes, ReadError> {
+        let endpoint = &mut *self.conn.endpoint.0.borrow_mut();
+        use quinn::ReadError::*;
+        let pending = endpoint.pending.get_mut(&self.conn.conn).unwrap();
+        match endpoint.inner.read_chunk(self.conn.conn, self.stream) {
+            Ok(chunk) => Ok(Async::Ready(chunk)),
+            Err(Blocked) => {
+                if let Some(ref x) = pending.error {
+                    return Err(ReadError::ConnectionClosed(x.clone()));
+                }
+                pending.blocked_readers.insert(self.stream, task::current());
+                Ok(Async::NotReady)
+            }
+            Err(Reset { error_code }) => {
+                self.recvd = true;
+                Err(ReadError::Reset { error_code })
+            }
+            Err(Finished) => {
+                self.recvd = true;
+                Err(ReadError::Finished)
+            }
+        }
+    }
+
+    fn poll_peek(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
+        let endpoint = &mut *self.conn.endpoint.0.borrow_mut();
+        use quinn::ReadError::*;
+        let pending = endpoint.pending.get_mut(&self.conn.conn).unwrap();
+        match endpoint.inner.peek(self.conn.conn, self.stream, buf) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(Blocked) => {
+                if let Some(ref x) = pending.error {
+                    return Err(ReadError::ConnectionClosed(x.clone()));
+                }
+                pending.blocked_readers.insert(self.stream, task::current());
+                Ok(Async::NotReady)
+            }
+            Err(Reset { error_code }) => Err(ReadError::Reset { error_code }),
+            Err(Finished) => Err(ReadError::Finished),
+        }
+    }
+
     fn stop(&mut self, error_code: u16) {
         let endpoint = &mut *self.conn.endpoint.0.borrow_mut();
         endpoint
@@ -1093,6 +1675,9 @@ impl io::Write for Stream {
                 io::ErrorKind::ConnectionReset,
                 format!("stream stopped by peer: error {}", error_code),
             )),
+            Err(WriteError::Reset) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream reset"))
+            }
             Err(WriteError::ConnectionClosed(e)) => Err(io::Error::new(
                 io::ErrorKind::ConnectionAborted,
                 format!("connection closed: {}", e),
@@ -1143,6 +1728,11 @@ pub enum WriteError {
         /// The error code supplied by the peer.
         error_code: u16,
     },
+    /// The stream was reset, so no more data may be written.
+    ///
+    /// Returned after `Write::reset`, and after `Stopped` has been reported once.
+    #[fail(display = "stream reset")]
+    Reset,
     /// The connection was closed.
     #[fail(display = "connection closed: {}", _0)]
     ConnectionClosed(ConnectionError),
@@ -1174,18 +1764,32 @@ impl AsyncRead for Stream {
 }
 
 /// A stream that can only be used to send data
+///
+/// Implements `AsyncWrite`, with writes waiting for flow control credit. Shutting down finishes the stream.
 pub struct SendStream(Stream);
 
 impl Write for SendStream {
     fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, WriteError> {
         Write::poll_write(&mut self.0, buf)
     }
+    fn poll_write_vectored(&mut self, bufs: &[&[u8]]) -> Poll<usize, WriteError> {
+        self.0.poll_write_vectored(bufs)
+    }
+    fn poll_write_chunks(&mut self, bufs: &[Bytes]) -> Poll<usize, WriteError> {
+        self.0.poll_write_chunks(bufs)
+    }
     fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
         self.0.poll_finish()
     }
     fn reset(&mut self, error_code: u16) {
         self.0.reset(error_code);
     }
+    fn set_priority(&mut self, priority: i32) {
+        self.0.set_priority(priority);
+    }
+    fn priority(&self) -> i32 {
+        self.0.priority()
+    }
 }
 
 impl io::Write for SendStream {
@@ -1204,6 +1808,8 @@ impl AsyncWrite for SendStream {
 }
 
 /// A stream that can only be used to receive data
+///
+/// Implements `AsyncRead`, with reads returning 0 bytes once the peer has finished the stream.
 pub struct RecvStream(Stream);
 
 impl Read for RecvStream {
@@ -1213,11 +1819,40 @@ impl Read for RecvStream {
     fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
         Read::poll_read(&mut self.0, buf)
     }
+    fn poll_read_chunk(&mut self) -> Poll<Bytes, ReadError> {
+        self.0.poll_read_chunk()
+    }
+    fn poll_peek(&mut self, buf: &mut [u8]) -> Poll<usize, ReadError> {
+        self.0.poll_peek(buf)
+    }
     fn stop(&mut self, error_code: u16) {
         self.0.stop(error_code)
     }
 }
 
+impl RecvStream {
+    /// The data on this stream, as a stream of the chunks `poll_read_chunk` produces
+    ///
+    /// `RecvStream` is itself such a stream; this makes the conversion explicit where that reads better.
+    pub fn into_stream(self) -> impl FuturesStream<Item = Bytes, Error = ReadError> {
+        self
+    }
+}
+
+/// Yields each chunk of data in order, ending once the stream is finished
+impl FuturesStream for RecvStream {
+    type Item = Bytes;
+    type Error = ReadError;
+    fn poll(&mut self) -> Poll<Option<Bytes>, ReadError> {
+        match self.poll_read_chunk() {
+            Ok(Async::Ready(chunk)) => Ok(Async::Ready(Some(chunk))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(ReadError::Finished) => Ok(Async::Ready(None)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
 impl io::Read for RecvStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.0.read(buf)
@@ -1330,6 +1965,29 @@ impl FuturesStream for IncomingSessionTickets {
     }
 }
 
+/// A stream of application datagrams sent by the peer.
+pub struct IncomingDatagrams(Rc<ConnectionInner>);
+
+impl FuturesStream for IncomingDatagrams {
+    type Item = Bytes;
+    type Error = ConnectionError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let endpoint = &mut *self.0.endpoint.0.borrow_mut();
+        if let Some(x) = endpoint.inner.recv_datagram(self.0.conn) {
+            return Ok(Async::Ready(Some(x)));
+        }
+        let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
+        if let Some(ref x) = pending.error {
+            Err(x.clone())
+        } else if pending.drained {
+            Ok(Async::Ready(None))
+        } else {
+            pending.incoming_datagrams_reader = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 /// Uses unordered reads to be more efficient than using `AsyncRead` would allow
 pub fn read_to_end<T: Read>(stream: T, size_limit: usize) -> ReadToEnd<T> {
     ReadToEnd {
@@ -0,0 +1,781 @@
+//! UDP socket I/O carrying explicit congestion notification codepoints
+//!
+//! The standard library doesn't expose the ECN bits of the IP header, so on Linux we read and write them as ancillary
+//! data via `recvmsg` and `sendmsg`. Elsewhere, incoming codepoints are ignored and outgoing packets are sent unmarked.
+//!
+//! Linux can also move many datagrams in one system call with `recvmmsg` and `sendmmsg`, saving the per-call overhead
+//! that dominates on busy servers. Elsewhere, batches are sent and received one datagram at a time.
+//!
+//! Where the kernel supports generic segmentation offload, runs of equally sized datagrams to the same destination are
+//! further merged into a single buffer, which the kernel or the network device splits back apart.
+//!
+//! Linux also lets several sockets bind the same port with `SO_REUSEPORT`, spreading incoming datagrams between them.
+
+use std::collections::VecDeque;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::{io, mem};
+
+use futures::{Async, Poll};
+use libc;
+use quinn::{EcnCodepoint, Transmit};
+use tokio_udp::UdpSocket;
+
+/// What we learned of a datagram received as part of a batch
+#[derive(Debug, Copy, Clone)]
+pub struct RecvMeta {
+    pub len: usize,
+    pub addr: SocketAddr,
+    pub ecn: Option<EcnCodepoint>,
+}
+
+impl Default for RecvMeta {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            ecn: None,
+        }
+    }
+}
+
+pub trait UdpExt {
+    /// Request that the ECN codepoints of incoming datagrams be reported
+    fn init_ext(&self) -> io::Result<()>;
+    fn poll_send_ext(
+        &self,
+        remote: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        msg: &[u8],
+    ) -> Poll<usize, io::Error>;
+    fn poll_recv_ext(
+        &self,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error>;
+    /// Send datagrams from the start of `transmits`, returning how many were sent
+    fn poll_send_batch_ext(&self, transmits: &[Transmit]) -> Poll<usize, io::Error>;
+    /// Receive up to `meta.len()` datagrams into consecutive equal slices of `buf`, returning how many were received
+    fn poll_recv_batch_ext(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> Poll<usize, io::Error>;
+    /// Most datagrams a single `Transmit` may carry using segmentation offload, 1 if it's unsupported
+    fn max_gso_segments(&self) -> usize;
+}
+
+/// Largest buffer to hand the kernel for segmentation, clear of the 64KiB limit on a single IP packet
+const MAX_GSO_BYTES: usize = 64_000;
+
+/// Merge runs of datagrams that can be sent together with segmentation offload
+///
+/// A run shares a destination and ECN codepoint, holds at most `max_segments` datagrams, and every datagram in it but
+/// the last is the same size as the first.
+pub fn coalesce(transmits: &mut VecDeque<Transmit>, max_segments: usize) {
+    let mut old = mem::replace(transmits, VecDeque::new())
+        .into_iter()
+        .peekable();
+    while let Some(first) = old.next() {
+        if first.segment_size.is_some() {
+            transmits.push_back(first);
+            continue;
+        }
+        let size = first.packet.len();
+        let mut run = Vec::new();
+        while run.len() + 1 < max_segments && (run.len() + 2) * size <= MAX_GSO_BYTES {
+            let fits = match old.peek() {
+                Some(x) => {
+                    x.segment_size.is_none()
+                        && x.destination == first.destination
+                        && x.ecn == first.ecn
+                        && x.packet.len() <= size
+                }
+                None => false,
+            };
+            if !fits {
+                break;
+            }
+            let x = old.next().unwrap();
+            let short = x.packet.len() < size;
+            run.push(x);
+            if short {
+                // Only the last datagram may be shorter
+                break;
+            }
+        }
+        if run.is_empty() {
+            transmits.push_back(first);
+            continue;
+        }
+        let mut packet = Vec::with_capacity(size * (run.len() + 1));
+        packet.extend_from_slice(&first.packet);
+        for x in &run {
+            packet.extend_from_slice(&x.packet);
+        }
+        transmits.push_back(Transmit {
+            destination: first.destination,
+            ecn: first.ecn,
+            packet: packet.into_boxed_slice(),
+            segment_size: Some(size as u16),
+        });
+    }
+}
+
+/// Undo `coalesce`, so that every transmit carries a single datagram
+pub fn split(transmits: &mut VecDeque<Transmit>) {
+    for x in mem::replace(transmits, VecDeque::new()) {
+        let size = match x.segment_size {
+            Some(size) => size as usize,
+            None => {
+                transmits.push_back(x);
+                continue;
+            }
+        };
+        for packet in x.packet.chunks(size) {
+            transmits.push_back(Transmit {
+                destination: x.destination,
+                ecn: x.ecn,
+                packet: packet.into(),
+                segment_size: None,
+            });
+        }
+    }
+}
+
+/// Whether a failed send may be due to segmentation offload being refused
+///
+/// Linux reports `EINVAL` for segment sizes it won't accept, and `EIO` when the device can't checksum the segments.
+pub fn is_gso_error(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::EIO) => true,
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::imp::bind_reuse_port;
+
+/// Bind a UDP socket to `addr` that other sockets in any process of the same user may bind to as well
+#[cfg(not(target_os = "linux"))]
+pub fn bind_reuse_port(_: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::ptr;
+
+    use libc;
+    use mio::Ready;
+
+    use super::*;
+
+    // Not yet exposed by the libc crate
+    const SOL_UDP: libc::c_int = 17;
+    const UDP_SEGMENT: libc::c_int = 103;
+    /// Most segments the kernel will split one send into
+    const MAX_SEGMENTS: usize = 64;
+
+    impl UdpExt for UdpSocket {
+        fn init_ext(&self) -> io::Result<()> {
+            let fd = self.as_raw_fd();
+            if self.local_addr()?.is_ipv4() {
+                set_opt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS)?;
+            } else {
+                set_opt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)?;
+                // Dual-stack sockets receive IPv4 datagrams too; failure just means we can't see their codepoints
+                let _ = set_opt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS);
+            }
+            Ok(())
+        }
+
+        fn poll_send_ext(
+            &self,
+            remote: &SocketAddr,
+            ecn: Option<EcnCodepoint>,
+            msg: &[u8],
+        ) -> Poll<usize, io::Error> {
+            match self.poll_write_ready()? {
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    return Ok(Async::NotReady);
+                }
+            }
+            match send(self.as_raw_fd(), remote, ecn, msg) {
+                Ok(n) => Ok(Async::Ready(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_write_ready()?;
+                    Ok(Async::NotReady)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        fn poll_recv_ext(
+            &self,
+            buf: &mut [u8],
+        ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+            match self.poll_read_ready(Ready::readable())? {
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    return Ok(Async::NotReady);
+                }
+            }
+            match recv(self.as_raw_fd(), buf) {
+                Ok(x) => Ok(Async::Ready(x)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_read_ready(Ready::readable())?;
+                    Ok(Async::NotReady)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        fn poll_send_batch_ext(&self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
+            match self.poll_write_ready()? {
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    return Ok(Async::NotReady);
+                }
+            }
+            match send_batch(self.as_raw_fd(), transmits) {
+                Ok(n) => Ok(Async::Ready(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_write_ready()?;
+                    Ok(Async::NotReady)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        fn poll_recv_batch_ext(
+            &self,
+            buf: &mut [u8],
+            meta: &mut [RecvMeta],
+        ) -> Poll<usize, io::Error> {
+            match self.poll_read_ready(Ready::readable())? {
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    return Ok(Async::NotReady);
+                }
+            }
+            match recv_batch(self.as_raw_fd(), buf, meta) {
+                Ok(n) => Ok(Async::Ready(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_read_ready(Ready::readable())?;
+                    Ok(Async::NotReady)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        fn max_gso_segments(&self) -> usize {
+            // Kernels that predate segmentation offload don't know the option
+            let mut size: libc::c_int = 0;
+            let mut len = mem::size_of_val(&size) as libc::socklen_t;
+            let rc = unsafe {
+                libc::getsockopt(
+                    self.as_raw_fd(),
+                    SOL_UDP,
+                    UDP_SEGMENT,
+                    &mut size as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if rc == -1 {
+                1
+            } else {
+                MAX_SEGMENTS
+            }
+        }
+    }
+
+    /// Bind a UDP socket to `addr` that other sockets in any process of the same user may bind to as well
+    pub fn bind_reuse_port(addr: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let family = if addr.is_ipv4() {
+            libc::AF_INET
+        } else {
+            libc::AF_INET6
+        };
+        let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owning the descriptor from the start closes it if anything below fails
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+        set_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT)?;
+        let (name, len) = encode_addr(addr);
+        let rc = unsafe { libc::bind(fd, &name as *const _ as *const libc::sockaddr, len) };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    fn set_opt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let rc = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &on as *const _ as *const libc::c_void,
+                mem::size_of_val(&on) as libc::socklen_t,
+            )
+        };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Room for a `c_int` and a `u16` control message, with `u64` elements for alignment
+    type ControlBuffer = [u64; 8];
+
+    fn send(
+        fd: libc::c_int,
+        remote: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        msg: &[u8],
+    ) -> io::Result<usize> {
+        let mut name = encode_addr(remote);
+        let mut iov = libc::iovec {
+            iov_base: msg.as_ptr() as *mut libc::c_void,
+            iov_len: msg.len(),
+        };
+        let mut ctrl: ControlBuffer = [0; 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        prepare_send(&mut hdr, &mut name, &mut iov, &mut ctrl, remote, ecn, None);
+        let n = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn send_batch(fd: libc::c_int, transmits: &[Transmit]) -> io::Result<usize> {
+        // The headers point into these, so they mustn't move until the call completes
+        let mut names = Vec::with_capacity(transmits.len());
+        let mut iovs = Vec::with_capacity(transmits.len());
+        let mut ctrls: Vec<ControlBuffer> = vec![[0; 8]; transmits.len()];
+        for x in transmits {
+            names.push(encode_addr(&x.destination.into()));
+            iovs.push(libc::iovec {
+                iov_base: x.packet.as_ptr() as *mut libc::c_void,
+                iov_len: x.packet.len(),
+            });
+        }
+        let mut msgs: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; transmits.len()];
+        for (i, x) in transmits.iter().enumerate() {
+            prepare_send(
+                &mut msgs[i].msg_hdr,
+                &mut names[i],
+                &mut iovs[i],
+                &mut ctrls[i],
+                &x.destination.into(),
+                x.ecn,
+                x.segment_size,
+            );
+        }
+        let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as libc::c_uint, 0) };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Address `hdr` to the encoded `name` and point it at the datagrams in `iov`
+    ///
+    /// They're marked with `ecn`, and split into `segment_size` pieces by segmentation offload, using control messages
+    /// written to `ctrl`.
+    fn prepare_send(
+        hdr: &mut libc::msghdr,
+        name: &mut (libc::sockaddr_storage, libc::socklen_t),
+        iov: &mut libc::iovec,
+        ctrl: &mut ControlBuffer,
+        remote: &SocketAddr,
+        ecn: Option<EcnCodepoint>,
+        segment_size: Option<u16>,
+    ) {
+        hdr.msg_name = &mut name.0 as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = name.1;
+        hdr.msg_iov = iov;
+        hdr.msg_iovlen = 1;
+        let ecn_len = mem::size_of::<libc::c_int>() as libc::c_uint;
+        let segment_len = mem::size_of::<u16>() as libc::c_uint;
+        let mut controllen = 0;
+        if ecn.is_some() {
+            controllen += unsafe { libc::CMSG_SPACE(ecn_len) };
+        }
+        if segment_size.is_some() {
+            controllen += unsafe { libc::CMSG_SPACE(segment_len) };
+        }
+        if controllen == 0 {
+            return;
+        }
+        hdr.msg_control = ctrl.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = controllen as _;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&*hdr);
+            if let Some(ecn) = ecn {
+                let (level, ty) = if remote.is_ipv4() {
+                    (libc::IPPROTO_IP, libc::IP_TOS)
+                } else {
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+                };
+                (*cmsg).cmsg_level = level;
+                (*cmsg).cmsg_type = ty;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(ecn_len) as _;
+                ptr::write_unaligned(
+                    libc::CMSG_DATA(cmsg) as *mut libc::c_int,
+                    ecn as libc::c_int,
+                );
+                cmsg = libc::CMSG_NXTHDR(&*hdr, cmsg);
+            }
+            if let Some(size) = segment_size {
+                (*cmsg).cmsg_level = SOL_UDP;
+                (*cmsg).cmsg_type = UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(segment_len) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+            }
+        }
+    }
+
+    fn recv(
+        fd: libc::c_int,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<EcnCodepoint>)> {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut ctrl: ControlBuffer = [0; 8];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        prepare_recv(&mut hdr, &mut name, &mut iov, &mut ctrl);
+        let n = unsafe { libc::recvmsg(fd, &mut hdr, 0) };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize, decode_addr(&name), decode_ecn(&hdr)))
+    }
+
+    fn recv_batch(fd: libc::c_int, buf: &mut [u8], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let slot = buf.len() / meta.len();
+        // The headers point into these, so they mustn't move until the call completes
+        let mut names: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; meta.len()];
+        let mut iovs = buf
+            .chunks_mut(slot)
+            .take(meta.len())
+            .map(|x| libc::iovec {
+                iov_base: x.as_mut_ptr() as *mut libc::c_void,
+                iov_len: x.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut ctrls: Vec<ControlBuffer> = vec![[0; 8]; meta.len()];
+        let mut msgs: Vec<libc::mmsghdr> = vec![unsafe { mem::zeroed() }; meta.len()];
+        for (((msg, name), iov), ctrl) in msgs
+            .iter_mut()
+            .zip(&mut names)
+            .zip(&mut iovs)
+            .zip(&mut ctrls)
+        {
+            prepare_recv(&mut msg.msg_hdr, name, iov, ctrl);
+        }
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if n == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        for (meta, (msg, name)) in meta
+            .iter_mut()
+            .zip(msgs.iter().zip(&names))
+            .take(n as usize)
+        {
+            *meta = RecvMeta {
+                len: msg.msg_len as usize,
+                addr: decode_addr(name),
+                ecn: decode_ecn(&msg.msg_hdr),
+            };
+        }
+        Ok(n as usize)
+    }
+
+    /// Point `hdr` at buffers to receive a datagram, its source address, and its ECN codepoint into
+    fn prepare_recv(
+        hdr: &mut libc::msghdr,
+        name: &mut libc::sockaddr_storage,
+        iov: &mut libc::iovec,
+        ctrl: &mut ControlBuffer,
+    ) {
+        hdr.msg_name = name as *mut _ as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = ctrl.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of::<ControlBuffer>() as _;
+    }
+
+    /// The ECN codepoint reported in the control messages of a received `hdr`, if any
+    fn decode_ecn(hdr: &libc::msghdr) -> Option<EcnCodepoint> {
+        let mut ecn = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    // IP_TOS is reported as a single byte, but IPV6_TCLASS as an int
+                    (libc::IPPROTO_IP, libc::IP_TOS) => {
+                        ecn = EcnCodepoint::from_bits(*data);
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                        let tclass = ptr::read_unaligned(data as *const libc::c_int);
+                        ecn = EcnCodepoint::from_bits(tclass as u8);
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        ecn
+    }
+
+    fn encode_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match *addr {
+            SocketAddr::V4(ref addr) => {
+                let out = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                out.sin_family = libc::AF_INET as libc::sa_family_t;
+                out.sin_port = addr.port().to_be();
+                out.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(ref addr) => {
+                let out = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                out.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                out.sin6_port = addr.port().to_be();
+                out.sin6_flowinfo = addr.flowinfo();
+                out.sin6_addr.s6_addr = addr.ip().octets();
+                out.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    fn decode_addr(storage: &libc::sockaddr_storage) -> SocketAddr {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                ))
+            }
+            _ => unreachable!("unexpected address family"),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl UdpExt for UdpSocket {
+    fn init_ext(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll_send_ext(
+        &self,
+        remote: &SocketAddr,
+        _: Option<EcnCodepoint>,
+        msg: &[u8],
+    ) -> Poll<usize, io::Error> {
+        self.poll_send_to(msg, remote)
+    }
+
+    fn poll_recv_ext(
+        &self,
+        buf: &mut [u8],
+    ) -> Poll<(usize, SocketAddr, Option<EcnCodepoint>), io::Error> {
+        match self.poll_recv_from(buf)? {
+            Async::Ready((n, addr)) => Ok(Async::Ready((n, addr, None))),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+
+    fn poll_send_batch_ext(&self, transmits: &[Transmit]) -> Poll<usize, io::Error> {
+        let mut sent = 0;
+        for x in transmits {
+            match self.poll_send_ext(&x.destination.into(), x.ecn, &x.packet) {
+                Ok(Async::Ready(_)) => {
+                    sent += 1;
+                }
+                Ok(Async::NotReady) if sent == 0 => return Ok(Async::NotReady),
+                Err(e) => {
+                    if sent == 0 {
+                        return Err(e);
+                    }
+                    // Report what got out; the error will recur on the next call
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+        Ok(Async::Ready(sent))
+    }
+
+    fn poll_recv_batch_ext(&self, buf: &mut [u8], meta: &mut [RecvMeta]) -> Poll<usize, io::Error> {
+        let slot = buf.len() / meta.len();
+        let mut received = 0;
+        for (buf, meta) in buf.chunks_mut(slot).zip(meta.iter_mut()) {
+            match self.poll_recv_ext(buf) {
+                Ok(Async::Ready((len, addr, ecn))) => {
+                    *meta = RecvMeta { len, addr, ecn };
+                    received += 1;
+                }
+                Ok(Async::NotReady) if received == 0 => return Ok(Async::NotReady),
+                Err(e) => {
+                    if received == 0 {
+                        return Err(e);
+                    }
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+            }
+        }
+        Ok(Async::Ready(received))
+    }
+
+    fn max_gso_segments(&self) -> usize {
+        1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddrV6;
+
+    fn transmit(destination: &str, ecn: Option<EcnCodepoint>, len: usize) -> Transmit {
+        Transmit {
+            destination: destination.parse().unwrap(),
+            ecn,
+            // Distinguish each datagram's contents, so misplaced bytes show up
+            packet: (0..len).map(|x| x as u8).collect::<Vec<_>>().into(),
+            segment_size: None,
+        }
+    }
+
+    /// Number of datagrams in each transmit, and their segment sizes
+    fn shape(transmits: &VecDeque<Transmit>) -> Vec<(usize, Option<u16>)> {
+        transmits
+            .iter()
+            .map(|x| (x.packet.len(), x.segment_size))
+            .collect()
+    }
+
+    const A: &str = "[::1]:4433";
+    const B: &str = "[::2]:4433";
+
+    #[test]
+    fn short_last_segment() {
+        let mut transmits = [100, 100, 50, 100]
+            .iter()
+            .map(|&len| transmit(A, None, len))
+            .collect();
+        coalesce(&mut transmits, 64);
+        // Nothing may follow a short datagram in the same run
+        assert_eq!(shape(&transmits), [(250, Some(100)), (100, None)]);
+        assert_eq!(
+            &transmits[0].packet[200..],
+            &transmit(A, None, 50).packet[..]
+        );
+    }
+
+    #[test]
+    fn boundaries() {
+        let ect0 = Some(EcnCodepoint::ECT0);
+        let mut transmits = VecDeque::from(vec![
+            transmit(A, None, 100),
+            transmit(A, None, 100),
+            // New ECN codepoint
+            transmit(A, ect0, 100),
+            transmit(A, ect0, 100),
+            // New destination
+            transmit(B, ect0, 100),
+            // Larger than the first of the run
+            transmit(B, ect0, 200),
+        ]);
+        coalesce(&mut transmits, 64);
+        assert_eq!(
+            shape(&transmits),
+            [(200, Some(100)), (200, Some(100)), (100, None), (200, None)]
+        );
+        assert_eq!(transmits[0].ecn, None);
+        assert_eq!(transmits[1].ecn, ect0);
+        let b: SocketAddrV6 = B.parse().unwrap();
+        assert_eq!(transmits[2].destination, b);
+    }
+
+    #[test]
+    fn segment_limit() {
+        let mut transmits = (0..70).map(|_| transmit(A, None, 100)).collect();
+        coalesce(&mut transmits, 64);
+        assert_eq!(shape(&transmits), [(6400, Some(100)), (600, Some(100))]);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut transmits = (0..60).map(|_| transmit(A, None, 1200)).collect();
+        coalesce(&mut transmits, 64);
+        // 53 datagrams of 1200 bytes fit in MAX_GSO_BYTES, 54 wouldn't
+        assert_eq!(
+            shape(&transmits),
+            [(53 * 1200, Some(1200)), (7 * 1200, Some(1200))]
+        );
+        assert!(transmits.iter().all(|x| x.packet.len() <= MAX_GSO_BYTES));
+    }
+
+    fn datagrams() -> Vec<Transmit> {
+        vec![
+            transmit(A, None, 100),
+            transmit(A, None, 100),
+            transmit(A, None, 30),
+            transmit(B, Some(EcnCodepoint::CE), 80),
+            transmit(A, None, 100),
+        ]
+    }
+
+    #[test]
+    fn split_roundtrip() {
+        let original = datagrams();
+        let mut transmits = VecDeque::from(datagrams());
+        coalesce(&mut transmits, 64);
+        assert_eq!(transmits.len(), 3);
+        split(&mut transmits);
+        assert_eq!(transmits.len(), original.len());
+        for (x, y) in transmits.iter().zip(&original) {
+            assert_eq!(x.destination, y.destination);
+            assert_eq!(x.ecn, y.ecn);
+            assert_eq!(x.packet, y.packet);
+            assert_eq!(x.segment_size, None);
+        }
+    }
+}
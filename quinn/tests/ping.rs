@@ -0,0 +1,77 @@
+//! Probing the peer with `Connection::ping`
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{Future, Stream};
+use rustls::internal::pemfile;
+use tokio::runtime::current_thread::Runtime;
+
+/// Connect a client to a server on the loopback interface, returning the client's and the server's ends
+fn connect(runtime: &mut Runtime) -> (quinn::NewClientConnection, quinn::NewConnection) {
+    let key = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.rsa").unwrap());
+        pemfile::rsa_private_keys(&mut reader).unwrap().remove(0)
+    };
+    let cert_chain = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.chain").unwrap());
+        pemfile::certs(&mut reader).unwrap()
+    };
+    let mut builder = quinn::Endpoint::new();
+    builder.listen();
+    builder.set_certificate(cert_chain, key).unwrap();
+    let (server, driver, incoming) = builder.bind("[::]:0").unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("server I/O failed: {}", e)));
+    let server_addr: SocketAddr = ([127, 0, 0, 1], server.local_addr().unwrap().port()).into();
+
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .add_certificate_authority(&fs::read("../certs/ca.der").unwrap())
+        .unwrap();
+    let (client, driver, _) = builder.bind("[::]:0").unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("client I/O failed: {}", e)));
+
+    let (client_conn, (server_conn, _)) = runtime
+        .block_on(
+            client
+                .connect(&server_addr, "localhost")
+                .unwrap()
+                .map_err(|e| format!("failed to connect: {}", e))
+                .join(
+                    incoming
+                        .into_future()
+                        .map_err(|_| "incoming connections failed".to_string()),
+                ),
+        )
+        .unwrap();
+    (client_conn, server_conn.expect("server didn't accept"))
+}
+
+#[test]
+fn ping_acknowledged() {
+    let mut runtime = Runtime::new().unwrap();
+    let (client_conn, server_conn) = connect(&mut runtime);
+    runtime.block_on(client_conn.connection.ping()).unwrap();
+    // Either side can ask
+    runtime.block_on(server_conn.connection.ping()).unwrap();
+}
+
+#[test]
+fn ping_after_close() {
+    let mut runtime = Runtime::new().unwrap();
+    let (client_conn, server_conn) = connect(&mut runtime);
+    runtime
+        .block_on(client_conn.connection.close(0, b"done"))
+        .unwrap();
+    // Wait for the server to find out
+    let _ = runtime.block_on(server_conn.incoming.into_future());
+    match runtime.block_on(server_conn.connection.ping()) {
+        Err(quinn::ConnectionError::ApplicationClosed { .. }) => {}
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
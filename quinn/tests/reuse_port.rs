@@ -0,0 +1,73 @@
+//! Several endpoints sharing a port with `SO_REUSEPORT`
+#![cfg(target_os = "linux")]
+
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+
+use futures::{future, Future, Stream};
+use rustls::internal::pemfile;
+use tokio::runtime::current_thread::Runtime;
+
+/// Enough clients that all of them hashing to the same endpoint is vanishingly unlikely
+const CLIENTS: usize = 32;
+
+fn server(runtime: &mut Runtime, addr: &str) -> (quinn::Endpoint, quinn::Incoming) {
+    let key = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.rsa").unwrap());
+        pemfile::rsa_private_keys(&mut reader).unwrap().remove(0)
+    };
+    let cert_chain = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.chain").unwrap());
+        pemfile::certs(&mut reader).unwrap()
+    };
+    let mut builder = quinn::Endpoint::new();
+    builder.listen().reuse_port(true);
+    builder.set_certificate(cert_chain, key).unwrap();
+    let (endpoint, driver, incoming) = builder.bind(addr).unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("server I/O failed: {}", e)));
+    (endpoint, incoming)
+}
+
+#[test]
+fn endpoints_share_port() {
+    let mut runtime = Runtime::new().unwrap();
+    let (first, first_incoming) = server(&mut runtime, "[::]:0");
+    let port = first.local_addr().unwrap().port();
+    let (_second, second_incoming) = server(&mut runtime, &format!("[::]:{}", port));
+
+    // Without the option, the port is taken
+    assert!(std::net::UdpSocket::bind(("::", port)).is_err());
+
+    let ca = fs::read("../certs/ca.der").unwrap();
+    let server_addr: SocketAddr = ([127, 0, 0, 1], port).into();
+    let clients = (0..CLIENTS)
+        .map(|_| {
+            // Each client gets its own socket, and hence source port, for the kernel to hash
+            let mut builder = quinn::Endpoint::new();
+            builder.add_certificate_authority(&ca).unwrap();
+            let (endpoint, driver, _) = builder.bind("[::]:0").unwrap();
+            runtime.spawn(driver.map_err(|e| panic!("client I/O failed: {}", e)));
+            endpoint.connect(&server_addr, "localhost").unwrap()
+        })
+        .collect::<Vec<_>>();
+    // Each endpoint accepts the connections the kernel hands it on its own
+    let accepted = first_incoming
+        .map(|conn| (0, conn))
+        .select(second_incoming.map(|conn| (1, conn)))
+        .take(CLIENTS as u64)
+        .collect()
+        .map_err(|()| unreachable!());
+    let (connected, accepted) = runtime
+        .block_on(future::join_all(clients).join(accepted))
+        .unwrap();
+
+    assert_eq!(connected.len(), CLIENTS);
+    assert!(accepted.iter().any(|&(i, _)| i == 0));
+    assert!(accepted.iter().any(|&(i, _)| i == 1));
+}
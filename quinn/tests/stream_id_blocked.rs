@@ -0,0 +1,84 @@
+//! Telling the peer we're blocked on its stream limit
+extern crate futures;
+extern crate quinn;
+extern crate rustls;
+extern crate tokio;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use rustls::internal::pemfile;
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
+
+#[test]
+fn blocked_open_notifies_peer() {
+    let mut runtime = Runtime::new().unwrap();
+
+    let key = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.rsa").unwrap());
+        pemfile::rsa_private_keys(&mut reader).unwrap().remove(0)
+    };
+    let cert_chain = {
+        let mut reader = io::BufReader::new(fs::File::open("../certs/server.chain").unwrap());
+        pemfile::certs(&mut reader).unwrap()
+    };
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .config(quinn::Config {
+            // Clients may not open any unidirectional streams
+            max_remote_uni_streams: 0,
+            ..Default::default()
+        })
+        .listen();
+    builder.set_certificate(cert_chain, key).unwrap();
+    let (server, driver, incoming) = builder.bind("[::]:0").unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("server I/O failed: {}", e)));
+    let server_addr: SocketAddr = ([127, 0, 0, 1], server.local_addr().unwrap().port()).into();
+
+    let mut builder = quinn::Endpoint::new();
+    builder
+        .add_certificate_authority(&fs::read("../certs/ca.der").unwrap())
+        .unwrap();
+    let (client, driver, _) = builder.bind("[::]:0").unwrap();
+    runtime.spawn(driver.map_err(|e| panic!("client I/O failed: {}", e)));
+
+    let (client_conn, (server_conn, _incoming)) = runtime
+        .block_on(
+            client
+                .connect(&server_addr, "localhost")
+                .unwrap()
+                .map_err(|e| format!("failed to connect: {}", e))
+                .join(
+                    incoming
+                        .into_future()
+                        .map_err(|_| "incoming connections failed".to_string()),
+                ),
+        )
+        .unwrap();
+    let server_conn = server_conn.expect("server didn't accept");
+
+    // Nothing else is sent once the handshake is over, so only the blocked open can make the client speak up
+    runtime.spawn(
+        client_conn
+            .connection
+            .open_uni()
+            .map(|_| ())
+            .map_err(|_| ()),
+    );
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server_conn
+        .connection
+        .flow_control_stats()
+        .stream_id_blocked_received
+        == 0
+    {
+        assert!(Instant::now() < deadline, "STREAM_ID_BLOCKED never arrived");
+        runtime
+            .block_on(Delay::new(Instant::now() + Duration::from_millis(10)))
+            .unwrap();
+    }
+}